@@ -0,0 +1,65 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use hash_db::storagev2::{
+    log::{CompressionLevel, Entry, EntryType},
+    page::PageInner,
+};
+
+const VALUE_SIZE: usize = 1024;
+const ENTRIES: usize = 64;
+
+// xorshift, good enough for incompressible-ish test data without pulling in rand
+fn random_values(n: usize) -> Vec<Vec<u8>> {
+    let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+    (0..n)
+        .map(|_| {
+            (0..VALUE_SIZE)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn write_entries(entries: &[Entry], level: Option<CompressionLevel>) {
+    let mut page = PageInner::new(0);
+    for entry in entries {
+        if page.write_entry(entry, level).is_err() {
+            page.reset();
+            page.write_entry(entry, level)
+                .expect("entry should fit in an empty page");
+        }
+    }
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let entries: Vec<_> = random_values(ENTRIES)
+        .iter()
+        .enumerate()
+        .map(|(i, v)| Entry::new(format!("key_{}", i).as_bytes(), v, EntryType::Put))
+        .collect();
+
+    let mut group = c.benchmark_group("write_entry");
+    group.throughput(Throughput::Bytes((ENTRIES * VALUE_SIZE) as u64));
+    group.bench_function("uncompressed", |b| {
+        b.iter_batched(
+            || &entries,
+            |e| write_entries(e, None),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("lz4", |b| {
+        b.iter_batched(
+            || &entries,
+            |e| write_entries(e, Some(CompressionLevel::Lz4)),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+use std::{io, thread};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hash_db::storagev2::{
+    disk::{Disk, DiskBackend, IoUringDisk},
+    page::PAGE_SIZE,
+    test::CleanUp,
+};
+use tokio::runtime::Runtime;
+
+const DB_FILE: &str = "./bench_disk_backend.db";
+const URING_FILE: &str = "./bench_disk_backend_uring.db";
+const PAGES: u32 = 1024;
+const TASKS: usize = 8;
+const READS: u32 = 128;
+
+fn fill(disk: &dyn DiskBackend) -> io::Result<()> {
+    for page_id in 0..PAGES {
+        disk.write_page_from(page_id, &[page_id as u8; PAGE_SIZE])?;
+    }
+
+    disk.sync()
+}
+
+// Every task reads its own stride of pages
+fn read_concurrently(disk: &dyn DiskBackend) {
+    thread::scope(|s| {
+        for task in 0..TASKS as u32 {
+            s.spawn(move || {
+                let mut buf = [0; PAGE_SIZE];
+                for i in 0..READS {
+                    let page_id = (task * 7919 + i * 131) % PAGES;
+                    disk.read_page_into(page_id, &mut buf)
+                        .expect("page should read");
+                }
+            });
+        }
+    });
+}
+
+fn bench_disk_backend(c: &mut Criterion) {
+    let _cu = CleanUp::segments(DB_FILE);
+    let _cu_uring = CleanUp::file(URING_FILE);
+
+    let rt = Runtime::new().expect("runtime should start");
+    let disk = rt.block_on(Disk::new(DB_FILE)).expect("disk should open");
+    let uring = IoUringDisk::open(URING_FILE).expect("io_uring disk should open");
+    fill(&disk).expect("disk should fill");
+    fill(&uring).expect("io_uring disk should fill");
+
+    let mut group = c.benchmark_group("concurrent_reads");
+    group.throughput(Throughput::Bytes(
+        (TASKS * READS as usize * PAGE_SIZE) as u64,
+    ));
+    group.bench_function("pread", |b| b.iter(|| read_concurrently(&disk)));
+    group.bench_function("io_uring", |b| b.iter(|| read_concurrently(&uring)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_disk_backend);
+criterion_main!(benches);
@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hash_db::storagev2::{
+    disk::{Disk, Durability},
+    page::PAGE_SIZE,
+    test::CleanUp,
+};
+use tokio::runtime::Runtime;
+
+const DB_FILE: &str = "./bench_durability.db";
+const PAGES: u32 = 64;
+
+fn write_pages(disk: &Disk, data: &[u8; PAGE_SIZE]) {
+    for page_id in 0..PAGES {
+        disk.write_page(page_id, data);
+    }
+}
+
+fn bench_durability(c: &mut Criterion) {
+    let _cu = CleanUp::segments(DB_FILE);
+    let rt = Runtime::new().expect("Couldn't start runtime");
+    let data = [0xAB; PAGE_SIZE];
+
+    let mut group = c.benchmark_group("write_page");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(PAGES as u64 * PAGE_SIZE as u64));
+    for (name, durability) in [
+        ("none", Durability::None),
+        ("fsync_data", Durability::FsyncData),
+        ("fsync", Durability::Fsync),
+    ] {
+        let disk = rt
+            .block_on(Disk::new(DB_FILE))
+            .expect("Couldn't open db file")
+            .with_durability(durability);
+
+        group.bench_function(name, |b| b.iter(|| write_pages(&disk, &data)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_durability);
+criterion_main!(benches);
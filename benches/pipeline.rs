@@ -0,0 +1,119 @@
+use std::{io, net::SocketAddr, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hash_db::{
+    serverv2::{connection::Connection, message::Message},
+    storagev2::{
+        disk::Disk,
+        key_dir::KeyDir,
+        page::Page,
+        page_manager::{PageCache, PageManagerBuilder},
+        test::CleanUp,
+        wal::WriteAheadLog,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    sync::RwLock,
+};
+
+const DB_FILE: &str = "./bench_pipeline.db";
+const WAL_FILE: &str = "./bench_pipeline.wal";
+const REQUESTS: usize = 200;
+// Same as redis-benchmark -P 10
+const PIPELINE: usize = 10;
+
+// Same loop as the server's accept_loop
+async fn serve(stream: TcpStream, depth: usize, pc: PageCache, kd: Arc<RwLock<KeyDir>>) {
+    let (reader, writer) = stream.into_split();
+    let mut conn = Connection::new_pipelined(BufReader::new(reader), BufWriter::new(writer), depth);
+
+    while let Ok(message) = conn.read().await {
+        let message = match message {
+            Some(Message::None) | None => continue,
+            Some(m) => m,
+        };
+
+        let res = message.exec(&pc, &kd).await;
+        if conn.write(res).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn start(depth: usize, pc: PageCache, kd: Arc<RwLock<KeyDir>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind");
+    let addr = listener.local_addr().expect("Should have an address");
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, depth, pc.clone(), kd.clone()));
+        }
+    });
+
+    addr
+}
+
+// Sends requests in batches of `PIPELINE`, reading every response of a batch before sending the
+// next one
+async fn client(stream: &mut TcpStream) -> io::Result<()> {
+    let batch = b"get key\n".repeat(PIPELINE);
+    let expected = b"key value\n".len() * PIPELINE;
+
+    let mut buf = vec![0; expected];
+    for _ in 0..REQUESTS / PIPELINE {
+        stream.write_all(&batch).await?;
+        stream.read_exact(&mut buf).await?;
+    }
+
+    Ok(())
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let _cu = CleanUp::segments(DB_FILE);
+    let _cu_wal = CleanUp::file(WAL_FILE);
+    let rt = Runtime::new().expect("Couldn't start runtime");
+
+    let (pc, kd) = rt.block_on(async {
+        let disk = Disk::new(DB_FILE).await.expect("Couldn't open db file");
+        let wal = WriteAheadLog::new(WAL_FILE)
+            .await
+            .expect("Couldn't open wal file");
+        let pc = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let insert = Message::Insert("key".into(), "value".into());
+        insert.exec(&pc, &kd).await;
+
+        (pc, kd)
+    });
+
+    let mut group = c.benchmark_group("pipeline");
+    // Unpipelined responses are small separate writes that stall on delayed acks, so a run takes
+    // long enough that a few samples are plenty
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    for depth in [1, PIPELINE] {
+        let mut stream = rt.block_on(async {
+            let addr = start(depth, pc.clone(), kd.clone()).await;
+            TcpStream::connect(addr).await.expect("Couldn't connect")
+        });
+
+        group.bench_function(format!("depth_{}", depth), |b| {
+            b.iter(|| {
+                rt.block_on(client(&mut stream))
+                    .expect("client should not fail")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pipeline);
+criterion_main!(benches);
@@ -0,0 +1,57 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hash_db::storagev2::{
+    log::{Entry, EntryType},
+    page::PageInner,
+};
+
+const VALUE_SIZE: usize = 64;
+
+// A page full of entries and where each one starts
+fn full_page() -> (PageInner, Vec<usize>) {
+    let mut page = PageInner::new(0);
+    let mut offsets = Vec::new();
+    for i in 0.. {
+        let entry = Entry::new(
+            format!("key_{}", i).as_bytes(),
+            &[b'v'; VALUE_SIZE],
+            EntryType::Put,
+        );
+        match page.write_entry(&entry, None) {
+            Ok(offset) => offsets.push(offset as usize),
+            Err(_) => break,
+        }
+    }
+
+    (page, offsets)
+}
+
+fn bench_read_entry(c: &mut Criterion) {
+    let (page, offsets) = full_page();
+
+    let mut group = c.benchmark_group("read_entry");
+    group.throughput(Throughput::Elements(offsets.len() as u64));
+    group.bench_function("copy", |b| {
+        b.iter(|| {
+            for offset in &offsets {
+                let entry = page.read_entry_raw(*offset).expect("entry should read");
+                black_box(entry.value.len());
+            }
+        })
+    });
+    group.bench_function("zerocopy", |b| {
+        b.iter(|| {
+            for offset in &offsets {
+                let entry = page
+                    .read_entry_zerocopy(*offset)
+                    .expect("entry should read");
+                black_box(entry.value.len());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_read_entry);
+criterion_main!(benches);
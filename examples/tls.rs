@@ -0,0 +1,85 @@
+//! Runs the server behind TLS and talks to it with a client that trusts the same self-signed
+//! certificate.
+//!
+//! Generate a certificate for localhost first:
+//!
+//! ```text
+//! openssl req -x509 -newkey rsa:2048 -nodes -days 365 \
+//!     -keyout key.pem -out cert.pem \
+//!     -subj /CN=localhost -addext subjectAltName=DNS:localhost \
+//!     -addext basicConstraints=critical,CA:FALSE
+//! cargo run --example tls -- cert.pem key.pem
+//! ```
+//!
+//! The server keeps running after the client is done, so it can also be reached with
+//! `openssl s_client -connect localhost:4444 -CAfile cert.pem`.
+use std::{env, io, sync::Arc, time::Duration};
+
+use hash_db::serverv2::{
+    server::{self, ServerConfig},
+    tls::TlsConfig,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let mut args = env::args().skip(1);
+    let (cert_path, key_path) = match (args.next(), args.next()) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => {
+            eprintln!("usage: tls <cert.pem> <key.pem>");
+            std::process::exit(1);
+        }
+    };
+
+    let server = tokio::spawn(server::run(
+        Some(TlsConfig::new(&cert_path, &key_path)),
+        None,
+        ServerConfig::default(),
+    ));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&cert_path).expect("Couldn't read cert") {
+        roots
+            .add(cert.expect("Couldn't parse cert"))
+            .expect("Couldn't trust cert");
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .expect("Couldn't pick protocol versions")
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    let stream = TcpStream::connect("localhost:4444").await?;
+    let domain = ServerName::try_from("localhost").expect("Invalid server name");
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(domain, stream)
+        .await?;
+    let mut stream = BufReader::new(stream);
+
+    for request in ["insert tls over tls\n", "get tls\n"] {
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        print!("> {}< {}", request, line);
+    }
+
+    server.await.expect("Server panicked");
+
+    Ok(())
+}
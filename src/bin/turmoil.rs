@@ -37,6 +37,7 @@ fn generate_inserts(range: Range<u16>) -> HashMap<String, String> {
 }
 
 const DB_FILE: &str = "main.db";
+const WAL_FILE: &str = "main.wal";
 
 struct Client<T: ToSocketAddrs> {
     id: u8,
@@ -56,7 +57,7 @@ impl<T: ToSocketAddrs> Client<T> {
         let mut buf = [0; 256];
         for (k, v) in &inserts {
             socket
-                .write(format!("insert {} {}\n", k, v).as_bytes())
+                .write_all(format!("insert {} {}\n", k, v).as_bytes())
                 .await?;
             socket.flush().await?;
 
@@ -66,7 +67,7 @@ impl<T: ToSocketAddrs> Client<T> {
         }
 
         for (k, v) in &inserts {
-            socket.write(format!("get {}\n", k).as_bytes()).await?;
+            socket.write_all(format!("get {}\n", k).as_bytes()).await?;
             socket.flush().await?;
 
             let n = socket.read(&mut buf).await?;
@@ -88,14 +89,15 @@ impl<T: ToSocketAddrs> Client<T> {
 
 #[tokio::main]
 pub async fn main() -> io::Result<()> {
-    let _cu = CleanUp::file(DB_FILE);
+    let _cu = CleanUp::segments(DB_FILE);
+    let _cu_wal = CleanUp::file(WAL_FILE);
 
     let notify = Arc::new(Notify::new());
 
     let sh_notify = notify.clone();
     tokio::spawn(async move {
         tokio::select! {
-            _ = hash_db::serverv2::server::run() => {}
+            _ = hash_db::serverv2::server::run(None, None, Default::default()) => {}
             _ = sh_notify.notified() => {
                 eprintln!("shutting down server");
             }
@@ -1,6 +1,80 @@
-use hash_db::serverv2::server;
+use std::time::Duration;
+
+use hash_db::serverv2::{
+    auth::Authenticator,
+    server::{self, ServerConfig},
+};
 
 #[tokio::main]
 async fn main() {
-    server::run().await
+    // Tokens to require, see serverv2::auth for the format
+    let auth = std::env::var_os("HASH_DB_AUTH")
+        .map(|path| Authenticator::from_file(path).expect("Failed to load auth config"));
+
+    let mut config = ServerConfig::new();
+    // Seconds between sweeps for expired keys
+    if let Ok(secs) = std::env::var("HASH_DB_SWEEP_INTERVAL") {
+        let secs = secs
+            .parse()
+            .expect("HASH_DB_SWEEP_INTERVAL should be a number of seconds");
+        config = config.sweep_interval(Duration::from_secs(secs));
+    }
+    if let Ok(limit) = std::env::var("HASH_DB_KEYS_LIMIT") {
+        let limit = limit
+            .parse()
+            .expect("HASH_DB_KEYS_LIMIT should be a number of keys");
+        config = config.keys_limit(limit);
+    }
+    if let Ok(max) = std::env::var("HASH_DB_DATABASES") {
+        let max = max
+            .parse()
+            .expect("HASH_DB_DATABASES should be a number of databases");
+        config = config.max_databases(max);
+    }
+    if let Ok(max) = std::env::var("HASH_DB_MAX_CONNECTIONS") {
+        let max = max
+            .parse()
+            .expect("HASH_DB_MAX_CONNECTIONS should be a number of connections");
+        config = config.max_connections(max);
+    }
+    if let Ok(max) = std::env::var("HASH_DB_MAX_KEY_SIZE") {
+        let max = max
+            .parse()
+            .expect("HASH_DB_MAX_KEY_SIZE should be a number of bytes");
+        config = config.max_key_size(max);
+    }
+    if let Ok(max) = std::env::var("HASH_DB_MAX_VALUE_SIZE") {
+        let max = max
+            .parse()
+            .expect("HASH_DB_MAX_VALUE_SIZE should be a number of bytes");
+        config = config.max_value_size(max);
+    }
+    if let Ok(us) = std::env::var("HASH_DB_SLOWLOG_THRESHOLD") {
+        let us = us
+            .parse()
+            .expect("HASH_DB_SLOWLOG_THRESHOLD should be a number of microseconds");
+        config = config.slowlog_threshold_us(us);
+    }
+    if let Ok(len) = std::env::var("HASH_DB_SLOWLOG_LEN") {
+        let len = len
+            .parse()
+            .expect("HASH_DB_SLOWLOG_LEN should be a number of entries");
+        config = config.slowlog_max_len(len);
+    }
+    // Seconds a connection may go without sending anything, 0 or unset for no limit
+    if let Ok(secs) = std::env::var("HASH_DB_IDLE_TIMEOUT") {
+        let secs = secs
+            .parse()
+            .expect("HASH_DB_IDLE_TIMEOUT should be a number of seconds");
+        config = config.idle_timeout(Duration::from_secs(secs));
+    }
+    // Off unless set to true, see ServerConfig::debug_commands_enabled
+    if let Ok(enabled) = std::env::var("HASH_DB_DEBUG_COMMANDS") {
+        let enabled = enabled
+            .parse()
+            .expect("HASH_DB_DEBUG_COMMANDS should be true or false");
+        config = config.debug_commands_enabled(enabled);
+    }
+
+    server::run(None, auth, config).await
 }
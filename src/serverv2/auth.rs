@@ -0,0 +1,208 @@
+//! Pre-shared token authentication. Tokens are read from a TOML file with one table per
+//! identity:
+//!
+//! ```toml
+//! [alice]
+//! token = "s3cret"
+//! permissions = ["read", "write"]
+//!
+//! [metrics]
+//! token = "r3adonly"
+//! permissions = ["read"]
+//! ```
+//!
+//! A connection sends `auth <token>` (or `AUTH <token>` over RESP3) before anything else, every
+//! other command gets an error until it does.
+
+use std::{collections::HashMap, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::serverv2::message::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl Permission {
+    /// The permission needed to run `m`, `None` if anyone who is authenticated can.
+    pub fn required(m: &Message) -> Option<Permission> {
+        match m {
+            Message::Get(_)
+            | Message::GetRange(_, _, _)
+            | Message::GetBit(_, _)
+            | Message::BitCount(_, _)
+            | Message::MGet(_)
+            | Message::Scan(_, _)
+            | Message::Keys(_, _)
+            | Message::KeyScan(_, _)
+            | Message::Ttl(_)
+            | Message::ObjectEncoding(_)
+            | Message::ObjectFreq(_)
+            | Message::ObjectIdleTime(_)
+            | Message::ObjectRefCount(_)
+            | Message::Type(_)
+            | Message::Strlen(_)
+            | Message::LLen(_)
+            | Message::SnapshotCreate
+            | Message::SnapshotGet(_, _)
+            | Message::Subscribe(_)
+            | Message::Stats
+            | Message::DbSize
+            | Message::SlowlogGet(_)
+            | Message::ClientList
+            | Message::ReplStream
+            | Message::Info => Some(Permission::Read),
+            Message::Insert(_, _)
+            | Message::MSet(_)
+            | Message::Delete(_)
+            | Message::FlushDb(_)
+            | Message::Incr(_)
+            | Message::IncrBy(_, _)
+            | Message::Decr(_)
+            | Message::DecrBy(_, _)
+            | Message::GetSet(_, _)
+            | Message::SetNx(_, _)
+            | Message::BitOp(_, _, _)
+            | Message::SetBit(_, _, _)
+            | Message::CompareAndSwap(_, _, _)
+            | Message::SetEx(_, _, _)
+            | Message::PSetEx(_, _, _)
+            | Message::Copy(_, _, _)
+            | Message::Rename(_, _)
+            | Message::RenameNx(_, _)
+            | Message::GetDel(_)
+            | Message::GetEx(_, _)
+            | Message::Append(_, _)
+            | Message::SetRange(_, _, _)
+            | Message::Persist(_)
+            | Message::LPush(_, _)
+            | Message::RPush(_, _)
+            | Message::LPop(_)
+            | Message::RPop(_)
+            | Message::DebugReload
+            | Message::DebugChangeReplId
+            | Message::CacheEvict(_)
+            | Message::SlowlogReset
+            | Message::ClientKill(_)
+            | Message::ReplicaOf(_)
+            | Message::Expire(_, _)
+            | Message::Publish(_, _) => Some(Permission::Write),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IdentityConfig {
+    token: String,
+    #[serde(default)]
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    // token -> identity
+    tokens: HashMap<String, String>,
+    // identity -> what it is allowed to do
+    permissions: HashMap<String, Vec<Permission>>,
+}
+
+impl Authenticator {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_toml(s: &str) -> io::Result<Self> {
+        let config: HashMap<String, IdentityConfig> =
+            toml::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut auth = Self::default();
+        for (identity, c) in config {
+            if auth.tokens.insert(c.token, identity.clone()).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("token for {} is already in use", identity),
+                ));
+            }
+            auth.permissions.insert(identity, c.permissions);
+        }
+
+        Ok(auth)
+    }
+
+    /// Returns the identity `token` belongs to.
+    pub fn authenticate(&self, token: &[u8]) -> Option<&str> {
+        let token = std::str::from_utf8(token).ok()?;
+        self.tokens.get(token).map(String::as_str)
+    }
+
+    /// Checks that `identity` may run `m`, returning the error to respond with if not.
+    pub fn check(&self, identity: Option<&str>, m: &Message) -> Result<(), Message> {
+        if matches!(m, Message::Auth(_) | Message::Ignore(_) | Message::None) {
+            return Ok(());
+        }
+
+        let Some(identity) = identity else {
+            return Err(Message::Error("NOAUTH Authentication required".to_string()));
+        };
+
+        match Permission::required(m) {
+            Some(p)
+                if !self
+                    .permissions
+                    .get(identity)
+                    .is_some_and(|ps| ps.contains(&p)) =>
+            {
+                Err(Message::Error(format!(
+                    "NOPERM {} has no {:?} permission",
+                    identity, p
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::serverv2::{
+        auth::{Authenticator, Permission},
+        message::Message,
+    };
+
+    #[test]
+    fn test_permissions() {
+        let auth = Authenticator::from_toml(
+            r#"
+            [alice]
+            token = "s3cret"
+            permissions = ["read", "write"]
+
+            [metrics]
+            token = "r3adonly"
+            permissions = ["read"]
+            "#,
+        )
+        .expect("should parse config");
+
+        assert!(auth.authenticate(b"s3cret") == Some("alice"));
+        assert!(auth.authenticate(b"r3adonly") == Some("metrics"));
+        assert!(auth.authenticate(b"alice").is_none());
+
+        let get = Message::Get("a".into());
+        let insert = Message::Insert("a".into(), "1".into());
+        assert!(Permission::required(&get) == Some(Permission::Read));
+        assert!(Permission::required(&insert) == Some(Permission::Write));
+
+        assert!(auth.check(None, &get).is_err());
+        assert!(auth.check(Some("alice"), &get).is_ok());
+        assert!(auth.check(Some("alice"), &insert).is_ok());
+        assert!(auth.check(Some("metrics"), &get).is_ok());
+        assert!(auth.check(Some("metrics"), &insert).is_err());
+        assert!(auth.check(Some("metrics"), &Message::Multi).is_ok());
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use tokio::sync::Notify;
+
+/// What `client list` shows about a connection.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: SocketAddr,
+    /// Set by the client, see `valid_name`.
+    pub client_name: Option<String>,
+    pub db: usize,
+    /// Empty until the first command.
+    pub last_cmd: String,
+    pub created_at: Instant,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} addr={} name={} db={} age={} cmd={}",
+            self.id,
+            self.addr,
+            self.client_name.as_deref().unwrap_or(""),
+            self.db,
+            self.created_at.elapsed().as_secs(),
+            self.last_cmd
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Client {
+    info: ClientInfo,
+    killed: Arc<Notify>,
+}
+
+/// Every connection being served, by id. Ids start at 1 and aren't reused.
+#[derive(Debug, Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<u64, Client>>,
+    next_id: AtomicU64,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new connection, which stays listed until the handle is dropped.
+    pub fn register(&self, addr: SocketAddr) -> ClientHandle<'_> {
+        let id = self.next_id.fetch_add(1, Relaxed) + 1;
+        let killed = Arc::new(Notify::new());
+        let info = ClientInfo {
+            id,
+            addr,
+            client_name: None,
+            db: 0,
+            last_cmd: String::new(),
+            created_at: Instant::now(),
+        };
+        self.clients.lock().unwrap().insert(
+            id,
+            Client {
+                info,
+                killed: killed.clone(),
+            },
+        );
+
+        ClientHandle {
+            clients: self,
+            id,
+            killed,
+        }
+    }
+
+    /// Every connection, oldest first.
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let mut list: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| c.info.clone())
+            .collect();
+        list.sort_by_key(|info| info.id);
+
+        list
+    }
+
+    /// Tells the connection to close, returning whether there is one with that id. It closes
+    /// once it is done with the request it is on, if any.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.clients.lock().unwrap().get(&id) {
+            Some(client) => {
+                client.killed.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A connection's entry in `Clients`, removed on drop.
+#[derive(Debug)]
+pub struct ClientHandle<'a> {
+    clients: &'a Clients,
+    id: u64,
+    killed: Arc<Notify>,
+}
+
+impl ClientHandle<'_> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Records the command the connection ran last and the database it has selected.
+    pub fn update(&self, db: usize, command: &str) {
+        if let Some(client) = self.clients.clients.lock().unwrap().get_mut(&self.id) {
+            client.info.db = db;
+            command.clone_into(&mut client.info.last_cmd);
+        }
+    }
+
+    /// Names the connection, or takes its name away if `name` is empty. Returns whether the name
+    /// is valid, it isn't changed if not.
+    pub fn set_name(&self, name: &[u8]) -> bool {
+        let Some(name) = valid_name(name) else {
+            return false;
+        };
+        if let Some(client) = self.clients.clients.lock().unwrap().get_mut(&self.id) {
+            client.info.client_name = (!name.is_empty()).then(|| name.to_string());
+        }
+
+        true
+    }
+
+    pub fn name(&self) -> Option<String> {
+        let clients = self.clients.clients.lock().unwrap();
+        clients.get(&self.id)?.info.client_name.clone()
+    }
+
+    /// Resolves once the connection has been killed, right away if it already was.
+    pub async fn killed(&self) {
+        self.killed.notified().await
+    }
+}
+
+impl Drop for ClientHandle<'_> {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Names are printable ASCII without spaces, so a `client list` line stays one line that splits
+/// on spaces.
+fn valid_name(name: &[u8]) -> Option<&str> {
+    match name.iter().all(|b| b.is_ascii_graphic()) {
+        true => std::str::from_utf8(name).ok(),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::serverv2::client::Clients;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_clients() {
+        let clients = Clients::new();
+        let addr: SocketAddr = "127.0.0.1:5000".parse().expect("valid address");
+
+        let first = clients.register(addr);
+        let second = clients.register(addr);
+        second.update(1, "get");
+        assert!(second.set_name(b"worker-1"));
+        assert!(!second.set_name(b"has space") && !second.set_name(b"new\nline"));
+        assert!(second.name().as_deref() == Some("worker-1"));
+        let got: Vec<_> = clients
+            .list()
+            .into_iter()
+            .map(|c| (c.id, c.db, c.last_cmd))
+            .collect();
+        let expected = vec![(1, 0, String::new()), (2, 1, "get".to_string())];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = clients.list()[1].to_string();
+        let expected = "id=2 addr=127.0.0.1:5000 name=worker-1 db=1 age=0 cmd=get";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(second.set_name(b"") && second.name().is_none());
+
+        assert!(clients.kill(first.id()));
+        first.killed().await;
+        assert!(!clients.kill(3));
+
+        drop(first);
+        assert!(clients.len() == 1 && clients.list()[0].id == 2);
+        drop(second);
+        assert!(clients.is_empty());
+    }
+}
@@ -0,0 +1,119 @@
+/// What `command info` says about a command.
+#[derive(Debug, PartialEq)]
+pub struct CommandSpec {
+    /// Lowercase, subcommands named `container|subcommand` as in Redis.
+    pub name: &'static str,
+    /// Arguments including the name itself, negative if that many or more.
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+}
+
+const fn spec(name: &'static str, arity: i64, flags: &'static [&'static str]) -> CommandSpec {
+    CommandSpec { name, arity, flags }
+}
+
+const WRITE: &[&str] = &["write"];
+const WRITE_FAST: &[&str] = &["write", "fast"];
+const READONLY: &[&str] = &["readonly"];
+const READONLY_FAST: &[&str] = &["readonly", "fast"];
+const FAST: &[&str] = &["fast"];
+const ADMIN: &[&str] = &["admin"];
+const PUBSUB: &[&str] = &["pubsub"];
+const NONE: &[&str] = &[];
+
+/// Every command served over RESP3. Commands only the text protocol has, like `stats`, aren't
+/// listed. Add an entry along with every new command.
+pub const COMMAND_REGISTRY: &[CommandSpec] = &[
+    spec("set", 3, WRITE),
+    spec("mset", -3, WRITE),
+    spec("get", 2, READONLY_FAST),
+    spec("mget", -2, READONLY_FAST),
+    spec("getrange", 4, READONLY),
+    spec("substr", 4, READONLY),
+    spec("bitcount", -2, READONLY),
+    spec("bitop", -4, WRITE),
+    spec("getbit", 3, READONLY_FAST),
+    spec("setbit", 4, WRITE),
+    spec("getset", 3, WRITE),
+    spec("setnx", 3, WRITE_FAST),
+    spec("compare_and_swap", 4, WRITE_FAST),
+    spec("setex", 4, WRITE),
+    spec("psetex", 4, WRITE),
+    spec("getdel", 2, WRITE_FAST),
+    spec("getex", -2, WRITE_FAST),
+    spec("append", 3, WRITE_FAST),
+    spec("setrange", 4, WRITE),
+    spec("strlen", 2, READONLY_FAST),
+    spec("incr", 2, WRITE_FAST),
+    spec("incrby", 3, WRITE_FAST),
+    spec("decr", 2, WRITE_FAST),
+    spec("decrby", 3, WRITE_FAST),
+    spec("del", 2, WRITE),
+    spec("copy", -3, WRITE),
+    spec("rename", 3, WRITE),
+    spec("renamenx", 3, WRITE_FAST),
+    spec("persist", 2, WRITE_FAST),
+    spec("expire", 3, WRITE_FAST),
+    spec("ttl", 2, READONLY_FAST),
+    spec("type", 2, READONLY_FAST),
+    spec("keys", 2, READONLY),
+    spec("scan", -2, READONLY),
+    spec("dbsize", 1, READONLY_FAST),
+    spec("flushdb", -1, WRITE),
+    spec("lpush", -3, WRITE_FAST),
+    spec("rpush", -3, WRITE_FAST),
+    spec("lpop", 2, WRITE_FAST),
+    spec("rpop", 2, WRITE_FAST),
+    spec("llen", 2, READONLY_FAST),
+    spec("object", -2, NONE),
+    spec("object|encoding", 3, READONLY),
+    spec("object|freq", 3, READONLY),
+    spec("object|idletime", 3, READONLY),
+    spec("object|refcount", 3, READONLY),
+    spec("object|help", 2, NONE),
+    spec("snapshot", -2, NONE),
+    spec("snapshot|create", 2, READONLY),
+    spec("snapshot|get", 4, READONLY),
+    spec("snapshot|release", 3, FAST),
+    spec("multi", 1, FAST),
+    spec("exec", 1, NONE),
+    spec("discard", 1, FAST),
+    spec("subscribe", -2, PUBSUB),
+    spec("unsubscribe", -1, PUBSUB),
+    spec("publish", 3, PUBSUB),
+    spec("auth", 2, FAST),
+    spec("select", 2, FAST),
+    spec("ping", -1, FAST),
+    spec("reset", 1, FAST),
+    spec("info", 1, NONE),
+    spec("lolwut", 1, READONLY_FAST),
+    spec("wait", 3, NONE),
+    spec("replicaof", 3, ADMIN),
+    spec("replstream", 1, ADMIN),
+    spec("cluster", -2, NONE),
+    spec("cluster|info", 2, NONE),
+    spec("command", -1, NONE),
+    spec("command|count", 2, NONE),
+    spec("command|info", 3, NONE),
+    spec("slowlog", -2, ADMIN),
+    spec("slowlog|get", -2, ADMIN),
+    spec("slowlog|reset", 2, ADMIN),
+    spec("client", -2, NONE),
+    spec("client|list", 2, ADMIN),
+    spec("client|kill", -3, ADMIN),
+    spec("client|setname", 3, FAST),
+    spec("client|getname", 2, FAST),
+    spec("cache", -2, ADMIN),
+    spec("cache|evict", 3, ADMIN),
+    spec("debug", -2, ADMIN),
+    spec("debug|reload", 2, ADMIN),
+    spec("debug|sleep", 3, ADMIN),
+    spec("debug|change-repl-id", 2, ADMIN),
+];
+
+/// The spec of the command named `name`, in any case.
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMAND_REGISTRY
+        .iter()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
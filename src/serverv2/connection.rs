@@ -1,14 +1,53 @@
-use std::io;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    future::Future,
+    io,
+    task::Poll,
+};
 
 use bytes::{Buf, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::serverv2::message::Message;
+use crate::{
+    serverv2::{
+        message::Message,
+        protocol::resp3::{self, Frame, FrameError},
+        pubsub::{PubSub, Subscriptions},
+    },
+    storagev2::{log::EntryLimits, snapshot::Snapshot},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Text,
+    Resp3,
+}
 
 pub struct Connection<R, W> {
     r: R,
     w: W,
     buf: bytes::BytesMut,
+    protocol: Protocol,
+    // RESP3 can only be negotiated before the first message
+    started: bool,
+    // Responses waiting to be written, flushed once there are `depth` of them or the next read
+    // would block
+    pipeline: VecDeque<Message>,
+    depth: usize,
+    // Messages queued between MULTI and EXEC
+    transaction: Option<Vec<Message>>,
+    // Who the connection authenticated as, if it has
+    identity: Option<String>,
+    // Index of the database commands run against
+    db: usize,
+    // Requests with larger keys or values are answered with an error instead of being returned
+    limits: EntryLimits,
+    // Snapshots taken by SNAPSHOT CREATE, by handle
+    snapshots: HashMap<u64, Snapshot>,
+    next_snapshot: u64,
+    // While there are any, only (un)subscribing is allowed and published messages are pushed
+    subscriptions: Subscriptions,
 }
 
 impl<R, W> Connection<R, W>
@@ -17,30 +56,523 @@ where
     W: AsyncWrite + Unpin,
 {
     pub fn new(r: R, w: W) -> Self {
+        Self::new_pipelined(r, w, 1)
+    }
+
+    /// Buffers up to `depth` responses before writing them out in one go.
+    pub fn new_pipelined(r: R, w: W, depth: usize) -> Self {
         let buf = BytesMut::with_capacity(4 * 1024);
+        let depth = depth.max(1);
+
+        Self {
+            r,
+            w,
+            buf,
+            protocol: Protocol::Text,
+            started: false,
+            pipeline: VecDeque::with_capacity(depth),
+            depth,
+            transaction: None,
+            identity: None,
+            db: 0,
+            limits: EntryLimits::default(),
+            snapshots: HashMap::new(),
+            next_snapshot: 1,
+            subscriptions: Subscriptions::new(),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    pub fn set_identity(&mut self, identity: String) {
+        self.identity = Some(identity);
+    }
+
+    pub fn db(&self) -> usize {
+        self.db
+    }
 
-        Self { r, w, buf }
+    pub fn select(&mut self, db: usize) {
+        self.db = db;
     }
 
+    pub fn set_limits(&mut self, limits: EntryLimits) {
+        self.limits = limits;
+    }
+
+    /// Keeps `snapshot` until it is released or the connection closes, returning its handle.
+    pub fn add_snapshot(&mut self, snapshot: Snapshot) -> u64 {
+        let handle = self.next_snapshot;
+        self.next_snapshot += 1;
+        self.snapshots.insert(handle, snapshot);
+
+        handle
+    }
+
+    pub fn snapshot(&self, handle: u64) -> Option<&Snapshot> {
+        self.snapshots.get(&handle)
+    }
+
+    /// Returns whether there was a snapshot with that handle.
+    pub fn release_snapshot(&mut self, handle: u64) -> bool {
+        self.snapshots.remove(&handle).is_some()
+    }
+
+    /// Reads the next message. While subscribed, published messages are written out as they
+    /// arrive in the meantime. Messages over the size limits are answered here and skipped.
     pub async fn read(&mut self) -> io::Result<Option<Message>> {
         loop {
-            if let Some(message) = Message::parse(&self.buf) {
-                self.buf.advance(message.len());
+            let resp3 = match self.protocol {
+                Protocol::Resp3 => true,
+                Protocol::Text => !self.started && self.buf.first() == Some(&b'*'),
+            };
+
+            let message = match resp3 {
+                true => self.read_resp3().await?,
+                false => Message::parse(&self.buf).inspect(|m| self.buf.advance(m.len())),
+            };
+            if let Some(message) = message {
+                self.started = true;
+                if let Err(e) = message.check_sizes(&self.limits) {
+                    self.write(e.into()).await?;
+                    continue;
+                }
 
                 return Ok(Some(message));
             }
 
-            if 0 == self.r.read_buf(&mut self.buf).await? {
+            if !self.subscriptions.is_empty() {
+                self.flush_pipeline().await?;
+
+                // Both are cancel safe, nothing is lost by whichever loses
+                let n = tokio::select! {
+                    n = self.r.read_buf(&mut self.buf) => n?,
+                    Some((channel, payload)) = self.subscriptions.recv() => {
+                        self.pipeline.push_back(Message::Published(channel, payload));
+                        self.flush_pipeline().await?;
+                        continue;
+                    }
+                };
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset));
+                }
+                continue;
+            }
+
+            let read = self.r.read_buf(&mut self.buf);
+            tokio::pin!(read);
+            let n = match poll_fn(|cx| Poll::Ready(read.as_mut().poll(cx))).await {
+                Poll::Ready(n) => n?,
+                Poll::Pending => {
+                    // No more requests for now, don't hold back responses while waiting
+                    write_pipeline(&mut self.w, &mut self.pipeline, self.protocol).await?;
+                    read.await?
+                }
+            };
+
+            if n == 0 {
+                self.flush_pipeline().await?;
                 return Err(io::Error::from(io::ErrorKind::ConnectionReset));
             }
         }
     }
 
+    /// Queues the response, writing out the pipeline once it is full.
     pub async fn write(&mut self, m: Message) -> io::Result<()> {
-        let b: Bytes = m.into();
+        self.pipeline.push_back(m);
+        if self.pipeline.len() >= self.depth {
+            self.flush_pipeline().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn flush_pipeline(&mut self) -> io::Result<()> {
+        write_pipeline(&mut self.w, &mut self.pipeline, self.protocol).await
+    }
+
+    /// Starts a transaction, commands are queued instead of executed until EXEC.
+    pub fn multi(&mut self) -> Message {
+        if self.transaction.is_some() {
+            return Message::Error("ERR MULTI calls can not be nested".to_string());
+        }
+        self.transaction = Some(Vec::new());
+
+        Message::Success
+    }
+
+    pub fn discard(&mut self) -> Message {
+        match self.transaction.take() {
+            Some(_) => Message::Success,
+            None => Message::Error("ERR DISCARD without MULTI".to_string()),
+        }
+    }
+
+    /// Ends the transaction, returning its queued commands. `None` if there is no transaction.
+    pub fn exec(&mut self) -> Option<Vec<Message>> {
+        self.transaction.take()
+    }
+
+    /// Queues `m` if a transaction is open, otherwise hands it back to be executed right away.
+    pub fn queue(&mut self, m: Message) -> Option<Message> {
+        match &mut self.transaction {
+            Some(queued) if !matches!(m, Message::Ignore(_)) => {
+                queued.push(m);
+                None
+            }
+            _ => Some(m),
+        }
+    }
+
+    /// Puts the connection back the way it started, on database 0 without a transaction,
+    /// subscriptions or snapshots, once the responses still waiting are written out. It stays
+    /// authenticated and keeps the protocol it negotiated.
+    pub async fn reset(&mut self) -> io::Result<()> {
+        self.flush_pipeline().await?;
+        self.transaction = None;
+        // Dropping them stops forwarding, along with anything published but not pushed yet
+        self.subscriptions = Subscriptions::new();
+        self.snapshots.clear();
+        self.db = 0;
+
+        Ok(())
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.subscriptions.is_empty()
+    }
+
+    /// Subscribes to every channel, with a confirmation for each.
+    pub fn subscribe(&mut self, pubsub: &PubSub, channels: Vec<Bytes>) -> Vec<Message> {
+        channels
+            .into_iter()
+            .map(|c| {
+                let n = self.subscriptions.subscribe(pubsub, c.clone());
+                Message::Subscribed(c, n)
+            })
+            .collect()
+    }
+
+    /// Unsubscribes from every channel, or all of them if there are none, with a confirmation
+    /// for each.
+    pub fn unsubscribe(&mut self, mut channels: Vec<Bytes>) -> Vec<Message> {
+        if channels.is_empty() {
+            channels = self.subscriptions.channels();
+        }
+        if channels.is_empty() {
+            return vec![Message::Unsubscribed(Bytes::new(), 0)];
+        }
+
+        channels
+            .into_iter()
+            .map(|c| {
+                let n = self.subscriptions.unsubscribe(&c);
+                Message::Unsubscribed(c, n)
+            })
+            .collect()
+    }
+
+    /// Parses as many buffered frames as it takes to get a message, answering HELLO and bad
+    /// commands itself. Returns `None` if more data has to be read first.
+    async fn read_resp3(&mut self) -> io::Result<Option<Message>> {
+        while !self.buf.is_empty() {
+            let (frame, len) = match Frame::parse(&self.buf) {
+                Ok(f) => f,
+                Err(FrameError::Incomplete) => return Ok(None),
+                Err(FrameError::Invalid) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid RESP3 frame",
+                    ))
+                }
+            };
+            self.buf.advance(len);
+
+            let Some(args) = frame.into_command() else {
+                self.write_frame(Frame::Error("ERR expected a command".to_string()))
+                    .await?;
+                continue;
+            };
+
+            if args.first().map(|a| a.to_ascii_uppercase()) == Some(b"HELLO".to_vec()) {
+                self.hello(&args).await?;
+                continue;
+            }
+
+            if self.protocol != Protocol::Resp3 {
+                self.write_frame(Frame::Error("NOPROTO send HELLO 3 first".to_string()))
+                    .await?;
+                continue;
+            }
+
+            match resp3::message(&args) {
+                Some(message) => return Ok(Some(message)),
+                None => {
+                    let name = String::from_utf8_lossy(&args[0]).into_owned();
+                    self.write_frame(Frame::Error(format!(
+                        "ERR unknown command or wrong number of arguments for '{}'",
+                        name
+                    )))
+                    .await?;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn hello(&mut self, args: &[Bytes]) -> io::Result<()> {
+        if args.get(1).map(|v| &v[..]) != Some(b"3") {
+            return self
+                .write_frame(Frame::Error(
+                    "NOPROTO unsupported protocol version".to_string(),
+                ))
+                .await;
+        }
+        self.protocol = Protocol::Resp3;
+
+        self.write_frame(Frame::Map(vec![
+            (
+                Frame::Bulk(Bytes::from("server")),
+                Frame::Bulk(Bytes::from(env!("CARGO_PKG_NAME"))),
+            ),
+            (
+                Frame::Bulk(Bytes::from("version")),
+                Frame::Bulk(Bytes::from(env!("CARGO_PKG_VERSION"))),
+            ),
+            (Frame::Bulk(Bytes::from("proto")), Frame::Integer(3)),
+        ]))
+        .await
+    }
+
+    async fn write_frame(&mut self, frame: Frame) -> io::Result<()> {
+        // Keep responses in request order
+        self.flush_pipeline().await?;
+
+        let b: Bytes = frame.into();
         self.w.write_all(&b).await?;
         self.w.flush().await?;
 
         Ok(())
     }
 }
+
+async fn write_pipeline<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    pipeline: &mut VecDeque<Message>,
+    protocol: Protocol,
+) -> io::Result<()> {
+    if pipeline.is_empty() {
+        return Ok(());
+    }
+
+    for m in pipeline.drain(..) {
+        let b: Bytes = match protocol {
+            Protocol::Text => m.into(),
+            Protocol::Resp3 => Frame::from(m).into(),
+        };
+        w.write_all(&b).await?;
+    }
+    w.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc, time::Duration};
+
+    use tokio::sync::RwLock;
+
+    use crate::{
+        serverv2::{
+            connection::{Connection, Protocol},
+            message::Message,
+            pubsub::PubSub,
+        },
+        storagev2::{
+            disk::Disk, key_dir::KeyDir, page::Page, page_manager::PageManagerBuilder,
+            test::CleanUp, wal::WriteAheadLog,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resp3_set() -> io::Result<()> {
+        const DB_FILE: &str = "./test_resp3_set.db";
+        const WAL_FILE: &str = "./test_resp3_set.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let input: &[u8] = b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n\
+            *3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n\
+            *2\r\n$3\r\nget\r\n$3\r\nkey\r\n";
+        let mut conn = Connection::new(input, Vec::new());
+
+        let message = conn.read().await?;
+        let expected = Some(Message::Insert("key".into(), "value".into()));
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(conn.protocol() == Protocol::Resp3);
+        let res = message.unwrap().exec(&m, &kd).await;
+        conn.write(res).await?;
+
+        let message = conn.read().await?;
+        let expected = Some(Message::Get("key".into()));
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        let res = message.unwrap().exec(&m, &kd).await;
+        conn.write(res).await?;
+
+        let expected = b"+OK\r\n$5\r\nvalue\r\n";
+        assert!(
+            conn.w.starts_with(b"%3\r\n") && conn.w.ends_with(expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&conn.w)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pipeline() -> io::Result<()> {
+        const DB_FILE: &str = "./test_pipeline.db";
+        const WAL_FILE: &str = "./test_pipeline.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let input: &[u8] = b"insert a 1\ninsert b 2\nget a\n";
+        let mut conn = Connection::new_pipelined(input, Vec::new(), 2);
+
+        // The second response fills the pipeline, the third waits for the next flush
+        let expected: [&[u8]; 3] = [b"", b"Success\nSuccess\n", b"Success\nSuccess\n"];
+        for expected in expected {
+            let message = conn.read().await?.expect("should parse message");
+            let res = message.exec(&m, &kd).await;
+            conn.write(res).await?;
+
+            assert!(
+                conn.w == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&conn.w)
+            );
+        }
+
+        conn.flush_pipeline().await?;
+        let expected = b"Success\nSuccess\na 1\n";
+        assert!(
+            conn.w == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&conn.w)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_subscribe() -> io::Result<()> {
+        let pubsub = PubSub::new();
+        // Kept open so reads wait instead of seeing the end of the stream
+        let (client, server) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(server, Vec::new());
+
+        let got = conn.subscribe(&pubsub, vec!["news".into(), "sports".into()]);
+        let expected = vec![
+            Message::Subscribed("news".into(), 1),
+            Message::Subscribed("sports".into(), 2),
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(conn.is_subscribed());
+
+        assert!(pubsub.publish(b"news", "hello".into()) == 1);
+        let res = tokio::time::timeout(Duration::from_millis(100), conn.read()).await;
+        assert!(res.is_err(), "should still be waiting for a request");
+
+        let expected = b"message news hello\n";
+        assert!(
+            conn.w == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&conn.w)
+        );
+
+        let got = conn.unsubscribe(Vec::new());
+        assert!(got.len() == 2, "Got: {:?}", got);
+        assert!(!conn.is_subscribed());
+        let got = conn.unsubscribe(Vec::new());
+        assert!(
+            got == vec![Message::Unsubscribed("".into(), 0)],
+            "Got: {:?}",
+            got
+        );
+        drop(client);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_state() {
+        let mut conn = Connection::new(&b""[..], Vec::new());
+
+        let got = conn.exec();
+        assert!(got.is_none(), "Got: {:?}", got);
+        let got = conn.discard();
+        assert!(matches!(got, Message::Error(_)), "Got: {:?}", got);
+
+        assert!(conn.multi() == Message::Success);
+        let got = conn.multi();
+        assert!(matches!(got, Message::Error(_)), "Got: {:?}", got);
+
+        assert!(conn.queue(Message::Get("a".into())).is_none());
+        assert!(conn.discard() == Message::Success);
+        let got = conn.queue(Message::Get("a".into()));
+        assert!(got == Some(Message::Get("a".into())), "Got: {:?}", got);
+
+        assert!(conn.multi() == Message::Success);
+        assert!(conn.queue(Message::Get("b".into())).is_none());
+        let got = conn.exec();
+        let expected = Some(vec![Message::Get("b".into())]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(conn.exec().is_none());
+    }
+}
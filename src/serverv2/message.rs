@@ -1,50 +1,561 @@
-use std::{io::Cursor, sync::Arc};
+//! Requests and responses, along with the newline delimited text protocol they are parsed from
+//! and encoded to. Each request is a single line, the command followed by its arguments separated
+//! by spaces, and each reply one or more lines. What every command does and answers with is on
+//! its `Message` variant:
+//!
+//! ```text
+//! > mget a b c
+//! < a 1
+//! < b
+//! < c 3
+//! <
+//! ```
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Cursor},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use bytes::{Buf, Bytes, BytesMut};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockWriteGuard};
 
-use crate::storagev2::{
-    key_dir::{KeyData, KeyDir},
-    log::{Entry, EntryType},
-    page::PageError,
-    page_manager::PageCache,
+use crate::{
+    serverv2::{
+        command::{self, CommandSpec, COMMAND_REGISTRY},
+        slowlog::SlowLogEntry,
+    },
+    storagev2::{
+        key_dir::{KeyData, KeyDir},
+        list::ListNode,
+        log::{timestamp_millis, Entry, EntryLimits, EntryType},
+        page::{PageID, PageInner},
+        page_manager::PageCache,
+    },
 };
 
+/// Most keys `keys` answers with unless the server is configured otherwise.
+pub const DEFAULT_KEYS_LIMIT: usize = 10_000;
+
+/// What `object help` answers with.
+const OBJECT_HELP: &[&str] = &[
+    "OBJECT <subcommand> [<arg> ...]. Subcommands are:",
+    "ENCODING <key> -- How the value of <key> is stored, embstr, raw or lz4.",
+    "FREQ <key> -- Accesses recorded for the page holding <key>.",
+    "IDLETIME <key> -- Seconds since the page holding <key> was last accessed.",
+    "REFCOUNT <key> -- Pins held on the page holding <key>.",
+    "HELP -- Prints this help.",
+];
+
+/// What `cluster info` answers with, there is never a cluster.
+const CLUSTER_INFO: &str = "cluster_enabled:0
+cluster_state:ok
+cluster_slots_assigned:0
+cluster_slots_ok:0
+cluster_slots_pfail:0
+cluster_slots_fail:0
+cluster_known_nodes:1
+cluster_size:0
+cluster_current_epoch:0
+cluster_my_epoch:0";
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
 #[derive(Debug, PartialEq)]
 pub enum Message {
+    /// `insert key value` writes a value, everything after the key being the value.
     Insert(Bytes, Bytes),
+    /// `mset key1 value1 key2 value2` writes every pair under one lock and answers with the number
+    /// written. Unlike `insert`, its values can't contain spaces.
+    MSet(Vec<(Bytes, Bytes)>),
+    /// `delete key` deletes a key.
     Delete(Bytes),
+    /// `flushdb` deletes every key in the selected database, writing a tombstone for each before
+    /// answering. With `async`, the flag, the keys are gone right away and the tombstones are
+    /// written in the background, without holding up other clients, skipping keys written again
+    /// meanwhile. Until they are, a restart brings the keys back.
+    FlushDb(bool),
+    /// `dbsize` answers with the number of keys, counting ones that expired but haven't been swept
+    /// yet.
+    DbSize,
+    /// `incr key` treats the value as a decimal i64, a missing key being 0, adds one and answers
+    /// with the new value. The read and the write happen under one lock, so concurrent increments
+    /// are never lost. `incrby`, `decr` and `decrby` work the same way.
+    Incr(Bytes),
+    /// `incrby key 5`, see `Incr`.
+    IncrBy(Bytes, i64),
+    /// `decr key`, see `Incr`.
+    Decr(Bytes),
+    /// `decrby key 5`, see `Incr`.
+    DecrBy(Bytes, i64),
+    /// `get key` answers with `key value`, or nothing if the key doesn't exist. For a key holding a
+    /// list it answers with a `WRONGTYPE` error.
     Get(Bytes),
+    /// `getrange key 0 -1` answers with the bytes of a value from the first to the last index
+    /// inclusive, negative ones counting back from the end so `0 -1` is all of it. Indices past
+    /// either end are clamped and an empty range or a missing key answers with an empty value. The
+    /// whole value is still read to slice it, so it costs as much as a `get`. Over RESP it is also
+    /// `SUBSTR`.
+    GetRange(Bytes, i64, i64),
+    /// `bitcount key [start end]` answers with the number of set bits in a value, or in the bytes
+    /// from `start` to `end` picked like `getrange` picks them, 0 for a missing key.
+    BitCount(Bytes, Option<(i64, i64)>),
+    /// `bitop and dest key1 key2` writes the bitwise `and`, `or` or `xor` of the values at the keys
+    /// to `dest`, or the `not` of a single one, and answers with its length. Shorter values and
+    /// missing keys count as zero bytes up to the longest value, and when every key is missing
+    /// `dest` is deleted instead.
+    BitOp(BitOpKind, Bytes, Vec<Bytes>),
+    /// `getbit key 7` answers with the bit at an offset, bit 0 being the most significant bit of
+    /// the first byte. Bits past the end of a value or of a missing key are 0.
+    GetBit(Bytes, u64),
+    /// `setbit key 7 1` sets or clears the bit at an offset under one lock, padding the value with
+    /// zero bytes up to it if it is shorter, keeps the key's expiry and answers with the bit it had
+    /// before.
+    SetBit(Bytes, u64, u8),
+    /// `getset key value` writes a value and answers with the one it replaced, like `get` would
+    /// have right before, under the same lock.
+    GetSet(Bytes, Bytes),
+    /// `setnx key value` writes a value and answers 1 only if the key doesn't exist, otherwise it
+    /// answers 0 without writing. The check and the write happen under one lock, so of several
+    /// clients racing to set a key only one does, which is enough for a simple lock.
+    SetNx(Bytes, Bytes),
+    /// `compare_and_swap key expected new_value` writes the new value only if the key's value is
+    /// `expected` and answers 1 if it did. Otherwise it answers 0 followed by the current value,
+    /// like `get` would answer, so the client can retry from it. A missing key counts as holding an
+    /// empty value, making a swap from nothing a `setnx`. The read and the write happen under one
+    /// lock.
+    CompareAndSwap(Bytes, Bytes, Bytes),
+    /// `setex key 10 value` writes a value that expires after that many seconds in a single write,
+    /// so the key is never seen without its expiry, and answers `Success`. It answers with an error
+    /// for 0.
+    SetEx(Bytes, u64, Bytes),
+    /// `psetex key 10000 value` is `SetEx` in milliseconds.
+    PSetEx(Bytes, u64, Bytes),
+    /// `getdel key` deletes a key and answers with the value it had, so of several clients racing
+    /// to consume a key only one gets it.
+    GetDel(Bytes),
+    /// `getex key ex 10` answers like `get` and rewrites the key's expiry under the same lock, see
+    /// `GetExOption`. Without an option it is just a `get`.
+    GetEx(Bytes, GetExOption),
+    /// `append key value` adds to the end of a value, a missing key being empty, and answers with
+    /// the new length.
+    Append(Bytes, Bytes),
+    /// `setrange key 6 value` overwrites a value from the byte offset on, padding it with zero
+    /// bytes up to the offset if it is shorter, keeps its expiry and answers with the new length.
+    /// Writing nothing leaves the key as it is.
+    SetRange(Bytes, u64, Bytes),
+    /// `copy source destination [replace]` writes a key's value, with its timestamp and expiry,
+    /// under another key and answers 1. It answers 0 without writing if the source doesn't exist or
+    /// the destination does, unless `replace` is given.
+    Copy(Bytes, Bytes, bool),
+    /// `rename source destination` moves a key like `copy` with `replace` and deletes the source,
+    /// under one lock so no reader sees neither or both. It answers with an error if the source
+    /// doesn't exist.
+    Rename(Bytes, Bytes),
+    /// `renamenx source destination` is `rename`, answering 1, or 0 without moving anything if the
+    /// destination exists.
+    RenameNx(Bytes, Bytes),
+    /// `persist key` rewrites a key without an expiry, answering 1 if it had one and 0 otherwise.
+    Persist(Bytes),
+    /// `expire key 10` rewrites a key to expire after that many seconds, 0 expiring it right away,
+    /// and answers 1, or 0 if the key doesn't exist.
+    Expire(Bytes, u64),
+    /// `ttl key` answers with the seconds a key has left, rounded up, -1 if it doesn't expire and
+    /// -2 if it doesn't exist. A key that expired but hasn't been swept yet has 0 left.
+    Ttl(Bytes),
+    /// `object encoding key` answers with how a key's value is stored, `lz4` when compressed,
+    /// `embstr` for values of up to 44 bytes and `raw` for longer ones.
+    ObjectEncoding(Bytes),
+    /// `object freq key` answers with how many accesses the page cache has recorded for the page
+    /// holding a key, 0 while it is the current page or isn't cached.
+    ObjectFreq(Bytes),
+    /// `object idletime key` answers with the whole seconds since the page holding a key was last
+    /// accessed, -1 while it is the current page or isn't cached.
+    ObjectIdleTime(Bytes),
+    /// `object refcount key` answers with how many readers have the page holding a key pinned, -1
+    /// while it is the current page or isn't cached.
+    ObjectRefCount(Bytes),
+    /// `object help` answers with a line per `object` subcommand saying what it does.
+    ObjectHelp,
+    /// `lolwut` answers with a dragon and the server's version, over several lines.
+    LolWut,
+    /// `cluster info` answers with `field:value` lines describing a cluster of one node with no
+    /// slots, `cluster_enabled:0` among them, so clients that probe for a cluster know they are
+    /// talking to a standalone server.
+    ClusterInfo,
+    /// `command count` answers with how many commands `COMMAND_REGISTRY` lists.
+    CommandCount,
+    /// `command info get` answers with the number of specs found, 0 or 1, then `name arity flag1
+    /// flag2` for the one found. Subcommands are named `container|subcommand`, like
+    /// `object|encoding`:
+    ///
+    /// ```text
+    /// > command info get
+    /// < 1
+    /// < get 2 readonly fast
+    /// ```
+    CommandInfo(Bytes),
+    /// `type key` answers with the type of a key's value, `string`, `list`, or `none` if it doesn't
+    /// exist.
+    Type(Bytes),
+    /// `strlen key` answers with the length of a value, 0 if the key doesn't exist. A compressed
+    /// value's length is stored with it, so it is never decompressed, though its page is still
+    /// read.
+    Strlen(Bytes),
+    /// `lpush key item1 item2` adds items to the front of the list at a key, creating it if it
+    /// doesn't exist, and answers with its new length. Like `mset` values, items can't contain
+    /// spaces. It only rewrites the head of the list.
+    ///
+    /// List commands answer with a `WRONGTYPE` error for a key holding a string. Every other
+    /// command but `get` treats a list as missing, writing a string over it replaces it.
+    LPush(Bytes, Vec<Bytes>),
+    /// `rpush key item1 item2` is `LPush` at the back, which rewrites all of the list.
+    RPush(Bytes, Vec<Bytes>),
+    /// `lpop key` removes and answers with the first item, nothing once the list is empty. An
+    /// emptied list's key is deleted.
+    LPop(Bytes),
+    /// `rpop key` is `LPop` at the back, which rewrites all of the list.
+    RPop(Bytes),
+    /// `llen key` answers with the length of a list, 0 if the key doesn't exist.
+    LLen(Bytes),
+    /// `mget key1 key2 key3` looks up every key under one key dir lock and answers with one line
+    /// per key in request order, `key value` if it exists and just `key` if it doesn't, followed by
+    /// an empty line. It saves a round trip per key compared to separate `get`s.
+    MGet(Vec<Bytes>),
+    /// `scan start end` answers with every key in the range and its value.
+    Scan(Bytes, Bytes),
+    /// `keys pattern` answers with every key matching a glob pattern (`*`, `?`, and `\` to escape),
+    /// sorted, up to the server's limit. The first line is the number of keys, followed by
+    /// `truncated` if there were more matches, then one key per line:
+    ///
+    /// ```text
+    /// > keys user:*
+    /// < 2
+    /// < user:1
+    /// < user:2
+    /// ```
+    ///
+    /// Matching is O(n) in the number of keys, only a literal prefix before the first wildcard
+    /// narrows the search, and the key dir stays read locked throughout. Prefer `scan` over a
+    /// prefix range on hot paths.
+    Keys(Bytes, usize),
+    /// `keyscan cursor count` walks every key a batch at a time, only locking the key dir for one
+    /// batch. Cursor `0` starts from the first key, and each reply is the cursor to pass next and
+    /// the number of keys, then up to `count` keys one per line. The walk is done once the cursor
+    /// is `0` again:
+    ///
+    /// ```text
+    /// > keyscan 0 2
+    /// < 75736572 2
+    /// < a
+    /// < b
+    /// > keyscan 75736572 2
+    /// < 0 1
+    /// < user
+    /// ```
+    ///
+    /// Every key present for the whole walk is answered with exactly once, keys written or deleted
+    /// meanwhile may or may not be.
+    KeyScan(Bytes, usize),
+    /// `stats` answers with the page cache's hits, misses, evictions and dirty flushes and the
+    /// number of live and deleted keys, as a JSON object.
+    Stats,
+    /// `info` answers with a report on the server, in `[server]`, `[keyspace]`, `[stats]` and
+    /// `[memory]` sections of `name:value` lines. `[stats]` has the calls, min, max, mean and p99
+    /// latency in microseconds of every command run so far, as `cmd_get_p99_us:42` and so on.
+    Info,
+    /// `debug reload` writes every page out, empties the page cache and rebuilds the key dir by
+    /// scanning the data file, answering with the number of keys found. Everything else waits while
+    /// it runs. Only database 0 can be reloaded, the data file doesn't tell the others apart.
+    DebugReload,
+    /// `debug sleep 100` answers after waiting that many milliseconds, for clients to test their
+    /// timeouts against. It is only served when the server has debug commands enabled.
+    DebugSleep(u64),
+    /// `debug change-repl-id` starts a new replication history the way promoting a replica would,
+    /// answering with the new 40 hex digit replication id. The replication offset goes back to 0.
+    /// It is only served when debug commands are enabled, too.
+    DebugChangeReplId,
+    /// `cache evict 3` drops a page from the page cache, writing it out first if it was modified,
+    /// so the memory it held can be reused. It answers with an error if the page isn't cached, is
+    /// the page being written to, or is in use.
+    CacheEvict(PageID),
+    /// `multi` starts a transaction, the commands after it are queued instead of run until `exec`.
+    Multi,
+    /// `exec` runs the queued commands holding the current page and the key dir, so no other
+    /// connection sees a partly applied transaction, and answers with each of their replies.
+    Exec,
+    /// `discard` drops the queued commands and ends the transaction.
+    Discard,
+    /// `auth token` authenticates the connection as the identity the token belongs to.
+    Auth(Bytes),
+    /// `subscribe channel1 channel2` answers with a `subscribe channel count` line per channel,
+    /// `count` being how many channels the connection is subscribed to afterwards. From then on the
+    /// connection only accepts `subscribe`, `unsubscribe`, `ping` and `reset`, and every message
+    /// published to its channels is pushed as a `message channel payload` line:
+    ///
+    /// ```text
+    /// > subscribe news
+    /// < subscribe news 1
+    /// < message news hello
+    /// > unsubscribe
+    /// < unsubscribe news 0
+    /// ```
+    Subscribe(Vec<Bytes>),
+    /// `unsubscribe channel1` leaves the channels, all of them if none are given.
+    Unsubscribe(Vec<Bytes>),
+    /// `publish channel message` answers with the number of connections that received the message.
+    Publish(Bytes, Bytes),
+    /// `select 1` switches the connection to another database, each with its own keys. Connections
+    /// start on database 0.
+    Select(usize),
+    /// `ping [payload]` answers `PONG`, or with the payload if there is one, and is also allowed
+    /// while subscribed. Clients can send it to keep an idle connection from being dropped, by the
+    /// server's idle timeout or by anything in between.
+    Ping(Option<Bytes>),
+    /// `reset` puts the connection back the way it started, answering `Reset`. It leaves any
+    /// transaction and every channel, releases its snapshots and selects database 0, so a pooled
+    /// connection can be handed to the next client whatever the last one left it in. It stays
+    /// authenticated.
+    Reset,
+    /// `snapshot create` copies the selected database's key dir and answers with a handle for it.
+    /// Snapshots belong to the connection that created them. Once the data file is compacted their
+    /// reads fail, take a new one.
+    SnapshotCreate,
+    /// `snapshot get 1 key` reads a key as it was when the snapshot was taken.
+    SnapshotGet(u64, Bytes),
+    /// `snapshot release 1` drops a snapshot.
+    SnapshotRelease(u64),
+    /// `wait replicas timeout` blocks the connection until that many replicas have acknowledged
+    /// every write so far or `timeout` milliseconds pass, 0 waiting indefinitely, and answers with
+    /// the number that have. Without replicas that is 0, right away.
+    Wait(usize, u64),
+    /// `replicaof host port` makes the server a read only replica of another one, see
+    /// `serverv2::replication` for how and what isn't replicated. It answers right away, the
+    /// initial sync happens in the background. `replicaof no one`, `None`, stops following the
+    /// master and takes writes again, keeping what was replicated so far.
+    ReplicaOf(Option<(String, u16)>),
+    /// `replstream` is what a replica sends its master. It is answered with the number of entries
+    /// the initial sync is made of, then those entries, and then every entry as it is written, for
+    /// as long as the connection stays open. Each entry is a `seq len` line followed by `len` bytes
+    /// of the entry as it is stored, `seq` being 0 for the sync and counting up one per write
+    /// afterwards.
+    ReplStream,
+    /// `slowlog get count` answers with up to `count` of the latest commands that took longer than
+    /// the server's slow log threshold, newest first. The first line is the number of entries, then
+    /// one line per entry with its id, the unix time it finished, how many microseconds it took and
+    /// the command:
+    ///
+    /// ```text
+    /// > slowlog get 10
+    /// < 1
+    /// < 0 1760000000 50123 debug
+    /// ```
+    SlowlogGet(usize),
+    /// `slowlog reset` empties the slow log.
+    SlowlogReset,
+    /// `client list` answers with a line per connection being served, oldest first, giving its id,
+    /// address, selected database, age in seconds and the last command it ran:
+    ///
+    /// ```text
+    /// > client list
+    /// < id=1 addr=127.0.0.1:52114 name=worker-1 db=0 age=12 cmd=get
+    /// < id=2 addr=127.0.0.1:52120 name= db=1 age=0 cmd=client
+    /// ```
+    ClientList,
+    /// `client kill id:3` closes the connection with that id once it is done with the request it is
+    /// on, answering with an error if there isn't one.
+    ClientKill(u64),
+    /// `client setname worker-1` names the connection for `client list`, for as long as it is open.
+    /// Names are printable ASCII without spaces, an empty one takes the name away.
+    ClientSetName(Bytes),
+    /// `client getname` answers with the name, empty if there is none.
+    ClientGetName,
 
     Result(Bytes, Bytes),
+    Results(Vec<(Bytes, Bytes)>),
+    Values(Vec<(Bytes, Option<Bytes>)>),
+    /// Matching keys and whether there were more than the limit
+    KeyList(Vec<Bytes>, bool),
+    /// Cursor to continue from, `0` once done, and the keys
+    ScanPage(Bytes, Vec<Bytes>),
+    SlowLogEntries(Vec<SlowLogEntry>),
+    CommandSpecs(Vec<&'static CommandSpec>),
+    /// Channel and how many the connection is subscribed to afterwards
+    Subscribed(Bytes, usize),
+    Unsubscribed(Bytes, usize),
+    /// Channel and payload pushed to a subscriber
+    Published(Bytes, Bytes),
+    Responses(Vec<Message>),
+    Text(String),
+    /// Lines of a help text
+    Help(&'static [&'static str]),
+    Error(String),
+    Queued,
+    Pong(Option<Bytes>),
+    /// Sequence number and the entry as it is stored, see `PageCache::subscribe_writes`
+    ReplEntry(u64, Bytes),
+    ResetDone,
+    Count(usize),
+    Integer(i64),
 
     Success,
     Ignore(usize),
     None,
 }
 
+/// How `getex` changes a key's expiry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GetExOption {
+    // Seconds from now
+    Ex(u64),
+    // Milliseconds from now
+    Px(u64),
+    // Unix time in seconds
+    ExAt(u64),
+    Persist,
+    // Left as it is
+    None,
+}
+
+/// The operation `bitop` applies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOpKind {
+    And,
+    Or,
+    Xor,
+    // Of a single value
+    Not,
+}
+
+impl BitOpKind {
+    /// As the text protocol spells it.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BitOpKind::And => "and",
+            BitOpKind::Or => "or",
+            BitOpKind::Xor => "xor",
+            BitOpKind::Not => "not",
+        }
+    }
+}
+
+/// A request refused before it gets anywhere near storage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientError {
+    KeyTooLarge(usize),
+    ValueTooLarge(usize),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::KeyTooLarge(len) => write!(f, "key of {len} bytes is too large"),
+            ClientError::ValueTooLarge(len) => write!(f, "value of {len} bytes is too large"),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<ClientError> for Message {
+    fn from(e: ClientError) -> Self {
+        Message::Error(format!("ERR {}", e))
+    }
+}
+
 impl Message {
+    /// Checks every key and value the request carries against `limits`.
+    pub fn check_sizes(&self, limits: &EntryLimits) -> Result<(), ClientError> {
+        let key = |k: &Bytes| match k.len() as u64 > limits.max_key_len {
+            true => Err(ClientError::KeyTooLarge(k.len())),
+            false => Ok(()),
+        };
+        let pair = |k: &Bytes, v: &Bytes| {
+            key(k)?;
+            match v.len() as u64 > limits.max_value_len {
+                true => Err(ClientError::ValueTooLarge(v.len())),
+                false => Ok(()),
+            }
+        };
+
+        match self {
+            Message::Insert(k, v)
+            | Message::GetSet(k, v)
+            | Message::SetNx(k, v)
+            | Message::CompareAndSwap(k, _, v)
+            | Message::SetEx(k, _, v)
+            | Message::PSetEx(k, _, v)
+            | Message::Append(k, v) => pair(k, v),
+            Message::MSet(pairs) => pairs.iter().try_for_each(|(k, v)| pair(k, v)),
+            // The padding counts too, it is as long as the value would be
+            Message::SetRange(k, offset, v) => {
+                key(k)?;
+                let len = offset.saturating_add(v.len() as u64);
+                match len > limits.max_value_len {
+                    true => Err(ClientError::ValueTooLarge(len as usize)),
+                    false => Ok(()),
+                }
+            }
+            // As does the padding up to the bit's byte
+            Message::SetBit(k, offset, _) => {
+                key(k)?;
+                let len = offset / 8 + 1;
+                match len > limits.max_value_len {
+                    true => Err(ClientError::ValueTooLarge(len as usize)),
+                    false => Ok(()),
+                }
+            }
+            Message::LPush(k, items) | Message::RPush(k, items) => {
+                items.iter().try_for_each(|item| pair(k, item))
+            }
+            Message::Delete(k)
+            | Message::Incr(k)
+            | Message::IncrBy(k, _)
+            | Message::Decr(k)
+            | Message::DecrBy(k, _)
+            | Message::Get(k)
+            | Message::GetRange(k, _, _)
+            | Message::GetBit(k, _)
+            | Message::GetDel(k)
+            | Message::GetEx(k, _)
+            | Message::Persist(k)
+            | Message::Expire(k, _)
+            | Message::Ttl(k)
+            | Message::ObjectEncoding(k)
+            | Message::ObjectFreq(k)
+            | Message::ObjectIdleTime(k)
+            | Message::ObjectRefCount(k)
+            | Message::Type(k)
+            | Message::Strlen(k)
+            | Message::LPop(k)
+            | Message::RPop(k)
+            | Message::LLen(k)
+            | Message::SnapshotGet(_, k) => key(k),
+            Message::Copy(src, dst, _)
+            | Message::Rename(src, dst)
+            | Message::RenameNx(src, dst) => key(src).and_then(|_| key(dst)),
+            Message::MGet(keys) => keys.iter().try_for_each(key),
+            Message::BitOp(_, dst, keys) => key(dst).and_then(|_| keys.iter().try_for_each(key)),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn exec(&self, m: &PageCache, kd: &Arc<RwLock<KeyDir>>) -> Message {
         match self {
             Message::Insert(k, v) => {
                 let mut current = m.get_current().await;
 
                 let entry = Entry::new(k, v, EntryType::Put);
-                let offset = match current.write_entry(&entry) {
+                let offset = match append(m, kd, &mut current, &entry).await {
                     Ok(o) => o,
-                    Err(e) if e == PageError::NotEnoughSpace => {
-                        if let Err(e) = m.replace_current(&mut current).await {
-                            todo!()
-                        }
-
-                        current.write_entry(&entry).unwrap()
-                    }
-                    Err(e) => {
-                        todo!()
-                    }
+                    Err(e) => return Message::Error(format!("ERR {}", e)),
                 };
 
-                let data = KeyData::new(current.id, offset);
+                let data = m.key_data(current.id, offset);
                 kd.write().await.insert(k, data);
 
                 Message::Success
@@ -53,38 +564,552 @@ impl Message {
                 let mut current = m.get_current().await;
 
                 let entry = Entry::new(k, &[], EntryType::Delete);
-                if let Err(e) = current.write_entry(&entry) {
-                    if e == PageError::NotEnoughSpace {
-                        if let Err(e) = m.replace_current(&mut current).await {
-                            todo!()
-                        }
-                        current.write_entry(&entry).unwrap();
-                    } else {
-                        todo!()
-                    }
-                };
+                if let Err(e) = append(m, kd, &mut current, &entry).await {
+                    return Message::Error(format!("ERR {}", e));
+                }
 
                 kd.write().await.remove(k);
 
                 Message::Success
             }
+            Message::MSet(pairs) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                put_all(m, kd, &mut current, &mut locked, pairs).await
+            }
+            Message::FlushDb(lazy) => {
+                // List keys are deleted too
+                let _lists = m.lock_lists().await;
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                flush_db(m, kd, &mut current, &mut locked, *lazy).await
+            }
+            // The server answers it from the key count handles instead, without the lock
+            Message::DbSize => Message::Count(kd.read().await.len_approx()),
+            Message::Incr(k) | Message::IncrBy(k, _) | Message::Decr(k) | Message::DecrBy(k, _) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                incr_by(m, kd, &mut current, &mut locked, k, self.delta()).await
+            }
+            Message::GetSet(k, v) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                get_set(m, kd, &mut current, &mut locked, k, v).await
+            }
+            Message::SetNx(k, v) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                set_nx(m, kd, &mut current, &mut locked, k, v).await
+            }
+            Message::CompareAndSwap(k, expected, v) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                compare_and_swap(m, kd, &mut current, &mut locked, k, expected, v).await
+            }
+            Message::SetEx(k, _, v) | Message::PSetEx(k, _, v) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                set_ex(m, kd, &mut current, &mut locked, k, v, self.expire_millis()).await
+            }
+            Message::GetDel(k) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                get_del(m, kd, &mut current, &mut locked, k).await
+            }
+            Message::GetEx(k, option) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                get_ex(m, kd, &mut current, &mut locked, k, *option).await
+            }
+            Message::BitOp(kind, dst, keys) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                bit_op(m, kd, &mut current, &mut locked, *kind, dst, keys).await
+            }
+            Message::Copy(src, dst, replace) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                copy(m, kd, &mut current, &mut locked, src, dst, *replace).await
+            }
+            Message::Rename(src, dst) | Message::RenameNx(src, dst) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+                let nx = matches!(self, Message::RenameNx(_, _));
+
+                rename(m, kd, &mut current, &mut locked, src, dst, nx).await
+            }
+            Message::Append(k, v) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                append_value(m, kd, &mut current, &mut locked, k, v).await
+            }
+            Message::SetRange(k, offset, v) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                set_range(m, kd, &mut current, &mut locked, k, *offset, v).await
+            }
+            Message::Persist(k) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                persist(m, kd, &mut current, &mut locked, k).await
+            }
+            Message::Expire(k, secs) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                expire(m, kd, &mut current, &mut locked, k, *secs).await
+            }
+            Message::Ttl(k) => ttl(get_raw(m, kd, k).await.as_ref()),
+            Message::ObjectEncoding(k) => object_encoding(get_stored(m, kd, k).await.as_ref()),
+            Message::ObjectFreq(k) => {
+                let page_id = kd.read().await.get(k).map(|data| data.page_id);
+                match page_id {
+                    Some(page_id) => Message::Count(m.access_count(page_id).await),
+                    None => Message::None,
+                }
+            }
+            Message::ObjectIdleTime(k) => {
+                let page_id = kd.read().await.get(k).map(|data| data.page_id);
+                match page_id {
+                    Some(page_id) => idle_time(m, page_id).await,
+                    None => Message::None,
+                }
+            }
+            Message::ObjectRefCount(k) => {
+                let page_id = kd.read().await.get(k).map(|data| data.page_id);
+                match page_id {
+                    Some(page_id) => ref_count(m, page_id).await,
+                    None => Message::None,
+                }
+            }
+            Message::ObjectHelp => Message::Help(OBJECT_HELP),
+            Message::LolWut => Message::Text(lolwut()),
+            Message::ClusterInfo => Message::Text(CLUSTER_INFO.to_string()),
+            Message::CommandCount => Message::Integer(COMMAND_REGISTRY.len() as i64),
+            Message::CommandInfo(name) => {
+                Message::CommandSpecs(command::lookup(name).into_iter().collect())
+            }
+            Message::Type(k) => {
+                let entry = get_raw(m, kd, k).await;
+
+                value_type(entry.filter(|e| !e.is_expired()).as_ref())
+            }
+            Message::Strlen(k) => strlen(get_stored(m, kd, k).await.as_ref()),
+            Message::LPush(k, items) | Message::RPush(k, items) => {
+                let _lists = m.lock_lists().await;
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+                let front = matches!(self, Message::LPush(_, _));
+
+                push(m, kd, &mut current, &mut locked, k, items, front).await
+            }
+            Message::LPop(k) | Message::RPop(k) => {
+                let _lists = m.lock_lists().await;
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+                let front = matches!(self, Message::LPop(_));
+
+                pop(m, kd, &mut current, &mut locked, k, front).await
+            }
+            Message::LLen(k) => {
+                // Same order as pushes and pops, see `get_raw`
+                let _lists = m.lock_lists().await;
+                let current = m.get_current().await;
+                let kd = kd.read().await;
+
+                match list_head(m, &current, &kd, k).await {
+                    Ok(head) => Message::Integer(head.map_or(0, |h| h.len as i64)),
+                    Err(e) => e,
+                }
+            }
             Message::Get(k) => {
                 let kd = kd.read().await;
-                let Some(data) = kd.get(k) else { return Message::None };
+                let Some(data) = kd.get(k) else {
+                    return Message::None;
+                };
+
+                // TODO: return error if replacer couldn't replace or page could not have held entry
+                get_result(m.fetch_entry(data.page_id, data.offset).await)
+            }
+            Message::GetRange(k, start, end) => {
+                let entry = get_raw(m, kd, k).await;
+
+                get_range(k, entry.filter(|e| !e.is_expired()), *start, *end)
+            }
+            Message::BitCount(k, range) => {
+                let entry = get_raw(m, kd, k).await;
+
+                bit_count(entry.filter(|e| !e.is_expired()), *range)
+            }
+            Message::GetBit(k, offset) => {
+                let entry = get_raw(m, kd, k).await;
+
+                get_bit(entry.filter(|e| !e.is_expired()), *offset)
+            }
+            Message::SetBit(k, offset, bit) => {
+                let mut current = m.get_current().await;
+                let mut locked = kd.write().await;
+
+                set_bit(m, kd, &mut current, &mut locked, k, *offset, *bit).await
+            }
+            Message::MGet(keys) => {
+                // Only hold the key dir for the lookups, not the page reads
+                let (locations, compactions) = {
+                    let kd = kd.read().await;
+                    let locations: Vec<_> = keys.iter().map(|k| kd.get(k).cloned()).collect();
+
+                    (locations, m.compactions())
+                };
 
-                // TODO: return error if replacer couldn't replace
-                let Some(page) = m.fetch_page(data.page_id).await else { return Message::None };
-                let page_w = page.read().await;
-                // TODO: return error page could not have held entry
-                let Some(entry) = page_w.read_entry(data.offset as usize) else { return Message::None };
+                let mut values = Vec::with_capacity(keys.len());
+                for (k, data) in keys.iter().zip(locations) {
+                    values.push((k.clone(), mget_value(m, k, data.as_ref()).await));
+                }
 
-                Message::Result(entry.key.into(), entry.value.into())
+                // A compaction since the lookups can have put other entries where they pointed,
+                // read them again with the key dir held so none can start. Writers take the
+                // current page before the key dir, so it is taken first here too.
+                if m.compactions() != compactions {
+                    let current = m.get_current().await;
+                    let kd = kd.read().await;
+                    values.clear();
+                    for k in keys {
+                        let value = match kd.get(k) {
+                            Some(data) => lookup(m, &current, data)
+                                .await
+                                .map(|e| Bytes::from(e.value)),
+                            None => None,
+                        };
+                        values.push((k.clone(), value));
+                    }
+                }
+
+                Message::Values(values)
             }
 
-            Message::Result(_, _) | Message::Success | Message::Ignore(_) | Message::None => {
-                Message::None
+            Message::Scan(start, end) => {
+                // Like MGET, only hold the key dir to find the keys, writers wait on it
+                let (locations, compactions) = {
+                    let kd = kd.read().await;
+                    let locations: Vec<_> = kd
+                        .scan(start, end)
+                        .map(|(k, data)| (Bytes::copy_from_slice(k), data.clone()))
+                        .collect();
+
+                    (locations, m.compactions())
+                };
+
+                let mut results = Vec::new();
+                for (k, data) in locations {
+                    let entry = m.fetch_entry(data.page_id, data.offset).await;
+                    if let Some(entry) = entry.filter(|e| e.key == k) {
+                        results.push((k, entry.value.into()));
+                    }
+                }
+
+                if m.compactions() != compactions {
+                    let current = m.get_current().await;
+                    let kd = kd.read().await;
+                    results.clear();
+                    for (k, data) in kd.scan(start, end) {
+                        let entry = lookup_raw(m, &current, data).await;
+                        if let Some(entry) = entry.filter(|e| !e.is_expired()) {
+                            results.push((Bytes::copy_from_slice(k), entry.value.into()));
+                        }
+                    }
+                }
+
+                Message::Results(results)
+            }
+            Message::Keys(pattern, limit) => keys(&*kd.read().await, pattern, *limit),
+            Message::KeyScan(cursor, count) => key_scan(&*kd.read().await, cursor, *count),
+            Message::Stats => stats(m, &*kd.read().await),
+            Message::Ping(payload) => Message::Pong(payload.clone()),
+            Message::DebugReload => match m.reload(kd).await {
+                Ok(n) => Message::Integer(n as i64),
+                Err(e) => Message::Error(format!("ERR {}", e)),
+            },
+            Message::DebugSleep(ms) => {
+                tokio::time::sleep(Duration::from_millis(*ms)).await;
+                Message::Success
             }
+            Message::CacheEvict(page_id) => cache_evict(m, *page_id).await,
+
+            // Transactions, authentication, pub-sub, databases and info are up to the connection
+            Message::Multi
+            | Message::Exec
+            | Message::Discard
+            | Message::Auth(_)
+            | Message::Subscribe(_)
+            | Message::Unsubscribe(_)
+            | Message::Publish(_, _)
+            | Message::Select(_)
+            | Message::Reset
+            | Message::SnapshotCreate
+            | Message::SnapshotGet(_, _)
+            | Message::SnapshotRelease(_)
+            | Message::Wait(_, _)
+            | Message::ReplicaOf(_)
+            | Message::ReplStream
+            | Message::DebugChangeReplId
+            | Message::SlowlogGet(_)
+            | Message::SlowlogReset
+            | Message::ClientList
+            | Message::ClientKill(_)
+            | Message::ClientSetName(_)
+            | Message::ClientGetName
+            | Message::Info => Message::None,
+
+            Message::Result(_, _)
+            | Message::Results(_)
+            | Message::Values(_)
+            | Message::KeyList(_, _)
+            | Message::ScanPage(_, _)
+            | Message::SlowLogEntries(_)
+            | Message::CommandSpecs(_)
+            | Message::Subscribed(_, _)
+            | Message::Unsubscribed(_, _)
+            | Message::Published(_, _)
+            | Message::Responses(_)
+            | Message::Text(_)
+            | Message::Help(_)
+            | Message::Error(_)
+            | Message::Queued
+            | Message::Pong(_)
+            | Message::ReplEntry(_, _)
+            | Message::ResetDone
+            | Message::Count(_)
+            | Message::Integer(_)
+            | Message::Success
+            | Message::Ignore(_)
+            | Message::None => Message::None,
+        }
+    }
+
+    /// Executes a transaction's messages while holding the current page and the key dir, so no
+    /// other connection sees or interleaves with a partially applied transaction. Each entry is
+    /// still logged on its own, a crash part way through leaves the writes before it in place.
+    pub async fn exec_all(
+        messages: &[Message],
+        m: &PageCache,
+        key_dir: &Arc<RwLock<KeyDir>>,
+    ) -> Message {
+        // Queued list commands write nodes
+        let _lists = m.lock_lists().await;
+        let mut current = m.get_current().await;
+        let mut kd = key_dir.write().await;
+
+        let mut responses = Vec::with_capacity(messages.len());
+        for message in messages {
+            let res = match message {
+                Message::Insert(k, v) => {
+                    let entry = Entry::new(k, v, EntryType::Put);
+                    match append(m, key_dir, &mut current, &entry).await {
+                        Ok(offset) => {
+                            kd.insert(k, m.key_data(current.id, offset));
+                            Message::Success
+                        }
+                        Err(e) => Message::Error(format!("ERR {}", e)),
+                    }
+                }
+                Message::Delete(k) => {
+                    let entry = Entry::new(k, &[], EntryType::Delete);
+                    match append(m, key_dir, &mut current, &entry).await {
+                        Ok(_) => {
+                            kd.remove(k);
+                            Message::Success
+                        }
+                        Err(e) => Message::Error(format!("ERR {}", e)),
+                    }
+                }
+                Message::MSet(pairs) => put_all(m, key_dir, &mut current, &mut kd, pairs).await,
+                Message::FlushDb(lazy) => flush_db(m, key_dir, &mut current, &mut kd, *lazy).await,
+                Message::DbSize => Message::Count(kd.len()),
+                Message::Incr(k)
+                | Message::IncrBy(k, _)
+                | Message::Decr(k)
+                | Message::DecrBy(k, _) => {
+                    incr_by(m, key_dir, &mut current, &mut kd, k, message.delta()).await
+                }
+                Message::GetSet(k, v) => get_set(m, key_dir, &mut current, &mut kd, k, v).await,
+                Message::SetNx(k, v) => set_nx(m, key_dir, &mut current, &mut kd, k, v).await,
+                Message::CompareAndSwap(k, expected, v) => {
+                    compare_and_swap(m, key_dir, &mut current, &mut kd, k, expected, v).await
+                }
+                Message::SetEx(k, _, v) | Message::PSetEx(k, _, v) => {
+                    let ms = message.expire_millis();
+                    set_ex(m, key_dir, &mut current, &mut kd, k, v, ms).await
+                }
+                Message::Copy(src, dst, replace) => {
+                    copy(m, key_dir, &mut current, &mut kd, src, dst, *replace).await
+                }
+                Message::BitOp(kind, dst, keys) => {
+                    bit_op(m, key_dir, &mut current, &mut kd, *kind, dst, keys).await
+                }
+                Message::Rename(src, dst) => {
+                    rename(m, key_dir, &mut current, &mut kd, src, dst, false).await
+                }
+                Message::RenameNx(src, dst) => {
+                    rename(m, key_dir, &mut current, &mut kd, src, dst, true).await
+                }
+                Message::GetDel(k) => get_del(m, key_dir, &mut current, &mut kd, k).await,
+                Message::GetEx(k, option) => {
+                    get_ex(m, key_dir, &mut current, &mut kd, k, *option).await
+                }
+                Message::Append(k, v) => {
+                    append_value(m, key_dir, &mut current, &mut kd, k, v).await
+                }
+                Message::SetRange(k, offset, v) => {
+                    set_range(m, key_dir, &mut current, &mut kd, k, *offset, v).await
+                }
+                Message::Persist(k) => persist(m, key_dir, &mut current, &mut kd, k).await,
+                Message::Expire(k, secs) => {
+                    expire(m, key_dir, &mut current, &mut kd, k, *secs).await
+                }
+                Message::Ttl(k) => match kd.get(k) {
+                    Some(data) => ttl(lookup_raw(m, &current, data).await.as_ref()),
+                    None => ttl(None),
+                },
+                Message::Type(k) => match kd.get(k) {
+                    Some(data) => {
+                        let entry = lookup_raw(m, &current, data).await;
+                        value_type(entry.filter(|e| !e.is_expired()).as_ref())
+                    }
+                    None => value_type(None),
+                },
+                Message::Strlen(k) => match kd.get(k) {
+                    Some(data) if data.page_id == current.id => {
+                        strlen(current.read_entry_raw(data.offset as usize).ok().as_ref())
+                    }
+                    Some(data) => strlen(fetch_stored(m, data).await.as_ref()),
+                    None => strlen(None),
+                },
+                Message::LPush(k, items) => {
+                    push(m, key_dir, &mut current, &mut kd, k, items, true).await
+                }
+                Message::RPush(k, items) => {
+                    push(m, key_dir, &mut current, &mut kd, k, items, false).await
+                }
+                Message::LPop(k) => pop(m, key_dir, &mut current, &mut kd, k, true).await,
+                Message::RPop(k) => pop(m, key_dir, &mut current, &mut kd, k, false).await,
+                Message::LLen(k) => match list_head(m, &current, &kd, k).await {
+                    Ok(head) => Message::Integer(head.map_or(0, |h| h.len as i64)),
+                    Err(e) => e,
+                },
+                Message::ObjectEncoding(k) => match kd.get(k) {
+                    Some(data) if data.page_id == current.id => {
+                        object_encoding(current.read_entry_raw(data.offset as usize).ok().as_ref())
+                    }
+                    Some(data) => object_encoding(fetch_stored(m, data).await.as_ref()),
+                    None => Message::None,
+                },
+                Message::ObjectFreq(k) => match kd.get(k) {
+                    Some(data) => Message::Count(m.access_count(data.page_id).await),
+                    None => Message::None,
+                },
+                Message::ObjectIdleTime(k) => match kd.get(k) {
+                    Some(data) => idle_time(m, data.page_id).await,
+                    None => Message::None,
+                },
+                Message::ObjectRefCount(k) => match kd.get(k) {
+                    Some(data) => ref_count(m, data.page_id).await,
+                    None => Message::None,
+                },
+                Message::ObjectHelp => Message::Help(OBJECT_HELP),
+                Message::LolWut => Message::Text(lolwut()),
+                Message::ClusterInfo => Message::Text(CLUSTER_INFO.to_string()),
+                Message::CommandCount => Message::Integer(COMMAND_REGISTRY.len() as i64),
+                Message::CommandInfo(name) => {
+                    Message::CommandSpecs(command::lookup(name).into_iter().collect())
+                }
+                Message::Get(k) => match kd.get(k) {
+                    Some(data) => {
+                        let entry = lookup_raw(m, &current, data).await;
+                        get_result(entry.filter(|e| !e.is_expired()))
+                    }
+                    None => Message::None,
+                },
+                Message::GetRange(k, start, end) => {
+                    let entry = match kd.get(k) {
+                        Some(data) => lookup_raw(m, &current, data).await,
+                        None => None,
+                    };
+                    get_range(k, entry.filter(|e| !e.is_expired()), *start, *end)
+                }
+                Message::BitCount(k, range) => {
+                    let entry = match kd.get(k) {
+                        Some(data) => lookup_raw(m, &current, data).await,
+                        None => None,
+                    };
+                    bit_count(entry.filter(|e| !e.is_expired()), *range)
+                }
+                Message::GetBit(k, offset) => {
+                    let entry = match kd.get(k) {
+                        Some(data) => lookup_raw(m, &current, data).await,
+                        None => None,
+                    };
+                    get_bit(entry.filter(|e| !e.is_expired()), *offset)
+                }
+                Message::SetBit(k, offset, bit) => {
+                    set_bit(m, key_dir, &mut current, &mut kd, k, *offset, *bit).await
+                }
+                Message::MGet(keys) => {
+                    let mut values = Vec::with_capacity(keys.len());
+                    for k in keys {
+                        let value = match kd.get(k) {
+                            Some(data) => lookup(m, &current, data)
+                                .await
+                                .map(|e| Bytes::from(e.value)),
+                            None => None,
+                        };
+                        values.push((k.clone(), value));
+                    }
+
+                    Message::Values(values)
+                }
+                Message::Scan(start, end) => {
+                    let mut results = Vec::new();
+                    for (k, data) in kd.scan(start, end) {
+                        if let Some(entry) = lookup(m, &current, data).await {
+                            results.push((Bytes::copy_from_slice(k), entry.value.into()));
+                        }
+                    }
+
+                    Message::Results(results)
+                }
+                Message::Keys(pattern, limit) => keys(&kd, pattern, *limit),
+                Message::KeyScan(cursor, count) => key_scan(&kd, cursor, *count),
+                Message::Stats => stats(m, &kd),
+                Message::Ping(payload) => Message::Pong(payload.clone()),
+                // Needs the locks this is holding
+                Message::DebugReload => {
+                    Message::Error("ERR DEBUG RELOAD isn't allowed in a transaction".to_string())
+                }
+                // Would hold them while it sleeps
+                Message::DebugSleep(_) => {
+                    Message::Error("ERR DEBUG SLEEP isn't allowed in a transaction".to_string())
+                }
+                Message::CacheEvict(page_id) => cache_evict(m, *page_id).await,
+                _ => Message::None,
+            };
+            responses.push(res);
         }
+
+        Message::Responses(responses)
     }
 
     pub fn parse(buf: &[u8]) -> Option<Self> {
@@ -94,95 +1119,4919 @@ impl Message {
             return Some(Message::Ignore(1));
         }
 
-        // check for "get " first
-        if buf.remaining() <= 4 {
-            return None;
+        // Commands without arguments, which may only be partially buffered
+        for (name, message) in [
+            (&b"stats\n"[..], Message::Stats),
+            (b"info\n", Message::Info),
+            (b"flushdb\n", Message::FlushDb(false)),
+            (b"flushdb async\n", Message::FlushDb(true)),
+            (b"dbsize\n", Message::DbSize),
+            (b"slowlog reset\n", Message::SlowlogReset),
+            (b"replicaof no one\n", Message::ReplicaOf(None)),
+            (b"replstream\n", Message::ReplStream),
+            (b"client list\n", Message::ClientList),
+            (b"client getname\n", Message::ClientGetName),
+            (b"object help\n", Message::ObjectHelp),
+            (b"lolwut\n", Message::LolWut),
+            (b"cluster info\n", Message::ClusterInfo),
+            (b"command count\n", Message::CommandCount),
+            (b"debug reload\n", Message::DebugReload),
+            (b"debug change-repl-id\n", Message::DebugChangeReplId),
+            (b"multi\n", Message::Multi),
+            (b"exec\n", Message::Exec),
+            (b"discard\n", Message::Discard),
+            (b"ping\n", Message::Ping(None)),
+            (b"reset\n", Message::Reset),
+            (b"unsubscribe\n", Message::Unsubscribe(Vec::new())),
+        ] {
+            if buf.get_ref().starts_with(name) {
+                return Some(message);
+            }
+            if name.starts_with(buf.get_ref()) {
+                return None;
+            }
         }
 
-        let maybe_get = &buf.get_ref()[0..3];
-        if maybe_get == b"get" {
-            buf.advance(4);
-            let Some(key) = read_until(&buf, b'\n') else { return None };
+        // check for "getset ", "getdel ", "getex ", "getrange " and "getbit " before "get ", which
+        // they start with
+        if buf.get_ref().starts_with(b"getbit ") {
+            buf.advance(7);
+            let line = read_until(&buf, b'\n')?;
+            let len = 7 + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let &[k, offset] = &args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let Some(offset) = parse_canonical(offset) else {
+                return Some(Message::Ignore(len));
+            };
 
-            return Some(Message::Get(key.into()));
+            return Some(Message::GetBit(line.slice_ref(k), offset));
         }
+        if buf.get_ref().starts_with(b"getdel ") {
+            buf.advance(7);
+            let key = read_until(&buf, b'\n')?;
 
-        // check for "insert " or "delete "
-        if buf.remaining() < 7 {
-            return None;
+            return Some(Message::GetDel(key));
         }
-        let maybe_insert_or_delete = &buf.get_ref()[0..6];
-        match maybe_insert_or_delete {
-            b"insert" => {
-                buf.advance(7);
-                let Some(key) = read_until(&buf, b' ') else { return None };
-                buf.advance(key.len() + 1);
-                let Some(value) = read_until(&buf, b'\n') else { return None };
+        if buf.get_ref().starts_with(b"getex ") {
+            buf.advance(6);
+            let line = read_until(&buf, b'\n')?;
+            let len = 6 + line.len() + 1;
 
-                Some(Message::Insert(key, value))
-            }
-            b"delete" => {
-                buf.advance(7);
-                let Some(key) = read_until(&buf, b'\n') else { return None };
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let option = match &args[1..] {
+                [] => Some(GetExOption::None),
+                [b"persist"] => Some(GetExOption::Persist),
+                [b"ex", n] => parse_canonical(n).map(GetExOption::Ex),
+                [b"px", n] => parse_canonical(n).map(GetExOption::Px),
+                [b"exat", n] => parse_canonical(n).map(GetExOption::ExAt),
+                _ => None,
+            };
+            let Some(option) = option else {
+                return Some(Message::Ignore(len));
+            };
+
+            return Some(Message::GetEx(line.slice_ref(args[0]), option));
+        }
+        if buf.get_ref().starts_with(b"getrange ") {
+            buf.advance(9);
+            let line = read_until(&buf, b'\n')?;
+            let len = 9 + line.len() + 1;
 
-                return Some(Message::Delete(key.into()));
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let &[k, start, end] = &args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let (Some(start), Some(end)) = (parse_canonical(start), parse_canonical(end)) else {
+                return Some(Message::Ignore(len));
+            };
+
+            return Some(Message::GetRange(line.slice_ref(k), start, end));
+        }
+        if buf.get_ref().starts_with(b"bitcount ") {
+            buf.advance(9);
+            let line = read_until(&buf, b'\n')?;
+            let len = 9 + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let range = match &args[1..] {
+                [] => Some(None),
+                [start, end] => parse_canonical(start).zip(parse_canonical(end)).map(Some),
+                _ => None,
+            };
+            let Some(range) = range else {
+                return Some(Message::Ignore(len));
+            };
+
+            return Some(Message::BitCount(line.slice_ref(args[0]), range));
+        }
+        if buf.get_ref().starts_with(b"getset ") {
+            buf.advance(7);
+            let key = read_until(&buf, b' ')?;
+            buf.advance(key.len() + 1);
+            let value = read_until(&buf, b'\n')?;
+
+            return Some(Message::GetSet(key, value));
+        }
+        if buf.get_ref().starts_with(b"setnx ") {
+            buf.advance(6);
+            let key = read_until(&buf, b' ')?;
+            buf.advance(key.len() + 1);
+            let value = read_until(&buf, b'\n')?;
+
+            return Some(Message::SetNx(key, value));
+        }
+        if buf.get_ref().starts_with(b"compare_and_swap ") {
+            buf.advance(17);
+            let key = read_until(&buf, b' ')?;
+            buf.advance(key.len() + 1);
+            let expected = read_until(&buf, b' ')?;
+            buf.advance(expected.len() + 1);
+            let value = read_until(&buf, b'\n')?;
+
+            return Some(Message::CompareAndSwap(key, expected, value));
+        }
+        for (prefix, millis) in [(&b"setex "[..], false), (b"psetex ", true)] {
+            if !buf.get_ref().starts_with(prefix) {
+                continue;
             }
-            _ => {
-                return Some(Message::Ignore(buf.get_ref().len()));
+            buf.advance(prefix.len());
+            let line = read_until(&buf, b'\n')?;
+            let len = prefix.len() + line.len() + 1;
+
+            // The value is the rest of the line, spaces and all
+            let args: Vec<_> = line.splitn(3, |c| *c == b' ').collect();
+            let &[k, ttl, v] = &args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let Some(ttl) = parse_canonical(ttl) else {
+                return Some(Message::Ignore(len));
+            };
+
+            let (k, v) = (line.slice_ref(k), line.slice_ref(v));
+            return Some(match millis {
+                true => Message::PSetEx(k, ttl, v),
+                false => Message::SetEx(k, ttl, v),
+            });
+        }
+
+        // check for "renamenx " before "rename ", which it starts with
+        if buf.get_ref().starts_with(b"renamenx ") {
+            buf.advance(9);
+            let src = read_until(&buf, b' ')?;
+            buf.advance(src.len() + 1);
+            let dst = read_until(&buf, b'\n')?;
+
+            return Some(Message::RenameNx(src, dst));
+        }
+        if buf.get_ref().starts_with(b"rename ") {
+            buf.advance(7);
+            let src = read_until(&buf, b' ')?;
+            buf.advance(src.len() + 1);
+            let dst = read_until(&buf, b'\n')?;
+
+            return Some(Message::Rename(src, dst));
+        }
+
+        if buf.get_ref().starts_with(b"bitop ") {
+            buf.advance(6);
+            let line = read_until(&buf, b'\n')?;
+            let len = 6 + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let kind = match &args[..] {
+                [b"and", _, _, ..] => Some(BitOpKind::And),
+                [b"or", _, _, ..] => Some(BitOpKind::Or),
+                [b"xor", _, _, ..] => Some(BitOpKind::Xor),
+                [b"not", _, _] => Some(BitOpKind::Not),
+                _ => None,
+            };
+            let Some(kind) = kind else {
+                return Some(Message::Ignore(len));
+            };
+            let keys = args[2..].iter().map(|k| line.slice_ref(k)).collect();
+
+            return Some(Message::BitOp(kind, line.slice_ref(args[1]), keys));
+        }
+
+        if buf.get_ref().starts_with(b"copy ") {
+            buf.advance(5);
+            let line = read_until(&buf, b'\n')?;
+            let len = 5 + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let message = match &args[..] {
+                [src, dst] => Some((src, dst, false)),
+                [src, dst, b"replace"] => Some((src, dst, true)),
+                _ => None,
             }
+            .map(|(src, dst, replace)| {
+                Message::Copy(line.slice_ref(src), line.slice_ref(dst), replace)
+            });
+
+            return Some(message.unwrap_or(Message::Ignore(len)));
         }
-    }
 
-    pub fn len(&self) -> usize {
-        match self {
-            Message::Insert(k, v) => 9 + k.len() + v.len(),
-            Message::Delete(k) => 7 + k.len(),
-            Message::Get(k) => 5 + k.len(),
+        // check for "append "
+        if buf.get_ref().starts_with(b"append ") {
+            buf.advance(7);
+            let key = read_until(&buf, b' ')?;
+            buf.advance(key.len() + 1);
+            let value = read_until(&buf, b'\n')?;
 
-            Message::Result(k, v) => k.len() + v.len() + 1,
-            Message::Success => 8,
-            Message::Ignore(l) => *l,
-            Message::None => 0,
+            return Some(Message::Append(key, value));
         }
-    }
-}
+        if buf.get_ref().starts_with(b"setbit ") {
+            buf.advance(7);
+            let line = read_until(&buf, b'\n')?;
+            let len = 7 + line.len() + 1;
 
-fn read_until(cursor: &Cursor<&[u8]>, c: u8) -> Option<Bytes> {
-    let start = cursor.position() as usize;
-    let end = cursor.get_ref().len();
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let &[k, offset, bit] = &args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let offset = parse_canonical(offset);
+            let bit = match bit {
+                b"0" => Some(0),
+                b"1" => Some(1),
+                _ => None,
+            };
+            let (Some(offset), Some(bit)) = (offset, bit) else {
+                return Some(Message::Ignore(len));
+            };
 
-    for i in start..end {
-        if cursor.get_ref()[i] == c {
-            let ret = BytesMut::from(&cursor.get_ref()[start..i]);
-            let ret = Bytes::from(ret);
-            return Some(ret);
+            return Some(Message::SetBit(line.slice_ref(k), offset, bit));
         }
-    }
+        if buf.get_ref().starts_with(b"setrange ") {
+            buf.advance(9);
+            let line = read_until(&buf, b'\n')?;
+            let len = 9 + line.len() + 1;
 
-    None
-}
+            // The value is the rest of the line, spaces and all
+            let args: Vec<_> = line.splitn(3, |c| *c == b' ').collect();
+            let &[k, offset, v] = &args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let Some(offset) = parse_canonical(offset) else {
+                return Some(Message::Ignore(len));
+            };
 
-impl Into<Bytes> for Message {
-    fn into(self) -> Bytes {
-        match self {
-            Message::Insert(_, _)
-            | Message::Delete(_)
-            | Message::Get(_)
-            | Message::Ignore(_)
-            | Message::None => Bytes::new(),
+            return Some(Message::SetRange(
+                line.slice_ref(k),
+                offset,
+                line.slice_ref(v),
+            ));
+        }
 
-            Message::Result(k, v) => {
-                let len = k.len() + v.len() + 2;
-                let mut dst = BytesMut::zeroed(len);
+        // check for "persist "
+        if buf.get_ref().starts_with(b"persist ") {
+            buf.advance(8);
+            let key = read_until(&buf, b'\n')?;
 
-                crate::put_bytes!(dst, k, 0, k.len());
-                dst[k.len()] = b' ';
-                crate::put_bytes!(dst, v, k.len() + 1, v.len());
-                dst[len - 1] = b'\n';
+            return Some(Message::Persist(key));
+        }
 
-                dst.into()
-            }
-            Message::Success => Bytes::from("Success\n"),
+        // check for "ttl "
+        if buf.get_ref().starts_with(b"ttl ") {
+            buf.advance(4);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Ttl(key));
+        }
+
+        if buf.get_ref().starts_with(b"object encoding ") {
+            buf.advance(16);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::ObjectEncoding(key));
+        }
+        if buf.get_ref().starts_with(b"object freq ") {
+            buf.advance(12);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::ObjectFreq(key));
+        }
+        if buf.get_ref().starts_with(b"object idletime ") {
+            buf.advance(16);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::ObjectIdleTime(key));
+        }
+        if buf.get_ref().starts_with(b"object refcount ") {
+            buf.advance(16);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::ObjectRefCount(key));
         }
+
+        for (name, front) in [(&b"lpush "[..], true), (b"rpush ", false)] {
+            if buf.get_ref().starts_with(name) {
+                buf.advance(6);
+                let line = read_until(&buf, b'\n')?;
+                let mut args = line.split(|c| *c == b' ').map(|a| line.slice_ref(a));
+                let key = args.next().expect("split yields at least one part");
+                let items: Vec<_> = args.collect();
+                if items.is_empty() {
+                    return Some(Message::Ignore(6 + line.len() + 1));
+                }
+
+                return Some(match front {
+                    true => Message::LPush(key, items),
+                    false => Message::RPush(key, items),
+                });
+            }
+        }
+        for (name, message) in [
+            (&b"lpop "[..], Message::LPop as fn(Bytes) -> Message),
+            (b"rpop ", Message::RPop),
+            (b"llen ", Message::LLen),
+        ] {
+            if buf.get_ref().starts_with(name) {
+                buf.advance(5);
+                let key = read_until(&buf, b'\n')?;
+
+                return Some(message(key));
+            }
+        }
+
+        if buf.get_ref().starts_with(b"type ") {
+            buf.advance(5);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Type(key));
+        }
+        if buf.get_ref().starts_with(b"strlen ") {
+            buf.advance(7);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Strlen(key));
+        }
+
+        // check for "expire "
+        if buf.get_ref().starts_with(b"expire ") {
+            buf.advance(7);
+            let line = read_until(&buf, b'\n')?;
+            let len = 7 + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let &[k, secs] = &args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let Some(secs) = parse_canonical(secs) else {
+                return Some(Message::Ignore(len));
+            };
+
+            return Some(Message::Expire(line.slice_ref(k), secs));
+        }
+
+        // check for "get " first
+        if buf.remaining() <= 4 {
+            return None;
+        }
+
+        let maybe_get = &buf.get_ref()[0..3];
+        if maybe_get == b"get" {
+            buf.advance(4);
+            let key = read_until(&buf, b'\n')?;
+
+            return Some(Message::Get(key));
+        }
+
+        // check for "scan "
+        if buf.remaining() < 5 {
+            return None;
+        }
+        let maybe_scan = &buf.get_ref()[0..4];
+        if maybe_scan == b"scan" {
+            buf.advance(5);
+            let start = read_until(&buf, b' ')?;
+            buf.advance(start.len() + 1);
+            let end = read_until(&buf, b'\n')?;
+
+            return Some(Message::Scan(start, end));
+        }
+
+        // Longest names first, "incr " would also match "incrby "
+        for name in [&b"incrby "[..], b"decrby ", b"incr ", b"decr "] {
+            if !buf.get_ref().starts_with(name) {
+                continue;
+            }
+            buf.advance(name.len());
+            let line = read_until(&buf, b'\n')?;
+            let len = name.len() + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let message = match (name, &args[..]) {
+                (b"incr ", [k]) => Message::Incr(line.slice_ref(k)),
+                (b"decr ", [k]) => Message::Decr(line.slice_ref(k)),
+                (b"incrby " | b"decrby ", [k, by]) => {
+                    let Some(by) = parse_canonical(by) else {
+                        return Some(Message::Ignore(len));
+                    };
+                    match name {
+                        b"incrby " => Message::IncrBy(line.slice_ref(k), by),
+                        _ => Message::DecrBy(line.slice_ref(k), by),
+                    }
+                }
+                _ => Message::Ignore(len),
+            };
+
+            return Some(message);
+        }
+
+        if buf.get_ref().starts_with(b"keyscan ") {
+            buf.advance(8);
+            let line = read_until(&buf, b'\n')?;
+            let len = 9 + line.len();
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let [cursor, count] = args[..] else {
+                return Some(Message::Ignore(len));
+            };
+            let Some(count) = parse_canonical(count).filter(|n| *n > 0) else {
+                return Some(Message::Ignore(len));
+            };
+
+            return Some(Message::KeyScan(line.slice_ref(cursor), count));
+        }
+
+        if buf.get_ref().starts_with(b"keys ") {
+            buf.advance(5);
+            let pattern = read_until(&buf, b'\n')?;
+
+            return Some(Message::Keys(pattern, DEFAULT_KEYS_LIMIT));
+        }
+
+        for (name, subscribe) in [(&b"subscribe "[..], true), (b"unsubscribe ", false)] {
+            if buf.get_ref().starts_with(name) {
+                buf.advance(name.len());
+                let line = read_until(&buf, b'\n')?;
+                let channels = line
+                    .split(|c| *c == b' ')
+                    .map(|c| line.slice_ref(c))
+                    .collect();
+
+                return Some(match subscribe {
+                    true => Message::Subscribe(channels),
+                    false => Message::Unsubscribe(channels),
+                });
+            }
+        }
+
+        if buf.get_ref().starts_with(b"ping ") {
+            buf.advance(5);
+            let payload = read_until(&buf, b'\n')?;
+
+            return Some(Message::Ping(Some(payload)));
+        }
+
+        if buf.get_ref().starts_with(b"publish ") {
+            buf.advance(8);
+            let channel = read_until(&buf, b' ')?;
+            buf.advance(channel.len() + 1);
+            let payload = read_until(&buf, b'\n')?;
+
+            return Some(Message::Publish(channel, payload));
+        }
+
+        if buf.get_ref().starts_with(b"debug sleep ") {
+            buf.advance(12);
+            let ms = read_until(&buf, b'\n')?;
+            let len = 12 + ms.len() + 1;
+
+            let ms = parse_canonical(&ms);
+
+            return Some(match ms {
+                Some(ms) => Message::DebugSleep(ms),
+                None => Message::Ignore(len),
+            });
+        }
+
+        if buf.get_ref().starts_with(b"cache evict ") {
+            buf.advance(12);
+            let page_id = read_until(&buf, b'\n')?;
+            let len = 12 + page_id.len() + 1;
+
+            let page_id = parse_canonical(&page_id);
+
+            return Some(match page_id {
+                Some(page_id) => Message::CacheEvict(page_id),
+                None => Message::Ignore(len),
+            });
+        }
+
+        if buf.get_ref().starts_with(b"select ") {
+            buf.advance(7);
+            let index = read_until(&buf, b'\n')?;
+            let len = 7 + index.len() + 1;
+
+            let index = parse_canonical(&index);
+
+            return Some(match index {
+                Some(index) => Message::Select(index),
+                None => Message::Ignore(len),
+            });
+        }
+
+        if buf.get_ref().starts_with(b"snapshot ") {
+            buf.advance(9);
+            let line = read_until(&buf, b'\n')?;
+            let len = 9 + line.len() + 1;
+
+            let args: Vec<_> = line.splitn(3, |c| *c == b' ').collect();
+            let message = match &args[..] {
+                [b"create"] => Some(Message::SnapshotCreate),
+                [b"get", h, k] => {
+                    parse_canonical(h).map(|h| Message::SnapshotGet(h, line.slice_ref(k)))
+                }
+                [b"release", h] => parse_canonical(h).map(Message::SnapshotRelease),
+                _ => None,
+            };
+
+            return Some(message.unwrap_or(Message::Ignore(len)));
+        }
+
+        if buf.get_ref().starts_with(b"replicaof ") {
+            buf.advance(10);
+            let line = read_until(&buf, b'\n')?;
+            let len = 10 + line.len() + 1;
+
+            let message = match line.split(|c| *c == b' ').collect::<Vec<_>>()[..] {
+                [host, port] => std::str::from_utf8(host)
+                    .ok()
+                    .zip(parse_canonical(port))
+                    .map(|(host, p)| Message::ReplicaOf(Some((host.to_string(), p)))),
+                _ => None,
+            };
+
+            return Some(message.unwrap_or(Message::Ignore(len)));
+        }
+
+        if buf.get_ref().starts_with(b"wait ") {
+            buf.advance(5);
+            let line = read_until(&buf, b'\n')?;
+            let len = 5 + line.len() + 1;
+
+            let args: Vec<_> = line.split(|c| *c == b' ').collect();
+            let message = match &args[..] {
+                [replicas, timeout] => parse_canonical(replicas)
+                    .zip(parse_canonical(timeout))
+                    .map(|(r, t)| Message::Wait(r, t)),
+                _ => None,
+            };
+
+            return Some(message.unwrap_or(Message::Ignore(len)));
+        }
+
+        if buf.get_ref().starts_with(b"slowlog get ") {
+            buf.advance(12);
+            let count = read_until(&buf, b'\n')?;
+            let len = 12 + count.len() + 1;
+
+            let count = parse_canonical(&count);
+
+            return Some(match count {
+                Some(count) => Message::SlowlogGet(count),
+                None => Message::Ignore(len),
+            });
+        }
+
+        if buf.get_ref().starts_with(b"command info ") {
+            buf.advance(13);
+            let name = read_until(&buf, b'\n')?;
+
+            return Some(Message::CommandInfo(name));
+        }
+
+        if buf.get_ref().starts_with(b"client setname ") {
+            buf.advance(15);
+            let name = read_until(&buf, b'\n')?;
+
+            return Some(Message::ClientSetName(name));
+        }
+
+        if buf.get_ref().starts_with(b"client kill id:") {
+            buf.advance(15);
+            let id = read_until(&buf, b'\n')?;
+            let len = 15 + id.len() + 1;
+
+            let id = parse_canonical(&id);
+
+            return Some(match id {
+                Some(id) => Message::ClientKill(id),
+                None => Message::Ignore(len),
+            });
+        }
+
+        if buf.get_ref().starts_with(b"auth ") {
+            buf.advance(5);
+            let token = read_until(&buf, b'\n')?;
+
+            return Some(Message::Auth(token));
+        }
+
+        if buf.get_ref().starts_with(b"mset ") {
+            buf.advance(5);
+            let line = read_until(&buf, b'\n')?;
+            let args: Vec<_> = line
+                .split(|c| *c == b' ')
+                .map(|a| line.slice_ref(a))
+                .collect();
+            if args.len() % 2 != 0 {
+                return Some(Message::Ignore(5 + line.len() + 1));
+            }
+
+            let pairs = args
+                .chunks(2)
+                .map(|kv| (kv[0].clone(), kv[1].clone()))
+                .collect();
+
+            return Some(Message::MSet(pairs));
+        }
+
+        if buf.get_ref().starts_with(b"mget ") {
+            buf.advance(5);
+            let line = read_until(&buf, b'\n')?;
+            let keys = line
+                .split(|c| *c == b' ')
+                .map(|k| line.slice_ref(k))
+                .collect();
+
+            return Some(Message::MGet(keys));
+        }
+
+        // check for "insert " or "delete "
+        if buf.remaining() < 7 {
+            return None;
+        }
+        let maybe_insert_or_delete = &buf.get_ref()[0..6];
+        match maybe_insert_or_delete {
+            b"insert" => {
+                buf.advance(7);
+                let key = read_until(&buf, b' ')?;
+                buf.advance(key.len() + 1);
+                let value = read_until(&buf, b'\n')?;
+
+                Some(Message::Insert(key, value))
+            }
+            b"delete" => {
+                buf.advance(7);
+                let key = read_until(&buf, b'\n')?;
+
+                Some(Message::Delete(key))
+            }
+            _ => Some(Message::Ignore(buf.get_ref().len())),
+        }
+    }
+
+    /// Name of the command, for logging. Empty for responses.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Insert(_, _) => "insert",
+            Message::MSet(_) => "mset",
+            Message::Delete(_) => "delete",
+            Message::FlushDb(_) => "flushdb",
+            Message::DbSize => "dbsize",
+            Message::Incr(_) => "incr",
+            Message::IncrBy(_, _) => "incrby",
+            Message::Decr(_) => "decr",
+            Message::DecrBy(_, _) => "decrby",
+            Message::Get(_) => "get",
+            Message::GetRange(_, _, _) => "getrange",
+            Message::BitCount(_, _) => "bitcount",
+            Message::BitOp(_, _, _) => "bitop",
+            Message::GetBit(_, _) => "getbit",
+            Message::SetBit(_, _, _) => "setbit",
+            Message::GetSet(_, _) => "getset",
+            Message::SetNx(_, _) => "setnx",
+            Message::CompareAndSwap(_, _, _) => "compare_and_swap",
+            Message::SetEx(_, _, _) => "setex",
+            Message::PSetEx(_, _, _) => "psetex",
+            Message::Copy(_, _, _) => "copy",
+            Message::Rename(_, _) => "rename",
+            Message::RenameNx(_, _) => "renamenx",
+            Message::GetDel(_) => "getdel",
+            Message::GetEx(_, _) => "getex",
+            Message::Append(_, _) => "append",
+            Message::SetRange(_, _, _) => "setrange",
+            Message::Persist(_) => "persist",
+            Message::Expire(_, _) => "expire",
+            Message::Ttl(_) => "ttl",
+            Message::ObjectEncoding(_)
+            | Message::ObjectFreq(_)
+            | Message::ObjectIdleTime(_)
+            | Message::ObjectRefCount(_)
+            | Message::ObjectHelp => "object",
+            Message::LolWut => "lolwut",
+            Message::ClusterInfo => "cluster",
+            Message::CommandCount | Message::CommandInfo(_) => "command",
+            Message::Type(_) => "type",
+            Message::Strlen(_) => "strlen",
+            Message::LPush(_, _) => "lpush",
+            Message::RPush(_, _) => "rpush",
+            Message::LPop(_) => "lpop",
+            Message::RPop(_) => "rpop",
+            Message::LLen(_) => "llen",
+            Message::MGet(_) => "mget",
+            Message::Scan(_, _) => "scan",
+            Message::Keys(_, _) => "keys",
+            Message::KeyScan(_, _) => "keyscan",
+            Message::Stats => "stats",
+            Message::Info => "info",
+            Message::DebugSleep(_) => "debug",
+            Message::CacheEvict(_) => "cache",
+            Message::DebugReload => "debug",
+            Message::DebugChangeReplId => "debug",
+            Message::Multi => "multi",
+            Message::Exec => "exec",
+            Message::Discard => "discard",
+            Message::Auth(_) => "auth",
+            Message::Select(_) => "select",
+            Message::Ping(_) => "ping",
+            Message::Reset => "reset",
+            Message::Wait(_, _) => "wait",
+            Message::ReplicaOf(_) => "replicaof",
+            Message::ReplStream => "replstream",
+            Message::SlowlogGet(_) | Message::SlowlogReset => "slowlog",
+            Message::ClientList
+            | Message::ClientKill(_)
+            | Message::ClientSetName(_)
+            | Message::ClientGetName => "client",
+            Message::SnapshotCreate | Message::SnapshotGet(_, _) | Message::SnapshotRelease(_) => {
+                "snapshot"
+            }
+            Message::Subscribe(_) => "subscribe",
+            Message::Unsubscribe(_) => "unsubscribe",
+            Message::Publish(_, _) => "publish",
+
+            Message::Result(_, _)
+            | Message::Results(_)
+            | Message::Values(_)
+            | Message::KeyList(_, _)
+            | Message::ScanPage(_, _)
+            | Message::SlowLogEntries(_)
+            | Message::CommandSpecs(_)
+            | Message::Subscribed(_, _)
+            | Message::Unsubscribed(_, _)
+            | Message::Published(_, _)
+            | Message::Responses(_)
+            | Message::Text(_)
+            | Message::Help(_)
+            | Message::Error(_)
+            | Message::Queued
+            | Message::Pong(_)
+            | Message::ReplEntry(_, _)
+            | Message::ResetDone
+            | Message::Count(_)
+            | Message::Integer(_)
+            | Message::Success
+            | Message::Ignore(_)
+            | Message::None => "",
+        }
+    }
+
+    /// How much an `incr`, `incrby`, `decr` or `decrby` adds. `None` if negating the `decrby`
+    /// amount overflows.
+    fn delta(&self) -> Option<i64> {
+        match self {
+            Message::Incr(_) => Some(1),
+            Message::IncrBy(_, n) => Some(*n),
+            Message::Decr(_) => Some(-1),
+            Message::DecrBy(_, n) => n.checked_neg(),
+            _ => Some(0),
+        }
+    }
+
+    /// How long until a `setex` or `psetex` value expires, in milliseconds.
+    fn expire_millis(&self) -> u64 {
+        match self {
+            Message::SetEx(_, secs, _) => secs.saturating_mul(1000),
+            Message::PSetEx(_, ms, _) => *ms,
+            _ => 0,
+        }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        match self {
+            Message::Insert(k, v) => 9 + k.len() + v.len(),
+            Message::MSet(pairs) => {
+                5 + pairs
+                    .iter()
+                    .map(|(k, v)| k.len() + v.len() + 2)
+                    .sum::<usize>()
+            }
+            Message::Delete(k) => 7 + k.len(),
+            Message::FlushDb(false) => 8,
+            Message::FlushDb(true) => 14,
+            Message::DbSize => 7,
+            Message::Incr(k) | Message::Decr(k) => 6 + k.len(),
+            Message::IncrBy(k, n) | Message::DecrBy(k, n) => 9 + k.len() + n.to_string().len(),
+            Message::Get(k) => 5 + k.len(),
+            Message::GetRange(k, start, end) => {
+                12 + k.len() + start.to_string().len() + end.to_string().len()
+            }
+            Message::BitCount(k, None) => 10 + k.len(),
+            Message::GetBit(k, offset) => 9 + k.len() + offset.to_string().len(),
+            Message::SetBit(k, offset, _) => 11 + k.len() + offset.to_string().len(),
+            Message::BitOp(kind, dst, keys) => {
+                8 + kind.name().len() + dst.len() + keys.iter().map(|k| k.len() + 1).sum::<usize>()
+            }
+            Message::BitCount(k, Some((start, end))) => {
+                12 + k.len() + start.to_string().len() + end.to_string().len()
+            }
+            Message::GetSet(k, v) | Message::Append(k, v) => 9 + k.len() + v.len(),
+            Message::SetRange(k, o, v) => 12 + k.len() + o.to_string().len() + v.len(),
+            Message::SetNx(k, v) => 8 + k.len() + v.len(),
+            Message::CompareAndSwap(k, e, v) => 20 + k.len() + e.len() + v.len(),
+            Message::SetEx(k, secs, v) => 9 + k.len() + secs.to_string().len() + v.len(),
+            Message::PSetEx(k, ms, v) => 10 + k.len() + ms.to_string().len() + v.len(),
+            Message::Copy(src, dst, false) => 7 + src.len() + dst.len(),
+            Message::Copy(src, dst, true) => 15 + src.len() + dst.len(),
+            Message::Rename(src, dst) => 9 + src.len() + dst.len(),
+            Message::RenameNx(src, dst) => 11 + src.len() + dst.len(),
+            Message::GetDel(k) => 8 + k.len(),
+            Message::GetEx(k, option) => {
+                let option = match option {
+                    GetExOption::Ex(n) | GetExOption::Px(n) => 4 + n.to_string().len(),
+                    GetExOption::ExAt(n) => 6 + n.to_string().len(),
+                    GetExOption::Persist => 8,
+                    GetExOption::None => 0,
+                };
+                7 + k.len() + option
+            }
+            Message::Persist(k) => 9 + k.len(),
+            Message::Expire(k, secs) => 9 + k.len() + secs.to_string().len(),
+            Message::Ttl(k) => 5 + k.len(),
+            Message::ObjectEncoding(k) => 17 + k.len(),
+            Message::ObjectFreq(k) => 13 + k.len(),
+            Message::ObjectIdleTime(k) => 17 + k.len(),
+            Message::ObjectRefCount(k) => 17 + k.len(),
+            Message::ObjectHelp => 12,
+            Message::LolWut => 7,
+            Message::ClusterInfo => 13,
+            Message::CommandCount => 14,
+            Message::CommandInfo(name) => 14 + name.len(),
+            Message::Type(k) => 6 + k.len(),
+            Message::Strlen(k) => 8 + k.len(),
+            Message::LPush(k, items) | Message::RPush(k, items) => {
+                7 + k.len() + items.iter().map(|i| 1 + i.len()).sum::<usize>()
+            }
+            Message::LPop(k) | Message::RPop(k) | Message::LLen(k) => 6 + k.len(),
+            Message::MGet(keys) => 5 + keys.iter().map(|k| k.len() + 1).sum::<usize>(),
+            Message::Scan(s, e) => 7 + s.len() + e.len(),
+            Message::Keys(p, _) => 6 + p.len(),
+            Message::KeyScan(c, n) => 10 + c.len() + n.to_string().len(),
+            Message::Stats => 6,
+            Message::Info => 5,
+            Message::DebugSleep(ms) => 13 + ms.to_string().len(),
+            Message::CacheEvict(page_id) => 13 + page_id.to_string().len(),
+            Message::DebugReload => 13,
+            Message::DebugChangeReplId => 21,
+            Message::Multi => 6,
+            Message::Exec => 5,
+            Message::Discard => 8,
+            Message::Auth(t) => 6 + t.len(),
+            Message::Select(i) => 8 + i.to_string().len(),
+            Message::Ping(None) => 5,
+            Message::Ping(Some(p)) => 6 + p.len(),
+            Message::Reset => 6,
+            Message::SnapshotCreate => 16,
+            Message::SnapshotGet(h, k) => 15 + h.to_string().len() + k.len(),
+            Message::SnapshotRelease(h) => 18 + h.to_string().len(),
+            Message::Wait(r, t) => 7 + r.to_string().len() + t.to_string().len(),
+            Message::ReplicaOf(None) => 17,
+            Message::ReplicaOf(Some((host, port))) => 12 + host.len() + port.to_string().len(),
+            Message::ReplStream => 11,
+            Message::SlowlogGet(n) => 13 + n.to_string().len(),
+            Message::SlowlogReset => 14,
+            Message::ClientList => 12,
+            Message::ClientKill(id) => 16 + id.to_string().len(),
+            Message::ClientSetName(name) => 16 + name.len(),
+            Message::ClientGetName => 15,
+            Message::Subscribe(c) => 10 + c.iter().map(|c| c.len() + 1).sum::<usize>(),
+            Message::Unsubscribe(c) if c.is_empty() => 12,
+            Message::Unsubscribe(c) => 12 + c.iter().map(|c| c.len() + 1).sum::<usize>(),
+            Message::Publish(c, p) => 10 + c.len() + p.len(),
+
+            Message::Result(k, v) => k.len() + v.len() + 1,
+            Message::Results(r) => r.iter().map(|(k, v)| k.len() + v.len() + 2).sum::<usize>() + 1,
+            Message::Values(v) => {
+                let line = |(k, v): &(Bytes, Option<Bytes>)| {
+                    k.len() + v.as_ref().map_or(0, |v| v.len() + 1) + 1
+                };
+                v.iter().map(line).sum::<usize>() + 1
+            }
+            Message::KeyList(keys, truncated) => {
+                let header = keys.len().to_string().len() + if *truncated { 10 } else { 0 } + 1;
+                header + keys.iter().map(|k| k.len() + 1).sum::<usize>()
+            }
+            Message::ScanPage(cursor, keys) => {
+                let header = cursor.len() + 1 + keys.len().to_string().len() + 1;
+                header + keys.iter().map(|k| k.len() + 1).sum::<usize>()
+            }
+            Message::SlowLogEntries(entries) => {
+                let line = |e: &SlowLogEntry| slowlog_line(e).len();
+                entries.len().to_string().len() + 1 + entries.iter().map(line).sum::<usize>()
+            }
+            Message::CommandSpecs(specs) => {
+                let line = |s: &&CommandSpec| command_spec_line(s).len();
+                specs.len().to_string().len() + 1 + specs.iter().map(line).sum::<usize>()
+            }
+            Message::Subscribed(c, n) => 12 + c.len() + n.to_string().len(),
+            Message::Unsubscribed(c, n) => 14 + c.len() + n.to_string().len(),
+            Message::Published(c, p) => 10 + c.len() + p.len(),
+            Message::Responses(r) => r.iter().map(Message::len).sum(),
+            Message::Text(t) | Message::Error(t) => t.len() + 1,
+            Message::Help(lines) => lines.iter().map(|l| l.len() + 1).sum(),
+            Message::Queued => 7,
+            Message::Pong(None) => 5,
+            Message::Pong(Some(p)) => p.len() + 1,
+            Message::ReplEntry(seq, e) => {
+                seq.to_string().len() + e.len().to_string().len() + 2 + e.len()
+            }
+            Message::ResetDone => 6,
+            Message::Count(n) => n.to_string().len() + 1,
+            Message::Integer(n) => n.to_string().len() + 1,
+            Message::Success => 8,
+            Message::Ignore(l) => *l,
+            Message::None => 0,
+        }
+    }
+}
+
+/// Appends `entry` to the current page, moving on to a new page if it is full, and logs the write.
+async fn append(
+    m: &PageCache,
+    kd: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    entry: &Entry,
+) -> io::Result<u64> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "entry doesn't fit in a page");
+
+    // Move on before writing rather than after a failed write
+    if current.remaining_capacity() < entry.len() {
+        m.replace_current(current, kd).await?;
+    }
+    let offset = current.write_entry(entry, None).map_err(|_| too_large())?;
+
+    m.log_write(current.id, offset, entry).await?;
+
+    Ok(offset)
+}
+
+/// Writes every pair in order under the already held locks. On error the pairs before the failing
+/// one stay written.
+async fn put_all(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    pairs: &[(Bytes, Bytes)],
+) -> Message {
+    for (k, v) in pairs {
+        let entry = Entry::new(k, v, EntryType::Put);
+        let offset = match append(m, key_dir, current, &entry).await {
+            Ok(o) => o,
+            Err(e) => return Message::Error(format!("ERR {}", e)),
+        };
+        kd.insert(k, m.key_data(current.id, offset));
+    }
+
+    Message::Count(pairs.len())
+}
+
+/// Adds `delta` to the integer stored at `k` under the already held locks, a missing key counting
+/// as 0. `None` is a delta that overflowed on its own, like `decrby` with `i64::MIN`.
+async fn incr_by(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    delta: Option<i64>,
+) -> Message {
+    let overflow = || Message::Error("ERR overflow".to_string());
+
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let old = match old {
+        Some(entry) => match std::str::from_utf8(&entry.value).map(str::parse::<i64>) {
+            Ok(Ok(n)) => n,
+            _ => return Message::Error("ERR not an integer".to_string()),
+        },
+        None => 0,
+    };
+    let Some(new) = delta.and_then(|d| old.checked_add(d)) else {
+        return overflow();
+    };
+
+    let entry = Entry::new(k, new.to_string().as_bytes(), EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Integer(new)
+}
+
+/// Writes `v` to `k` under the already held locks and answers with the value it replaced, or
+/// `None` if there was none.
+async fn get_set(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    v: &Bytes,
+) -> Message {
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+
+    let entry = Entry::new(k, v, EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    match old {
+        Some(entry) => Message::Result(entry.key.into(), entry.value.into()),
+        None => Message::None,
+    }
+}
+
+/// Writes `v` to `k` under the already held locks if it doesn't exist, answering with 1 if it
+/// was written and 0 if not.
+async fn set_nx(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    v: &Bytes,
+) -> Message {
+    if let Some(data) = kd.get(k) {
+        if lookup(m, current, data).await.is_some() {
+            return Message::Integer(0);
+        }
+    }
+
+    let entry = Entry::new(k, v, EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Integer(1)
+}
+
+/// Writes `v` to `k` under the already held locks if its value is `expected`, a missing key
+/// holding an empty one. Answers with 1 if it was written, otherwise with 0 and the current value.
+async fn compare_and_swap(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    expected: &Bytes,
+    v: &Bytes,
+) -> Message {
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let matches = match &old {
+        Some(entry) => entry.value == expected[..],
+        None => expected.is_empty(),
+    };
+    if !matches {
+        let old = match old {
+            Some(entry) => Message::Result(entry.key.into(), entry.value.into()),
+            None => Message::None,
+        };
+        return Message::Responses(vec![Message::Integer(0), old]);
+    }
+
+    let entry = Entry::new(k, v, EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Integer(1)
+}
+
+/// Writes `v` to `k` under the already held locks, expiring `ms` milliseconds from now.
+async fn set_ex(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    v: &Bytes,
+    ms: u64,
+) -> Message {
+    if ms == 0 {
+        return Message::Error("ERR invalid expire time".to_string());
+    }
+
+    let mut entry = Entry::new(k, v, EntryType::Put);
+    entry.expire_at = Some(timestamp_millis().saturating_add(ms));
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Success
+}
+
+/// Copies `src` to `dst` under the already held locks, keeping its timestamp and expiry. Answers
+/// with 1, or 0 if `src` doesn't exist or `dst` does and isn't to be replaced.
+async fn copy(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    src: &Bytes,
+    dst: &Bytes,
+    replace: bool,
+) -> Message {
+    let source = match kd.get(src) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let Some(source) = source else {
+        return Message::Integer(0);
+    };
+    if !replace {
+        if let Some(data) = kd.get(dst) {
+            if lookup(m, current, data).await.is_some() {
+                return Message::Integer(0);
+            }
+        }
+    }
+
+    let entry = Entry {
+        key: dst.as_ref().into(),
+        ..source
+    };
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(dst, m.key_data(current.id, offset));
+
+    Message::Integer(1)
+}
+
+/// Moves `src` to `dst` under the already held locks, keeping its timestamp and expiry. The
+/// destination is written before the source's tombstone, so a crash in between leaves both
+/// rather than neither. With `nx` answers with 1, or 0 if `dst` exists.
+async fn rename(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    src: &Bytes,
+    dst: &Bytes,
+    nx: bool,
+) -> Message {
+    let source = match kd.get(src) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let Some(source) = source else {
+        return Message::Error("ERR no such key".to_string());
+    };
+    let done = match nx {
+        true => Message::Integer(1),
+        false => Message::Success,
+    };
+    if nx {
+        let exists = match kd.get(dst) {
+            Some(data) => lookup(m, current, data).await.is_some(),
+            None => false,
+        };
+        if exists {
+            return Message::Integer(0);
+        }
+    }
+    if src == dst {
+        return done;
+    }
+
+    let entry = Entry {
+        key: dst.as_ref().into(),
+        ..source
+    };
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(dst, m.key_data(current.id, offset));
+
+    let tombstone = Entry::new(src, &[], EntryType::Delete);
+    if let Err(e) = append(m, key_dir, current, &tombstone).await {
+        return Message::Error(format!("ERR {}", e));
+    }
+    kd.remove(src);
+
+    done
+}
+
+/// Deletes `k` under the already held locks and answers with the value it had, or `None` if there
+/// was none. Nothing is written for a missing key.
+async fn get_del(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+) -> Message {
+    let Some(data) = kd.get(k) else {
+        return Message::None;
+    };
+    let old = lookup(m, current, data).await;
+
+    let entry = Entry::new(k, &[], EntryType::Delete);
+    if let Err(e) = append(m, key_dir, current, &entry).await {
+        return Message::Error(format!("ERR {}", e));
+    }
+    kd.remove(k);
+
+    match old {
+        Some(entry) => Message::Result(entry.key.into(), entry.value.into()),
+        None => Message::None,
+    }
+}
+
+/// Deletes every key under the already held locks. Unless `lazy`, a tombstone is written for each
+/// before answering, stopping at the first that fails. Otherwise the keys are removed right away
+/// and a task writes the tombstones once the locks are free, one key at a time so other writers
+/// get a turn.
+async fn flush_db(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    lazy: bool,
+) -> Message {
+    if lazy {
+        let keys = kd.take_keys();
+        let m = m.clone();
+        let key_dir = key_dir.clone();
+        tokio::spawn(async move {
+            for k in keys {
+                let _lists = m.lock_lists().await;
+                let mut current = m.get_current().await;
+                let mut kd = key_dir.write().await;
+                // Written again since, the new entry supersedes the flushed one
+                if kd.get(&k).is_some() {
+                    continue;
+                }
+
+                let entry = Entry::new(&k, &[], EntryType::Delete);
+                if let Err(e) = append(&m, &key_dir, &mut current, &entry).await {
+                    eprintln!("flushdb error: {}", e);
+                    return;
+                }
+                kd.remove(&k);
+            }
+        });
+
+        return Message::Success;
+    }
+
+    let keys: Vec<_> = kd
+        .scan(b"", b"")
+        .map(|(k, _)| Bytes::copy_from_slice(k))
+        .collect();
+    for k in keys {
+        let entry = Entry::new(&k, &[], EntryType::Delete);
+        if let Err(e) = append(m, key_dir, current, &entry).await {
+            return Message::Error(format!("ERR {}", e));
+        }
+        kd.remove(&k);
+    }
+
+    Message::Success
+}
+
+/// Adds `v` to the end of the value at `k` under the already held locks and answers with the new
+/// length. A missing key counts as empty.
+async fn append_value(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    v: &Bytes,
+) -> Message {
+    let mut value = match kd.get(k) {
+        Some(data) => lookup(m, current, data)
+            .await
+            .map(|e| e.value)
+            .unwrap_or_default(),
+        None => BytesMut::new(),
+    };
+    value.extend_from_slice(v);
+
+    let entry = Entry::new(k, &value, EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Count(value.len())
+}
+
+/// Writes `v` over the value at `k` from `offset` on under the already held locks, padding it
+/// with zeroes up to `offset`, and answers with the new length. A missing key counts as empty,
+/// and nothing is written if `v` is.
+async fn set_range(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    offset: u64,
+    v: &Bytes,
+) -> Message {
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let (mut value, expire_at) = match old {
+        Some(old) => (old.value, old.expire_at),
+        None => (BytesMut::new(), None),
+    };
+    if v.is_empty() {
+        return Message::Count(value.len());
+    }
+
+    let start = offset as usize;
+    let end = start + v.len();
+    if value.len() < end {
+        value.resize(end, 0);
+    }
+    value[start..end].copy_from_slice(v);
+
+    let mut entry = Entry::new(k, &value, EntryType::Put);
+    entry.expire_at = expire_at;
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Count(value.len())
+}
+
+/// Rewrites `k` without an expiry under the already held locks. Answers with 1 if it had one, 0
+/// if it didn't or doesn't exist, in which case nothing is written.
+async fn persist(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+) -> Message {
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let Some(old) = old.filter(|e| e.expire_at.is_some()) else {
+        return Message::Integer(0);
+    };
+
+    let entry = Entry::new(k, &old.value, EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Integer(1)
+}
+
+/// Answers with `k`'s value like `get`, rewriting its expiry first under the already held locks
+/// unless `option` leaves it as it is. Nothing is written if `k` doesn't exist, or it is to
+/// persist and doesn't expire anyway.
+async fn get_ex(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    option: GetExOption,
+) -> Message {
+    // The new expiry, if there is one to write
+    let expire_at = match option {
+        GetExOption::Ex(0) | GetExOption::Px(0) => {
+            return Message::Error("ERR invalid expire time".to_string())
+        }
+        GetExOption::Ex(secs) => Some(Some(
+            timestamp_millis().saturating_add(secs.saturating_mul(1000)),
+        )),
+        GetExOption::Px(ms) => Some(Some(timestamp_millis().saturating_add(ms))),
+        GetExOption::ExAt(unix) => Some(Some(unix.saturating_mul(1000))),
+        GetExOption::Persist => Some(None),
+        GetExOption::None => None,
+    };
+
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let Some(old) = old else {
+        return Message::None;
+    };
+
+    if let Some(expire_at) = expire_at.filter(|at| at.is_some() || old.expire_at.is_some()) {
+        let mut entry = Entry::new(k, &old.value, EntryType::Put);
+        entry.expire_at = expire_at;
+        let offset = match append(m, key_dir, current, &entry).await {
+            Ok(o) => o,
+            Err(e) => return Message::Error(format!("ERR {}", e)),
+        };
+        kd.insert(k, m.key_data(current.id, offset));
+    }
+
+    Message::Result(old.key.into(), old.value.into())
+}
+
+/// Rewrites `k` to expire `secs` from now under the already held locks. Answers with 1, or 0 if
+/// `k` doesn't exist, in which case nothing is written.
+async fn expire(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    secs: u64,
+) -> Message {
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let Some(old) = old else {
+        return Message::Integer(0);
+    };
+
+    let mut entry = Entry::new(k, &old.value, EntryType::Put);
+    entry.expire_at = Some(timestamp_millis().saturating_add(secs.saturating_mul(1000)));
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Integer(1)
+}
+
+/// Adds `items` to the front of the list at `k`, or its back, under the already held locks and
+/// answers with its new length. Only the head and any new nodes before it are written for the
+/// front, every node for the back.
+async fn push(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    items: &[Bytes],
+    front: bool,
+) -> Message {
+    if let Some(item) = items.iter().find(|i| !ListNode::default().has_room(k, i)) {
+        let e = format!(
+            "ERR list item of {} bytes doesn't fit in a node",
+            item.len()
+        );
+        return Message::Error(e);
+    }
+    let nodes = match front {
+        true => list_head(m, current, kd, k).await.map(Vec::from_iter),
+        false => list_nodes(m, current, kd, k).await,
+    };
+    let mut nodes = match nodes {
+        Ok(nodes) => nodes,
+        Err(e) => return e,
+    };
+
+    for item in items {
+        let end = match front {
+            true => nodes.first_mut(),
+            false => nodes.last_mut(),
+        };
+        match end {
+            Some(node) if node.has_room(k, item) => {
+                match front {
+                    true => node.items.push_front(item.clone()),
+                    false => node.items.push_back(item.clone()),
+                }
+                node.len += 1;
+            }
+            _ => {
+                let node = ListNode {
+                    next: None,
+                    len: 1,
+                    items: [item.clone()].into(),
+                };
+                match front {
+                    true => nodes.insert(0, node),
+                    false => nodes.push(node),
+                }
+            }
+        }
+    }
+
+    match write_list(m, key_dir, current, kd, k, nodes).await {
+        Ok(len) => Message::Integer(len as i64),
+        Err(e) => e,
+    }
+}
+
+/// Removes the first item of the list at `k`, or its last, under the already held locks and
+/// answers with it, or `None` if there is no list. Popping the last item deletes `k`.
+async fn pop(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    front: bool,
+) -> Message {
+    let nodes = match front {
+        true => list_head(m, current, kd, k).await.map(Vec::from_iter),
+        false => list_nodes(m, current, kd, k).await,
+    };
+    let mut nodes = match nodes {
+        Ok(nodes) => nodes,
+        Err(e) => return e,
+    };
+
+    let item = match front {
+        true => {
+            let Some(head) = nodes.first_mut() else {
+                return Message::None;
+            };
+            let item = head.items.pop_front();
+            head.len = head.len.saturating_sub(1);
+            if head.items.is_empty() {
+                match head.next {
+                    // The next node becomes the head as it is
+                    Some(next) => match list_node(m, current, next).await {
+                        Ok(next) => nodes[0] = next,
+                        Err(e) => return e,
+                    },
+                    None => nodes.clear(),
+                }
+            }
+            item
+        }
+        false => {
+            let Some(tail) = nodes.last_mut() else {
+                return Message::None;
+            };
+            let item = tail.items.pop_back();
+            tail.len = tail.len.saturating_sub(1);
+            if tail.items.is_empty() {
+                nodes.pop();
+                if let Some(tail) = nodes.last_mut() {
+                    tail.next = None;
+                    tail.len = tail.items.len() as u64;
+                }
+            }
+            item
+        }
+    };
+
+    if let Err(e) = write_list(m, key_dir, current, kd, k, nodes).await {
+        return e;
+    }
+    match item {
+        Some(item) => Message::Result(k.clone(), item),
+        None => Message::None,
+    }
+}
+
+/// Writes `nodes`, in list order, tail first so that the head is written last, and points `k` at
+/// the head. The last node keeps its `next` and `len`, covering whatever of the list wasn't
+/// rewritten, the others are pointed at the node after them. No nodes deletes `k`. Answers with
+/// the length of the list.
+async fn write_list(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    mut nodes: Vec<ListNode>,
+) -> Result<u64, Message> {
+    let error = |e: io::Error| Message::Error(format!("ERR {}", e));
+
+    let Some(mut node) = nodes.pop() else {
+        let entry = Entry::new(k, &[], EntryType::Delete);
+        append(m, key_dir, current, &entry).await.map_err(error)?;
+        kd.remove(k);
+        return Ok(0);
+    };
+    loop {
+        let entry = Entry::new(k, &node.encode(), EntryType::ListNode);
+        let offset = append(m, key_dir, current, &entry).await.map_err(error)?;
+
+        let Some(mut prev) = nodes.pop() else {
+            kd.insert(k, m.key_data(current.id, offset));
+            return Ok(node.len);
+        };
+        prev.next = Some((current.id, offset));
+        prev.len = node.len + prev.items.len() as u64;
+        node = prev;
+    }
+}
+
+/// The head of the list at `k`, `None` if there is no such key.
+async fn list_head(
+    m: &PageCache,
+    current: &PageInner,
+    kd: &KeyDir,
+    k: &[u8],
+) -> Result<Option<ListNode>, Message> {
+    match kd.get(k) {
+        Some(data) => decode_head(lookup_raw(m, current, data).await),
+        None => Ok(None),
+    }
+}
+
+/// Every node of the list at `k`, from the head to the tail.
+async fn list_nodes(
+    m: &PageCache,
+    current: &PageInner,
+    kd: &KeyDir,
+    k: &[u8],
+) -> Result<Vec<ListNode>, Message> {
+    let mut nodes = Vec::from_iter(list_head(m, current, kd, k).await?);
+    while let Some(next) = nodes.last().and_then(|n| n.next) {
+        nodes.push(list_node(m, current, next).await?);
+    }
+
+    Ok(nodes)
+}
+
+async fn list_node(
+    m: &PageCache,
+    current: &PageInner,
+    (page_id, offset): (PageID, u64),
+) -> Result<ListNode, Message> {
+    let entry = lookup_raw(m, current, &m.key_data(page_id, offset)).await;
+    entry
+        .filter(|e| e.t == EntryType::ListNode)
+        .and_then(|e| ListNode::decode(&e.value))
+        .ok_or_else(|| {
+            let e = format!(
+                "ERR missing list node at page {} offset {}",
+                page_id, offset
+            );
+            Message::Error(e)
+        })
+}
+
+/// The list node in `entry`, `None` if there is no entry or it expired. An error for a string.
+fn decode_head(entry: Option<Entry>) -> Result<Option<ListNode>, Message> {
+    match entry {
+        Some(e) if e.t == EntryType::ListNode => match ListNode::decode(&e.value) {
+            Some(head) => Ok(Some(head)),
+            None => Err(Message::Error("ERR corrupt list node".to_string())),
+        },
+        Some(e) if !e.is_expired() => Err(Message::Error(WRONGTYPE.to_string())),
+        _ => Ok(None),
+    }
+}
+
+/// The seconds `entry` has left, rounded up. -1 if it doesn't expire and -2 if there is no entry.
+fn ttl(entry: Option<&Entry>) -> Message {
+    let Some(entry) = entry else {
+        return Message::Integer(-2);
+    };
+
+    match entry.expire_at {
+        Some(expire_at) => {
+            let left = expire_at.saturating_sub(timestamp_millis()).div_ceil(1000);
+            Message::Integer(left.try_into().unwrap_or(i64::MAX))
+        }
+        None => Message::Integer(-1),
+    }
+}
+
+/// Like `PageCache::fetch_entry`, but reads entries in the current page from `current` since the
+/// caller already holds its lock. Lists count as missing, to string commands their key is free.
+async fn lookup(m: &PageCache, current: &PageInner, data: &KeyData) -> Option<Entry> {
+    lookup_raw(m, current, data)
+        .await
+        .filter(|entry| entry.t == EntryType::Put && !entry.is_expired())
+}
+
+/// The entry for `k` including if it has expired, for commands that only read. Writers take the
+/// current page before the key dir, so it is taken first here too. The other way round a read of
+/// a key in the current page and a write block each other forever.
+async fn get_raw(m: &PageCache, key_dir: &RwLock<KeyDir>, k: &[u8]) -> Option<Entry> {
+    let current = m.get_current().await;
+    let kd = key_dir.read().await;
+
+    lookup_raw(m, &current, kd.get(k)?).await
+}
+
+/// `get_raw`, leaving the value as it is stored instead of decompressing it.
+async fn get_stored(m: &PageCache, key_dir: &RwLock<KeyDir>, k: &[u8]) -> Option<Entry> {
+    let current = m.get_current().await;
+    let kd = key_dir.read().await;
+    let data = kd.get(k)?;
+    if data.page_id == current.id {
+        return current.read_entry_raw(data.offset as usize).ok();
+    }
+
+    fetch_stored(m, data).await
+}
+
+/// `lookup`, including entries that have expired.
+async fn lookup_raw(m: &PageCache, current: &PageInner, data: &KeyData) -> Option<Entry> {
+    if data.page_id != current.id {
+        return fetch_raw(m, data).await;
+    }
+
+    current.read_entry(data.offset as usize).ok()
+}
+
+/// The value of the entry for `k` at `data`, if it is still there.
+async fn mget_value(m: &PageCache, k: &[u8], data: Option<&KeyData>) -> Option<Bytes> {
+    let data = data?;
+
+    m.fetch_entry(data.page_id, data.offset)
+        .await
+        .filter(|e| e.t == EntryType::Put && e.key == k)
+        .map(|e| Bytes::from(e.value))
+}
+
+/// `PageCache::fetch_entry`, including entries that have expired.
+async fn fetch_raw(m: &PageCache, data: &KeyData) -> Option<Entry> {
+    let page = m.fetch_page(data.page_id).await?;
+    let entry = page.read().await.read_entry(data.offset as usize).ok();
+
+    entry
+}
+
+/// `fetch_raw`, leaving the value as it is stored instead of decompressing it.
+async fn fetch_stored(m: &PageCache, data: &KeyData) -> Option<Entry> {
+    let page = m.fetch_page(data.page_id).await?;
+    let entry = page.read().await.read_entry_raw(data.offset as usize).ok();
+
+    entry
+}
+
+fn get_result(entry: Option<Entry>) -> Message {
+    match entry {
+        Some(entry) if entry.t == EntryType::ListNode => Message::Error(WRONGTYPE.to_string()),
+        Some(entry) => Message::Result(entry.key.into(), entry.value.into()),
+        None => Message::None,
+    }
+}
+
+/// The bytes of `entry`'s value from `start` to `end` inclusive, clamped to the value.
+// TODO: read just the range from the page instead of the whole value
+fn get_range(k: &Bytes, entry: Option<Entry>, start: i64, end: i64) -> Message {
+    let value = match entry {
+        Some(entry) if entry.t == EntryType::ListNode => {
+            return Message::Error(WRONGTYPE.to_string())
+        }
+        Some(entry) => entry.value.freeze(),
+        None => Bytes::new(),
+    };
+
+    let range = match byte_range(value.len(), start, end) {
+        Some(range) => value.slice(range),
+        None => Bytes::new(),
+    };
+
+    Message::Result(k.clone(), range)
+}
+
+/// The bytes from `start` to `end` inclusive of a value `len` bytes long, negative indices
+/// counting back from the end and indices past either end clamped. `None` if that leaves nothing.
+fn byte_range(len: usize, start: i64, end: i64) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+    let index = |i: i64| match i < 0 {
+        true => len + i,
+        false => i,
+    };
+    // An end before the start of the value leaves nothing, like a Python slice
+    let (start, end) = (index(start).max(0), index(end).min(len - 1));
+
+    (start <= end).then_some(start as usize..=end as usize)
+}
+
+/// Writes the bitwise `kind` of the values at `keys` to `dst` under the already held locks,
+/// answering with its length. Missing keys are empty, every value is padded with zero bytes up
+/// to the longest and an empty result deletes `dst`.
+async fn bit_op(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    kind: BitOpKind,
+    dst: &Bytes,
+    keys: &[Bytes],
+) -> Message {
+    let mut values = Vec::with_capacity(keys.len());
+    for k in keys {
+        let entry = match kd.get(k) {
+            Some(data) => lookup_raw(m, current, data).await,
+            None => None,
+        };
+        match entry.filter(|e| !e.is_expired()) {
+            Some(entry) if entry.t == EntryType::ListNode => {
+                return Message::Error(WRONGTYPE.to_string())
+            }
+            Some(entry) => values.push(entry.value),
+            None => values.push(BytesMut::new()),
+        }
+    }
+
+    let len = values.iter().map(|v| v.len()).max().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = values.iter().map(|v| v.get(i).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match kind {
+                BitOpKind::And => bytes.fold(first, |a, b| a & b),
+                BitOpKind::Or => bytes.fold(first, |a, b| a | b),
+                BitOpKind::Xor => bytes.fold(first, |a, b| a ^ b),
+                BitOpKind::Not => !first,
+            }
+        })
+        .collect();
+
+    if result.is_empty() {
+        if kd.get(dst).is_some() {
+            let entry = Entry::new(dst, &[], EntryType::Delete);
+            if let Err(e) = append(m, key_dir, current, &entry).await {
+                return Message::Error(format!("ERR {}", e));
+            }
+            kd.remove(dst);
+        }
+
+        return Message::Count(0);
+    }
+
+    let entry = Entry::new(dst, &result, EntryType::Put);
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(dst, m.key_data(current.id, offset));
+
+    Message::Count(result.len())
+}
+
+/// The bit at `offset` of the entry's value, 0 past its end.
+fn get_bit(entry: Option<Entry>, offset: u64) -> Message {
+    let value = match entry {
+        Some(entry) if entry.t == EntryType::ListNode => {
+            return Message::Error(WRONGTYPE.to_string())
+        }
+        Some(entry) => entry.value,
+        None => return Message::Integer(0),
+    };
+    let bit = match value.get((offset / 8) as usize) {
+        Some(byte) => (byte >> (7 - offset % 8)) & 1,
+        None => 0,
+    };
+
+    Message::Integer(bit as i64)
+}
+
+/// Sets the bit at `offset` of `k` to `bit` under the already held locks, padding the value with
+/// zero bytes up to it and keeping its expiry. Answers with the bit it had.
+async fn set_bit(
+    m: &PageCache,
+    key_dir: &Arc<RwLock<KeyDir>>,
+    current: &mut RwLockWriteGuard<'_, PageInner>,
+    kd: &mut KeyDir,
+    k: &Bytes,
+    offset: u64,
+    bit: u8,
+) -> Message {
+    let old = match kd.get(k) {
+        Some(data) => lookup(m, current, data).await,
+        None => None,
+    };
+    let (mut value, expire_at) = match old {
+        Some(old) => (old.value, old.expire_at),
+        None => (BytesMut::new(), None),
+    };
+
+    let byte = (offset / 8) as usize;
+    let mask = 1 << (7 - offset % 8);
+    if value.len() <= byte {
+        value.resize(byte + 1, 0);
+    }
+    let old_bit = (value[byte] & mask != 0) as i64;
+    match bit {
+        0 => value[byte] &= !mask,
+        _ => value[byte] |= mask,
+    }
+
+    let mut entry = Entry::new(k, &value, EntryType::Put);
+    entry.expire_at = expire_at;
+    let offset = match append(m, key_dir, current, &entry).await {
+        Ok(o) => o,
+        Err(e) => return Message::Error(format!("ERR {}", e)),
+    };
+    kd.insert(k, m.key_data(current.id, offset));
+
+    Message::Integer(old_bit)
+}
+
+/// Counts the set bits of the entry's value, or of the bytes `range` picks like `get_range`.
+fn bit_count(entry: Option<Entry>, range: Option<(i64, i64)>) -> Message {
+    let value = match entry {
+        Some(entry) if entry.t == EntryType::ListNode => {
+            return Message::Error(WRONGTYPE.to_string())
+        }
+        Some(entry) => entry.value,
+        None => return Message::Integer(0),
+    };
+    let bytes = match range {
+        Some((start, end)) => match byte_range(value.len(), start, end) {
+            Some(range) => &value[range],
+            None => &[][..],
+        },
+        None => &value[..],
+    };
+
+    // A word at a time, which compiles to POPCNT where the target has it
+    let mut words = bytes.chunks_exact(8);
+    let mut n: u64 = words
+        .by_ref()
+        .map(|w| u64::from_ne_bytes(w.try_into().expect("chunks are 8 bytes")).count_ones() as u64)
+        .sum();
+    n += words
+        .remainder()
+        .iter()
+        .map(|b| b.count_ones() as u64)
+        .sum::<u64>();
+
+    Message::Integer(n as i64)
+}
+
+fn lolwut() -> String {
+    format!(
+        r"                 __====-_  _-====__
+           _--^^^#####//      \\#####^^^--_
+        _-^##########// (    ) \\##########^-_
+       -############//  |\^^/|  \\############-
+     _/############//   (@::@)   \\############\_
+    /#############((     \\//     ))#############\
+   -###############\\    (oo)    //###############-
+  -#################\\  / VV \  //#################-
+ -###################\\/      \//###################-
+_#/|##########/\######(   /\   )######/\##########|\#_
+|/ |#/\#/\#/\/  \#/\##\  |  |  /##/\#/  \/\#/\#/\#| \|
+`  |/  V  V  `   V  \#\| |  | |/#/  V   '  V  V  \|  '
+   `   `  `      `   / | |  | | \   '      '  '   '
+                    (  | |  | |  )
+                   __\ | |  | | /__
+                  (vvv(VVV)(VVV)vvv)
+
+hash_db ver. {}",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn value_type(entry: Option<&Entry>) -> Message {
+    let t = entry.map_or("none", |e| e.value_type());
+
+    Message::Text(t.to_string())
+}
+
+fn strlen(stored: Option<&Entry>) -> Message {
+    match stored {
+        Some(entry) if entry.t == EntryType::ListNode => Message::Error(WRONGTYPE.to_string()),
+        Some(entry) if entry.t == EntryType::Put && !entry.is_expired() => {
+            Message::Count(entry.value_len())
+        }
+        _ => Message::Count(0),
+    }
+}
+
+fn object_encoding(stored: Option<&Entry>) -> Message {
+    match stored {
+        Some(entry) if !entry.is_expired() => Message::Text(entry.encoding().to_string()),
+        _ => Message::None,
+    }
+}
+
+/// Up to `limit` keys matching `pattern`, in order.
+fn keys(kd: &KeyDir, pattern: &[u8], limit: usize) -> Message {
+    let mut matching = kd.matching(pattern).map(|(k, _)| Bytes::copy_from_slice(k));
+    let keys = matching.by_ref().take(limit).collect();
+    let truncated = matching.next().is_some();
+
+    Message::KeyList(keys, truncated)
+}
+
+// A cursor is the hex of the next key to answer with, so it stays valid as other keys come and
+// go. `0` is never one, its length is odd.
+fn key_scan(kd: &KeyDir, cursor: &[u8], count: usize) -> Message {
+    let start = match cursor {
+        b"0" => Vec::new(),
+        c => match decode_cursor(c) {
+            Some(start) => start,
+            None => return Message::Error("ERR invalid cursor".to_string()),
+        },
+    };
+
+    let mut keys = kd.scan(&start, b"").map(|(k, _)| Bytes::copy_from_slice(k));
+    let page = keys.by_ref().take(count).collect();
+    let cursor = match keys.next() {
+        Some(next) => next
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>(),
+        None => "0".to_string(),
+    };
+
+    Message::ScanPage(cursor.into(), page)
+}
+
+fn decode_cursor(cursor: &[u8]) -> Option<Vec<u8>> {
+    if !cursor.len().is_multiple_of(2) {
+        return None;
+    }
+
+    cursor
+        .chunks(2)
+        .map(|b| u8::from_str_radix(std::str::from_utf8(b).ok()?, 16).ok())
+        .collect()
+}
+
+async fn cache_evict(m: &PageCache, page_id: PageID) -> Message {
+    match m.evict_page(page_id).await {
+        Ok(()) => Message::Success,
+        Err(e) => Message::Error(format!("ERR {}", e)),
+    }
+}
+
+async fn idle_time(m: &PageCache, page_id: PageID) -> Message {
+    match m.last_access_time(page_id).await {
+        Some(at) => Message::Integer(at.elapsed().as_secs() as i64),
+        None => Message::Integer(-1),
+    }
+}
+
+async fn ref_count(m: &PageCache, page_id: PageID) -> Message {
+    match m.pin_count(page_id).await {
+        Some(pins) => Message::Integer(pins as i64),
+        None => Message::Integer(-1),
+    }
+}
+
+fn slowlog_line(e: &SlowLogEntry) -> String {
+    format!("{} {} {} {}\n", e.id, e.timestamp, e.duration_us, e.command)
+}
+
+fn command_spec_line(s: &CommandSpec) -> String {
+    let mut line = format!("{} {}", s.name, s.arity);
+    for flag in s.flags {
+        line += " ";
+        line += flag;
+    }
+
+    line + "\n"
+}
+
+fn stats(m: &PageCache, kd: &KeyDir) -> Message {
+    let stats = m.stats();
+
+    Message::Text(format!(
+        r#"{{"hits":{},"misses":{},"evictions":{},"dirty_flushes":{},"keys":{},"deleted":{}}}"#,
+        stats.hits,
+        stats.misses,
+        stats.evictions,
+        stats.dirty_flushes,
+        kd.len(),
+        kd.count_deleted()
+    ))
+}
+
+/// The number `arg` is written as, only if it is written the way `T` would print it. Anything
+/// else, like a leading `+` or zero, is rejected so `len` can tell how long the line was from the
+/// parsed message.
+fn parse_canonical<T: FromStr + ToString>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse::<T>().ok())
+        .filter(|n| n.to_string().as_bytes() == arg)
+}
+
+fn read_until(cursor: &Cursor<&[u8]>, c: u8) -> Option<Bytes> {
+    let start = cursor.position() as usize;
+    let end = cursor.get_ref().len();
+
+    for i in start..end {
+        if cursor.get_ref()[i] == c {
+            let ret = BytesMut::from(&cursor.get_ref()[start..i]);
+            let ret = Bytes::from(ret);
+            return Some(ret);
+        }
+    }
+
+    None
+}
+
+/// `parts` joined by spaces, on their own line.
+fn push_line(parts: &[&[u8]]) -> Bytes {
+    let mut dst = BytesMut::from(&parts.join(&b' ')[..]);
+    dst.extend_from_slice(b"\n");
+
+    dst.into()
+}
+
+impl From<Message> for Bytes {
+    fn from(value: Message) -> Self {
+        match value {
+            Message::Insert(_, _)
+            | Message::MSet(_)
+            | Message::Delete(_)
+            | Message::FlushDb(_)
+            | Message::DbSize
+            | Message::Incr(_)
+            | Message::IncrBy(_, _)
+            | Message::Decr(_)
+            | Message::DecrBy(_, _)
+            | Message::Get(_)
+            | Message::GetRange(_, _, _)
+            | Message::BitCount(_, _)
+            | Message::BitOp(_, _, _)
+            | Message::GetBit(_, _)
+            | Message::SetBit(_, _, _)
+            | Message::GetSet(_, _)
+            | Message::SetNx(_, _)
+            | Message::CompareAndSwap(_, _, _)
+            | Message::SetEx(_, _, _)
+            | Message::PSetEx(_, _, _)
+            | Message::Copy(_, _, _)
+            | Message::Rename(_, _)
+            | Message::RenameNx(_, _)
+            | Message::GetDel(_)
+            | Message::GetEx(_, _)
+            | Message::Append(_, _)
+            | Message::SetRange(_, _, _)
+            | Message::Persist(_)
+            | Message::Expire(_, _)
+            | Message::Ttl(_)
+            | Message::ObjectEncoding(_)
+            | Message::ObjectFreq(_)
+            | Message::ObjectIdleTime(_)
+            | Message::ObjectRefCount(_)
+            | Message::ObjectHelp
+            | Message::LolWut
+            | Message::ClusterInfo
+            | Message::CommandCount
+            | Message::CommandInfo(_)
+            | Message::Type(_)
+            | Message::Strlen(_)
+            | Message::LPush(_, _)
+            | Message::RPush(_, _)
+            | Message::LPop(_)
+            | Message::RPop(_)
+            | Message::LLen(_)
+            | Message::MGet(_)
+            | Message::Scan(_, _)
+            | Message::Keys(_, _)
+            | Message::KeyScan(_, _)
+            | Message::Stats
+            | Message::Info
+            | Message::DebugReload
+            | Message::DebugSleep(_)
+            | Message::DebugChangeReplId
+            | Message::CacheEvict(_)
+            | Message::Multi
+            | Message::Exec
+            | Message::Discard
+            | Message::Auth(_)
+            | Message::Subscribe(_)
+            | Message::Unsubscribe(_)
+            | Message::Publish(_, _)
+            | Message::Select(_)
+            | Message::Reset
+            | Message::Ping(_)
+            | Message::SnapshotCreate
+            | Message::SnapshotGet(_, _)
+            | Message::SnapshotRelease(_)
+            | Message::Wait(_, _)
+            | Message::ReplicaOf(_)
+            | Message::ReplStream
+            | Message::SlowlogGet(_)
+            | Message::SlowlogReset
+            | Message::ClientList
+            | Message::ClientKill(_)
+            | Message::ClientSetName(_)
+            | Message::ClientGetName
+            | Message::Ignore(_)
+            | Message::None => Bytes::new(),
+
+            Message::Result(k, v) => {
+                let len = k.len() + v.len() + 2;
+                let mut dst = BytesMut::zeroed(len);
+
+                crate::put_bytes!(dst, k, 0, k.len());
+                dst[k.len()] = b' ';
+                crate::put_bytes!(dst, v, k.len() + 1, v.len());
+                dst[len - 1] = b'\n';
+
+                dst.into()
+            }
+            Message::Results(r) => {
+                // One "key value" line per result, terminated by an empty line
+                let mut dst = BytesMut::new();
+                for (k, v) in r {
+                    dst.extend_from_slice(&k);
+                    dst.extend_from_slice(b" ");
+                    dst.extend_from_slice(&v);
+                    dst.extend_from_slice(b"\n");
+                }
+                dst.extend_from_slice(b"\n");
+
+                dst.into()
+            }
+            Message::Values(v) => {
+                // "key value" for each key found, just "key" for each one missing, then an empty
+                // line
+                let mut dst = BytesMut::new();
+                for (k, v) in v {
+                    dst.extend_from_slice(&k);
+                    if let Some(v) = v {
+                        dst.extend_from_slice(b" ");
+                        dst.extend_from_slice(&v);
+                    }
+                    dst.extend_from_slice(b"\n");
+                }
+                dst.extend_from_slice(b"\n");
+
+                dst.into()
+            }
+            Message::KeyList(keys, truncated) => {
+                // The number of keys, marked if there were more, then one key per line
+                let mut dst = BytesMut::new();
+                dst.extend_from_slice(keys.len().to_string().as_bytes());
+                if truncated {
+                    dst.extend_from_slice(b" truncated");
+                }
+                dst.extend_from_slice(b"\n");
+                for k in keys {
+                    dst.extend_from_slice(&k);
+                    dst.extend_from_slice(b"\n");
+                }
+
+                dst.into()
+            }
+            Message::ScanPage(cursor, keys) => {
+                // The next cursor and the number of keys, then one key per line
+                let mut dst = BytesMut::new();
+                dst.extend_from_slice(&cursor);
+                dst.extend_from_slice(b" ");
+                dst.extend_from_slice(keys.len().to_string().as_bytes());
+                dst.extend_from_slice(b"\n");
+                for k in keys {
+                    dst.extend_from_slice(&k);
+                    dst.extend_from_slice(b"\n");
+                }
+
+                dst.into()
+            }
+            Message::SlowLogEntries(entries) => {
+                // The number of entries, then one line per entry
+                let mut dst = BytesMut::new();
+                dst.extend_from_slice(entries.len().to_string().as_bytes());
+                dst.extend_from_slice(b"\n");
+                for e in &entries {
+                    dst.extend_from_slice(slowlog_line(e).as_bytes());
+                }
+
+                dst.into()
+            }
+            Message::CommandSpecs(specs) => {
+                // The number of specs, then one line per spec
+                let mut dst = BytesMut::new();
+                dst.extend_from_slice(specs.len().to_string().as_bytes());
+                dst.extend_from_slice(b"\n");
+                for s in specs {
+                    dst.extend_from_slice(command_spec_line(s).as_bytes());
+                }
+
+                dst.into()
+            }
+            Message::Subscribed(c, n) => push_line(&[b"subscribe", &c, n.to_string().as_bytes()]),
+            Message::Unsubscribed(c, n) => {
+                push_line(&[b"unsubscribe", &c, n.to_string().as_bytes()])
+            }
+            Message::Published(c, p) => push_line(&[b"message", &c, &p]),
+            Message::Responses(r) => {
+                let mut dst = BytesMut::new();
+                for m in r {
+                    dst.extend_from_slice(&Bytes::from(m));
+                }
+
+                dst.into()
+            }
+            Message::Text(t) | Message::Error(t) => Bytes::from(t + "\n"),
+            Message::Help(lines) => lines
+                .iter()
+                .map(|l| format!("{}\n", l))
+                .collect::<String>()
+                .into(),
+            Message::Queued => Bytes::from("Queued\n"),
+            Message::Pong(None) => Bytes::from("PONG\n"),
+            Message::Pong(Some(p)) => [&p[..], b"\n"].concat().into(),
+            Message::ReplEntry(seq, e) => [format!("{} {}\n", seq, e.len()).as_bytes(), &e[..]]
+                .concat()
+                .into(),
+            Message::ResetDone => Bytes::from("Reset\n"),
+            Message::Count(n) => Bytes::from(format!("{}\n", n)),
+            Message::Integer(n) => Bytes::from(format!("{}\n", n)),
+            Message::Success => Bytes::from("Success\n"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering::SeqCst},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use tokio::sync::RwLock;
+
+    use crate::{
+        serverv2::{
+            command::COMMAND_REGISTRY,
+            message::{
+                BitOpKind, ClientError, GetExOption, Message, DEFAULT_KEYS_LIMIT, WRONGTYPE,
+            },
+            protocol::resp3::Frame,
+        },
+        storagev2::{
+            disk::Disk,
+            key_dir::KeyDir,
+            log::{timestamp_millis, Entry, EntryLimits, EntryType},
+            page::Page,
+            page_manager::{PageManagerBuilder, PageManagerConfig},
+            test::CleanUp,
+            wal::WriteAheadLog,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_exec_all() -> io::Result<()> {
+        const DB_FILE: &str = "./test_exec_all.db";
+        const WAL_FILE: &str = "./test_exec_all.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        Message::Insert("a".into(), "1".into()).exec(&m, &kd).await;
+
+        // Reads inside the transaction see its earlier writes, including ones in the current page
+        let messages = [
+            Message::Insert("b".into(), "2".into()),
+            Message::Delete("a".into()),
+            Message::Get("a".into()),
+            Message::Scan("".into(), "".into()),
+        ];
+        let got = Message::exec_all(&messages, &m, &kd).await;
+        let expected = Message::Responses(vec![
+            Message::Success,
+            Message::Success,
+            Message::None,
+            Message::Results(vec![(Bytes::from("b"), Bytes::from("2"))]),
+        ]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = Message::Get("b".into()).exec(&m, &kd).await;
+        let expected = Message::Result("b".into(), "2".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mget() -> io::Result<()> {
+        const DB_FILE: &str = "./test_mget.db";
+        const WAL_FILE: &str = "./test_mget.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        Message::Insert("a".into(), "1".into()).exec(&m, &kd).await;
+        Message::Insert("c".into(), "3".into()).exec(&m, &kd).await;
+
+        let buf = b"mget a b c\n";
+        let message = Message::parse(buf).expect("should parse mget");
+        let expected = Message::MGet(vec!["a".into(), "b".into(), "c".into()]);
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        let got = message.exec(&m, &kd).await;
+        let expected = Message::Values(vec![
+            ("a".into(), Some("1".into())),
+            ("b".into(), None),
+            ("c".into(), Some("3".into())),
+        ]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let len = got.len();
+        let got = Bytes::from(got);
+        let expected = Bytes::from("a 1\nb\nc 3\n\n");
+        assert!(
+            got == expected && len == expected.len(),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mget_during_compaction() -> io::Result<()> {
+        const DB_FILE: &str = "./test_mget_during_compaction.db";
+        const WAL_FILE: &str = "./test_mget_during_compaction.wal";
+        const HINT_FILE: &str = "./test_mget_during_compaction.hint";
+        const KEYS: usize = 40;
+        const ROUNDS: usize = 30;
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let _cu_hint = CleanUp::file(HINT_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let key = |i: usize| Bytes::from(format!("key_{}", i));
+        let value = |i: usize| Bytes::from(format!("{}_{}", i, "v".repeat(200)));
+        for i in 0..KEYS {
+            Message::Insert(key(i), value(i)).exec(&m, &kd).await;
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (m, kd, done) = (m.clone(), kd.clone(), done.clone());
+            tokio::spawn(async move {
+                let keys: Vec<_> = (0..KEYS).map(key).collect();
+                while !done.load(SeqCst) {
+                    let got = Message::MGet(keys.clone()).exec(&m, &kd).await;
+                    let expected =
+                        Message::Values((0..KEYS).map(|i| (key(i), Some(value(i)))).collect());
+                    assert!(
+                        got == expected,
+                        "\nExpected: {:?}\nGot: {:?}\n",
+                        expected,
+                        got
+                    );
+
+                    // Scans read their pages outside the key dir the same way
+                    let got = Message::Scan("key_".into(), "key_~".into())
+                        .exec(&m, &kd)
+                        .await;
+                    let mut expected: Vec<_> = (0..KEYS).map(|i| (key(i), value(i))).collect();
+                    expected.sort();
+                    let expected = Message::Results(expected);
+                    assert!(
+                        got == expected,
+                        "\nExpected: {:?}\nGot: {:?}\n",
+                        expected,
+                        got
+                    );
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        // Every round rewrites some of the keys and drops padding, so compacting moves the rest
+        for round in 0..ROUNDS {
+            for i in (round % 3..KEYS).step_by(3) {
+                Message::Insert(key(i), value(i)).exec(&m, &kd).await;
+            }
+            for i in 0..10 {
+                let pad = Bytes::from(format!("pad_{}", i));
+                Message::Insert(pad.clone(), value(i)).exec(&m, &kd).await;
+                Message::Delete(pad).exec(&m, &kd).await;
+            }
+            m.compact(&kd).await?;
+            tokio::task::yield_now().await;
+        }
+        done.store(true, SeqCst);
+        reader
+            .await
+            .expect("reader should see every key's own value");
+        assert!(m.compactions() > 0, "Got: {}", m.compactions());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_mset() -> io::Result<()> {
+        const DB_FILE: &str = "./test_mset.db";
+        const WAL_FILE: &str = "./test_mset.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"mset a 1 b 2\n";
+        let message = Message::parse(buf).expect("should parse mset");
+        let expected = Message::MSet(vec![("a".into(), "1".into()), ("b".into(), "2".into())]);
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        // Enough pairs to spill over the first page
+        let pairs: Vec<(Bytes, Bytes)> = (0..20)
+            .map(|i| (format!("key_{}", i).into(), format!("value_{}", i).into()))
+            .collect();
+        let got = Message::MSet(pairs.clone()).exec(&m, &kd).await;
+        assert!(got == Message::Count(pairs.len()), "Got: {:?}", got);
+        assert!(m.get_current().await.id > 0);
+
+        for (k, v) in pairs {
+            let got = Message::Get(k.clone()).exec(&m, &kd).await;
+            let expected = Message::Result(k, v);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_keys() -> io::Result<()> {
+        const DB_FILE: &str = "./test_keys.db";
+        const WAL_FILE: &str = "./test_keys.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"keys user:*\n";
+        let message = Message::parse(buf).expect("should parse keys");
+        let expected = Message::Keys("user:*".into(), DEFAULT_KEYS_LIMIT);
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        for k in ["user:2", "user:1", "user:3", "other"] {
+            Message::Insert(k.to_string().into(), "1".into())
+                .exec(&m, &kd)
+                .await;
+        }
+
+        let got = message.exec(&m, &kd).await;
+        let expected = Message::KeyList(
+            vec!["user:1".into(), "user:2".into(), "user:3".into()],
+            false,
+        );
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = Message::Keys("*".into(), 2).exec(&m, &kd).await;
+        let expected = Message::KeyList(vec!["other".into(), "user:1".into()], true);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let bytes = Bytes::from(got);
+        assert!(
+            &bytes[..] == b"2 truncated\nother\nuser:1\n",
+            "Got: {:?}",
+            bytes
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_key_scan() -> io::Result<()> {
+        const DB_FILE: &str = "./test_key_scan.db";
+        const WAL_FILE: &str = "./test_key_scan.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"keyscan 0 10\n";
+        let message = Message::parse(buf).expect("should parse keyscan");
+        let expected = Message::KeyScan("0".into(), 10);
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+        assert!(Message::parse(b"keyscan 0 0\n") == Some(Message::Ignore(12)));
+
+        for i in 0..25 {
+            Message::Insert(format!("key_{}", i).into(), "1".into())
+                .exec(&m, &kd)
+                .await;
+        }
+
+        let mut scanned = Vec::new();
+        let mut cursor = Bytes::from("0");
+        for _ in 0..3 {
+            let line = format!("keyscan {} 10\n", String::from_utf8_lossy(&cursor));
+            let message = Message::parse(line.as_bytes()).expect("should parse keyscan");
+            let Message::ScanPage(next, keys) = message.exec(&m, &kd).await else {
+                panic!("should answer with a page of keys");
+            };
+            assert!(keys.len() <= 10);
+            scanned.extend(keys);
+            cursor = next;
+        }
+        assert!(&cursor[..] == b"0", "Got: {:?}", cursor);
+
+        let Message::KeyList(expected, _) = Message::Keys("*".into(), 100).exec(&m, &kd).await
+        else {
+            panic!("should answer with the keys");
+        };
+        assert!(
+            scanned == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            scanned
+        );
+
+        let got = Message::KeyScan("0".into(), 1).exec(&m, &kd).await;
+        let expected = Message::ScanPage("6b65795f31".into(), vec!["key_0".into()]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(got.len() == 19, "Got: {}", got.len());
+        let bytes = Bytes::from(got);
+        assert!(&bytes[..] == b"6b65795f31 1\nkey_0\n", "Got: {:?}", bytes);
+
+        let got = Message::KeyScan("xyz".into(), 10).exec(&m, &kd).await;
+        assert!(matches!(got, Message::Error(_)), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_db() -> io::Result<()> {
+        const DB_FILE: &str = "./test_flush_db.db";
+        const WAL_FILE: &str = "./test_flush_db.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        // Flushing is all tombstones, which would start a compaction
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .config(PageManagerConfig::new().deletion_ratio_threshold(1.0))
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (&b"flushdb\n"[..], Message::FlushDb(false)),
+            (b"flushdb async\n", Message::FlushDb(true)),
+            (b"dbsize\n", Message::DbSize),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        for i in 0..100 {
+            Message::Insert(format!("key_{}", i).into(), "value".into())
+                .exec(&m, &kd)
+                .await;
+        }
+        Message::RPush("list".into(), vec!["a".into()])
+            .exec(&m, &kd)
+            .await;
+        let got = Message::DbSize.exec(&m, &kd).await;
+        assert!(got == Message::Count(101), "Got: {:?}", got);
+
+        let got = Message::FlushDb(false).exec(&m, &kd).await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+        let got = Message::DbSize.exec(&m, &kd).await;
+        assert!(got == Message::Count(0), "Got: {:?}", got);
+        for i in 0..100 {
+            let got = Message::Get(format!("key_{}", i).into())
+                .exec(&m, &kd)
+                .await;
+            assert!(got == Message::None, "Got: {:?}", got);
+        }
+        assert!(Message::LLen("list".into()).exec(&m, &kd).await == Message::Integer(0));
+        assert!(kd.read().await.count_deleted() == 101);
+
+        // The keys go right away, the tombstones follow
+        for i in 0..10 {
+            Message::Insert(format!("key_{}", i).into(), "value".into())
+                .exec(&m, &kd)
+                .await;
+        }
+        let got = Message::Responses(vec![
+            Message::FlushDb(true).exec(&m, &kd).await,
+            Message::DbSize.exec(&m, &kd).await,
+            Message::Insert("key_0".into(), "again".into())
+                .exec(&m, &kd)
+                .await,
+        ]);
+        let expected =
+            Message::Responses(vec![Message::Success, Message::Count(0), Message::Success]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while kd.read().await.count_deleted() < 101 + 9 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("tombstones should be written");
+        // Written again after the flush, so it kept its new value
+        let got = Message::Get("key_0".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key_0".into(), "again".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_incr() -> io::Result<()> {
+        const DB_FILE: &str = "./test_incr.db";
+        const WAL_FILE: &str = "./test_incr.wal";
+        const TASKS: i64 = 8;
+        const INCREMENTS: i64 = 50;
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (&b"incr a\n"[..], Message::Incr("a".into())),
+            (b"decr a\n", Message::Decr("a".into())),
+            (b"incrby a -5\n", Message::IncrBy("a".into(), -5)),
+            (b"decrby a 5\n", Message::DecrBy("a".into(), 5)),
+            (b"incrby a 05\n", Message::Ignore(12)),
+            (b"incr a 5\n", Message::Ignore(9)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        // Increments from every task are kept, none overwrite each other
+        let mut tasks = Vec::new();
+        for i in 0..TASKS {
+            let (m, kd) = (m.clone(), kd.clone());
+            tasks.push(tokio::spawn(async move {
+                for _ in 0..INCREMENTS {
+                    let message = match i % 2 {
+                        0 => Message::Incr("counter".into()),
+                        _ => Message::IncrBy("counter".into(), 2),
+                    };
+                    let got = message.exec(&m, &kd).await;
+                    assert!(matches!(got, Message::Integer(_)), "Got: {:?}", got);
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.expect("task shouldn't panic");
+        }
+
+        let expected = TASKS / 2 * INCREMENTS * 3;
+        let got = Message::DecrBy("counter".into(), 0).exec(&m, &kd).await;
+        assert!(
+            got == Message::Integer(expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::Get("counter".into()).exec(&m, &kd).await;
+        let expected = Message::Result("counter".into(), expected.to_string().into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Message::Insert("text".into(), "abc".into())
+            .exec(&m, &kd)
+            .await;
+        let got = Message::Incr("text".into()).exec(&m, &kd).await;
+        let expected = Message::Error("ERR not an integer".to_string());
+        assert!(got == expected, "Got: {:?}", got);
+
+        Message::IncrBy("max".into(), i64::MAX).exec(&m, &kd).await;
+        let got = Message::Incr("max".into()).exec(&m, &kd).await;
+        let expected = Message::Error("ERR overflow".to_string());
+        assert!(got == expected, "Got: {:?}", got);
+        let got = Message::DecrBy("min".into(), i64::MIN).exec(&m, &kd).await;
+        assert!(got == expected, "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_getset() -> io::Result<()> {
+        const DB_FILE: &str = "./test_getset.db";
+        const WAL_FILE: &str = "./test_getset.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"getset key some value\n";
+        let message = Message::parse(buf).expect("should parse getset");
+        let expected = Message::GetSet("key".into(), "some value".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        // Whichever runs first finds nothing, the other gets what the first wrote
+        let tasks: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|v| {
+                let (m, kd) = (m.clone(), kd.clone());
+                tokio::spawn(
+                    async move { Message::GetSet("key".into(), v.into()).exec(&m, &kd).await },
+                )
+            })
+            .collect();
+        let mut got = Vec::new();
+        for task in tasks {
+            got.push(task.await.expect("task shouldn't panic"));
+        }
+
+        let nulls = got.iter().filter(|r| **r == Message::None).count();
+        assert!(nulls == 1, "Got: {:?}", got);
+        let old = got
+            .iter()
+            .find_map(|r| match r {
+                Message::Result(_, v) => Some(v.clone()),
+                _ => None,
+            })
+            .expect("one should return the old value");
+
+        let last = if &old[..] == b"a" { "b" } else { "a" };
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), last.into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_getrange() -> io::Result<()> {
+        const DB_FILE: &str = "./test_getrange.db";
+        const WAL_FILE: &str = "./test_getrange.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (
+                &b"getrange key 0 -1\n"[..],
+                Message::GetRange("key".into(), 0, -1),
+            ),
+            (b"getrange key -0 1\n", Message::Ignore(18)),
+            (b"getrange key 0\n", Message::Ignore(15)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        let range = |start, end| Message::GetRange("key".into(), start, end);
+        let got = range(0, -1).exec(&m, &kd).await;
+        assert!(
+            got == Message::Result("key".into(), "".into()),
+            "Got: {:?}",
+            got
+        );
+
+        Message::Insert("key".into(), "hello world".into())
+            .exec(&m, &kd)
+            .await;
+        for ((start, end), expected) in [
+            ((0, -1), "hello world"),
+            ((0, 4), "hello"),
+            ((-5, -1), "world"),
+            ((6, 100), "world"),
+            ((-100, 1), "he"),
+            ((3, 2), ""),
+            ((11, 20), ""),
+            ((-100, -50), ""),
+        ] {
+            let expected = Message::Result("key".into(), expected.into());
+            let got = range(start, end).exec(&m, &kd).await;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+            let got = Message::exec_all(&[range(start, end)], &m, &kd).await;
+            assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bitcount() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bitcount.db";
+        const WAL_FILE: &str = "./test_bitcount.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (
+                &b"bitcount key\n"[..],
+                Message::BitCount("key".into(), None),
+            ),
+            (
+                b"bitcount key 1 -1\n",
+                Message::BitCount("key".into(), Some((1, -1))),
+            ),
+            (b"bitcount key 1\n", Message::Ignore(15)),
+            (b"bitcount key +1 2\n", Message::Ignore(18)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        let count = |range| Message::BitCount("key".into(), range);
+        let got = count(None).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        // 1, 8, 0 and 4 bits set, then 8 bytes of 2 each past the first word
+        let value: Vec<u8> = [0x01, 0xff, 0x00, 0x0f]
+            .into_iter()
+            .chain([0x03; 8])
+            .collect();
+        Message::Insert("key".into(), value.into())
+            .exec(&m, &kd)
+            .await;
+        for (range, expected) in [
+            (None, 29),
+            (Some((0, -1)), 29),
+            (Some((1, 1)), 8),
+            (Some((0, 3)), 13),
+            (Some((-8, -1)), 16),
+            (Some((-100, 1)), 9),
+            (Some((3, 2)), 0),
+            (Some((12, 20)), 0),
+        ] {
+            let expected = Message::Integer(expected);
+            let got = count(range).exec(&m, &kd).await;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+            let got = Message::exec_all(&[count(range)], &m, &kd).await;
+            assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_bitop() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bitop.db";
+        const WAL_FILE: &str = "./test_bitop.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let keys = |keys: &[&'static str]| keys.iter().map(|k| Bytes::from(*k)).collect();
+        for (buf, expected) in [
+            (
+                &b"bitop and dst a b\n"[..],
+                Message::BitOp(BitOpKind::And, "dst".into(), keys(&["a", "b"])),
+            ),
+            (
+                b"bitop not dst a\n",
+                Message::BitOp(BitOpKind::Not, "dst".into(), keys(&["a"])),
+            ),
+            (b"bitop not dst a b\n", Message::Ignore(18)),
+            (b"bitop nand dst a b\n", Message::Ignore(19)),
+            (b"bitop or dst\n", Message::Ignore(13)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        Message::Insert("a".into(), Bytes::from_static(&[0b1100, 0xff, 0x0f]))
+            .exec(&m, &kd)
+            .await;
+        Message::Insert("b".into(), Bytes::from_static(&[0b1010, 0x0f]))
+            .exec(&m, &kd)
+            .await;
+        // The shorter value is padded with a zero byte
+        for (kind, expected) in [
+            (BitOpKind::And, &[0b1000, 0x0f, 0x00][..]),
+            (BitOpKind::Or, &[0b1110, 0xff, 0x0f]),
+            (BitOpKind::Xor, &[0b0110, 0xf0, 0x0f]),
+        ] {
+            let got = Message::BitOp(kind, "dst".into(), keys(&["a", "b"]))
+                .exec(&m, &kd)
+                .await;
+            assert!(got == Message::Count(3), "Got: {:?}", got);
+            let got = Message::Get("dst".into()).exec(&m, &kd).await;
+            let expected = Message::Result("dst".into(), Bytes::from_static(expected));
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let got = Message::BitOp(BitOpKind::Not, "dst".into(), keys(&["b"]))
+            .exec(&m, &kd)
+            .await;
+        assert!(got == Message::Count(2), "Got: {:?}", got);
+        let got = Message::Get("dst".into()).exec(&m, &kd).await;
+        let expected = Message::Result("dst".into(), Bytes::from_static(&[0xf5, 0xf0]));
+        assert!(got == expected, "Got: {:?}", got);
+
+        // Nothing to operate on deletes the destination
+        let got = Message::BitOp(BitOpKind::Or, "dst".into(), keys(&["x", "y"]))
+            .exec(&m, &kd)
+            .await;
+        assert!(got == Message::Count(0), "Got: {:?}", got);
+        let got = Message::Get("dst".into()).exec(&m, &kd).await;
+        assert!(got == Message::None, "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_getbit_setbit() -> io::Result<()> {
+        const DB_FILE: &str = "./test_getbit_setbit.db";
+        const WAL_FILE: &str = "./test_getbit_setbit.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (&b"getbit key 7\n"[..], Message::GetBit("key".into(), 7)),
+            (b"setbit key 7 1\n", Message::SetBit("key".into(), 7, 1)),
+            (b"setbit key 7 2\n", Message::Ignore(15)),
+            (b"getbit key -1\n", Message::Ignore(14)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        // The padding counts against the value limit
+        let limits = EntryLimits {
+            max_key_len: 16,
+            max_value_len: 16,
+        };
+        let got = Message::SetBit("key".into(), 128, 1).check_sizes(&limits);
+        assert!(got == Err(ClientError::ValueTooLarge(17)), "Got: {:?}", got);
+
+        let get_bit = |offset| Message::GetBit("key".into(), offset);
+        let got = get_bit(7).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        let got = Message::SetBit("key".into(), 7, 1).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+        let got = get_bit(0).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+        let got = get_bit(7).exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = Message::exec_all(&[get_bit(7), get_bit(100)], &m, &kd).await;
+        let expected = Message::Responses(vec![Message::Integer(1), Message::Integer(0)]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // Bit 0 is the most significant, and the value grows to reach bit 17
+        Message::SetBit("key".into(), 0, 1).exec(&m, &kd).await;
+        let got = Message::SetBit("key".into(), 17, 1).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), Bytes::from_static(&[0x81, 0x00, 0x40]));
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = Message::SetBit("key".into(), 7, 0).exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = get_bit(7).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_reads_during_writes() -> io::Result<()> {
+        const DB_FILE: &str = "./test_reads_during_writes.db";
+        const WAL_FILE: &str = "./test_reads_during_writes.wal";
+        const ROUNDS: usize = 10000;
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        // Each read against writes to the key it reads, see `get_raw` for why they could block
+        let insert = || Message::Insert("key".into(), "value".into());
+        let cases = [
+            (insert(), Message::GetBit("key".into(), 1)),
+            (insert(), Message::BitCount("key".into(), None)),
+            (insert(), Message::GetRange("key".into(), 0, -1)),
+            (insert(), Message::Strlen("key".into())),
+            (
+                Message::LPush("list".into(), vec!["item".into()]),
+                Message::LLen("list".into()),
+            ),
+            (insert(), Message::Type("key".into())),
+            (insert(), Message::ObjectEncoding("key".into())),
+            (insert(), Message::Ttl("key".into())),
+            (insert(), Message::Scan("a".into(), "z".into())),
+        ];
+        for (write, read) in cases {
+            let (write, read) = (Arc::new(write), Arc::new(read));
+            let writer = {
+                let (m, kd, write) = (m.clone(), kd.clone(), write.clone());
+                tokio::spawn(async move {
+                    for _ in 0..ROUNDS {
+                        write.exec(&m, &kd).await;
+                    }
+                })
+            };
+            let reader = {
+                let (m, kd, read) = (m.clone(), kd.clone(), read.clone());
+                tokio::spawn(async move {
+                    for _ in 0..ROUNDS {
+                        read.exec(&m, &kd).await;
+                    }
+                })
+            };
+
+            let res = tokio::time::timeout(Duration::from_secs(20), async {
+                writer.await.expect("writer shouldn't panic");
+                reader.await.expect("reader shouldn't panic");
+            })
+            .await;
+            assert!(res.is_ok(), "{:?} and {:?} deadlocked", read, write);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_setnx() -> io::Result<()> {
+        const DB_FILE: &str = "./test_setnx.db";
+        const WAL_FILE: &str = "./test_setnx.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"setnx lock \n";
+        let message = Message::parse(buf).expect("should parse setnx");
+        let expected = Message::SetNx("lock".into(), "".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        // Only one of them takes the lock
+        let tasks: Vec<_> = (0..100)
+            .map(|_| {
+                let (m, kd) = (m.clone(), kd.clone());
+                tokio::spawn(
+                    async move { Message::SetNx("lock".into(), "".into()).exec(&m, &kd).await },
+                )
+            })
+            .collect();
+        let mut got = Vec::new();
+        for task in tasks {
+            got.push(task.await.expect("task shouldn't panic"));
+        }
+
+        let taken = got.iter().filter(|r| **r == Message::Integer(1)).count();
+        let refused = got.iter().filter(|r| **r == Message::Integer(0)).count();
+        assert!(taken == 1 && refused == 99, "Got: {:?}", got);
+
+        // Free again once deleted
+        Message::Delete("lock".into()).exec(&m, &kd).await;
+        let got = Message::SetNx("lock".into(), "me".into())
+            .exec(&m, &kd)
+            .await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = Message::Get("lock".into()).exec(&m, &kd).await;
+        let expected = Message::Result("lock".into(), "me".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compare_and_swap() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compare_and_swap.db";
+        const WAL_FILE: &str = "./test_compare_and_swap.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"compare_and_swap key  0\n";
+        let message = Message::parse(buf).expect("should parse compare_and_swap");
+        let expected = Message::CompareAndSwap("key".into(), "".into(), "0".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        // A missing key swaps from nothing
+        let got = message.exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+
+        // Only one of them sees the value it expects
+        let tasks: Vec<_> = (0..100)
+            .map(|_| {
+                let (m, kd) = (m.clone(), kd.clone());
+                tokio::spawn(async move {
+                    Message::CompareAndSwap("key".into(), "0".into(), "1".into())
+                        .exec(&m, &kd)
+                        .await
+                })
+            })
+            .collect();
+        let mut got = Vec::new();
+        for task in tasks {
+            got.push(task.await.expect("task shouldn't panic"));
+        }
+
+        let lost = Message::Responses(vec![
+            Message::Integer(0),
+            Message::Result("key".into(), "1".into()),
+        ]);
+        let swapped = got.iter().filter(|r| **r == Message::Integer(1)).count();
+        let refused = got.iter().filter(|r| **r == lost).count();
+        assert!(swapped == 1 && refused == 99, "Got: {:?}", got);
+        assert!(Bytes::from(lost) == "0\nkey 1\n");
+
+        let got = Message::CompareAndSwap("other".into(), "0".into(), "1".into())
+            .exec(&m, &kd)
+            .await;
+        let expected = Message::Responses(vec![Message::Integer(0), Message::None]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_append() -> io::Result<()> {
+        const DB_FILE: &str = "./test_append.db";
+        const WAL_FILE: &str = "./test_append.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"append key more value\n";
+        let message = Message::parse(buf).expect("should parse append");
+        let expected = Message::Append("key".into(), "more value".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        let mut len = 0;
+        for part in ["hello", " ", "world"] {
+            len += part.len();
+            let got = Message::Append("key".into(), part.into())
+                .exec(&m, &kd)
+                .await;
+            assert!(
+                got == Message::Count(len),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                Message::Count(len),
+                got
+            );
+        }
+
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), "hello world".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_setrange() -> io::Result<()> {
+        const DB_FILE: &str = "./test_setrange.db";
+        const WAL_FILE: &str = "./test_setrange.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"setrange key 6 big world\n";
+        let message = Message::parse(buf).expect("should parse setrange");
+        let expected = Message::SetRange("key".into(), 6, "big world".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        // The padding counts against the value limit
+        let limits = EntryLimits {
+            max_key_len: 16,
+            max_value_len: 16,
+        };
+        let got = Message::SetRange("key".into(), 12, "world".into()).check_sizes(&limits);
+        assert!(got == Err(ClientError::ValueTooLarge(17)), "Got: {:?}", got);
+
+        let set_range = |offset, v: &'static str| Message::SetRange("key".into(), offset, v.into());
+        let got = set_range(0, "").exec(&m, &kd).await;
+        assert!(got == Message::Count(0), "Got: {:?}", got);
+        assert!(kd.read().await.get(b"key").is_none());
+
+        Message::Insert("key".into(), "Hello".into())
+            .exec(&m, &kd)
+            .await;
+        Message::Expire("key".into(), 100).exec(&m, &kd).await;
+        let got = set_range(6, "World").exec(&m, &kd).await;
+        assert!(got == Message::Count(11), "Got: {:?}", got);
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), "Hello\0World".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        // Still expires
+        let got = Message::Ttl("key".into()).exec(&m, &kd).await;
+        assert!(got == Message::Integer(100), "Got: {:?}", got);
+
+        let got = Message::exec_all(&[set_range(0, "J")], &m, &kd).await;
+        assert!(
+            got == Message::Responses(vec![Message::Count(11)]),
+            "Got: {:?}",
+            got
+        );
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), "Jello\0World".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_getdel() -> io::Result<()> {
+        const DB_FILE: &str = "./test_getdel.db";
+        const WAL_FILE: &str = "./test_getdel.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"getdel key\n";
+        let message = Message::parse(buf).expect("should parse getdel");
+        let expected = Message::GetDel("key".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+
+        let got = Message::GetDel("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), "value".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(kd.read().await.get(b"key").is_none());
+
+        // Already consumed
+        let got = Message::GetDel("key".into()).exec(&m, &kd).await;
+        assert!(got == Message::None, "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_persist() -> io::Result<()> {
+        const DB_FILE: &str = "./test_persist.db";
+        const WAL_FILE: &str = "./test_persist.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let buf = b"persist key\n";
+        let message = Message::parse(buf).expect("should parse persist");
+        let expected = Message::Persist("key".into());
+        assert!(
+            message == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            message
+        );
+        assert!(message.len() == buf.len(), "Got: {}", message.len());
+
+        let mut entry = Entry::new(b"key", b"value", EntryType::Put);
+        entry.expire_at = Some(timestamp_millis() + 100);
+        let (page_id, offset) = m.write_entry_auto(&entry).await?;
+        kd.write()
+            .await
+            .insert(b"key", m.key_data(page_id, offset as u64));
+
+        let got = Message::Persist("key".into()).exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), "value".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // Nothing left to remove
+        let got = Message::Persist("key".into()).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+        let got = Message::Persist("missing".into()).exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expire() -> io::Result<()> {
+        const DB_FILE: &str = "./test_expire.db";
+        const WAL_FILE: &str = "./test_expire.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (&b"expire key 10\n"[..], Message::Expire("key".into(), 10)),
+            (b"ttl key\n", Message::Ttl("key".into())),
+            (b"expire key -1\n", Message::Ignore(14)),
+            (b"expire key\n", Message::Ignore(11)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        let ttl = |k: &'static str| Message::Ttl(k.into());
+        assert!(ttl("key").exec(&m, &kd).await == Message::Integer(-2));
+        assert!(Message::Expire("key".into(), 10).exec(&m, &kd).await == Message::Integer(0));
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        assert!(ttl("key").exec(&m, &kd).await == Message::Integer(-1));
+
+        let got = Message::Expire("key".into(), 2).exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = ttl("key").exec(&m, &kd).await;
+        assert!(got == Message::Integer(2), "Got: {:?}", got);
+
+        // Counts down
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let got = ttl("key").exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        assert!(got != Message::None, "Got: {:?}", got);
+
+        // Gone right away, but stays in the key dir until swept
+        let got = Message::Expire("key".into(), 0).exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        assert!(got == Message::None, "Got: {:?}", got);
+        let got = ttl("key").exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_setex() -> io::Result<()> {
+        const DB_FILE: &str = "./test_setex.db";
+        const WAL_FILE: &str = "./test_setex.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (
+                &b"setex key 1 some value\n"[..],
+                Message::SetEx("key".into(), 1, "some value".into()),
+            ),
+            (
+                b"psetex key 500 value\n",
+                Message::PSetEx("key".into(), 500, "value".into()),
+            ),
+            (b"setex key 01 value\n", Message::Ignore(19)),
+            (b"psetex key value\n", Message::Ignore(17)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        for message in [
+            Message::SetEx("key".into(), 0, "value".into()),
+            Message::PSetEx("key".into(), 0, "value".into()),
+        ] {
+            let got = message.exec(&m, &kd).await;
+            let expected = Message::Error("ERR invalid expire time".to_string());
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+        assert!(Message::Get("key".into()).exec(&m, &kd).await == Message::None);
+
+        let got = Message::SetEx("key".into(), 1, "value".into())
+            .exec(&m, &kd)
+            .await;
+        assert!(got == Message::Success, "Got: {:?}", got);
+        let got = Message::Ttl("key".into()).exec(&m, &kd).await;
+        assert!(got == Message::Integer(1), "Got: {:?}", got);
+        let got = Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Result("key".into(), "value".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        let got = Message::exec_all(
+            &[Message::PSetEx("other".into(), 500, "value".into())],
+            &m,
+            &kd,
+        )
+        .await;
+        assert!(
+            got == Message::Responses(vec![Message::Success]),
+            "Got: {:?}",
+            got
+        );
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        for k in ["key", "other"] {
+            let got = Message::Get(k.into()).exec(&m, &kd).await;
+            assert!(got == Message::None, "Got: {:?}", got);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_getex() -> io::Result<()> {
+        const DB_FILE: &str = "./test_getex.db";
+        const WAL_FILE: &str = "./test_getex.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let getex = |option| Message::GetEx("key".into(), option);
+        for (buf, expected) in [
+            (&b"getex key\n"[..], getex(GetExOption::None)),
+            (b"getex key ex 10\n", getex(GetExOption::Ex(10))),
+            (b"getex key px 500\n", getex(GetExOption::Px(500))),
+            (
+                b"getex key exat 1700000000\n",
+                getex(GetExOption::ExAt(1_700_000_000)),
+            ),
+            (b"getex key persist\n", getex(GetExOption::Persist)),
+            (b"getex key ex\n", Message::Ignore(13)),
+            (b"getex key in 10\n", Message::Ignore(16)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        let ttl = || Message::Ttl("key".into());
+        let value = Message::Result("key".into(), "value".into());
+        assert!(getex(GetExOption::Ex(10)).exec(&m, &kd).await == Message::None);
+        assert!(ttl().exec(&m, &kd).await == Message::Integer(-2));
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        let got = getex(GetExOption::None).exec(&m, &kd).await;
+        assert!(got == value, "Got: {:?}", got);
+        assert!(ttl().exec(&m, &kd).await == Message::Integer(-1));
+
+        for (option, expected) in [
+            (GetExOption::Ex(10), 10),
+            (GetExOption::Px(5000), 5),
+            (GetExOption::ExAt(timestamp_millis() / 1000 + 100), 100),
+        ] {
+            let got = getex(option).exec(&m, &kd).await;
+            assert!(got == value, "Got: {:?}", got);
+            let got = ttl().exec(&m, &kd).await;
+            // The unix time is rounded down to the second
+            assert!(
+                matches!(got, Message::Integer(n) if n == expected || n == expected - 1),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let got = Message::exec_all(&[getex(GetExOption::Persist)], &m, &kd).await;
+        assert!(got == Message::Responses(vec![value]), "Got: {:?}", got);
+        let got = ttl().exec(&m, &kd).await;
+        assert!(got == Message::Integer(-1), "Got: {:?}", got);
+
+        let got = getex(GetExOption::Px(0)).exec(&m, &kd).await;
+        let expected = Message::Error("ERR invalid expire time".to_string());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_reload() -> io::Result<()> {
+        const DB_FILE: &str = "./test_debug_reload.db";
+        const WAL_FILE: &str = "./test_debug_reload.wal";
+        const KEYS: usize = 50;
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"debug reload\n").expect("should parse debug reload");
+        assert!(message == Message::DebugReload, "Got: {:?}", message);
+        assert!(message.len() == 13);
+
+        // Spread over many pages, the last few only in the current one
+        for i in 0..KEYS {
+            let (k, v) = (format!("key_{}", i), format!("value_{}", i));
+            Message::Insert(k.into(), v.into()).exec(&m, &kd).await;
+        }
+        let before = kd.read().await.clone();
+
+        let got = Message::DebugReload.exec(&m, &kd).await;
+        assert!(
+            got == Message::Integer(KEYS as i64),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            Message::Integer(KEYS as i64),
+            got
+        );
+        assert!(*kd.read().await == before);
+
+        for i in 0..KEYS {
+            let (k, v) = (format!("key_{}", i), format!("value_{}", i));
+            let got = Message::Get(k.clone().into()).exec(&m, &kd).await;
+            let expected = Message::Result(k.into(), v.into());
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        let got = Message::exec_all(&[Message::DebugReload], &m, &kd).await;
+        assert!(
+            matches!(&got, Message::Responses(r) if matches!(r[..], [Message::Error(_)])),
+            "Got: {:?}",
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_strlen() -> io::Result<()> {
+        const DB_FILE: &str = "./test_strlen.db";
+        const WAL_FILE: &str = "./test_strlen.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"strlen key\n").expect("should parse");
+        assert!(
+            message == Message::Strlen("key".into()),
+            "Got: {:?}",
+            message
+        );
+        assert!(message.len() == 11);
+
+        let strlen = |k: &'static str| Message::Strlen(k.into());
+        assert!(strlen("missing").exec(&m, &kd).await == Message::Count(0));
+
+        Message::Insert("raw".into(), "hello world".into())
+            .exec(&m, &kd)
+            .await;
+        let entry = Entry::new(b"lz4", &[b'a'; 100], EntryType::Put).compress();
+        let (page_id, offset) = m.write_entry_auto(&entry).await?;
+        kd.write()
+            .await
+            .insert(b"lz4", m.key_data(page_id, offset as u64));
+        Message::RPush("list".into(), vec!["a".into()])
+            .exec(&m, &kd)
+            .await;
+
+        let wrong_type = Message::Error(WRONGTYPE.to_string());
+        for (k, expected) in [
+            ("raw", Message::Count(11)),
+            ("lz4", Message::Count(100)),
+            ("missing", Message::Count(0)),
+            ("list", wrong_type),
+        ] {
+            let got = strlen(k).exec(&m, &kd).await;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+
+            let got = Message::exec_all(&[strlen(k)], &m, &kd).await;
+            assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_encoding() -> io::Result<()> {
+        const DB_FILE: &str = "./test_object_encoding.db";
+        const WAL_FILE: &str = "./test_object_encoding.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"object encoding key\n").expect("should parse");
+        assert!(
+            message == Message::ObjectEncoding("key".into()),
+            "Got: {:?}",
+            message
+        );
+        assert!(message.len() == 20);
+
+        let encoding = |k: &'static str| Message::ObjectEncoding(k.into());
+        assert!(encoding("raw").exec(&m, &kd).await == Message::None);
+
+        Message::Insert(
+            "embstr".into(),
+            Bytes::from(vec![b'e'; Entry::EMBSTR_MAX_LEN]),
+        )
+        .exec(&m, &kd)
+        .await;
+        Message::Insert(
+            "raw".into(),
+            Bytes::from(vec![b'r'; Entry::EMBSTR_MAX_LEN + 1]),
+        )
+        .exec(&m, &kd)
+        .await;
+        let entry = Entry::new(b"lz4", &[b'a'; 100], EntryType::Put).compress();
+        let (page_id, offset) = m.write_entry_auto(&entry).await?;
+        kd.write()
+            .await
+            .insert(b"lz4", m.key_data(page_id, offset as u64));
+
+        for (k, expected) in [("embstr", "embstr"), ("raw", "raw"), ("lz4", "lz4")] {
+            let expected = Message::Text(expected.to_string());
+            let got = encoding(k).exec(&m, &kd).await;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+
+            // All still in the current page, which a transaction reads while holding it
+            let got = Message::exec_all(&[encoding(k)], &m, &kd).await;
+            assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+        }
+
+        // Reads still see the value decompressed
+        let got = Message::Get("lz4".into()).exec(&m, &kd).await;
+        let expected = Message::Result("lz4".into(), Bytes::from(&[b'a'; 100][..]));
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_copy() -> io::Result<()> {
+        const DB_FILE: &str = "./test_copy.db";
+        const WAL_FILE: &str = "./test_copy.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (
+                &b"copy a b\n"[..],
+                Message::Copy("a".into(), "b".into(), false),
+            ),
+            (
+                b"copy a b replace\n",
+                Message::Copy("a".into(), "b".into(), true),
+            ),
+            (b"copy a\n", Message::Ignore(7)),
+            (b"copy a b c\n", Message::Ignore(11)),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        let copy = |replace| Message::Copy("src".into(), "dst".into(), replace);
+        let get = |k: &'static str| Message::Get(k.into());
+        assert!(copy(false).exec(&m, &kd).await == Message::Integer(0));
+
+        Message::Insert("src".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        Message::Expire("src".into(), 100).exec(&m, &kd).await;
+        assert!(copy(false).exec(&m, &kd).await == Message::Integer(1));
+        let got = get("dst").exec(&m, &kd).await;
+        let expected = Message::Result("dst".into(), "value".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::Ttl("dst".into()).exec(&m, &kd).await;
+        assert!(
+            matches!(got, Message::Integer(99..=100)),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            Message::Integer(100),
+            got
+        );
+
+        // The copy is its own entry
+        Message::Insert("dst".into(), "changed".into())
+            .exec(&m, &kd)
+            .await;
+        let got = get("src").exec(&m, &kd).await;
+        let expected = Message::Result("src".into(), "value".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        assert!(copy(false).exec(&m, &kd).await == Message::Integer(0));
+        assert!(get("dst").exec(&m, &kd).await == Message::Result("dst".into(), "changed".into()));
+        assert!(copy(true).exec(&m, &kd).await == Message::Integer(1));
+        assert!(get("dst").exec(&m, &kd).await == Message::Result("dst".into(), "value".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rename() -> io::Result<()> {
+        const DB_FILE: &str = "./test_rename.db";
+        const WAL_FILE: &str = "./test_rename.wal";
+        const RENAMES: usize = 200;
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        // The tombstones would otherwise start a compaction that can outlive the test and write
+        // its files after they were cleaned up
+        let config = PageManagerConfig::new().deletion_ratio_threshold(1.0);
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .config(config)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for (buf, expected) in [
+            (
+                &b"rename a b\n"[..],
+                Message::Rename("a".into(), "b".into()),
+            ),
+            (b"renamenx a b\n", Message::RenameNx("a".into(), "b".into())),
+        ] {
+            let message = Message::parse(buf).expect("should parse");
+            assert!(
+                message == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                message
+            );
+            assert!(message.len() == buf.len(), "Got: {}", message.len());
+        }
+
+        let got = Message::Rename("a".into(), "b".into()).exec(&m, &kd).await;
+        assert!(matches!(got, Message::Error(_)), "Got: {:?}", got);
+
+        Message::Insert("a".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        Message::Insert("c".into(), "other".into())
+            .exec(&m, &kd)
+            .await;
+        let got = Message::RenameNx("a".into(), "c".into())
+            .exec(&m, &kd)
+            .await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        // Bounces the key between a and b while another task keeps reading both
+        let reader = {
+            let (m, kd) = (m.clone(), kd.clone());
+            tokio::spawn(async move {
+                for _ in 0..RENAMES {
+                    let got = Message::MGet(vec!["a".into(), "b".into()])
+                        .exec(&m, &kd)
+                        .await;
+                    let Message::Values(values) = &got else {
+                        panic!("Got: {:?}", got);
+                    };
+                    let found = values.iter().filter(|(_, v)| v.is_some()).count();
+                    assert!(found == 1, "Got: {:?}", got);
+                }
+            })
+        };
+        for i in 0..RENAMES {
+            let (src, dst) = match i % 2 {
+                0 => ("a", "b"),
+                _ => ("b", "a"),
+            };
+            let got = match i % 4 {
+                0 | 1 => Message::Rename(src.into(), dst.into()).exec(&m, &kd).await,
+                _ => {
+                    Message::RenameNx(src.into(), dst.into())
+                        .exec(&m, &kd)
+                        .await
+                }
+            };
+            assert!(
+                matches!(got, Message::Success | Message::Integer(1)),
+                "Got: {:?}",
+                got
+            );
+        }
+        reader
+            .await
+            .expect("reader should never see neither or both");
+
+        let got = Message::Get("a".into()).exec(&m, &kd).await;
+        let expected = Message::Result("a".into(), "value".into());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(Message::Get("b".into()).exec(&m, &kd).await == Message::None);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_freq() -> io::Result<()> {
+        const DB_FILE: &str = "./test_object_freq.db";
+        const WAL_FILE: &str = "./test_object_freq.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"object freq key\n").expect("should parse");
+        assert!(
+            message == Message::ObjectFreq("key".into()),
+            "Got: {:?}",
+            message
+        );
+        assert!(message.len() == 16);
+
+        let freq = |k: &'static str| Message::ObjectFreq(k.into());
+        let get = || Message::Get("key".into());
+        assert!(freq("key").exec(&m, &kd).await == Message::None);
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        // Not tracked while in the current page
+        let got = freq("key").exec(&m, &kd).await;
+        assert!(got == Message::Count(0), "Got: {:?}", got);
+
+        // Pushed out of the current page
+        for i in 0..20 {
+            let key = Bytes::from(format!("key_{}", i));
+            Message::Insert(key, "value".into()).exec(&m, &kd).await;
+        }
+        get().exec(&m, &kd).await;
+        let Message::Count(first) = freq("key").exec(&m, &kd).await else {
+            panic!("Expected a count");
+        };
+        assert!(first > 0);
+
+        get().exec(&m, &kd).await;
+        get().exec(&m, &kd).await;
+        let expected = Message::Count(first + 2);
+        let got = freq("key").exec(&m, &kd).await;
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::exec_all(&[freq("key")], &m, &kd).await;
+        assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_idletime() -> io::Result<()> {
+        const DB_FILE: &str = "./test_object_idletime.db";
+        const WAL_FILE: &str = "./test_object_idletime.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"object idletime key\n").expect("should parse");
+        assert!(
+            message == Message::ObjectIdleTime("key".into()),
+            "Got: {:?}",
+            message
+        );
+        assert!(message.len() == 20);
+
+        let idle = |k: &'static str| Message::ObjectIdleTime(k.into());
+        assert!(idle("key").exec(&m, &kd).await == Message::None);
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        // Not tracked while in the current page
+        let got = idle("key").exec(&m, &kd).await;
+        assert!(got == Message::Integer(-1), "Got: {:?}", got);
+
+        // Pushed out of the current page
+        for i in 0..20 {
+            let key = Bytes::from(format!("key_{}", i));
+            Message::Insert(key, "value".into()).exec(&m, &kd).await;
+        }
+        Message::Get("key".into()).exec(&m, &kd).await;
+        let expected = Message::Integer(0);
+        let got = idle("key").exec(&m, &kd).await;
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::exec_all(&[idle("key")], &m, &kd).await;
+        assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_refcount() -> io::Result<()> {
+        const DB_FILE: &str = "./test_object_refcount.db";
+        const WAL_FILE: &str = "./test_object_refcount.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"object refcount key\n").expect("should parse");
+        assert!(
+            message == Message::ObjectRefCount("key".into()),
+            "Got: {:?}",
+            message
+        );
+        assert!(message.len() == 20);
+
+        let refcount = |k: &'static str| Message::ObjectRefCount(k.into());
+        assert!(refcount("key").exec(&m, &kd).await == Message::None);
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        // Not pinned while in the current page
+        let got = refcount("key").exec(&m, &kd).await;
+        assert!(got == Message::Integer(-1), "Got: {:?}", got);
+
+        // Pushed out of the current page
+        for i in 0..20 {
+            let key = Bytes::from(format!("key_{}", i));
+            Message::Insert(key, "value".into()).exec(&m, &kd).await;
+        }
+        let page_id = kd
+            .read()
+            .await
+            .get(b"key")
+            .expect("key was inserted")
+            .page_id;
+        let pin = m.fetch_page(page_id).await.expect("page should be read");
+        let expected = Message::Integer(1);
+        let got = refcount("key").exec(&m, &kd).await;
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        let got = Message::exec_all(&[refcount("key")], &m, &kd).await;
+        assert!(got == Message::Responses(vec![expected]), "Got: {:?}", got);
+
+        drop(pin);
+        let got = refcount("key").exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lolwut() -> io::Result<()> {
+        const DB_FILE: &str = "./test_lolwut.db";
+        const WAL_FILE: &str = "./test_lolwut.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"lolwut\n").expect("should parse");
+        assert!(message == Message::LolWut, "Got: {:?}", message);
+        assert!(message.len() == 7);
+
+        let response = message.exec(&m, &kd).await;
+        let Message::Text(text) = response else {
+            panic!("lolwut should answer with text, got {:?}", response);
+        };
+        assert!(text.lines().count() > 1);
+        assert!(text.ends_with(env!("CARGO_PKG_VERSION")), "Got: {:?}", text);
+
+        // Sent over RESP3 as one bulk string, newlines and all
+        let expected = Frame::Bulk(Bytes::from(text.clone()));
+        let bytes = Bytes::from(Frame::from(Message::Text(text)));
+        let got = Frame::parse(&bytes);
+        assert!(
+            got == Ok((expected.clone(), bytes.len())),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cluster_info() -> io::Result<()> {
+        const DB_FILE: &str = "./test_cluster_info.db";
+        const WAL_FILE: &str = "./test_cluster_info.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"cluster info\n").expect("should parse");
+        assert!(message == Message::ClusterInfo, "Got: {:?}", message);
+        assert!(message.len() == 13);
+
+        let response = message.exec(&m, &kd).await;
+        let Message::Text(text) = response else {
+            panic!("cluster info should answer with text, got {:?}", response);
+        };
+        let fields: HashMap<_, _> = text
+            .lines()
+            .map(|line| line.split_once(':').expect("every line is a field"))
+            .collect();
+        let got = fields.get("cluster_enabled");
+        assert!(got == Some(&"0"), "Got: {:?}", text);
+        assert!(
+            fields.get("cluster_state") == Some(&"ok"),
+            "Got: {:?}",
+            text
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_command() -> io::Result<()> {
+        const DB_FILE: &str = "./test_command.db";
+        const WAL_FILE: &str = "./test_command.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let b = || Bytes::from("a");
+        let requests = [
+            Message::Insert(b(), b()),
+            Message::MSet(vec![]),
+            Message::Delete(b()),
+            Message::FlushDb(false),
+            Message::DbSize,
+            Message::Incr(b()),
+            Message::IncrBy(b(), 1),
+            Message::Decr(b()),
+            Message::DecrBy(b(), 1),
+            Message::Get(b()),
+            Message::GetRange(b(), 0, 1),
+            Message::BitCount(b(), None),
+            Message::BitOp(BitOpKind::Not, b(), vec![b()]),
+            Message::GetBit(b(), 0),
+            Message::SetBit(b(), 0, 1),
+            Message::GetSet(b(), b()),
+            Message::SetNx(b(), b()),
+            Message::CompareAndSwap(b(), b(), b()),
+            Message::SetEx(b(), 1, b()),
+            Message::PSetEx(b(), 1, b()),
+            Message::GetDel(b()),
+            Message::GetEx(b(), GetExOption::None),
+            Message::Append(b(), b()),
+            Message::SetRange(b(), 0, b()),
+            Message::Copy(b(), b(), false),
+            Message::Rename(b(), b()),
+            Message::RenameNx(b(), b()),
+            Message::Persist(b()),
+            Message::Expire(b(), 1),
+            Message::Ttl(b()),
+            Message::ObjectEncoding(b()),
+            Message::ObjectFreq(b()),
+            Message::ObjectIdleTime(b()),
+            Message::ObjectRefCount(b()),
+            Message::ObjectHelp,
+            Message::LolWut,
+            Message::ClusterInfo,
+            Message::CommandCount,
+            Message::CommandInfo(b()),
+            Message::Type(b()),
+            Message::Strlen(b()),
+            Message::LPush(b(), vec![]),
+            Message::RPush(b(), vec![]),
+            Message::LPop(b()),
+            Message::RPop(b()),
+            Message::LLen(b()),
+            Message::MGet(vec![]),
+            Message::Scan(b(), b()),
+            Message::Keys(b(), 1),
+            Message::KeyScan(b(), 1),
+            Message::Stats,
+            Message::Info,
+            Message::DebugReload,
+            Message::DebugSleep(1),
+            Message::DebugChangeReplId,
+            Message::CacheEvict(0),
+            Message::Multi,
+            Message::Exec,
+            Message::Discard,
+            Message::Auth(b()),
+            Message::Subscribe(vec![]),
+            Message::Unsubscribe(vec![]),
+            Message::Publish(b(), b()),
+            Message::Select(0),
+            Message::Reset,
+            Message::Ping(None),
+            Message::SnapshotCreate,
+            Message::SnapshotGet(0, b()),
+            Message::SnapshotRelease(0),
+            Message::Wait(0, 0),
+            Message::ReplicaOf(None),
+            Message::ReplStream,
+            Message::SlowlogGet(1),
+            Message::SlowlogReset,
+            Message::ClientList,
+            Message::ClientKill(0),
+            Message::ClientSetName(b()),
+            Message::ClientGetName,
+        ];
+        // Every message but the responses has a command
+        assert!(requests.iter().all(|r| !r.command().is_empty()));
+
+        let message = Message::parse(b"command count\n").expect("should parse");
+        assert!(message == Message::CommandCount, "Got: {:?}", message);
+        let Message::Integer(count) = message.exec(&m, &kd).await else {
+            panic!("command count should answer with an integer");
+        };
+        assert!(
+            count as usize >= requests.len(),
+            "\nExpected at least: {:?}\nGot: {:?}\n",
+            requests.len(),
+            count
+        );
+
+        let message = Message::parse(b"command info GET\n").expect("should parse");
+        assert!(message.len() == 17);
+        let got = message.exec(&m, &kd).await;
+        let get = COMMAND_REGISTRY.iter().find(|s| s.name == "get");
+        let expected = Message::CommandSpecs(get.into_iter().collect());
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(Bytes::from(got) == "1\nget 2 readonly fast\n");
+
+        let got = Message::CommandInfo(Bytes::from("object|encoding"))
+            .exec(&m, &kd)
+            .await;
+        let Message::CommandSpecs(specs) = got else {
+            panic!("command info should answer with specs, got {:?}", got);
+        };
+        assert!(specs.len() == 1 && specs[0].arity == 3);
+
+        let got = Message::CommandInfo(Bytes::from("nope"))
+            .exec(&m, &kd)
+            .await;
+        assert!(got == Message::CommandSpecs(vec![]), "Got: {:?}", got);
+        assert!(Bytes::from(got) == "0\n");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_object_help() -> io::Result<()> {
+        const DB_FILE: &str = "./test_object_help.db";
+        const WAL_FILE: &str = "./test_object_help.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"object help\n").expect("should parse");
+        assert!(message == Message::ObjectHelp, "Got: {:?}", message);
+        assert!(message.len() == 12);
+
+        let Message::Help(lines) = message.exec(&m, &kd).await else {
+            panic!("object help should answer with help");
+        };
+        assert!(!lines.is_empty());
+        assert!(
+            lines.iter().any(|l| l.contains("ENCODING")),
+            "Got: {:?}",
+            lines
+        );
+
+        let text = Bytes::from(Message::Help(lines));
+        assert!(text.len() == Message::Help(lines).len());
+        assert!(text.ends_with(b"\n") && text.split(|b| *b == b'\n').count() == lines.len() + 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_type() -> io::Result<()> {
+        const DB_FILE: &str = "./test_type.db";
+        const WAL_FILE: &str = "./test_type.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"type key\n").expect("should parse");
+        assert!(message == Message::Type("key".into()), "Got: {:?}", message);
+        assert!(message.len() == 9);
+
+        let value_type = |k: &'static str| Message::Type(k.into());
+        let none = Message::Text("none".to_string());
+        let string = Message::Text("string".to_string());
+
+        Message::Insert("key".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+
+        // Not even the page cache is asked about a missing key
+        let before = m.stats();
+        let got = value_type("missing").exec(&m, &kd).await;
+        assert!(got == none, "\nExpected: {:?}\nGot: {:?}\n", none, got);
+        assert!(m.stats() == before);
+
+        let got = value_type("key").exec(&m, &kd).await;
+        assert!(got == string, "\nExpected: {:?}\nGot: {:?}\n", string, got);
+
+        let got = Message::exec_all(&[value_type("key"), value_type("missing")], &m, &kd).await;
+        let expected = Message::Responses(vec![string, none]);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list() -> io::Result<()> {
+        const DB_FILE: &str = "./test_list.db";
+        const WAL_FILE: &str = "./test_list.wal";
+        const HINT_FILE: &str = "./test_list.hint";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let _cu_hint = CleanUp::file(HINT_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        // Only compacted when asked to
+        let config = PageManagerConfig::new().deletion_ratio_threshold(1.0);
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .config(config)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        let message = Message::parse(b"lpush list a b c\n").expect("should parse");
+        let items = vec!["a".into(), "b".into(), "c".into()];
+        let expected = Message::LPush("list".into(), items);
+        assert!(message == expected, "Got: {:?}", message);
+        assert!(message.len() == 17);
+        let message = Message::parse(b"rpop list\n").expect("should parse");
+        assert!(
+            message == Message::RPop("list".into()),
+            "Got: {:?}",
+            message
+        );
+        assert!(message.len() == 10);
+        // Nothing to push
+        let message = Message::parse(b"rpush list\n").expect("should parse");
+        assert!(message == Message::Ignore(11), "Got: {:?}", message);
+
+        let lpop = || Message::LPop("list".into());
+        let rpop = || Message::RPop("list".into());
+        let llen = || Message::LLen("list".into());
+        let wrongtype = Message::Error(WRONGTYPE.to_string());
+
+        // Strings to be deleted, so compaction moves the list
+        for i in 0..20 {
+            let key = Bytes::from(format!("pad_{}", i));
+            Message::Insert(key, "padding".into()).exec(&m, &kd).await;
+        }
+
+        // Spans several nodes
+        let items = (0..10).map(|i| Bytes::from(i.to_string())).collect();
+        let got = Message::RPush("list".into(), items).exec(&m, &kd).await;
+        assert!(got == Message::Integer(10), "Got: {:?}", got);
+        let items = vec!["x".into(), "y".into()];
+        let got = Message::LPush("list".into(), items).exec(&m, &kd).await;
+        assert!(got == Message::Integer(12), "Got: {:?}", got);
+
+        let got = Message::Type("list".into()).exec(&m, &kd).await;
+        assert!(got == Message::Text("list".to_string()), "Got: {:?}", got);
+        let got = Message::Get("list".into()).exec(&m, &kd).await;
+        assert!(
+            got == wrongtype,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            wrongtype,
+            got
+        );
+        Message::Insert("string".into(), "value".into())
+            .exec(&m, &kd)
+            .await;
+        let got = Message::LPush("string".into(), vec!["a".into()])
+            .exec(&m, &kd)
+            .await;
+        assert!(
+            got == wrongtype,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            wrongtype,
+            got
+        );
+
+        for i in 0..20 {
+            let key = Bytes::from(format!("pad_{}", i));
+            Message::Delete(key).exec(&m, &kd).await;
+        }
+        m.compact(&kd).await?;
+        assert!(m.compactions() == 1);
+
+        // The latest node written under the key is still its head
+        Message::DebugReload.exec(&m, &kd).await;
+        let got = llen().exec(&m, &kd).await;
+        assert!(got == Message::Integer(12), "Got: {:?}", got);
+
+        let mut popped = Vec::new();
+        for _ in 0..6 {
+            popped.push(lpop().exec(&m, &kd).await);
+        }
+        let got = Message::exec_all(&[rpop(), llen(), rpop()], &m, &kd).await;
+        let Message::Responses(mut responses) = got else {
+            panic!("Expected responses, got {:?}", got);
+        };
+        assert!(responses.remove(1) == Message::Integer(5));
+        popped.extend(responses);
+        while let Message::Result(_, item) = rpop().exec(&m, &kd).await {
+            popped.push(Message::Result("list".into(), item));
+        }
+
+        let expected: Vec<_> = ["y", "x", "0", "1", "2", "3", "9", "8", "7", "6", "5", "4"]
+            .into_iter()
+            .map(|item| Message::Result("list".into(), item.into()))
+            .collect();
+        assert!(
+            popped == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            popped
+        );
+
+        // Emptied lists are deleted
+        assert!(kd.read().await.get(b"list").is_none());
+        let got = llen().exec(&m, &kd).await;
+        assert!(got == Message::Integer(0), "Got: {:?}", got);
+        let got = lpop().exec(&m, &kd).await;
+        assert!(got == Message::None, "Got: {:?}", got);
+
+        Ok(())
     }
 }
@@ -0,0 +1,174 @@
+use std::{collections::HashMap, fmt::Write, sync::Mutex, time::Duration};
+
+// One bucket per bit length of a latency in microseconds, bucket i holding [2^(i-1), 2^i - 1]
+const BUCKETS: usize = u64::BITS as usize + 1;
+
+/// Latencies in microseconds, bucketed by powers of two. Percentiles are the upper bound of the
+/// bucket they fall in, so they are off by at most a factor of two and never below the truth.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, us: u64) {
+        self.buckets[(u64::BITS - us.leading_zeros()) as usize] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(us);
+        self.min = self.min.min(us);
+        self.max = self.max.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// 0 if nothing was recorded, as are `max`, `mean` and `percentile`.
+    pub fn min(&self) -> u64 {
+        match self.count {
+            0 => 0,
+            _ => self.min,
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// The latency `p` (0.0 to 1.0) of the recorded ones are at or below, e.g. 0.99 for the p99.
+    pub fn percentile(&self, p: f64) -> u64 {
+        let rank = ((p * self.count as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return match i {
+                    0 => 0,
+                    i => (u64::MAX >> (u64::BITS as usize - i)).min(self.max),
+                };
+            }
+        }
+
+        0
+    }
+}
+
+/// How long each command takes to execute, by command name. Doesn't count reading the request or
+/// writing the response.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands: Mutex<HashMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rounded up to the microsecond, so only a command that took no time at all counts as 0.
+    pub fn record(&self, command: &'static str, elapsed: Duration) {
+        let us = elapsed.as_nanos().div_ceil(1000).min(u64::MAX as u128) as u64;
+
+        self.commands
+            .lock()
+            .unwrap()
+            .entry(command)
+            .or_default()
+            .record(us);
+    }
+
+    pub fn histogram(&self, command: &str) -> Option<Histogram> {
+        self.commands.lock().unwrap().get(command).cloned()
+    }
+
+    /// `cmd_<name>_<stat>:value` lines for every command run so far, sorted by name.
+    pub fn report(&self) -> String {
+        let commands = self.commands.lock().unwrap();
+        let mut names: Vec<_> = commands.keys().collect();
+        names.sort();
+
+        let mut report = String::new();
+        for name in names {
+            let h = &commands[name];
+            let _ = write!(
+                report,
+                "cmd_{name}_calls:{}\ncmd_{name}_min_us:{}\ncmd_{name}_max_us:{}\n\
+                 cmd_{name}_mean_us:{}\ncmd_{name}_p99_us:{}\n",
+                h.count(),
+                h.min(),
+                h.max(),
+                h.mean(),
+                h.percentile(0.99),
+            );
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::serverv2::metrics::{Histogram, Metrics};
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::new();
+        assert!(h.min() == 0 && h.max() == 0 && h.mean() == 0 && h.percentile(0.99) == 0);
+
+        let mut h = Histogram::new();
+        for us in 1..=100 {
+            h.record(us);
+        }
+        assert!(h.count() == 100);
+        assert!(h.min() == 1 && h.max() == 100 && h.mean() == 50);
+        // 99 falls in [64, 127], capped by the max
+        let got = h.percentile(0.99);
+        assert!(got == 100, "\nExpected: {:?}\nGot: {:?}\n", 100, got);
+        // 50 falls in [32, 63]
+        let got = h.percentile(0.5);
+        assert!(got == 63, "\nExpected: {:?}\nGot: {:?}\n", 63, got);
+
+        h.record(0);
+        h.record(u64::MAX);
+        assert!(h.min() == 0 && h.max() == u64::MAX);
+        assert!(h.percentile(1.0) == u64::MAX);
+        assert!(h.percentile(0.0) == 0);
+
+        let metrics = Metrics::new();
+        metrics.record("get", Duration::from_nanos(1));
+        metrics.record("get", Duration::from_micros(3));
+        let got = metrics
+            .histogram("get")
+            .map(|h| (h.count(), h.min(), h.max()));
+        assert!(got == Some((2, 1, 3)), "Got: {:?}", got);
+        assert!(metrics.histogram("insert").is_none());
+        assert!(metrics.report().contains("cmd_get_p99_us:3\n"));
+    }
+}
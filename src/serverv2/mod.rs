@@ -1,3 +1,13 @@
+pub mod auth;
+pub mod client;
+pub mod command;
 pub mod connection;
 pub mod message;
+pub mod metrics;
+pub mod protocol;
+pub mod pubsub;
+pub mod replication;
 pub mod server;
+pub mod slowlog;
+pub mod sweeper;
+pub mod tls;
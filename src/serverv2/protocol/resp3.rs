@@ -0,0 +1,680 @@
+use std::{io::Cursor, str::FromStr};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::serverv2::message::{BitOpKind, GetExOption, Message, DEFAULT_KEYS_LIMIT};
+
+// Keys `SCAN` answers with at a time unless given a `COUNT`, as in Redis
+const DEFAULT_SCAN_COUNT: usize = 10;
+// Entries `SLOWLOG GET` answers with unless given a count, as in Redis
+const DEFAULT_SLOWLOG_GET: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Boolean(bool),
+    Null,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+    // Metadata about the frame that follows it
+    Attribute(Vec<(Frame, Frame)>, Box<Frame>),
+    // Out of band data, like an array that isn't a response to any request
+    Push(Vec<Frame>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    Incomplete,
+    Invalid,
+}
+
+impl Frame {
+    /// Parses one frame from the start of `buf`, returning it along with the number of bytes it
+    /// took up.
+    pub fn parse(buf: &[u8]) -> Result<(Frame, usize), FrameError> {
+        let mut src = Cursor::new(buf);
+        let frame = parse_frame(&mut src)?;
+
+        Ok((frame, src.position() as usize))
+    }
+
+    /// Splits a command, an array of bulk or simple strings, into its arguments.
+    pub fn into_command(self) -> Option<Vec<Bytes>> {
+        let Frame::Array(frames) = self else {
+            return None;
+        };
+
+        frames
+            .into_iter()
+            .map(|f| match f {
+                Frame::Bulk(b) => Some(b),
+                Frame::Simple(s) => Some(Bytes::from(s)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Frame::Simple(s) => {
+                dst.put_u8(b'+');
+                dst.put_slice(s.as_bytes());
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Error(e) => {
+                dst.put_u8(b'-');
+                dst.put_slice(e.as_bytes());
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Integer(i) => {
+                dst.put_slice(format!(":{}\r\n", i).as_bytes());
+            }
+            Frame::Bulk(b) => {
+                dst.put_slice(format!("${}\r\n", b.len()).as_bytes());
+                dst.put_slice(b);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Boolean(b) => {
+                dst.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" });
+            }
+            Frame::Null => dst.put_slice(b"_\r\n"),
+            Frame::Array(a) => {
+                dst.put_slice(format!("*{}\r\n", a.len()).as_bytes());
+                for f in a {
+                    f.encode(dst);
+                }
+            }
+            Frame::Push(p) => {
+                dst.put_slice(format!(">{}\r\n", p.len()).as_bytes());
+                for f in p {
+                    f.encode(dst);
+                }
+            }
+            Frame::Map(m) => {
+                dst.put_slice(format!("%{}\r\n", m.len()).as_bytes());
+                for (k, v) in m {
+                    k.encode(dst);
+                    v.encode(dst);
+                }
+            }
+            Frame::Attribute(a, f) => {
+                dst.put_slice(format!("|{}\r\n", a.len()).as_bytes());
+                for (k, v) in a {
+                    k.encode(dst);
+                    v.encode(dst);
+                }
+                f.encode(dst);
+            }
+        }
+    }
+}
+
+impl From<Frame> for Bytes {
+    fn from(value: Frame) -> Self {
+        let mut dst = BytesMut::new();
+        value.encode(&mut dst);
+
+        dst.into()
+    }
+}
+
+impl From<Message> for Frame {
+    fn from(value: Message) -> Self {
+        match value {
+            Message::Result(_, v) => Frame::Bulk(v),
+            Message::Results(r) => Frame::Map(
+                r.into_iter()
+                    .map(|(k, v)| (Frame::Bulk(k), Frame::Bulk(v)))
+                    .collect(),
+            ),
+            Message::Values(v) => Frame::Array(
+                v.into_iter()
+                    .map(|(_, v)| v.map_or(Frame::Null, Frame::Bulk))
+                    .collect(),
+            ),
+            Message::KeyList(keys, truncated) => {
+                let keys = Frame::Array(keys.into_iter().map(Frame::Bulk).collect());
+                match truncated {
+                    true => Frame::Attribute(
+                        vec![(Frame::Simple("truncated".to_string()), Frame::Boolean(true))],
+                        Box::new(keys),
+                    ),
+                    false => keys,
+                }
+            }
+            Message::SlowLogEntries(entries) => Frame::Array(
+                entries
+                    .into_iter()
+                    .map(|e| {
+                        Frame::Array(vec![
+                            Frame::Integer(e.id as i64),
+                            Frame::Integer(e.timestamp as i64),
+                            Frame::Integer(e.duration_us as i64),
+                            Frame::Bulk(Bytes::from(e.command)),
+                        ])
+                    })
+                    .collect(),
+            ),
+            // Like Redis, but without the key positions, ACL categories and the like
+            Message::CommandSpecs(specs) => Frame::Array(
+                specs
+                    .into_iter()
+                    .map(|s| {
+                        Frame::Array(vec![
+                            Frame::Bulk(Bytes::from(s.name)),
+                            Frame::Integer(s.arity),
+                            Frame::Array(
+                                s.flags
+                                    .iter()
+                                    .map(|f| Frame::Simple(f.to_string()))
+                                    .collect(),
+                            ),
+                        ])
+                    })
+                    .collect(),
+            ),
+            Message::ScanPage(cursor, keys) => Frame::Array(vec![
+                Frame::Bulk(cursor),
+                Frame::Array(keys.into_iter().map(Frame::Bulk).collect()),
+            ]),
+            Message::Subscribed(c, n) => Frame::Push(vec![
+                Frame::Bulk(Bytes::from("subscribe")),
+                Frame::Bulk(c),
+                Frame::Integer(n as i64),
+            ]),
+            Message::Unsubscribed(c, n) => Frame::Push(vec![
+                Frame::Bulk(Bytes::from("unsubscribe")),
+                Frame::Bulk(c),
+                Frame::Integer(n as i64),
+            ]),
+            Message::Published(c, p) => Frame::Push(vec![
+                Frame::Bulk(Bytes::from("message")),
+                Frame::Bulk(c),
+                Frame::Bulk(p),
+            ]),
+            Message::Responses(r) => Frame::Array(r.into_iter().map(Frame::from).collect()),
+            Message::Text(t) => Frame::Bulk(Bytes::from(t)),
+            Message::Help(lines) => {
+                Frame::Array(lines.iter().map(|l| Frame::Simple(l.to_string())).collect())
+            }
+            Message::Error(e) => Frame::Error(e),
+            Message::Queued => Frame::Simple("QUEUED".to_string()),
+            Message::Pong(None) => Frame::Simple("PONG".to_string()),
+            // A simple string can't hold a line break
+            Message::Pong(Some(p)) => match std::str::from_utf8(&p) {
+                Ok(s) if !s.contains(['\r', '\n']) => Frame::Simple(s.to_string()),
+                _ => Frame::Bulk(p),
+            },
+            Message::ReplEntry(seq, e) => Frame::Push(vec![
+                Frame::Bulk(Bytes::from("replentry")),
+                Frame::Integer(seq as i64),
+                Frame::Bulk(e),
+            ]),
+            Message::ResetDone => Frame::Simple("RESET".to_string()),
+            Message::Count(n) => Frame::Integer(n as i64),
+            Message::Integer(n) => Frame::Integer(n),
+            Message::Success => Frame::Simple("OK".to_string()),
+            Message::Insert(_, _)
+            | Message::MSet(_)
+            | Message::Delete(_)
+            | Message::FlushDb(_)
+            | Message::DbSize
+            | Message::Incr(_)
+            | Message::IncrBy(_, _)
+            | Message::Decr(_)
+            | Message::DecrBy(_, _)
+            | Message::Get(_)
+            | Message::GetRange(_, _, _)
+            | Message::BitCount(_, _)
+            | Message::BitOp(_, _, _)
+            | Message::GetBit(_, _)
+            | Message::SetBit(_, _, _)
+            | Message::GetSet(_, _)
+            | Message::SetNx(_, _)
+            | Message::CompareAndSwap(_, _, _)
+            | Message::SetEx(_, _, _)
+            | Message::PSetEx(_, _, _)
+            | Message::Copy(_, _, _)
+            | Message::Rename(_, _)
+            | Message::RenameNx(_, _)
+            | Message::GetDel(_)
+            | Message::GetEx(_, _)
+            | Message::Append(_, _)
+            | Message::SetRange(_, _, _)
+            | Message::Persist(_)
+            | Message::Expire(_, _)
+            | Message::Ttl(_)
+            | Message::ObjectEncoding(_)
+            | Message::ObjectFreq(_)
+            | Message::ObjectIdleTime(_)
+            | Message::ObjectRefCount(_)
+            | Message::ObjectHelp
+            | Message::LolWut
+            | Message::ClusterInfo
+            | Message::CommandCount
+            | Message::CommandInfo(_)
+            | Message::Type(_)
+            | Message::Strlen(_)
+            | Message::LPush(_, _)
+            | Message::RPush(_, _)
+            | Message::LPop(_)
+            | Message::RPop(_)
+            | Message::LLen(_)
+            | Message::MGet(_)
+            | Message::Scan(_, _)
+            | Message::Keys(_, _)
+            | Message::KeyScan(_, _)
+            | Message::Stats
+            | Message::Info
+            | Message::DebugReload
+            | Message::DebugSleep(_)
+            | Message::DebugChangeReplId
+            | Message::CacheEvict(_)
+            | Message::Multi
+            | Message::Exec
+            | Message::Discard
+            | Message::Auth(_)
+            | Message::Subscribe(_)
+            | Message::Unsubscribe(_)
+            | Message::Publish(_, _)
+            | Message::Select(_)
+            | Message::Reset
+            | Message::Ping(_)
+            | Message::SnapshotCreate
+            | Message::SnapshotGet(_, _)
+            | Message::SnapshotRelease(_)
+            | Message::Wait(_, _)
+            | Message::ReplicaOf(_)
+            | Message::ReplStream
+            | Message::SlowlogGet(_)
+            | Message::SlowlogReset
+            | Message::ClientList
+            | Message::ClientKill(_)
+            | Message::ClientSetName(_)
+            | Message::ClientGetName
+            | Message::Ignore(_)
+            | Message::None => Frame::Null,
+        }
+    }
+}
+
+/// Maps a command's arguments onto the message that executes it. Command names are case
+/// insensitive.
+pub fn message(args: &[Bytes]) -> Option<Message> {
+    let name = args.first()?.to_ascii_uppercase();
+    match (&name[..], args.len()) {
+        (b"SET", 3) => Some(Message::Insert(args[1].clone(), args[2].clone())),
+        (b"GET", 2) => Some(Message::Get(args[1].clone())),
+        (b"GETRANGE" | b"SUBSTR", 4) => Some(Message::GetRange(
+            args[1].clone(),
+            integer(&args[2])?,
+            integer(&args[3])?,
+        )),
+        (b"BITCOUNT", 2) => Some(Message::BitCount(args[1].clone(), None)),
+        (b"BITCOUNT", 4) => Some(Message::BitCount(
+            args[1].clone(),
+            Some((integer(&args[2])?, integer(&args[3])?)),
+        )),
+        (b"BITOP", n) if n > 3 => bit_op(args),
+        (b"GETBIT", 3) => Some(Message::GetBit(args[1].clone(), integer(&args[2])?)),
+        (b"SETBIT", 4) => Some(Message::SetBit(
+            args[1].clone(),
+            integer(&args[2])?,
+            integer(&args[3]).filter(|bit| *bit <= 1)?,
+        )),
+        (b"GETSET", 3) => Some(Message::GetSet(args[1].clone(), args[2].clone())),
+        (b"SETNX", 3) => Some(Message::SetNx(args[1].clone(), args[2].clone())),
+        (b"COMPARE_AND_SWAP", 4) => Some(Message::CompareAndSwap(
+            args[1].clone(),
+            args[2].clone(),
+            args[3].clone(),
+        )),
+        (b"SETEX", 4) => Some(Message::SetEx(
+            args[1].clone(),
+            integer(&args[2])?,
+            args[3].clone(),
+        )),
+        (b"PSETEX", 4) => Some(Message::PSetEx(
+            args[1].clone(),
+            integer(&args[2])?,
+            args[3].clone(),
+        )),
+        (b"RENAME", 3) => Some(Message::Rename(args[1].clone(), args[2].clone())),
+        (b"RENAMENX", 3) => Some(Message::RenameNx(args[1].clone(), args[2].clone())),
+        (b"COPY", 3) => Some(Message::Copy(args[1].clone(), args[2].clone(), false)),
+        (b"COPY", 4) if args[3].eq_ignore_ascii_case(b"REPLACE") => {
+            Some(Message::Copy(args[1].clone(), args[2].clone(), true))
+        }
+        (b"GETDEL", 2) => Some(Message::GetDel(args[1].clone())),
+        (b"GETEX", 2..=4) => Some(Message::GetEx(args[1].clone(), get_ex_option(&args[2..])?)),
+        (b"APPEND", 3) => Some(Message::Append(args[1].clone(), args[2].clone())),
+        (b"SETRANGE", 4) => Some(Message::SetRange(
+            args[1].clone(),
+            integer(&args[2])?,
+            args[3].clone(),
+        )),
+        (b"PERSIST", 2) => Some(Message::Persist(args[1].clone())),
+        (b"EXPIRE", 3) => Some(Message::Expire(args[1].clone(), integer(&args[2])?)),
+        (b"TTL", 2) => Some(Message::Ttl(args[1].clone())),
+        (b"TYPE", 2) => Some(Message::Type(args[1].clone())),
+        (b"STRLEN", 2) => Some(Message::Strlen(args[1].clone())),
+        (b"LPUSH", n) if n > 2 => Some(Message::LPush(args[1].clone(), args[2..].to_vec())),
+        (b"RPUSH", n) if n > 2 => Some(Message::RPush(args[1].clone(), args[2..].to_vec())),
+        (b"LPOP", 2) => Some(Message::LPop(args[1].clone())),
+        (b"RPOP", 2) => Some(Message::RPop(args[1].clone())),
+        (b"LLEN", 2) => Some(Message::LLen(args[1].clone())),
+        (b"OBJECT", 3) if args[1].eq_ignore_ascii_case(b"ENCODING") => {
+            Some(Message::ObjectEncoding(args[2].clone()))
+        }
+        (b"OBJECT", 3) if args[1].eq_ignore_ascii_case(b"FREQ") => {
+            Some(Message::ObjectFreq(args[2].clone()))
+        }
+        (b"OBJECT", 3) if args[1].eq_ignore_ascii_case(b"IDLETIME") => {
+            Some(Message::ObjectIdleTime(args[2].clone()))
+        }
+        (b"OBJECT", 3) if args[1].eq_ignore_ascii_case(b"REFCOUNT") => {
+            Some(Message::ObjectRefCount(args[2].clone()))
+        }
+        (b"OBJECT", 2) if args[1].eq_ignore_ascii_case(b"HELP") => Some(Message::ObjectHelp),
+        (b"LOLWUT", 1) => Some(Message::LolWut),
+        (b"CLUSTER", 2) if args[1].eq_ignore_ascii_case(b"INFO") => Some(Message::ClusterInfo),
+        (b"COMMAND", 2) if args[1].eq_ignore_ascii_case(b"COUNT") => Some(Message::CommandCount),
+        (b"COMMAND", 3) if args[1].eq_ignore_ascii_case(b"INFO") => {
+            Some(Message::CommandInfo(args[2].clone()))
+        }
+        (b"DEL", 2) => Some(Message::Delete(args[1].clone())),
+        (b"FLUSHDB", 1) => Some(Message::FlushDb(false)),
+        (b"FLUSHDB", 2) if args[1].eq_ignore_ascii_case(b"ASYNC") => Some(Message::FlushDb(true)),
+        (b"FLUSHDB", 2) if args[1].eq_ignore_ascii_case(b"SYNC") => Some(Message::FlushDb(false)),
+        (b"DBSIZE", 1) => Some(Message::DbSize),
+        (b"INCR", 2) => Some(Message::Incr(args[1].clone())),
+        (b"DECR", 2) => Some(Message::Decr(args[1].clone())),
+        (b"INCRBY", 3) => Some(Message::IncrBy(args[1].clone(), integer(&args[2])?)),
+        (b"DECRBY", 3) => Some(Message::DecrBy(args[1].clone(), integer(&args[2])?)),
+        (b"MGET", n) if n > 1 => Some(Message::MGet(args[1..].to_vec())),
+        (b"KEYS", 2) => Some(Message::Keys(args[1].clone(), DEFAULT_KEYS_LIMIT)),
+        (b"SCAN", 2) => Some(Message::KeyScan(args[1].clone(), DEFAULT_SCAN_COUNT)),
+        (b"SCAN", 4) if args[2].eq_ignore_ascii_case(b"COUNT") => Some(Message::KeyScan(
+            args[1].clone(),
+            integer(&args[3]).filter(|n| *n > 0)?,
+        )),
+        (b"MSET", n) if n > 1 && n % 2 == 1 => Some(Message::MSet(
+            args[1..]
+                .chunks(2)
+                .map(|kv| (kv[0].clone(), kv[1].clone()))
+                .collect(),
+        )),
+        (b"DEBUG", _) => debug(args),
+        (b"CACHE", 3) if args[1].eq_ignore_ascii_case(b"EVICT") => {
+            Some(Message::CacheEvict(integer(&args[2])?))
+        }
+        (b"SLOWLOG", _) => slowlog(args),
+        (b"CLIENT", _) => client(args),
+        (b"INFO", 1) => Some(Message::Info),
+        (b"MULTI", 1) => Some(Message::Multi),
+        (b"AUTH", 2) => Some(Message::Auth(args[1].clone())),
+        (b"SELECT", 2) => Some(Message::Select(integer(&args[1])?)),
+        (b"SNAPSHOT", _) => snapshot(args),
+        (b"WAIT", 3) => Some(Message::Wait(integer(&args[1])?, integer(&args[2])?)),
+        (b"REPLICAOF", 3)
+            if args[1].eq_ignore_ascii_case(b"NO") && args[2].eq_ignore_ascii_case(b"ONE") =>
+        {
+            Some(Message::ReplicaOf(None))
+        }
+        (b"REPLICAOF", 3) => {
+            let host = std::str::from_utf8(&args[1]).ok()?.to_string();
+            Some(Message::ReplicaOf(Some((host, integer(&args[2])?))))
+        }
+        (b"REPLSTREAM", 1) => Some(Message::ReplStream),
+        (b"SUBSCRIBE", n) if n > 1 => Some(Message::Subscribe(args[1..].to_vec())),
+        (b"UNSUBSCRIBE", _) => Some(Message::Unsubscribe(args[1..].to_vec())),
+        (b"PUBLISH", 3) => Some(Message::Publish(args[1].clone(), args[2].clone())),
+        (b"EXEC", 1) => Some(Message::Exec),
+        (b"DISCARD", 1) => Some(Message::Discard),
+        (b"PING", 1) => Some(Message::Ping(None)),
+        (b"PING", 2) => Some(Message::Ping(Some(args[1].clone()))),
+        (b"RESET", 1) => Some(Message::Reset),
+        _ => None,
+    }
+}
+
+/// The SNAPSHOT subcommands, named by the second argument.
+fn snapshot(args: &[Bytes]) -> Option<Message> {
+    let sub = args.get(1)?.to_ascii_uppercase();
+    match (&sub[..], args.len()) {
+        (b"CREATE", 2) => Some(Message::SnapshotCreate),
+        (b"GET", 4) => Some(Message::SnapshotGet(integer(&args[2])?, args[3].clone())),
+        (b"RELEASE", 3) => Some(Message::SnapshotRelease(integer(&args[2])?)),
+        _ => None,
+    }
+}
+
+fn debug(args: &[Bytes]) -> Option<Message> {
+    let sub = args.get(1)?.to_ascii_uppercase();
+    match (&sub[..], args.len()) {
+        (b"RELOAD", 2) => Some(Message::DebugReload),
+        (b"SLEEP", 3) => Some(Message::DebugSleep(integer(&args[2])?)),
+        (b"CHANGE-REPL-ID", 2) => Some(Message::DebugChangeReplId),
+        _ => None,
+    }
+}
+
+/// BITOP, whose NOT takes exactly one key.
+fn bit_op(args: &[Bytes]) -> Option<Message> {
+    let kind = match (&args[1].to_ascii_uppercase()[..], args.len()) {
+        (b"AND", _) => BitOpKind::And,
+        (b"OR", _) => BitOpKind::Or,
+        (b"XOR", _) => BitOpKind::Xor,
+        (b"NOT", 4) => BitOpKind::Not,
+        _ => return None,
+    };
+
+    Some(Message::BitOp(kind, args[2].clone(), args[3..].to_vec()))
+}
+
+fn slowlog(args: &[Bytes]) -> Option<Message> {
+    let sub = args.get(1)?.to_ascii_uppercase();
+    match (&sub[..], args.len()) {
+        (b"GET", 2) => Some(Message::SlowlogGet(DEFAULT_SLOWLOG_GET)),
+        (b"GET", 3) => Some(Message::SlowlogGet(integer(&args[2])?)),
+        (b"RESET", 2) => Some(Message::SlowlogReset),
+        _ => None,
+    }
+}
+
+/// The CLIENT subcommands. KILL takes the id as either `ID n` or `id:n`.
+fn client(args: &[Bytes]) -> Option<Message> {
+    let sub = args.get(1)?.to_ascii_uppercase();
+    match (&sub[..], args.len()) {
+        (b"LIST", 2) => Some(Message::ClientList),
+        (b"SETNAME", 3) => Some(Message::ClientSetName(args[2].clone())),
+        (b"GETNAME", 2) => Some(Message::ClientGetName),
+        (b"KILL", 3) => Some(Message::ClientKill(integer(args[2].strip_prefix(b"id:")?)?)),
+        (b"KILL", 4) if args[2].eq_ignore_ascii_case(b"ID") => {
+            Some(Message::ClientKill(integer(&args[3])?))
+        }
+        _ => None,
+    }
+}
+
+fn get_ex_option(args: &[Bytes]) -> Option<GetExOption> {
+    let Some(option) = args.first().map(|a| a.to_ascii_uppercase()) else {
+        return Some(GetExOption::None);
+    };
+    match (&option[..], args.len()) {
+        (b"EX", 2) => Some(GetExOption::Ex(integer(&args[1])?)),
+        (b"PX", 2) => Some(GetExOption::Px(integer(&args[1])?)),
+        (b"EXAT", 2) => Some(GetExOption::ExAt(integer(&args[1])?)),
+        (b"PERSIST", 1) => Some(GetExOption::Persist),
+        _ => None,
+    }
+}
+
+fn integer<T: FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+fn parse_frame(src: &mut Cursor<&[u8]>) -> Result<Frame, FrameError> {
+    if !src.has_remaining() {
+        return Err(FrameError::Incomplete);
+    }
+
+    match src.get_u8() {
+        b'+' => Ok(Frame::Simple(line_string(src)?)),
+        b'-' => Ok(Frame::Error(line_string(src)?)),
+        b':' => Ok(Frame::Integer(line_int(src)?)),
+        b'#' => match line(src)? {
+            b"t" => Ok(Frame::Boolean(true)),
+            b"f" => Ok(Frame::Boolean(false)),
+            _ => Err(FrameError::Invalid),
+        },
+        b'_' => match line(src)? {
+            b"" => Ok(Frame::Null),
+            _ => Err(FrameError::Invalid),
+        },
+        b'$' => {
+            // RESP2 clients send a null bulk string as a length of -1
+            let len = match line_int(src)? {
+                -1 => return Ok(Frame::Null),
+                len => usize::try_from(len).map_err(|_| FrameError::Invalid)?,
+            };
+            if src.remaining() < len + 2 {
+                return Err(FrameError::Incomplete);
+            }
+
+            let start = src.position() as usize;
+            let data = Bytes::copy_from_slice(&src.get_ref()[start..start + len]);
+            src.advance(len);
+            if src.get_u16() != u16::from_be_bytes(*b"\r\n") {
+                return Err(FrameError::Invalid);
+            }
+
+            Ok(Frame::Bulk(data))
+        }
+        b'*' => {
+            let len = match line_int(src)? {
+                -1 => return Ok(Frame::Null),
+                len => usize::try_from(len).map_err(|_| FrameError::Invalid)?,
+            };
+
+            // Not preallocated, the length comes from the client
+            let mut frames = Vec::new();
+            for _ in 0..len {
+                frames.push(parse_frame(src)?);
+            }
+
+            Ok(Frame::Array(frames))
+        }
+        b'>' => {
+            let len = usize::try_from(line_int(src)?).map_err(|_| FrameError::Invalid)?;
+
+            let mut frames = Vec::new();
+            for _ in 0..len {
+                frames.push(parse_frame(src)?);
+            }
+
+            Ok(Frame::Push(frames))
+        }
+        b'%' => Ok(Frame::Map(parse_pairs(src)?)),
+        b'|' => {
+            let attributes = parse_pairs(src)?;
+            let frame = parse_frame(src)?;
+
+            Ok(Frame::Attribute(attributes, Box::new(frame)))
+        }
+        _ => Err(FrameError::Invalid),
+    }
+}
+
+/// Reads the length line of a map or attribute and that many key value pairs.
+fn parse_pairs(src: &mut Cursor<&[u8]>) -> Result<Vec<(Frame, Frame)>, FrameError> {
+    let len = usize::try_from(line_int(src)?).map_err(|_| FrameError::Invalid)?;
+
+    let mut pairs = Vec::new();
+    for _ in 0..len {
+        let k = parse_frame(src)?;
+        let v = parse_frame(src)?;
+        pairs.push((k, v));
+    }
+
+    Ok(pairs)
+}
+
+/// Reads up to the next "\r\n", leaving the cursor after it.
+fn line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], FrameError> {
+    let buf: &'a [u8] = src.get_ref();
+    let start = src.position() as usize;
+
+    let end = buf[start..]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or(FrameError::Incomplete)?;
+    src.set_position((start + end + 2) as u64);
+
+    Ok(&buf[start..start + end])
+}
+
+fn line_string(src: &mut Cursor<&[u8]>) -> Result<String, FrameError> {
+    let line = line(src)?;
+    String::from_utf8(line.to_vec()).map_err(|_| FrameError::Invalid)
+}
+
+fn line_int(src: &mut Cursor<&[u8]>) -> Result<i64, FrameError> {
+    line_string(src)?.parse().map_err(|_| FrameError::Invalid)
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use crate::serverv2::protocol::resp3::{Frame, FrameError};
+
+    #[test]
+    fn test_frame_round_trip() {
+        let frame = Frame::Map(vec![
+            (Frame::Simple("proto".to_string()), Frame::Integer(3)),
+            (
+                Frame::Bulk(Bytes::from("values")),
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from("a\r\nb")),
+                    Frame::Boolean(true),
+                    Frame::Null,
+                    Frame::Error("ERR oops".to_string()),
+                ]),
+            ),
+            (
+                Frame::Simple("keys".to_string()),
+                Frame::Attribute(
+                    vec![(Frame::Simple("truncated".to_string()), Frame::Boolean(true))],
+                    Box::new(Frame::Array(vec![Frame::Bulk(Bytes::from("a"))])),
+                ),
+            ),
+            (
+                Frame::Simple("push".to_string()),
+                Frame::Push(vec![Frame::Bulk(Bytes::from("message")), Frame::Integer(1)]),
+            ),
+        ]);
+
+        let bytes: Bytes = frame.clone().into();
+        let got = Frame::parse(&bytes);
+        assert!(
+            got == Ok((frame.clone(), bytes.len())),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            frame,
+            got
+        );
+
+        for len in 0..bytes.len() {
+            let got = Frame::parse(&bytes[..len]);
+            assert!(
+                got == Err(FrameError::Incomplete),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                FrameError::Incomplete,
+                got
+            );
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use bytes::Bytes;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc,
+    },
+    task::JoinHandle,
+};
+
+// Messages a slow subscriber can fall behind by before it starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Every channel with subscribers. A channel is created by its first subscriber and dropped by
+/// the first publish that finds nobody left listening.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: RwLock<HashMap<Bytes, broadcast::Sender<Bytes>>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self, channel: &Bytes) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.write().unwrap();
+        match channels.get(channel) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
+                channels.insert(channel.clone(), tx);
+                rx
+            }
+        }
+    }
+
+    /// Sends `payload` to everyone subscribed to `channel`, returning how many that was.
+    pub fn publish(&self, channel: &[u8], payload: Bytes) -> usize {
+        let sent = match self.channels.read().unwrap().get(channel) {
+            Some(tx) => tx.send(payload).ok(),
+            None => return 0,
+        };
+
+        match sent {
+            Some(n) => n,
+            None => {
+                let mut channels = self.channels.write().unwrap();
+                // Someone may have subscribed since
+                if channels
+                    .get(channel)
+                    .is_some_and(|tx| tx.receiver_count() == 0)
+                {
+                    channels.remove(channel);
+                }
+                0
+            }
+        }
+    }
+}
+
+/// The channels one connection is subscribed to. Each has a task forwarding its messages into
+/// a single queue, so the connection only has to wait on `recv`.
+#[derive(Debug)]
+pub struct Subscriptions {
+    tasks: HashMap<Bytes, JoinHandle<()>>,
+    tx: mpsc::Sender<(Bytes, Bytes)>,
+    rx: mpsc::Receiver<(Bytes, Bytes)>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+        Self {
+            tasks: HashMap::new(),
+            tx,
+            rx,
+        }
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for task in self.tasks.values() {
+            task.abort();
+        }
+    }
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of channels subscribed to afterwards. Subscribing twice is a no-op.
+    pub fn subscribe(&mut self, pubsub: &PubSub, channel: Bytes) -> usize {
+        if !self.tasks.contains_key(&channel) {
+            let mut rx = pubsub.subscribe(&channel);
+            let tx = self.tx.clone();
+            let name = channel.clone();
+
+            let task = tokio::spawn(async move {
+                loop {
+                    let payload = match rx.recv().await {
+                        Ok(payload) => payload,
+                        Err(RecvError::Lagged(n)) => {
+                            eprintln!("subscriber to {:?} missed {} messages", name, n);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if tx.send((name.clone(), payload)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            self.tasks.insert(channel, task);
+        }
+
+        self.tasks.len()
+    }
+
+    /// Returns the number of channels still subscribed to.
+    pub fn unsubscribe(&mut self, channel: &[u8]) -> usize {
+        if let Some(task) = self.tasks.remove(channel) {
+            task.abort();
+        }
+
+        self.tasks.len()
+    }
+
+    pub fn channels(&self) -> Vec<Bytes> {
+        self.tasks.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Waits for the next `(channel, payload)` on any subscribed channel. Cancel safe.
+    pub async fn recv(&mut self) -> Option<(Bytes, Bytes)> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::serverv2::pubsub::{PubSub, Subscriptions};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_publish() {
+        let pubsub = PubSub::new();
+        assert!(pubsub.publish(b"news", "nobody".into()) == 0);
+
+        let mut a = Subscriptions::new();
+        let mut b = Subscriptions::new();
+        assert!(a.subscribe(&pubsub, "news".into()) == 1);
+        assert!(a.subscribe(&pubsub, "sports".into()) == 2);
+        assert!(a.subscribe(&pubsub, "news".into()) == 2);
+        assert!(b.subscribe(&pubsub, "news".into()) == 1);
+
+        let n = pubsub.publish(b"news", "hello".into());
+        assert!(n == 2, "\nExpected: {}\nGot: {}\n", 2, n);
+
+        let expected = Some((Bytes::from("news"), Bytes::from("hello")));
+        for s in [&mut a, &mut b] {
+            let got = tokio::time::timeout(Duration::from_secs(1), s.recv())
+                .await
+                .expect("should receive the message");
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        assert!(a.unsubscribe(b"news") == 1);
+        // The forwarding task is aborted, its receiver goes away once it has stopped
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(pubsub.publish(b"news", "again".into()) == 1);
+
+        drop(b);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(pubsub.publish(b"news", "gone".into()) == 0);
+        assert!(pubsub.publish(b"sports", "still here".into()) == 1);
+    }
+}
@@ -0,0 +1,386 @@
+//! A first pass at read replicas. A server made a replica with `replicaof host port` connects to
+//! its master and sends `replstream`. The master answers with every live entry of database 0,
+//! which the replica writes after flushing its own database 0 and then rebuilds its key dir from
+//! the data file the way `debug reload` does. After that the master streams every entry as it is
+//! logged and the replica writes each one and updates its key dir. Whenever the connection drops,
+//! or the replica falls too far behind for the master to keep its writes around, the replica
+//! connects again and syncs from scratch.
+//!
+//! Its limitations:
+//!
+//! - Entries don't record their database, so writes to any database on the master end up in
+//!   database 0 on the replica, and only database 0 is synced.
+//! - Lists aren't replicated. Their nodes point at where the next one is in the master's data
+//!   file, which means nothing on the replica.
+//! - Replicas don't acknowledge anything, so `wait` doesn't count them.
+//! - The replica can't authenticate, the master can't require it.
+//! - The initial sync holds every live entry in memory on the master, and reads on the replica
+//!   see a partial database until it is done.
+//! - Replicas only refuse writes from clients, expired keys are still swept on their own.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt::Write as _,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering::*},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{watch, Notify, RwLock},
+    time::Instant,
+};
+
+use crate::{
+    serverv2::message::Message,
+    storagev2::{
+        key_dir::KeyDir,
+        log::{Entry, EntryType},
+        page::decode_entry,
+        page_manager::PageCache,
+    },
+};
+
+// How long a replica waits before connecting to its master again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether the server takes writes itself or follows another one.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplicationRole {
+    #[default]
+    Master,
+    Replica {
+        master_addr: SocketAddr,
+    },
+}
+
+/// Where replication stands: how far the write stream has got and how far each replica has
+/// acknowledged it. Replicas following with `replstream` don't acknowledge anything yet, so the
+/// offset only moves with `advance` and replicas only exist once something registers them.
+///
+/// The offset only means something together with the replication id, a new history starts
+/// whenever the id changes.
+#[derive(Debug)]
+pub struct ReplicationState {
+    // 40 hex digits, see `change_repl_id`
+    repl_id: Mutex<String>,
+    // Bytes of the write stream sent to replicas so far
+    offset: Arc<AtomicU64>,
+    // The offset each replica acknowledged last, by id
+    replicas: Mutex<HashMap<u64, u64>>,
+    next_id: AtomicU64,
+    acked: Notify,
+}
+
+impl Default for ReplicationState {
+    fn default() -> Self {
+        Self {
+            repl_id: Mutex::new(new_repl_id()),
+            offset: Arc::default(),
+            replicas: Mutex::default(),
+            next_id: AtomicU64::default(),
+            acked: Notify::new(),
+        }
+    }
+}
+
+impl ReplicationState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn repl_id(&self) -> String {
+        self.repl_id.lock().unwrap().clone()
+    }
+
+    /// Starts a new history under a new id and returns it. The offset goes back to 0 and so does
+    /// what every replica acknowledged, none of it is comparable with the new offset.
+    pub fn change_repl_id(&self) -> String {
+        let mut repl_id = self.repl_id.lock().unwrap();
+        *repl_id = new_repl_id();
+        self.offset.store(0, SeqCst);
+        for acked in self.replicas.lock().unwrap().values_mut() {
+            *acked = 0;
+        }
+
+        repl_id.clone()
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset.load(SeqCst)
+    }
+
+    /// The offset counter itself, for whatever writes the stream to bump as it goes.
+    pub fn offset_handle(&self) -> Arc<AtomicU64> {
+        self.offset.clone()
+    }
+
+    /// Moves the offset `n` bytes further and returns the new one.
+    pub fn advance(&self, n: u64) -> u64 {
+        self.offset.fetch_add(n, SeqCst) + n
+    }
+
+    /// Registers a replica that hasn't acknowledged anything yet and returns its id.
+    pub fn add_replica(&self) -> u64 {
+        let id = self.next_id.fetch_add(1, SeqCst);
+        self.replicas.lock().unwrap().insert(id, 0);
+
+        id
+    }
+
+    pub fn remove_replica(&self, id: u64) {
+        self.replicas.lock().unwrap().remove(&id);
+        self.acked.notify_waiters();
+    }
+
+    /// Records that replica `id` has everything up to `offset`. Acknowledgements never move a
+    /// replica back.
+    pub fn ack(&self, id: u64, offset: u64) {
+        if let Some(acked) = self.replicas.lock().unwrap().get_mut(&id) {
+            *acked = (*acked).max(offset);
+        }
+        self.acked.notify_waiters();
+    }
+
+    pub fn replicas(&self) -> usize {
+        self.replicas.lock().unwrap().len()
+    }
+
+    /// How many replicas have acknowledged at least `offset`.
+    pub fn caught_up(&self, offset: u64) -> usize {
+        let replicas = self.replicas.lock().unwrap();
+        replicas.values().filter(|acked| **acked >= offset).count()
+    }
+
+    /// Waits until `replicas` replicas have acknowledged the offset as it is now, or `timeout`
+    /// passes, and returns how many have. A zero timeout waits for as long as it takes. Returns
+    /// early once every replica has, including when there are none.
+    pub async fn wait(&self, replicas: usize, timeout: Duration) -> usize {
+        let target = self.offset();
+        let deadline = (!timeout.is_zero()).then(|| Instant::now() + timeout);
+
+        loop {
+            // Registered before counting so an ack in between isn't missed
+            let acked = self.acked.notified();
+            tokio::pin!(acked);
+            acked.as_mut().enable();
+
+            let n = self.caught_up(target);
+            if n >= replicas || n == self.replicas() {
+                return n;
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, acked).await.is_err() {
+                        return self.caught_up(target);
+                    }
+                }
+                None => acked.await,
+            }
+        }
+    }
+}
+
+// Every `RandomState` is keyed differently, which is random enough to tell histories apart
+fn new_repl_id() -> String {
+    let mut id = String::with_capacity(48);
+    for i in 0..3u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(i);
+        let _ = write!(id, "{:016x}", hasher.finish());
+    }
+    id.truncate(40);
+
+    id
+}
+
+/// Follows the master at `master_addr` into `kd` until `stop` is set or its sender is dropped,
+/// connecting again whenever the connection fails.
+pub async fn replicate(
+    master_addr: SocketAddr,
+    m: PageCache,
+    kd: Arc<RwLock<KeyDir>>,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        match follow(master_addr, &m, &kd, &mut stop).await {
+            Ok(()) => return,
+            Err(e) => eprintln!("replication error: {}: {}", master_addr, e),
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = stop.wait_for(|stop| *stop) => return,
+        }
+    }
+}
+
+/// Syncs from the master and then applies its writes as they come, only returning `Ok` once told
+/// to stop.
+async fn follow(
+    master_addr: SocketAddr,
+    m: &PageCache,
+    kd: &Arc<RwLock<KeyDir>>,
+    stop: &mut watch::Receiver<bool>,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(master_addr).await?;
+    let (r, mut w) = stream.into_split();
+    let mut r = BufReader::new(r);
+    w.write_all(b"replstream\n").await?;
+
+    let Some(n) = or_stop(stop, read_count(&mut r)).await else {
+        return Ok(());
+    };
+    let n = n?;
+    if let Message::Error(e) = Message::FlushDb(false).exec(m, kd).await {
+        return Err(io::Error::other(e));
+    }
+    for _ in 0..n {
+        let Some(record) = or_stop(stop, read_record(&mut r)).await else {
+            return Ok(());
+        };
+        let (_, entry) = record?;
+        if entry.t != EntryType::ListNode {
+            m.write_entry_auto(&entry).await?;
+        }
+    }
+    let keys = m.reload(kd).await?;
+    eprintln!("replication: synced {} keys from {}", keys, master_addr);
+
+    let mut last = None;
+    loop {
+        let Some(record) = or_stop(stop, read_record(&mut r)).await else {
+            return Ok(());
+        };
+        let (seq, entry) = record?;
+        if last.is_some_and(|last| seq != last + 1) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected write {} but got {}", last.unwrap_or(0) + 1, seq),
+            ));
+        }
+        last = Some(seq);
+
+        apply(m, kd, &entry).await?;
+    }
+}
+
+/// Writes an entry from the master and points the key dir at it.
+async fn apply(m: &PageCache, kd: &RwLock<KeyDir>, entry: &Entry) -> io::Result<()> {
+    if entry.t == EntryType::ListNode {
+        return Ok(());
+    }
+
+    let (page_id, offset) = m.write_entry_auto(entry).await?;
+    let mut kd = kd.write().await;
+    match entry.t {
+        EntryType::Delete => kd.remove(&entry.key),
+        _ => kd.insert(&entry.key, m.key_data(page_id, offset as u64)),
+    };
+
+    Ok(())
+}
+
+// `None` once told to stop, whatever was half read by then doesn't matter
+async fn or_stop<T>(
+    stop: &mut watch::Receiver<bool>,
+    read: impl std::future::Future<Output = io::Result<T>>,
+) -> Option<io::Result<T>> {
+    tokio::select! {
+        res = read => Some(res),
+        _ = stop.wait_for(|stop| *stop) => None,
+    }
+}
+
+async fn read_line(r: &mut (impl AsyncBufRead + Unpin)) -> io::Result<String> {
+    let mut line = String::new();
+    if r.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.truncate(line.trim_end_matches('\n').len());
+
+    Ok(line)
+}
+
+// The first line of the answer to `replstream`, or an error the master answered with instead
+async fn read_count(r: &mut (impl AsyncBufRead + Unpin)) -> io::Result<usize> {
+    let line = read_line(r).await?;
+    line.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("master answered replstream with {:?}", line),
+        )
+    })
+}
+
+async fn read_record(r: &mut (impl AsyncBufRead + Unpin)) -> io::Result<(u64, Entry)> {
+    let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+
+    let line = read_line(r).await?;
+    let (seq, len) = line
+        .split_once(' ')
+        .and_then(|(seq, len)| Some((seq.parse().ok()?, len.parse().ok()?)))
+        .ok_or_else(|| invalid(format!("not a replicated entry: {:?}", line)))?;
+    let mut bytes = vec![0; len];
+    r.read_exact(&mut bytes).await?;
+    let entry = decode_entry(&bytes).map_err(|e| invalid(format!("bad entry: {:?}", e)))?;
+
+    Ok((seq, entry))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::Instant;
+
+    use crate::serverv2::replication::ReplicationState;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait() {
+        let state = Arc::new(ReplicationState::new());
+
+        // Nobody to wait for
+        let start = Instant::now();
+        assert!(state.wait(1, Duration::from_secs(10)).await == 0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let a = state.add_replica();
+        let b = state.add_replica();
+        let offset = state.advance(100);
+        assert!(offset == 100);
+        assert!(state.caught_up(offset) == 0);
+
+        let acker = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            acker.ack(a, 100);
+        });
+        let got = state.wait(1, Duration::from_secs(10)).await;
+        assert!(got == 1, "\nExpected: {}\nGot: {}\n", 1, got);
+
+        // Only one of them ever catches up
+        let start = Instant::now();
+        let got = state.wait(2, Duration::from_millis(50)).await;
+        assert!(got == 1, "\nExpected: {}\nGot: {}\n", 1, got);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Going away counts as no longer lagging behind
+        state.ack(b, 50);
+        state.remove_replica(b);
+        assert!(state.wait(2, Duration::ZERO).await == 1);
+
+        // A new history starts from nothing
+        let repl_id = state.repl_id();
+        assert!(state.change_repl_id() != repl_id);
+        assert!(state.offset() == 0 && state.caught_up(1) == 0);
+    }
+}
@@ -1,55 +1,406 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicU64, AtomicUsize,
+            Ordering::{Acquire, Relaxed},
+        },
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    serverv2::{connection::Connection, message::Message},
+    serverv2::{
+        auth::{Authenticator, Permission},
+        client::Clients,
+        connection::Connection,
+        message::{Message, DEFAULT_KEYS_LIMIT},
+        metrics::Metrics,
+        pubsub::PubSub,
+        replication::{self, ReplicationRole, ReplicationState},
+        slowlog::{SlowLog, DEFAULT_SLOWLOG_MAX_LEN, DEFAULT_SLOWLOG_THRESHOLD_US},
+        sweeper::{BackgroundSweeper, DEFAULT_SWEEP_INTERVAL},
+        tls::TlsConfig,
+    },
     storagev2::{
         disk::Disk,
         key_dir::{self, KeyDir},
-        page_manager::PageCache,
+        log::EntryLimits,
+        page_manager::{PageCache, PageManagerBuilder},
+        snapshot::Snapshot,
+        wal::WriteAheadLog,
     },
 };
+use bytes::Bytes;
 use tokio::{
-    io::{BufReader, BufWriter},
-    net::{TcpListener, TcpStream},
+    io::{self as aio, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::{self as tnet, TcpListener, TcpStream},
     signal,
-    sync::RwLock,
+    sync::{broadcast::error::RecvError, watch, OwnedSemaphorePermit, RwLock, Semaphore},
 };
+use tokio_rustls::TlsAcceptor;
 
 const DB_FILE: &str = "main.db";
+const WAL_FILE: &str = "main.wal";
+// Responses buffered per connection before they are written back
+const PIPELINE_DEPTH: usize = 16;
+pub const DEFAULT_MAX_DATABASES: usize = 16;
+pub const DEFAULT_MAX_KEY_SIZE: usize = 512;
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 512 * 1024 * 1024;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 10_000;
+// How long a turned away client gets to notice before the socket is closed regardless
+const REJECT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Settings for `run` besides TLS and authentication.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    sweep_interval: Duration,
+    keys_limit: usize,
+    max_databases: usize,
+    max_key_size: usize,
+    max_value_size: usize,
+    max_connections: usize,
+    debug_commands_enabled: bool,
+    slowlog_threshold_us: u64,
+    slowlog_max_len: usize,
+    idle_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            keys_limit: DEFAULT_KEYS_LIMIT,
+            max_databases: DEFAULT_MAX_DATABASES,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            debug_commands_enabled: false,
+            slowlog_threshold_us: DEFAULT_SLOWLOG_THRESHOLD_US,
+            slowlog_max_len: DEFAULT_SLOWLOG_MAX_LEN,
+            idle_timeout: Duration::ZERO,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How often expired keys are dropped in the background.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Most keys a `keys` command answers with.
+    pub fn keys_limit(mut self, limit: usize) -> Self {
+        self.keys_limit = limit;
+        self
+    }
+
+    /// How many databases `select` can switch between, at least 1.
+    pub fn max_databases(mut self, max: usize) -> Self {
+        self.max_databases = max.max(1);
+        self
+    }
+
+    /// Longest key a request may carry, longer ones are refused without touching storage.
+    pub fn max_key_size(mut self, max: usize) -> Self {
+        self.max_key_size = max;
+        self
+    }
+
+    /// Longest value a request may carry, longer ones are refused without touching storage.
+    pub fn max_value_size(mut self, max: usize) -> Self {
+        self.max_value_size = max;
+        self
+    }
+
+    /// Most connections served at once, any more are turned away with `SERVER_BUSY`.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Whether `debug sleep` is served, off by default.
+    pub fn debug_commands_enabled(mut self, enabled: bool) -> Self {
+        self.debug_commands_enabled = enabled;
+        self
+    }
+
+    /// Commands taking longer than this many microseconds go in the slow log.
+    pub fn slowlog_threshold_us(mut self, us: u64) -> Self {
+        self.slowlog_threshold_us = us;
+        self
+    }
+
+    /// How many entries the slow log keeps, 0 turning it off.
+    pub fn slowlog_max_len(mut self, len: usize) -> Self {
+        self.slowlog_max_len = len;
+        self
+    }
+
+    /// Connections that send no request for this long are closed, zero keeping them open for as
+    /// long as the client likes. The default. Subscribed connections are never closed for it.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+}
+
+/// Caps how many connections are served at once. Each one holds a permit until its task ends.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    permits: Arc<Semaphore>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// A permit for one more connection, `None` if there are already as many as allowed.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+}
+
+/// Key dirs of the databases `select` switches between. They share one page cache, so only the
+/// key dirs tell them apart. Entries don't record which database they were written to, only
+/// database 0 is checkpointed and after a restart every key is found in database 0.
+type Databases = Arc<Vec<Arc<RwLock<KeyDir>>>>;
+
+/// Live keys in each of the `Databases`, read by `dbsize` and `info` without locking the key
+/// dirs. See `KeyDir::len_handle`.
+type KeyCounts = Vec<Arc<AtomicUsize>>;
+
+async fn key_counts(databases: &Databases) -> KeyCounts {
+    let mut counts = Vec::with_capacity(databases.len());
+    for kd in databases.iter() {
+        counts.push(kd.read().await.len_handle());
+    }
+
+    counts
+}
+
+/// What every connection shares besides the storage.
+struct Shared {
+    auth: Option<Authenticator>,
+    keys_limit: usize,
+    pubsub: PubSub,
+    replication: ReplicationState,
+    limits: EntryLimits,
+    debug_commands: bool,
+    // Zero for none
+    idle_timeout: Duration,
+    // For `info`
+    addr: SocketAddr,
+    started: Instant,
+    commands: AtomicU64,
+    metrics: Metrics,
+    slowlog: SlowLog,
+    clients: Clients,
+    role: Mutex<ReplicationRole>,
+    // Dropped to stop following the master
+    replica_stop: Mutex<Option<watch::Sender<bool>>>,
+}
+
+impl Shared {
+    /// Records how long a command took to execute, in its latencies and the slow log.
+    fn record(&self, command: &'static str, elapsed: Duration) {
+        self.metrics.record(command, elapsed);
+        self.slowlog.record(command, elapsed);
+    }
+
+    fn role(&self) -> ReplicationRole {
+        *self.role.lock().unwrap()
+    }
+
+    /// Starts following `master_addr` into `kd`, instead of whichever master it followed before.
+    fn replicate_from(&self, master_addr: SocketAddr, m: PageCache, kd: Arc<RwLock<KeyDir>>) {
+        let (stop, stop_rx) = watch::channel(false);
+        *self.replica_stop.lock().unwrap() = Some(stop);
+        *self.role.lock().unwrap() = ReplicationRole::Replica { master_addr };
+        tokio::spawn(replication::replicate(master_addr, m, kd, stop_rx));
+    }
+
+    fn stop_replicating(&self) {
+        self.replica_stop.lock().unwrap().take();
+        *self.role.lock().unwrap() = ReplicationRole::Master;
+    }
+}
+
+/// Serves on port 4444, over TLS when `tls` is given and in cleartext otherwise. With `auth`,
+/// connections have to authenticate before running any command.
+pub async fn run(tls: Option<TlsConfig>, auth: Option<Authenticator>, config: ServerConfig) {
+    let started = Instant::now();
+    let acceptor = tls.map(|tls| tls.acceptor().expect("Failed to load tls config"));
 
-pub async fn run() {
     let disk = Disk::new(DB_FILE).await.expect("Failed to open db file");
+    let wal = WriteAheadLog::new(WAL_FILE)
+        .await
+        .expect("Failed to open wal file");
+
+    // Replay before bootstrapping so recovered entries make it into the key dir
+    wal.replay(&disk).expect("Failed to replay wal");
     let (kd, latest, latest_id) = key_dir::bootstrap(&disk).await;
     let kd = Arc::new(RwLock::new(kd));
+    let databases: Databases = Arc::new(
+        std::iter::once(kd.clone())
+            .chain((1..config.max_databases).map(|_| Arc::default()))
+            .collect(),
+    );
 
-    let m = PageCache::new(disk, 2, latest, latest_id);
+    let m = PageManagerBuilder::new(disk, wal, latest, latest_id)
+        .build()
+        .expect("Invalid page manager config");
 
     let listener = TcpListener::bind("0.0.0.0:4444")
         .await
         .expect("Could not bind");
+    let shared = Arc::new(Shared {
+        auth,
+        keys_limit: config.keys_limit,
+        pubsub: PubSub::new(),
+        replication: ReplicationState::new(),
+        limits: EntryLimits {
+            max_key_len: config.max_key_size as u64,
+            max_value_len: config.max_value_size as u64,
+        },
+        debug_commands: config.debug_commands_enabled,
+        idle_timeout: config.idle_timeout,
+        addr: listener
+            .local_addr()
+            .expect("Bound listener has an address"),
+        started,
+        commands: AtomicU64::new(0),
+        metrics: Metrics::new(),
+        slowlog: SlowLog::new(config.slowlog_threshold_us, config.slowlog_max_len),
+        clients: Clients::new(),
+        role: Mutex::default(),
+        replica_stop: Mutex::default(),
+    });
+
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    let sweepers: Vec<_> = databases
+        .iter()
+        .map(|kd| {
+            BackgroundSweeper::new(config.sweep_interval).spawn(
+                m.clone(),
+                kd.clone(),
+                shutdown_rx.clone(),
+            )
+        })
+        .collect();
 
     let mut _m = m.clone();
+    let _kd = kd.clone();
     tokio::spawn(async move {
         if let Err(e) = signal::ctrl_c().await {
             eprintln!("signal error: {}", e);
         }
 
-        _m.flush_current().await;
+        // Let a sweep in progress finish before checkpointing
+        let _ = shutdown.send(true);
+        for sweeper in sweepers {
+            if let Err(e) = sweeper.await {
+                eprintln!("sweeper error: {}", e);
+            }
+        }
+
+        // Flushes the current page and every dirty read page before truncating the wal, then
+        // writes the hint file
+        if let Err(e) = _m.checkpoint(&_kd).await {
+            eprintln!("checkpoint error: {}", e);
+        }
         std::process::exit(0);
     });
 
+    let limiter = ConnectionLimiter::new(config.max_connections);
+    listen(listener, acceptor, shared, m, databases, limiter).await
+}
+
+/// Accepts connections for as long as the listener works, turning away those over the limit.
+async fn listen(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    shared: Arc<Shared>,
+    m: PageCache,
+    databases: Databases,
+    limiter: ConnectionLimiter,
+) -> ! {
     loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                tokio::spawn(accept(stream, addr, m.clone(), kd.clone()));
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                continue;
             }
-            Err(e) => eprintln!("error: {}", e),
-        }
+        };
+
+        let Some(permit) = limiter.try_acquire() else {
+            tokio::spawn(reject(stream, acceptor.is_some()));
+            continue;
+        };
+        let conn = accept(
+            stream,
+            addr,
+            acceptor.clone(),
+            shared.clone(),
+            m.clone(),
+            databases.clone(),
+        );
+        tokio::spawn(async move {
+            conn.await;
+            drop(permit);
+        });
     }
 }
 
-async fn accept(stream: TcpStream, addr: SocketAddr, pc: PageCache, kd: Arc<RwLock<KeyDir>>) {
-    if let Err(e) = accept_loop(stream, addr, pc, kd).await {
+/// Closes a connection the server has no room for. Cleartext clients are told why first, before
+/// a TLS handshake there is no way to. Whatever the client already sent is read and dropped,
+/// closing with it unread would reset the connection and could lose the error.
+async fn reject(mut stream: TcpStream, tls: bool) {
+    if !tls {
+        let busy = Message::Error("SERVER_BUSY too many connections".to_string());
+        let _ = stream.write_all(&Bytes::from(busy)).await;
+    }
+    let _ = stream.shutdown().await;
+
+    let mut buf = [0; 1024];
+    let drain = async { while let Ok(1..) = stream.read(&mut buf).await {} };
+    let _ = tokio::time::timeout(REJECT_DRAIN_TIMEOUT, drain).await;
+}
+
+async fn accept<S>(
+    stream: S,
+    addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    shared: Arc<Shared>,
+    pc: PageCache,
+    databases: Databases,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let res = match acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => accept_loop(stream, addr, &shared, pc, databases).await,
+            Err(e) => Err(e),
+        },
+        None => accept_loop(stream, addr, &shared, pc, databases).await,
+    };
+
+    if let Err(e) = res {
         match e.kind() {
             io::ErrorKind::ConnectionReset => {}
             e => eprintln!("error: {}", e),
@@ -57,27 +408,1120 @@ async fn accept(stream: TcpStream, addr: SocketAddr, pc: PageCache, kd: Arc<RwLo
     }
 }
 
-async fn accept_loop(
-    stream: TcpStream,
-    _addr: SocketAddr,
+async fn accept_loop<S>(
+    stream: S,
+    addr: SocketAddr,
+    shared: &Shared,
     pc: PageCache,
-    kd: Arc<RwLock<KeyDir>>,
-) -> io::Result<()> {
-    let (reader, writer) = stream.into_split();
+    databases: Databases,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, writer) = aio::split(stream);
     let reader = BufReader::new(reader);
     let writer = BufWriter::new(writer);
 
-    let mut conn = Connection::new(reader, writer);
+    // Responses are written out every PIPELINE_DEPTH messages, or sooner if reading the next
+    // request would block
+    let mut conn = Connection::new_pipelined(reader, writer, PIPELINE_DEPTH);
+    conn.set_limits(shared.limits);
+    let client = shared.clients.register(addr);
+    let key_counts = key_counts(&databases).await;
 
     loop {
-        let message = match conn.read().await? {
-            Some(m) if m == Message::None => continue,
+        let idle_timeout = shared.idle_timeout;
+        let read = async {
+            // Subscribers are expected to sit and wait for messages
+            match idle_timeout {
+                t if t.is_zero() || conn.is_subscribed() => conn.read().await,
+                t => tokio::time::timeout(t, conn.read())
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            }
+        };
+        let read = tokio::select! {
+            read = read => read,
+            () = client.killed() => {
+                eprintln!("CLIENT_KILLED {}: id {}", addr, client.id());
+                return conn.flush_pipeline().await;
+            }
+        };
+        let message = match read {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                eprintln!("CLIENT_TIMEOUT {}: idle for {:?}", addr, idle_timeout);
+                return Ok(());
+            }
+            read => read?,
+        };
+        let message = match message {
+            Some(Message::None) => continue,
             Some(m) => m,
             None => continue,
         };
 
-        let res = message.exec(&pc, &kd).await;
+        if let Some(identity) = conn.identity() {
+            eprintln!("{}: {}", identity, message.command());
+        }
+
+        let auth = &shared.auth;
+        if let Some(auth) = auth {
+            if let Err(e) = auth.check(conn.identity(), &message) {
+                conn.write(e).await?;
+                continue;
+            }
+        }
+        shared.commands.fetch_add(1, Relaxed);
+        client.update(conn.db(), message.command());
+
+        // Writes to a replica would never make it to the master
+        if shared.role() != ReplicationRole::Master && writes(&message) {
+            let e = "READONLY You can't write against a read only replica";
+            conn.write(Message::Error(e.to_string())).await?;
+            continue;
+        }
+
+        // Parsing fills in the default limit, which also caps a scan's count
+        let message = match message {
+            Message::Keys(pattern, _) => Message::Keys(pattern, shared.keys_limit),
+            Message::KeyScan(cursor, count) => {
+                Message::KeyScan(cursor, count.min(shared.keys_limit))
+            }
+            m => m,
+        };
+
+        // Subscribing and publishing take effect right away, even inside a transaction, and so
+        // do resetting, switching databases, snapshots, info and the slow log. Queued commands
+        // run against whichever database is selected at EXEC.
+        let kd = &databases[conn.db()];
+        let responses = match message {
+            Message::Subscribe(channels) => conn.subscribe(&shared.pubsub, channels),
+            Message::Unsubscribe(channels) => conn.unsubscribe(channels),
+            Message::Reset => {
+                conn.reset().await?;
+                vec![Message::ResetDone]
+            }
+            Message::Ping(payload) if conn.is_subscribed() => vec![Message::Pong(payload)],
+            m if conn.is_subscribed() => vec![Message::Error(format!(
+                "ERR '{}' isn't allowed while subscribed, only (UN)SUBSCRIBE, PING and RESET are",
+                m.command()
+            ))],
+            Message::Publish(channel, payload) => {
+                vec![Message::Count(shared.pubsub.publish(&channel, payload))]
+            }
+            Message::Auth(token) => match auth.as_ref().and_then(|a| a.authenticate(&token)) {
+                Some(identity) => {
+                    conn.set_identity(identity.to_string());
+                    vec![Message::Success]
+                }
+                None if auth.is_none() => vec![Message::Error(
+                    "ERR AUTH called without any tokens configured".to_string(),
+                )],
+                None => vec![Message::Error("WRONGPASS invalid token".to_string())],
+            },
+            Message::Select(db) if db < databases.len() => {
+                conn.select(db);
+                vec![Message::Success]
+            }
+            Message::Select(_) => vec![Message::Error("ERR DB index is out of range".to_string())],
+            Message::SnapshotCreate => {
+                let snapshot = Snapshot::take(&pc, kd).await;
+                vec![Message::Integer(conn.add_snapshot(snapshot) as i64)]
+            }
+            Message::SnapshotGet(handle, k) => match conn.snapshot(handle) {
+                Some(snapshot) => match snapshot.get(&pc, &k).await {
+                    Ok(Some(entry)) => vec![Message::Result(k, entry.value.into())],
+                    Ok(None) => vec![Message::None],
+                    Err(e) => vec![Message::Error(format!("ERR {}", e))],
+                },
+                None => vec![Message::Error("ERR no such snapshot".to_string())],
+            },
+            Message::SnapshotRelease(handle) => {
+                vec![Message::Integer(conn.release_snapshot(handle) as i64)]
+            }
+            Message::Wait(replicas, timeout) => {
+                let timeout = Duration::from_millis(timeout);
+                let n = shared.replication.wait(replicas, timeout).await;
+                vec![Message::Integer(n as i64)]
+            }
+            Message::ReplicaOf(Some((host, port))) => {
+                match tnet::lookup_host((host.as_str(), port)).await {
+                    Ok(mut addrs) => match addrs.next() {
+                        Some(master_addr) => {
+                            shared.replicate_from(master_addr, pc.clone(), databases[0].clone());
+                            vec![Message::Success]
+                        }
+                        None => vec![Message::Error(format!("ERR no address for {}", host))],
+                    },
+                    Err(e) => vec![Message::Error(format!("ERR {}", e))],
+                }
+            }
+            Message::ReplicaOf(None) => {
+                shared.stop_replicating();
+                vec![Message::Success]
+            }
+            // The connection is the replica's from here on
+            Message::ReplStream => return repl_stream(&mut conn, &pc, &databases[0]).await,
+            Message::Info => {
+                let info = info(shared, &pc, &databases, &key_counts).await;
+                vec![Message::Text(info)]
+            }
+            Message::SlowlogGet(n) => vec![Message::SlowLogEntries(shared.slowlog.get(n))],
+            Message::SlowlogReset => {
+                shared.slowlog.reset();
+                vec![Message::Success]
+            }
+            Message::ClientList => {
+                let list: Vec<_> = shared
+                    .clients
+                    .list()
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect();
+                vec![Message::Text(list.join("\n"))]
+            }
+            Message::ClientKill(id) if shared.clients.kill(id) => vec![Message::Success],
+            Message::ClientKill(_) => vec![Message::Error("ERR No such client".to_string())],
+            Message::ClientSetName(name) if client.set_name(&name) => vec![Message::Success],
+            Message::ClientSetName(_) => vec![Message::Error(
+                "ERR Client names cannot contain spaces, newlines or special characters"
+                    .to_string(),
+            )],
+            Message::ClientGetName => vec![Message::Text(client.name().unwrap_or_default())],
+            Message::DebugReload if conn.db() != 0 => vec![Message::Error(
+                "ERR DEBUG RELOAD only works on database 0".to_string(),
+            )],
+            Message::DebugSleep(_) | Message::DebugChangeReplId if !shared.debug_commands => {
+                vec![Message::Error("ERR debug commands disabled".to_string())]
+            }
+            Message::DebugChangeReplId => {
+                vec![Message::Text(shared.replication.change_repl_id())]
+            }
+            Message::Multi => vec![conn.multi()],
+            Message::Discard => vec![conn.discard()],
+            Message::Exec => match conn.exec() {
+                Some(messages) => {
+                    let started = Instant::now();
+                    let res = Message::exec_all(&messages, &pc, kd).await;
+                    shared.record("exec", started.elapsed());
+                    vec![res]
+                }
+                None => vec![Message::Error("ERR EXEC without MULTI".to_string())],
+            },
+            m => match conn.queue(m) {
+                Some(Message::DbSize) => {
+                    vec![Message::Count(key_counts[conn.db()].load(Acquire))]
+                }
+                Some(m) => {
+                    let started = Instant::now();
+                    let res = m.exec(&pc, kd).await;
+                    shared.record(m.command(), started.elapsed());
+                    vec![res]
+                }
+                None => vec![Message::Queued],
+            },
+        };
+
+        for res in responses {
+            conn.write(res).await?;
+        }
+    }
+}
+
+/// Whether `m` changes what is stored, which a replica only lets its master do.
+fn writes(m: &Message) -> bool {
+    match m {
+        Message::Publish(_, _)
+        | Message::SlowlogReset
+        | Message::CacheEvict(_)
+        | Message::ClientKill(_)
+        | Message::ReplicaOf(_)
+        | Message::DebugChangeReplId => false,
+        m => Permission::required(m) == Some(Permission::Write),
+    }
+}
+
+/// Answers `replstream`, see `serverv2::replication`. Streams writes until the replica goes away
+/// or falls too far behind, after which it has to sync again.
+async fn repl_stream<R, W>(
+    conn: &mut Connection<R, W>,
+    m: &PageCache,
+    kd: &RwLock<KeyDir>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // Subscribed first, writes made while syncing are sent again afterwards and leave the same
+    let mut writes = m.subscribe_writes();
+    let snapshot = Snapshot::take(m, kd).await;
+    let mut entries = Vec::with_capacity(snapshot.len());
+    for (k, data) in snapshot.iter() {
+        match snapshot.fetch(m, k, data).await {
+            Ok(Some(entry)) => entries.push(entry.as_bytes().freeze()),
+            // Expired since
+            Ok(None) => {}
+            Err(e) => return Err(io::Error::other(e)),
+        }
+    }
+
+    conn.write(Message::Count(entries.len())).await?;
+    for entry in entries {
+        conn.write(Message::ReplEntry(0, entry)).await?;
+    }
+    conn.flush_pipeline().await?;
+
+    loop {
+        match writes.recv().await {
+            Ok((seq, entry)) => {
+                conn.write(Message::ReplEntry(seq, entry)).await?;
+                // Written out together while more are waiting
+                if writes.is_empty() {
+                    conn.flush_pipeline().await?;
+                }
+            }
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("replstream: replica fell {} writes behind", n);
+                return conn.flush_pipeline().await;
+            }
+            Err(RecvError::Closed) => return conn.flush_pipeline().await,
+        }
+    }
+}
+
+/// The report `info` answers with. Memory use is estimated from the size of what is stored, not
+/// measured. Command latencies are in `[stats]`, see `Metrics::report`.
+async fn info(
+    shared: &Shared,
+    m: &PageCache,
+    databases: &Databases,
+    key_counts: &KeyCounts,
+) -> String {
+    let mut keyspace = String::new();
+    let mut key_dir_bytes = 0;
+    for (i, (kd, keys)) in databases.iter().zip(key_counts).enumerate() {
+        let keys = keys.load(Acquire);
+        // Only the memory and expiry figures need the map
+        let kd = kd.read().await;
+        key_dir_bytes += kd.memory_usage();
+        let expired = kd.count_expired();
+        drop(kd);
+
+        // Databases that were never used are left out
+        if keys > 0 || expired > 0 {
+            keyspace += &format!("db{}:keys={},expired={}\n", i, keys, expired);
+        }
+    }
+    let stats = m.stats();
+    let role = match shared.role() {
+        ReplicationRole::Master => "master".to_string(),
+        ReplicationRole::Replica { master_addr } => format!("replica\nmaster_addr:{}", master_addr),
+    };
+
+    format!(
+        "[server]\nversion:{}\nuptime_seconds:{}\naddress:{}\nrole:{}\n\n\
+         [keyspace]\n{}\n\
+         [stats]\nhits:{}\nmisses:{}\nevictions:{}\ndirty_flushes:{}\ncommands_processed:{}\n{}\n\
+         [memory]\nkey_dir_bytes:{}\npage_pool_bytes:{}",
+        env!("CARGO_PKG_VERSION"),
+        shared.started.elapsed().as_secs(),
+        shared.addr,
+        role,
+        keyspace,
+        stats.hits,
+        stats.misses,
+        stats.evictions,
+        stats.dirty_flushes,
+        shared.commands.load(Relaxed),
+        shared.metrics.report(),
+        key_dir_bytes,
+        m.memory_usage()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::{atomic::AtomicU64, Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::RwLock,
+    };
+
+    use crate::{
+        serverv2::{
+            client::Clients,
+            message::DEFAULT_KEYS_LIMIT,
+            metrics::Metrics,
+            pubsub::PubSub,
+            replication::{ReplicationRole, ReplicationState},
+            server::{
+                accept_loop, listen, ConnectionLimiter, Databases, Shared, DEFAULT_MAX_KEY_SIZE,
+                DEFAULT_MAX_VALUE_SIZE,
+            },
+            slowlog::SlowLog,
+            sweeper::sweep,
+        },
+        storagev2::{
+            disk::Disk,
+            key_dir::KeyDir,
+            log::EntryLimits,
+            page::Page,
+            page_manager::{PageCache, PageManagerBuilder},
+            test::CleanUp,
+            wal::WriteAheadLog,
+        },
+    };
+
+    async fn page_cache(db_file: &str, wal_file: &str) -> io::Result<PageCache> {
+        let disk = Disk::new(db_file).await?;
+        let wal = WriteAheadLog::new(wal_file).await?;
+
+        Ok(PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid"))
+    }
+
+    fn shared(addr: SocketAddr) -> Shared {
+        Shared {
+            auth: None,
+            keys_limit: DEFAULT_KEYS_LIMIT,
+            pubsub: PubSub::new(),
+            replication: ReplicationState::new(),
+            limits: EntryLimits {
+                max_key_len: DEFAULT_MAX_KEY_SIZE as u64,
+                max_value_len: DEFAULT_MAX_VALUE_SIZE as u64,
+            },
+            debug_commands: false,
+            idle_timeout: Duration::ZERO,
+            addr,
+            started: Instant::now(),
+            commands: AtomicU64::new(0),
+            metrics: Metrics::new(),
+            slowlog: SlowLog::default(),
+            clients: Clients::new(),
+            role: Mutex::default(),
+            replica_stop: Mutex::default(),
+        }
+    }
+
+    /// Sends `requests` over one connection and returns everything written back once it closes.
+    async fn serve(m: PageCache, databases: Databases, requests: &[u8]) -> io::Result<Vec<u8>> {
+        let addr = "127.0.0.1:4444".parse().expect("valid address");
+        serve_with(shared(addr), m, databases, requests).await
+    }
+
+    async fn serve_with(
+        shared: Shared,
+        m: PageCache,
+        databases: Databases,
+        requests: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let addr = shared.addr;
+        let (client, server) = tokio::io::duplex(4096);
+        let conn =
+            tokio::spawn(async move { accept_loop(server, addr, &shared, m, databases).await });
+
+        let (mut r, mut w) = tokio::io::split(client);
+        w.write_all(requests).await?;
+        // Closing our side ends the connection once everything is answered
+        w.shutdown().await?;
+        drop(w);
+
+        let mut got = Vec::new();
+        r.read_to_end(&mut got).await?;
+        let res = conn.await.expect("connection shouldn't panic");
+        assert!(res.is_err_and(|e| e.kind() == io::ErrorKind::ConnectionReset));
+
+        Ok(got)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_select() -> io::Result<()> {
+        const DB_FILE: &str = "./test_select.db";
+        const WAL_FILE: &str = "./test_select.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![
+            Arc::new(RwLock::new(KeyDir::default())),
+            Arc::new(RwLock::new(KeyDir::default())),
+        ]);
+
+        let requests = b"insert key value\nselect 1\nget key\nselect 2\nselect 0\nget key\n";
+        let got = serve(m, databases.clone(), requests).await?;
+
+        // The get on database 1 finds nothing and answers with nothing
+        let expected = b"Success\nSuccess\nERR DB index is out of range\nSuccess\nkey value\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+        assert!(databases[0].read().await.get(b"key").is_some());
+        assert!(databases[1].read().await.get(b"key").is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reset() -> io::Result<()> {
+        const DB_FILE: &str = "./test_reset.db";
+        const WAL_FILE: &str = "./test_reset.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![
+            Arc::new(RwLock::new(KeyDir::default())),
+            Arc::new(RwLock::new(KeyDir::default())),
+        ]);
+
+        let requests = b"insert key value\nselect 1\nmulti\ninsert other value\nreset\nget key\n\
+                         exec\nsubscribe news\nget key\nreset\nget key\n";
+        let got = serve(m, databases.clone(), requests).await?;
+
+        let expected = b"Success\nSuccess\nSuccess\nQueued\nReset\nkey value\n\
+                         ERR EXEC without MULTI\nsubscribe news 1\n\
+                         ERR 'get' isn't allowed while subscribed, only (UN)SUBSCRIBE, PING and \
+                         RESET are\n\
+                         Reset\nkey value\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+        // The queued insert was dropped along with the transaction
+        assert!(databases[1].read().await.get(b"other").is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_idle_timeout() -> io::Result<()> {
+        const DB_FILE: &str = "./test_idle_timeout.db";
+        const WAL_FILE: &str = "./test_idle_timeout.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+        let addr = "127.0.0.1:4444".parse().expect("valid address");
+        let shared = Shared {
+            idle_timeout: Duration::from_millis(100),
+            ..shared(addr)
+        };
+
+        let (client, server) = tokio::io::duplex(4096);
+        let conn =
+            tokio::spawn(async move { accept_loop(server, addr, &shared, m, databases).await });
+
+        // Left open, the server closes it once the client has been quiet for long enough
+        let (mut r, mut w) = tokio::io::split(client);
+        w.write_all(b"ping\nping hello\nsubscribe news\nping\nunsubscribe\n")
+            .await?;
+        let mut got = Vec::new();
+        let started = Instant::now();
+        r.read_to_end(&mut got).await?;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let res = conn.await.expect("connection shouldn't panic");
+        assert!(res.is_ok(), "Got: {:?}", res);
+
+        let expected = b"PONG\nhello\nsubscribe news 1\nPONG\nunsubscribe news 0\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replica_of() -> io::Result<()> {
+        const DB_FILE: &str = "./test_replica_of.db";
+        const WAL_FILE: &str = "./test_replica_of.wal";
+        const REPLICA_DB_FILE: &str = "./test_replica_of_replica.db";
+        const REPLICA_WAL_FILE: &str = "./test_replica_of_replica.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let _cu_replica = CleanUp::segments(REPLICA_DB_FILE);
+        let _cu_replica_wal = CleanUp::file(REPLICA_WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let master_addr = listener.local_addr()?;
+        let master = Arc::new(shared(master_addr));
+        tokio::spawn(listen(
+            listener,
+            None,
+            master,
+            m,
+            databases,
+            ConnectionLimiter::new(16),
+        ));
+
+        async fn request(stream: &mut TcpStream, req: &[u8], expected: &[u8]) -> io::Result<()> {
+            stream.write_all(req).await?;
+            let mut got = vec![0; expected.len()];
+            stream.read_exact(&mut got).await?;
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&got)
+            );
+            Ok(())
+        }
+
+        let mut to_master = TcpStream::connect(master_addr).await?;
+        request(&mut to_master, b"insert before 1\n", b"Success\n").await?;
+
+        let replica_m = page_cache(REPLICA_DB_FILE, REPLICA_WAL_FILE).await?;
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+        let replica_databases: Databases = Arc::new(vec![kd.clone()]);
+        let replica = Arc::new(shared("127.0.0.1:4444".parse().expect("valid address")));
+        let (client, server) = tokio::io::duplex(4096);
+        let conn = {
+            let replica = replica.clone();
+            let addr = replica.addr;
+            tokio::spawn(async move {
+                accept_loop(server, addr, &replica, replica_m, replica_databases).await
+            })
+        };
+        let (mut r, mut w) = tokio::io::split(client);
+
+        let req = format!(
+            "replicaof 127.0.0.1 {}\ninsert key value\n",
+            master_addr.port()
+        );
+        w.write_all(req.as_bytes()).await?;
+        let expected = b"Success\nREADONLY You can't write against a read only replica\n";
+        let mut got = vec![0; expected.len()];
+        r.read_exact(&mut got).await?;
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        // Synced first and then streamed
+        request(&mut to_master, b"insert after 2\n", b"Success\n").await?;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while kd.read().await.get(b"before").is_none() || kd.read().await.get(b"after").is_none() {
+            assert!(Instant::now() < deadline, "replica didn't catch up");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        w.write_all(b"get after\n").await?;
+        let mut got = [0; 8];
+        r.read_exact(&mut got).await?;
+        assert!(
+            &got == b"after 2\n",
+            "Got: {:?}",
+            String::from_utf8_lossy(&got)
+        );
+
+        // Back to taking writes of its own
+        w.write_all(b"replicaof no one\ninsert key value\n").await?;
+        w.shutdown().await?;
+        drop(w);
+        let mut got = Vec::new();
+        r.read_to_end(&mut got).await?;
+        assert!(
+            got == b"Success\nSuccess\n",
+            "Got: {:?}",
+            String::from_utf8_lossy(&got)
+        );
+        let res = conn.await.expect("connection shouldn't panic");
+        assert!(res.is_err_and(|e| e.kind() == io::ErrorKind::ConnectionReset));
+        assert!(replica.role() == ReplicationRole::Master);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_list() -> io::Result<()> {
+        const DB_FILE: &str = "./test_client_list.db";
+        const WAL_FILE: &str = "./test_client_list.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+        let shared = Arc::new(shared("127.0.0.1:4444".parse().expect("valid address")));
+
+        let connect = |addr: &str| {
+            let (client, server) = tokio::io::duplex(4096);
+            let addr = addr.parse().expect("valid address");
+            let (shared, m, databases) = (shared.clone(), m.clone(), databases.clone());
+            let conn =
+                tokio::spawn(async move { accept_loop(server, addr, &shared, m, databases).await });
+            (tokio::io::split(client), conn)
+        };
+
+        // Registered by the time it answers
+        let ((mut r1, mut w1), conn1) = connect("127.0.0.1:5001");
+        w1.write_all(b"ping\n").await?;
+        let mut pong = [0; 5];
+        r1.read_exact(&mut pong).await?;
+
+        let ((mut r2, mut w2), conn2) = connect("127.0.0.1:5002");
+        w2.write_all(b"client list\nclient kill id:1\nclient kill id:3\n")
+            .await?;
+        w2.shutdown().await?;
+        let mut got = Vec::new();
+        r2.read_to_end(&mut got).await?;
+
+        let expected = b"id=1 addr=127.0.0.1:5001 name= db=0 age=0 cmd=ping\n\
+                         id=2 addr=127.0.0.1:5002 name= db=0 age=0 cmd=client\n\
+                         Success\nERR No such client\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        // The killed connection is closed without the client doing anything
+        let mut rest = Vec::new();
+        r1.read_to_end(&mut rest).await?;
+        assert!(rest.is_empty());
+        assert!(conn1.await.expect("connection shouldn't panic").is_ok());
+        let res = conn2.await.expect("connection shouldn't panic");
+        assert!(res.is_err_and(|e| e.kind() == io::ErrorKind::ConnectionReset));
+        assert!(shared.clients.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_client_name() -> io::Result<()> {
+        const DB_FILE: &str = "./test_client_name.db";
+        const WAL_FILE: &str = "./test_client_name.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let requests = b"client getname\nclient setname worker-1\ninsert key value\nreset\n\
+                         client getname\nclient list\nclient setname bad\x01name\nclient getname\n";
+        let got = serve(m, databases, requests).await?;
+
+        // Kept through a reset, and a bad name leaves it as it was
+        let expected = b"\nSuccess\nSuccess\nReset\nworker-1\n\
+                         id=1 addr=127.0.0.1:4444 name=worker-1 db=0 age=0 cmd=client\n\
+                         ERR Client names cannot contain spaces, newlines or special characters\n\
+                         worker-1\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot_commands() -> io::Result<()> {
+        const DB_FILE: &str = "./test_snapshot_commands.db";
+        const WAL_FILE: &str = "./test_snapshot_commands.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let requests = [
+            &b"insert key old\n"[..],
+            b"snapshot create\n",
+            b"insert key new\n",
+            b"snapshot get 1 key\n",
+            b"get key\n",
+            b"snapshot release 1\n",
+            b"snapshot get 1 key\n",
+            b"wait 1 0\n",
+        ]
+        .concat();
+        let got = serve(m, databases, &requests).await?;
+
+        let expected = [
+            &b"Success\n"[..],
+            b"1\n",
+            b"Success\n",
+            b"key old\n",
+            b"key new\n",
+            b"1\n",
+            b"ERR no such snapshot\n",
+            b"0\n",
+        ]
+        .concat();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(&expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_size_limits() -> io::Result<()> {
+        const DB_FILE: &str = "./test_size_limits.db";
+        const WAL_FILE: &str = "./test_size_limits.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let key = "k".repeat(DEFAULT_MAX_KEY_SIZE + 1);
+        let requests = format!("insert {key} value\nmget a {key}\ninsert key value\nget key\n");
+        let got = serve(m, databases.clone(), requests.as_bytes()).await?;
+
+        // Both refused, and the connection carries on
+        let refused = format!("ERR key of {} bytes is too large\n", key.len());
+        let expected = format!("{refused}{refused}Success\nkey value\n");
+        assert!(
+            got == expected.as_bytes(),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            String::from_utf8_lossy(&got)
+        );
+        assert!(databases[0].read().await.len() == 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connection_limit() -> io::Result<()> {
+        const DB_FILE: &str = "./test_connection_limit.db";
+        const WAL_FILE: &str = "./test_connection_limit.wal";
+        const MAX_CONNECTIONS: usize = 100;
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(shared(addr));
+        let limiter = ConnectionLimiter::new(MAX_CONNECTIONS);
+        tokio::spawn(listen(
+            listener,
+            None,
+            shared,
+            m,
+            databases,
+            limiter.clone(),
+        ));
+
+        // Accepted in order, so these take every permit before the next one is looked at
+        let mut conns = Vec::new();
+        for _ in 0..MAX_CONNECTIONS {
+            conns.push(TcpStream::connect(addr).await?);
+        }
+
+        let mut busy = TcpStream::connect(addr).await?;
+        busy.write_all(b"get key\n").await?;
+        let mut got = Vec::new();
+        busy.read_to_end(&mut got).await?;
+        let expected = b"SERVER_BUSY too many connections\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+        assert!(limiter.available() == 0);
+
+        // Closing one makes room again once its task has noticed
+        drop(conns.pop());
+        while limiter.available() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let mut conn = TcpStream::connect(addr).await?;
+        conn.write_all(b"insert key value\n").await?;
+        let mut got = [0; 8];
+        conn.read_exact(&mut got).await?;
+        assert!(
+            &got == b"Success\n",
+            "Got: {:?}",
+            String::from_utf8_lossy(&got)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_info() -> io::Result<()> {
+        const DB_FILE: &str = "./test_info.db";
+        const WAL_FILE: &str = "./test_info.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![
+            Arc::new(RwLock::new(KeyDir::default())),
+            Arc::new(RwLock::new(KeyDir::default())),
+            Arc::new(RwLock::new(KeyDir::default())),
+        ]);
+
+        let requests = b"insert a 1\ninsert b 2\nselect 1\ninsert c 3\ninfo\n";
+        let got = serve(m, databases, requests).await?;
+        let got = String::from_utf8_lossy(&got);
+        let info = got
+            .strip_prefix("Success\nSuccess\nSuccess\nSuccess\n")
+            .unwrap_or_else(|| panic!("Got: {:?}", got));
+
+        let mut sections = Vec::new();
+        let mut fields = Vec::new();
+        for line in info.lines().filter(|l| !l.is_empty()) {
+            match line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                Some(section) => sections.push(section),
+                None => fields.push(line.split_once(':').expect("fields are name:value")),
+            }
+        }
+        let expected = ["server", "keyspace", "stats", "memory"];
+        assert!(
+            sections == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            sections
+        );
+
+        for expected in [
+            ("address", "127.0.0.1:4444"),
+            ("db0", "keys=2,expired=0"),
+            ("db1", "keys=1,expired=0"),
+            ("commands_processed", "5"),
+        ] {
+            assert!(
+                fields.contains(&expected),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                fields
+            );
+        }
+        // Database 2 was never used
+        assert!(!fields.iter().any(|(name, _)| *name == "db2"));
+        for name in [
+            "version",
+            "uptime_seconds",
+            "role",
+            "hits",
+            "key_dir_bytes",
+            "page_pool_bytes",
+        ] {
+            assert!(fields.iter().any(|(n, _)| *n == name), "missing {}", name);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_command_latency() -> io::Result<()> {
+        const DB_FILE: &str = "./test_command_latency.db";
+        const WAL_FILE: &str = "./test_command_latency.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let mut requests = b"insert a 1\n".to_vec();
+        for _ in 0..1000 {
+            requests.extend_from_slice(b"get a\n");
+        }
+        requests.extend_from_slice(b"info\n");
+        let got = serve(m, databases, &requests).await?;
+        let got = String::from_utf8_lossy(&got);
+
+        let field = |name: &str| -> u64 {
+            got.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(':'))
+                .unwrap_or_else(|| panic!("missing {}", name))
+                .parse()
+                .expect("latencies are integers")
+        };
+        assert!(field("cmd_get_calls") == 1000);
+        assert!(field("cmd_insert_calls") == 1);
+        let p99 = field("cmd_get_p99_us");
+        assert!(p99 > 0 && p99 < 1_000_000, "Got: {}", p99);
+        assert!(field("cmd_get_min_us") <= field("cmd_get_mean_us"));
+        assert!(field("cmd_get_mean_us") <= field("cmd_get_max_us"));
+        // Info isn't executed against storage
+        assert!(!got.contains("cmd_info_"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slowlog() -> io::Result<()> {
+        const DB_FILE: &str = "./test_slowlog.db";
+        const WAL_FILE: &str = "./test_slowlog.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let addr = "127.0.0.1:4444".parse().expect("valid address");
+        let shared = Shared {
+            debug_commands: true,
+            slowlog: SlowLog::new(20_000, 8),
+            ..shared(addr)
+        };
+        let requests = b"insert a 1\ndebug sleep 50\nget a\n\
+            slowlog get 10\nslowlog reset\nslowlog get 10\n";
+        let got = serve_with(shared, m, databases, requests).await?;
+        let got = String::from_utf8_lossy(&got);
+
+        let lines: Vec<_> = got.lines().collect();
+        // Only the sleep was slow
+        assert!(
+            lines[..4] == ["Success", "Success", "a 1", "1"],
+            "Got: {:?}",
+            got
+        );
+        let entry: Vec<_> = lines[4].split(' ').collect();
+        assert!(entry.len() == 4, "Got: {:?}", lines[4]);
+        assert!(entry[0] == "0" && entry[3] == "debug", "Got: {:?}", entry);
+        let duration_us: u64 = entry[2].parse().expect("duration is an integer");
+        assert!(duration_us >= 50_000, "Got: {}", duration_us);
+        assert!(lines[5..] == ["Success", "0"], "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dbsize() -> io::Result<()> {
+        const DB_FILE: &str = "./test_dbsize.db";
+        const WAL_FILE: &str = "./test_dbsize.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![
+            Arc::new(RwLock::new(KeyDir::default())),
+            Arc::new(RwLock::new(KeyDir::default())),
+        ]);
+
+        let requests = b"dbsize\ninsert a 1\ninsert b 2\ninsert c 3\ndbsize\ndelete a\ndbsize\n\
+            expire b 0\ndbsize\nselect 1\ndbsize\n";
+        let got = serve(m.clone(), databases.clone(), requests).await?;
+        // An expired key counts until it is swept
+        let expected = "0\nSuccess\nSuccess\nSuccess\n3\nSuccess\n2\n1\n2\nSuccess\n0\n";
+        assert!(
+            got == expected.as_bytes(),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            String::from_utf8_lossy(&got)
+        );
+
+        assert!(sweep(&m, &databases[0]).await == 1);
+        let got = serve(m, databases, b"dbsize\n").await?;
+        assert!(got == b"1\n", "Got: {:?}", String::from_utf8_lossy(&got));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_sleep() -> io::Result<()> {
+        const DB_FILE: &str = "./test_debug_sleep.db";
+        const WAL_FILE: &str = "./test_debug_sleep.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        // Disabled by default, answered right away instead of sleeping
+        let got = tokio::time::timeout(
+            Duration::from_secs(5),
+            serve(m.clone(), databases.clone(), b"debug sleep 60000\n"),
+        )
+        .await
+        .expect("shouldn't sleep")?;
+        let expected = b"ERR debug commands disabled\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        let addr = "127.0.0.1:4444".parse().expect("valid address");
+        let shared = Shared {
+            debug_commands: true,
+            ..shared(addr)
+        };
+        let start = Instant::now();
+        let got = serve_with(shared, m, databases, b"debug sleep 50\n").await?;
+        assert!(
+            got == b"Success\n",
+            "Got: {:?}",
+            String::from_utf8_lossy(&got)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_debug_change_repl_id() -> io::Result<()> {
+        const DB_FILE: &str = "./test_debug_change_repl_id.db";
+        const WAL_FILE: &str = "./test_debug_change_repl_id.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let m = page_cache(DB_FILE, WAL_FILE).await?;
+        let databases: Databases = Arc::new(vec![Arc::new(RwLock::new(KeyDir::default()))]);
+
+        let got = serve(m.clone(), databases.clone(), b"debug change-repl-id\n").await?;
+        let expected = b"ERR debug commands disabled\n";
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            String::from_utf8_lossy(expected),
+            String::from_utf8_lossy(&got)
+        );
+
+        let addr = "127.0.0.1:4444".parse().expect("valid address");
+        let shared = Shared {
+            debug_commands: true,
+            ..shared(addr)
+        };
+        let requests = b"debug change-repl-id\ndebug change-repl-id\n";
+        let got = serve_with(shared, m, databases, requests).await?;
+        let got = String::from_utf8_lossy(&got);
+
+        let ids: Vec<_> = got.lines().collect();
+        assert!(ids.len() == 2, "Got: {:?}", got);
+        for id in &ids {
+            assert!(
+                id.len() == 40 && id.bytes().all(|b| b.is_ascii_hexdigit()),
+                "Got: {:?}",
+                id
+            );
+        }
+        assert!(ids[0] != ids[1], "Got: {:?}", ids);
 
-        conn.write(res).await?;
+        Ok(())
     }
 }
@@ -0,0 +1,135 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use crate::storagev2::log::timestamp_millis;
+
+/// Commands taking longer than this many microseconds are logged unless configured otherwise.
+pub const DEFAULT_SLOWLOG_THRESHOLD_US: u64 = 10_000;
+/// Entries kept unless configured otherwise.
+pub const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowLogEntry {
+    /// One more than the entry before it, resets don't start it over.
+    pub id: u64,
+    pub command: String,
+    pub duration_us: u64,
+    /// Unix time in seconds when the command finished.
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: VecDeque<SlowLogEntry>,
+    next_id: u64,
+}
+
+/// The latest commands that took longer than a threshold, the oldest dropped once it is full.
+#[derive(Debug)]
+pub struct SlowLog {
+    inner: Mutex<Inner>,
+    threshold_us: u64,
+    max_len: usize,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_SLOWLOG_THRESHOLD_US, DEFAULT_SLOWLOG_MAX_LEN)
+    }
+}
+
+impl SlowLog {
+    /// A `max_len` of 0 logs nothing.
+    pub fn new(threshold_us: u64, max_len: usize) -> Self {
+        Self {
+            inner: Mutex::default(),
+            threshold_us,
+            max_len,
+        }
+    }
+
+    /// Logs `command` if it took longer than the threshold, returning whether it did.
+    pub fn record(&self, command: &str, elapsed: Duration) -> bool {
+        let duration_us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        if duration_us <= self.threshold_us || self.max_len == 0 {
+            return false;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.entries.len() == self.max_len {
+            inner.entries.pop_front();
+        }
+        inner.entries.push_back(SlowLogEntry {
+            id,
+            command: command.to_string(),
+            duration_us,
+            timestamp: timestamp_millis() / 1000,
+        });
+
+        true
+    }
+
+    /// The latest `n` entries, newest first.
+    pub fn get(&self, n: usize) -> Vec<SlowLogEntry> {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.iter().rev().take(n).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::serverv2::slowlog::SlowLog;
+
+    #[test]
+    fn test_slowlog() {
+        let log = SlowLog::new(100, 3);
+        assert!(!log.record("get", Duration::from_micros(100)));
+        assert!(log.is_empty());
+
+        for (i, command) in ["insert", "get", "delete", "keys"].iter().enumerate() {
+            assert!(log.record(command, Duration::from_micros(200 + i as u64)));
+        }
+        // The first was dropped to make room
+        assert!(log.len() == 3);
+        let got: Vec<_> = log
+            .get(10)
+            .into_iter()
+            .map(|e| (e.id, e.command, e.duration_us))
+            .collect();
+        let expected = vec![
+            (3, "keys".to_string(), 203),
+            (2, "delete".to_string(), 202),
+            (1, "get".to_string(), 201),
+        ];
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(log.get(1).len() == 1 && log.get(0).is_empty());
+
+        log.reset();
+        assert!(log.is_empty());
+        log.record("get", Duration::from_millis(1));
+        assert!(log.get(1)[0].id == 4);
+
+        let disabled = SlowLog::new(0, 0);
+        assert!(!disabled.record("get", Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,182 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::BytesMut;
+use tokio::{
+    sync::{watch, RwLock},
+    task::JoinHandle,
+    time::{self, MissedTickBehavior},
+};
+
+use crate::storagev2::{key_dir::KeyDir, page::PageID, page_manager::PageCache};
+
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Periodically drops keys whose entries have expired. Without it they stay in the key dir until
+/// a compaction runs, reads just skip them.
+pub struct BackgroundSweeper {
+    interval: Duration,
+}
+
+impl Default for BackgroundSweeper {
+    fn default() -> Self {
+        Self::new(DEFAULT_SWEEP_INTERVAL)
+    }
+}
+
+impl BackgroundSweeper {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Sweeps every `interval` until `shutdown` is set to true or its sender is dropped. Only the
+    /// wait between sweeps is interrupted, a sweep that already started runs to the end, so
+    /// awaiting the handle after signalling shutdown leaves the key dir consistent.
+    pub fn spawn(
+        self,
+        m: PageCache,
+        kd: Arc<RwLock<KeyDir>>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = time::interval(self.interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes immediately
+            interval.tick().await;
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                }
+
+                let expired = sweep(&m, &kd).await;
+                if expired > 0 {
+                    eprintln!("sweeper: expired {} keys", expired);
+                }
+            }
+        })
+    }
+}
+
+/// Removes every key whose entry has expired and returns how many there were. The key dir is read
+/// locked to copy out where each key lives and write locked to remove the expired ones, but not
+/// while the entries are read. As with compaction no tombstones are written, expired entries are
+/// already skipped on reads and bootstrap.
+pub async fn sweep(m: &PageCache, key_dir: &RwLock<KeyDir>) -> usize {
+    let mut locations: Vec<(PageID, u64, BytesMut)> = key_dir
+        .read()
+        .await
+        .scan(b"", b"")
+        .map(|(k, data)| (data.page_id, data.offset, BytesMut::from(k)))
+        .collect();
+    // Each page is only fetched once
+    locations.sort_unstable_by_key(|(page_id, offset, _)| (*page_id, *offset));
+
+    let mut expired = Vec::new();
+    for keys in locations.chunk_by(|a, b| a.0 == b.0) {
+        let page_id = keys[0].0;
+        let Some(pin) = m.fetch_page(page_id).await else {
+            eprintln!("sweeper: no free frame to read page {}", page_id);
+            continue;
+        };
+
+        let page = pin.read().await;
+        for (_, offset, key) in keys {
+            if page
+                .read_entry_raw(*offset as usize)
+                .is_ok_and(|e| e.is_expired())
+            {
+                expired.push((key, m.key_data(page_id, *offset)));
+            }
+        }
+        drop(page);
+        pin.unpin().await;
+    }
+
+    // Keys written again since they were copied out point somewhere else now
+    let mut kd = key_dir.write().await;
+    let mut count = 0;
+    for (key, old) in expired {
+        if kd.get(key) == Some(&old) {
+            kd.expire(key);
+            count += 1;
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc, time::Duration};
+
+    use tokio::sync::{watch, RwLock};
+
+    use crate::{
+        serverv2::sweeper::{sweep, BackgroundSweeper},
+        storagev2::{
+            disk::Disk,
+            key_dir::KeyDir,
+            log::{timestamp_millis, Entry, EntryType},
+            page::Page,
+            page_manager::PageManagerBuilder,
+            test::CleanUp,
+            wal::WriteAheadLog,
+        },
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sweep() -> io::Result<()> {
+        const DB_FILE: &str = "./test_sweep.db";
+        const WAL_FILE: &str = "./test_sweep.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        // Spread over a few pages, every third key has expired
+        for i in 0..30 {
+            let key = format!("key_{}", i);
+            let mut entry = Entry::new(key.as_bytes(), b"value", EntryType::Put);
+            if i % 3 == 0 {
+                entry.expire_at = Some(timestamp_millis() - 1000);
+            }
+            let (page_id, offset) = m.write_entry_auto(&entry).await?;
+            kd.write()
+                .await
+                .insert(key.as_bytes(), m.key_data(page_id, offset as u64));
+        }
+
+        let expired = sweep(&m, &kd).await;
+        assert!(expired == 10, "\nExpected: {}\nGot: {}\n", 10, expired);
+
+        let kd_r = kd.read().await;
+        assert!(kd_r.len() == 20);
+        assert!(kd_r.get(b"key_0").is_none());
+        assert!(kd_r.get(b"key_1").is_some());
+        drop(kd_r);
+
+        assert!(sweep(&m, &kd).await == 0);
+
+        // Stops between sweeps once shut down
+        let (shutdown, rx) = watch::channel(false);
+        let sweeper = BackgroundSweeper::new(Duration::from_millis(10)).spawn(m, kd, rx);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.send(true).expect("sweeper should be running");
+        tokio::time::timeout(Duration::from_secs(1), sweeper)
+            .await
+            .expect("sweeper should stop")
+            .expect("sweeper shouldn't panic");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    f64::consts::LN_2,
+    hash::{Hash, Hasher},
+};
+
+/// Fixed size bloom filter over byte keys. Keys can't be removed, so bits set by deleted keys stay
+/// set until the filter is rebuilt.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    len: usize,
+    hashes: usize,
+}
+
+impl BloomFilter {
+    /// Creates a filter with `len` bits (rounded up to a multiple of 64) that sets `hashes` bits
+    /// per key.
+    pub fn new(len: usize, hashes: usize) -> Self {
+        let words = len.max(1).div_ceil(64);
+        let len = words * 64;
+        let hashes = hashes.max(1);
+
+        Self {
+            bits: vec![0; words],
+            len,
+            hashes,
+        }
+    }
+
+    /// Creates a filter sized to hold `capacity` keys at roughly the given false positive rate.
+    pub fn with_rate(capacity: usize, rate: f64) -> Self {
+        let capacity = capacity.max(1) as f64;
+        let len = (-capacity * rate.ln() / (LN_2 * LN_2)).ceil();
+        let hashes = (len / capacity * LN_2).round();
+
+        Self::new(len as usize, hashes as usize)
+    }
+
+    pub fn insert(&mut self, k: &[u8]) {
+        for i in self.indexes(k) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    /// False means `k` was definitely never inserted.
+    pub fn contains(&self, k: &[u8]) -> bool {
+        self.indexes(k)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    /// Estimated from the fraction of bits set, so it accounts for bits left behind by removed keys.
+    pub fn false_positive_rate(&self) -> f64 {
+        let set: u32 = self.bits.iter().map(|w| w.count_ones()).sum();
+        (set as f64 / self.len as f64).powi(self.hashes as i32)
+    }
+
+    /// Bytes taken by the bits.
+    pub fn memory_usage(&self) -> usize {
+        self.bits.len() * size_of::<u64>()
+    }
+
+    // Double hashing, index i = h1 + i * h2
+    fn indexes(&self, k: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // Keep going from the first hash's state to derive an independent second hash
+        0xB10Cu16.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+
+        let len = self.len as u64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::bloom::BloomFilter;
+
+    #[test]
+    fn test_false_positives() {
+        const ENTRIES: usize = 10_000;
+
+        let mut bloom = BloomFilter::new(ENTRIES * 16, 7);
+        for i in 0..ENTRIES {
+            bloom.insert(format!("key_{}", i).as_bytes());
+        }
+
+        for i in 0..ENTRIES {
+            assert!(
+                bloom.contains(format!("key_{}", i).as_bytes()),
+                "false negative for key_{}",
+                i
+            );
+        }
+
+        let false_positives = (ENTRIES..2 * ENTRIES)
+            .filter(|i| bloom.contains(format!("key_{}", i).as_bytes()))
+            .count();
+        let rate = false_positives as f64 / ENTRIES as f64;
+        assert!(rate < 0.01, "Got: {}", rate);
+        assert!(
+            bloom.false_positive_rate() < 0.01,
+            "Got: {}",
+            bloom.false_positive_rate()
+        );
+    }
+}
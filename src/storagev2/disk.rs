@@ -1,54 +1,903 @@
-use std::{io, os::fd::AsRawFd, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read},
+    path::Path,
+    path::PathBuf,
+    time::SystemTime,
+};
 
-use nix::sys::uio;
-use tokio::fs::{File, OpenOptions};
+use bytes::BytesMut;
+use futures_util::{stream, Stream};
+use tokio::sync::RwLock;
 
-use crate::storagev2::page::{PageID, PAGE_SIZE};
+use crate::storagev2::{
+    key_dir::{KeyData, KeyDir},
+    list::ListNode,
+    log::{Entry, EntryType},
+    page::{set_page_checksum, verify_page_checksum, PageError, PageID, PageInner, PAGE_SIZE},
+    segment::{segment_path, FileID, SegmentManager, DEFAULT_SEGMENT_SIZE},
+};
 
+pub use crate::storagev2::segment::{IncompatibleVersion, StorageVersion};
+
+/// How far a page write goes before it returns.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Left to the OS to write back, the WAL is what makes writes durable.
+    #[default]
+    None,
+    /// The page's data is synced, its file's metadata may not be.
+    FsyncData,
+    /// The page and its file's metadata are synced.
+    Fsync,
+}
+
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+pub use crate::storagev2::uring::IoUringDisk;
+
+/// Reads and writes of whole pages, the page size being the length of the buffer. `Disk` does
+/// them with blocking syscalls on the calling thread, `IoUringDisk` hands them to io_uring.
+pub trait DiskBackend: Send + Sync {
+    /// Whatever lies past the end of the data is left as is in `buf`.
+    fn read_page_into(&self, page_id: PageID, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_page_from(&self, page_id: PageID, data: &[u8]) -> io::Result<()>;
+
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// The data file, pages are numbered across all of its segments.
+///
+/// A page always lives at `page_id * page size`, segments splitting that range at fixed byte
+/// boundaries, so a page id is all it takes to find a page and no byte positions are kept in the
+/// key dir. Compaction keeps this by writing its pages from 0 up into a fresh file.
 pub struct Disk {
-    file: File,
+    segments: SegmentManager,
+    path: PathBuf,
+    durability: Durability,
 }
 
 impl Disk {
+    /// Fails with an `IncompatibleVersion` inside the `io::Error` if the data file was written
+    /// with another `StorageVersion`, see `migrate_v1_to_v2`.
     pub async fn new(file: impl AsRef<Path>) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(file)
-            .await?;
+        Self::with_segment_size(file, DEFAULT_SEGMENT_SIZE).await
+    }
+
+    /// Starts a new segment file once the last one reaches `segment_size` bytes.
+    pub async fn with_segment_size(file: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        let path = file.as_ref().to_path_buf();
+        let segments = SegmentManager::open(&path, segment_size)?;
 
-        Ok(Self { file })
+        Ok(Self {
+            segments,
+            path,
+            durability: Durability::default(),
+        })
     }
 
-    pub fn read_page(&self, page_id: PageID) -> io::Result<[u8; PAGE_SIZE]> {
-        let offset = PAGE_SIZE as i64 * i64::from(page_id);
-        let fd = self.file.as_raw_fd();
+    /// Opens the data file with `O_DIRECT` on Linux, so pages skip the OS page cache on their way
+    /// to and from disk, the page cache being the only cache. Pages, and every read and write,
+    /// have to be a multiple of `DIRECT_BLOCK` bytes. Fails on other platforms.
+    pub async fn new_o_direct(file: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_direct(file, DEFAULT_SEGMENT_SIZE).await
+    }
 
-        let mut buf = [0; PAGE_SIZE];
-        match uio::pread(fd, &mut buf, offset) {
-            Ok(_) => {}
-            Err(e) => panic!("{e}"),
+    async fn open_direct(file: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        let path = file.as_ref().to_path_buf();
+        let segments = SegmentManager::open_direct(&path, segment_size)?;
+
+        Ok(Self {
+            segments,
+            path,
+            durability: Durability::default(),
+        })
+    }
+
+    /// Where upgrading a data file written with storage v1 goes once there is a v2. Every segment
+    /// is checked to be v1 first, then each would be rewritten to a temporary file with its
+    /// entries converted and a v2 header, and renamed over the old one, so a crash part way
+    /// leaves some segments done and running it again carries on from there. There is only v1
+    /// for now, so it stops after the check.
+    pub fn migrate_v1_to_v2(file: impl AsRef<Path>) -> io::Result<()> {
+        let base = file.as_ref();
+        let mut header = [0; 8];
+        for file_id in 0.. {
+            let path = segment_path(base, file_id);
+            if !path.exists() {
+                break;
+            }
+
+            std::fs::File::open(&path)?.read_exact(&mut header)?;
+            let found = u64::from_be_bytes(header);
+            if found != StorageVersion::V1 as u64 {
+                let e = IncompatibleVersion {
+                    found,
+                    supported: StorageVersion::V1,
+                };
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
         }
 
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "there is no storage v2 to migrate to yet",
+        ))
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn segment_size(&self) -> u64 {
+        self.segments.segment_size()
+    }
+
+    /// The segment `page_id` is stored in.
+    pub fn file_id(&self, page_id: PageID) -> FileID {
+        self.segments.file_id(PAGE_SIZE as u64 * u64::from(page_id))
+    }
+
+    /// Where the entry at `offset` in `page_id` lives.
+    pub fn key_data(&self, page_id: PageID, offset: u64) -> KeyData {
+        KeyData::new(self.file_id(page_id), page_id, offset)
+    }
+
+    /// Most recent modification time of any segment.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.segments.modified()
+    }
+
+    /// Fails with `InvalidData` if the page doesn't match its checksum, see `read_page_checked`.
+    pub fn read_page(&self, page_id: PageID) -> io::Result<[u8; PAGE_SIZE]> {
+        let mut buf = [0; PAGE_SIZE];
+        self.read_page_checked(page_id, &mut buf)?;
+
         Ok(buf)
     }
 
+    /// Reads a page and checks it against the checksum `write_page` stored in its last
+    /// `PAGE_CHECKSUM_LEN` bytes, failing with `InvalidData` on a mismatch. `buf` holds what was
+    /// read either way.
+    pub fn read_page_checked(&self, page_id: PageID, buf: &mut [u8; PAGE_SIZE]) -> io::Result<()> {
+        self.read_page_into(page_id, buf)?;
+        if !verify_page_checksum(buf) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "page checksum mismatch",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Stores the page's checksum in its last `PAGE_CHECKSUM_LEN` bytes on the way, whatever
+    /// `data` has there.
     pub fn write_page(&self, page_id: PageID, data: &[u8; PAGE_SIZE]) {
-        let offset = PAGE_SIZE as i64 * i64::from(page_id);
-        let fd = self.file.as_raw_fd();
+        let mut page = *data;
+        set_page_checksum(&mut page);
+        if let Err(e) = self.write_page_from(page_id, &page) {
+            panic!("{e}");
+        }
+    }
 
-        match uio::pwrite(fd, data, offset) {
-            Ok(_) => {}
-            Err(e) => panic!("{e}"),
-        };
+    /// Reads a page of `buf.len()` bytes, for pages sized at runtime. Nothing is checked.
+    pub fn read_page_into(&self, page_id: PageID, buf: &mut [u8]) -> io::Result<()> {
+        let offset = buf.len() as u64 * u64::from(page_id);
+
+        self.segments.read_at(buf, offset)
+    }
+
+    /// Writes a page of `data.len()` bytes, for pages sized at runtime, as it is. Synced before
+    /// returning unless the durability is `None`.
+    pub fn write_page_from(&self, page_id: PageID, data: &[u8]) -> io::Result<()> {
+        let offset = data.len() as u64 * u64::from(page_id);
+
+        self.segments.write_at(data, offset)?;
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::FsyncData => self.segments.sync_at(offset, data.len(), true),
+            Durability::Fsync => self.segments.sync_at(offset, data.len(), false),
+        }
+    }
+
+    /// Frees the disk space of `len` bytes of the data from `offset`, which read as zeros from then
+    /// on, see `SegmentManager::punch_hole`. Meant for whole pages nothing refers to anymore, a
+    /// page zeroed in part fails its checksum.
+    #[cfg(target_os = "linux")]
+    pub fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.segments.punch_hole(offset, len)
+    }
+
+    /// Number of `page_size` pages in the file, rounding a partial page up.
+    pub fn page_count(&self, page_size: usize) -> io::Result<usize> {
+        let len = self.segments.len()? as usize;
+
+        Ok(len.div_ceil(page_size))
     }
 
     pub async fn len(&self) -> usize {
-        self.file
-            .metadata()
-            .await
-            .expect("error getting metadata")
-            .len() as usize
+        self.segments.len().expect("error getting metadata") as usize
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Streams every entry in the file in order, along with where it is stored. A corrupt entry
+    /// yields an `InvalidData` error and the stream carries on from the next page, since the rest
+    /// of its page can't be trusted. An entry cut off by the end of the file yields an
+    /// `UnexpectedEof` error and ends the stream.
+    pub fn entries(&self) -> impl Stream<Item = io::Result<(KeyData, Entry)>> + '_ {
+        let len = self.segments.len().map(|len| len as usize);
+
+        // One page buffer is read into for the whole scan, `loaded` is whether it holds `page_id`
+        let start = (0 as PageID, 0, Box::new(PageInner::new(0)), false, len);
+        stream::unfold(Some(start), move |state| async move {
+            let (mut page_id, mut offset, mut page, mut loaded, len) = state?;
+            let len = match len {
+                Ok(len) => len,
+                Err(e) => return Some((Err(e), None)),
+            };
+
+            loop {
+                let page_start = page_id as usize * PAGE_SIZE;
+                if page_start >= len {
+                    return None;
+                }
+                // Less than a page if the file was cut short
+                let in_file = len - page_start;
+
+                if !loaded {
+                    page.clear(page_id);
+                    match self.read_page_checked(page_id, &mut page.data) {
+                        Err(e) if e.kind() != io::ErrorKind::InvalidData => {
+                            return Some((Err(e), None))
+                        }
+                        // A page cut short can't match, its entries tell how far it goes
+                        Err(e) if in_file >= PAGE_SIZE => {
+                            let e = io::Error::new(e.kind(), format!("page {page_id}: {e}"));
+                            return Some((Err(e), Some((page_id + 1, 0, page, false, Ok(len)))));
+                        }
+                        _ => {}
+                    }
+                    loaded = true;
+                }
+                let p = &page;
+
+                let at = self.key_data(page_id, offset as u64);
+                let truncated = || {
+                    let e = format!("entry at page {page_id} offset {offset} is truncated");
+                    io::Error::new(io::ErrorKind::UnexpectedEof, e)
+                };
+
+                match p.read_entry_raw(offset) {
+                    Ok(entry) if offset + entry.len() > in_file => {
+                        return Some((Err(truncated()), None))
+                    }
+                    Ok(entry) => {
+                        let next = offset + entry.len();
+                        let state = (page_id, next, page, loaded, Ok(len));
+                        return Some((Ok((at, entry)), Some(state)));
+                    }
+                    // A header cut off early enough reads as empty, but there is still data left
+                    Err(PageError::NoEntry)
+                        if in_file < PAGE_SIZE
+                            && p.data[offset.min(in_file)..in_file].iter().any(|b| *b != 0) =>
+                    {
+                        return Some((Err(truncated()), None))
+                    }
+                    Err(PageError::NoEntry) => {
+                        page_id += 1;
+                        offset = 0;
+                        loaded = false;
+                    }
+                    // The missing bytes read as zeros, which fail to validate
+                    Err(_) if in_file < PAGE_SIZE => return Some((Err(truncated()), None)),
+                    Err(e) => {
+                        let e = format!("page {page_id} offset {offset}: {e:?}");
+                        let e = io::Error::new(io::ErrorKind::InvalidData, e);
+                        return Some((Err(e), Some((page_id + 1, 0, page, false, Ok(len)))));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Rewrites the data file keeping only the entries `key_dir` still points to, dropping
+    /// tombstones, overwritten values and expired entries. `key_dir` is only write locked while
+    /// the compacted file is swapped in and the new locations are applied.
+    pub async fn compact(&mut self, key_dir: &RwLock<KeyDir>) -> io::Result<()> {
+        let pages = (self.len().await / PAGE_SIZE) as PageID;
+        let compaction = self.compact_pages(pages, key_dir).await?;
+
+        let mut kd = key_dir.write().await;
+        self.swap(compaction, &mut kd).await
+    }
+
+    /// First half of `compact`, writes the live entries of the pages before `end` to the
+    /// compacted file. The data file itself is left alone, so pages can still be written to while
+    /// this runs, as long as they come at or after `end`.
+    ///
+    /// List nodes are kept as long as they can be reached from a head, and pointed at where their
+    /// next node went. A node written at or after `end` could point at one that moves, so lists
+    /// must not be written to until the swap and none may be in the pages from `end` on.
+    pub async fn compact_pages(
+        &self,
+        end: PageID,
+        key_dir: &RwLock<KeyDir>,
+    ) -> io::Result<Compaction> {
+        let tmp = self.path.with_extension("compact");
+        SegmentManager::remove(&tmp)?;
+        let compacted = match self.segments.is_direct() {
+            true => Disk::open_direct(&tmp, self.segment_size()).await?,
+            false => Disk::with_segment_size(&tmp, self.segment_size()).await?,
+        };
+
+        let mut moved = Vec::new();
+        let mut expired = Vec::new();
+        let mut tombstones = 0;
+        let mut kept = 0;
+        let mut current = PageInner::new(0);
+        // Old location of every list node carried over to its new one
+        let mut relocated = HashMap::new();
+
+        let kd = key_dir.read().await;
+        let reachable = self.reachable_list_nodes(end, &kd)?;
+        let mut page = PageInner::new(0);
+        for page_id in 0..end {
+            page.clear(page_id);
+            self.read_page_into(page_id, &mut page.data)?;
+
+            let mut offset = 0;
+            loop {
+                let entry = match page.read_entry_raw(offset) {
+                    Ok(entry) => entry,
+                    Err(PageError::NoEntry) => break,
+                    Err(e) => {
+                        eprintln!("error: page {page_id} offset {offset}: {e:?}");
+                        break;
+                    }
+                };
+                let old = self.key_data(page_id, offset as u64);
+                offset += entry.len();
+
+                if entry.t == EntryType::Delete {
+                    tombstones += 1;
+                    continue;
+                }
+                let is_node = entry.t == EntryType::ListNode;
+                let live = match is_node {
+                    true => reachable.contains(&old),
+                    false => kd.get(&entry.key) == Some(&old),
+                };
+                if !live {
+                    continue;
+                }
+                if entry.is_expired() {
+                    expired.push((entry.key, old));
+                    continue;
+                }
+
+                // Next nodes come before the ones pointing at them, so they have already moved
+                let entry = match is_node {
+                    true => self.relink(entry, &relocated),
+                    false => entry,
+                };
+
+                let new_offset = match current.write_entry(&entry, None) {
+                    Ok(o) => o,
+                    Err(_) => {
+                        compacted.write_page(current.id, &current.data);
+                        let page_id = current.id + 1;
+                        current.clear(page_id);
+                        current
+                            .write_entry(&entry, None)
+                            .expect("new current should have space")
+                    }
+                };
+                kept += 1;
+                let new = compacted.key_data(current.id, new_offset);
+                if is_node {
+                    relocated.insert(old.clone(), new.clone());
+                }
+                // Only heads are in the key dir
+                if kd.get(&entry.key) == Some(&old) {
+                    moved.push((entry.key, old, new));
+                }
+            }
+        }
+        drop(kd);
+
+        if kept > 0 {
+            compacted.write_page(current.id, &current.data);
+        }
+
+        Ok(Compaction {
+            disk: compacted,
+            end,
+            kept,
+            moved,
+            expired,
+            tombstones,
+        })
+    }
+
+    /// Locations of the list nodes in the pages before `end` that can be reached from a head
+    /// `kd` points at, heads included.
+    fn reachable_list_nodes(&self, end: PageID, kd: &KeyDir) -> io::Result<HashSet<KeyData>> {
+        let mut heads = Vec::new();
+        let mut next = HashMap::new();
+        let mut page = PageInner::new(0);
+        for page_id in 0..end {
+            page.clear(page_id);
+            self.read_page_into(page_id, &mut page.data)?;
+
+            let mut offset = 0;
+            // Whatever fails to read here is reported by the compaction itself
+            while let Ok(entry) = page.read_entry_raw(offset) {
+                let at = self.key_data(page_id, offset as u64);
+                offset += entry.len();
+                if entry.t != EntryType::ListNode {
+                    continue;
+                }
+
+                if kd.get(&entry.key) == Some(&at) {
+                    heads.push(at.clone());
+                }
+                if let Some(node) = ListNode::decode(&entry.value) {
+                    next.insert(at, node.next.map(|(p, o)| self.key_data(p, o)));
+                }
+            }
+        }
+
+        let mut reachable = HashSet::new();
+        for head in heads {
+            let mut at = Some(head);
+            while let Some(node) = at.take() {
+                at = next.get(&node).cloned().flatten();
+                if !reachable.insert(node) {
+                    break;
+                }
+            }
+        }
+
+        Ok(reachable)
+    }
+
+    /// Points the list node in `entry` at where its next node was carried over to.
+    fn relink(&self, mut entry: Entry, relocated: &HashMap<KeyData, KeyData>) -> Entry {
+        let Some(mut node) = ListNode::decode(&entry.value) else {
+            return entry;
+        };
+        let Some((page_id, offset)) = node.next else {
+            return entry;
+        };
+
+        match relocated.get(&self.key_data(page_id, offset)) {
+            Some(new) => {
+                node.next = Some((new.page_id, new.offset));
+                entry.value = node.encode();
+            }
+            None => eprintln!("error: list node at page {page_id} offset {offset} went missing"),
+        }
+
+        entry
+    }
+
+    /// Second half of `compact`, swaps the compacted file in and points `kd` at the moved
+    /// entries. Pages from the compaction's `end` on are copied over unchanged, keeping their ids.
+    /// `kd` has to stay locked until the swap is done so nothing reads the old locations.
+    pub async fn swap(&mut self, compaction: Compaction, kd: &mut KeyDir) -> io::Result<()> {
+        let Compaction {
+            disk: compacted,
+            end,
+            moved,
+            expired,
+            tombstones,
+            ..
+        } = compaction;
+
+        // Live entries never take up more pages than they did before, so the copied pages can't
+        // collide with compacted ones
+        let pages = (self.len().await / PAGE_SIZE) as PageID;
+        for page_id in end..pages {
+            compacted.write_page(page_id, &self.read_page(page_id)?);
+        }
+        compacted.sync()?;
+
+        // Replace the old segments before the fds pointing at them are dropped
+        let Disk { segments, .. } = compacted;
+        let old = std::mem::replace(&mut self.segments, segments.rename_to(&self.path)?);
+        drop(old);
+
+        // Anything written since the scan already points somewhere else
+        for (key, old, new) in moved {
+            if kd.get(&key) == Some(&old) {
+                kd.insert(&key, new);
+            }
+        }
+        for (key, old) in expired {
+            if kd.get(&key) == Some(&old) {
+                kd.expire(&key);
+            }
+        }
+        kd.tombstones_dropped(tombstones);
+
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.segments.sync()
+    }
+}
+
+impl DiskBackend for Disk {
+    fn read_page_into(&self, page_id: PageID, buf: &mut [u8]) -> io::Result<()> {
+        self.read_page_into(page_id, buf)
+    }
+
+    fn write_page_from(&self, page_id: PageID, data: &[u8]) -> io::Result<()> {
+        self.write_page_from(page_id, data)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+/// Result of `Disk::compact_pages`, waiting to be swapped in.
+pub struct Compaction {
+    disk: Disk,
+    end: PageID,
+    moved: Vec<(BytesMut, KeyData, KeyData)>,
+    expired: Vec<(BytesMut, KeyData)>,
+    tombstones: usize,
+    kept: usize,
+}
+
+impl Compaction {
+    /// Number of entries carried over to the compacted file.
+    pub fn kept(&self) -> usize {
+        self.kept
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use futures_util::StreamExt;
+    use tokio::sync::RwLock;
+
+    use crate::storagev2::{
+        disk::{Disk, Durability, IncompatibleVersion, StorageVersion},
+        key_dir::bootstrap,
+        log::{Entry, EntryType},
+        page::{PageInner, PAGE_CHECKSUM_LEN, PAGE_SIZE},
+        segment::{segment_path, DIRECT_BLOCK, HEADER_LEN},
+        test::CleanUp,
+    };
+
+    #[tokio::test]
+    async fn test_storage_version() -> io::Result<()> {
+        const DB_FILE: &str = "./test_storage_version.db";
+        let _cu = CleanUp::segments(DB_FILE);
+
+        let disk = Disk::new(DB_FILE).await?;
+        disk.write_page(0, &[1; PAGE_SIZE]);
+        drop(disk);
+        let segment = segment_path(DB_FILE.as_ref(), 0);
+        let written = std::fs::read(&segment)?;
+        assert!(written[..8] == (StorageVersion::V1 as u64).to_be_bytes());
+        assert!(written.len() == HEADER_LEN as usize + PAGE_SIZE);
+
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(
+            disk.read_page(0)?[..PAGE_SIZE - PAGE_CHECKSUM_LEN]
+                == [1; PAGE_SIZE - PAGE_CHECKSUM_LEN]
+        );
+        drop(disk);
+        let err = Disk::migrate_v1_to_v2(DB_FILE).expect_err("there is no v2");
+        assert!(err.kind() == io::ErrorKind::Unsupported, "Got: {:?}", err);
+
+        // As a v2 file would be, which this build can't read
+        let mut v2 = written;
+        v2[..8].copy_from_slice(&2u64.to_be_bytes());
+        std::fs::write(&segment, &v2)?;
+        let err = match Disk::new(DB_FILE).await {
+            Ok(_) => panic!("a v2 file shouldn't open"),
+            Err(e) => e,
+        };
+        assert!(err.kind() == io::ErrorKind::InvalidData);
+        let expected = IncompatibleVersion {
+            found: 2,
+            supported: StorageVersion::V1,
+        };
+        let got = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<IncompatibleVersion>());
+        assert!(
+            got == Some(&expected),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+        assert!(
+            Disk::migrate_v1_to_v2(DB_FILE).is_err_and(|e| e.kind() == io::ErrorKind::InvalidData)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_page_checksum() -> io::Result<()> {
+        const DB_FILE: &str = "./test_page_checksum.db";
+        let _cu = CleanUp::segments(DB_FILE);
+
+        let disk = Disk::new(DB_FILE).await?;
+        let mut page = PageInner::new(0);
+        page.write_entry(&Entry::new(b"key", b"value", EntryType::Put), None)
+            .expect("should have space");
+        for page_id in 0..2 {
+            disk.write_page(page_id, &page.data);
+        }
+        let got = disk.read_page(0)?;
+        assert!(got[..PAGE_SIZE - PAGE_CHECKSUM_LEN] == page.data[..PAGE_SIZE - PAGE_CHECKSUM_LEN]);
+        assert!(got[PAGE_SIZE - PAGE_CHECKSUM_LEN..] == page.checksum().to_be_bytes());
+        // Never written, so there is nothing to check
+        assert!(disk.read_page(5)? == [0; PAGE_SIZE]);
+
+        // Flip the last byte of page 0, which is part of its checksum
+        let segment = segment_path(DB_FILE.as_ref(), 0);
+        let mut file = std::fs::read(&segment)?;
+        file[HEADER_LEN as usize + PAGE_SIZE - 1] ^= 0xFF;
+        std::fs::write(&segment, &file)?;
+
+        let err = disk.read_page(0).expect_err("checksum shouldn't match");
+        assert!(err.kind() == io::ErrorKind::InvalidData, "Got: {:?}", err);
+        assert!(err.to_string() == "page checksum mismatch", "Got: {}", err);
+        assert!(disk.read_page(1).is_ok());
+
+        // Scanning reports the page and carries on with the next one
+        let got: Vec<_> = disk.entries().collect().await;
+        assert!(got.len() == 2, "Got: {:?}", got);
+        assert!(got[0]
+            .as_ref()
+            .is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+        assert!(got[1].as_ref().is_ok_and(|(at, _)| at.page_id == 1));
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_punch_hole() -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        const DB_FILE: &str = "./test_punch_hole.db";
+        const PAGES: u32 = 512;
+        let _cu = CleanUp::segments(DB_FILE);
+
+        let disk = Disk::new(DB_FILE).await?;
+        for page_id in 0..PAGES {
+            disk.write_page(page_id, &[page_id as u8 | 1; PAGE_SIZE]);
+        }
+        disk.sync()?;
+        let segment = segment_path(DB_FILE.as_ref(), 0);
+        let before = std::fs::metadata(&segment)?;
+
+        // The first half of the pages, in whole 4 KiB blocks
+        let len = (PAGES / 2) as u64 * PAGE_SIZE as u64;
+        disk.punch_hole(0, len)?;
+        disk.sync()?;
+        let after = std::fs::metadata(&segment)?;
+        assert!(
+            after.blocks() < before.blocks(),
+            "\nExpected fewer than: {:?}\nGot: {:?}\n",
+            before.blocks(),
+            after.blocks()
+        );
+        assert!(after.len() == before.len());
+
+        // Punched pages read as never written, the rest are untouched
+        assert!(disk.read_page(0)? == [0; PAGE_SIZE]);
+        assert!(disk.read_page(PAGES / 2 - 1)? == [0; PAGE_SIZE]);
+        let got = disk.read_page(PAGES / 2)?;
+        assert!(got[0] == (PAGES / 2) as u8 | 1, "Got: {}", got[0]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_durability() -> io::Result<()> {
+        const DB_FILE: &str = "./test_durability.db";
+        let _cu = CleanUp::segments(DB_FILE);
+
+        for durability in [Durability::None, Durability::FsyncData, Durability::Fsync] {
+            let disk = Disk::new(DB_FILE).await?.with_durability(durability);
+            assert!(disk.durability() == durability);
+
+            let data = [durability as u8 + 1; PAGE_SIZE];
+            disk.write_page_from(durability as u32, &data)?;
+            let mut got = [0; PAGE_SIZE];
+            disk.read_page_into(durability as u32, &mut got)?;
+            assert!(
+                got == data,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                durability,
+                got[0]
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_o_direct() -> io::Result<()> {
+        const DB_FILE: &str = "./test_o_direct.db";
+        // Test pages are smaller than a block, so this reads and writes pages sized at runtime
+        const DIRECT_PAGE_SIZE: usize = 2 * DIRECT_BLOCK;
+        let _cu = CleanUp::segments(DB_FILE);
+
+        let disk = Disk::new_o_direct(DB_FILE).await?;
+        for page_id in 0..4 {
+            disk.write_page_from(page_id, &[page_id as u8 + 1; DIRECT_PAGE_SIZE])?;
+        }
+        disk.sync()?;
+        drop(disk);
+
+        let disk = Disk::new_o_direct(DB_FILE).await?;
+        let mut buf = [0; DIRECT_PAGE_SIZE];
+        for page_id in (0..4).rev() {
+            disk.read_page_into(page_id, &mut buf)?;
+            assert!(
+                buf == [page_id as u8 + 1; DIRECT_PAGE_SIZE],
+                "page {}",
+                page_id
+            );
+        }
+        // Past the end of the file
+        disk.read_page_into(10, &mut buf)?;
+        assert!(buf == [1; DIRECT_PAGE_SIZE]);
+
+        let got = disk.read_page_into(0, &mut [0; 100]).map_err(|e| e.kind());
+        assert!(got == Err(io::ErrorKind::InvalidInput), "Got: {:?}", got);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compact.db";
+        let _cu = CleanUp::segments(DB_FILE);
+        let mut disk = Disk::new(DB_FILE).await?;
+
+        let puts = (0..1000).map(|i| {
+            let key = format!("key_{}", i);
+            let value = format!("value_{}", i);
+            Entry::new(key.as_bytes(), value.as_bytes(), EntryType::Put)
+        });
+        let deletes = (0..1000).step_by(2).map(|i| {
+            let key = format!("key_{}", i);
+            Entry::new(key.as_bytes(), &[], EntryType::Delete)
+        });
+
+        let mut current = PageInner::new(0);
+        for e in puts.chain(deletes) {
+            if current.write_entry(&e, None).is_err() {
+                disk.write_page(current.id, &current.data);
+                current = PageInner::new(current.id + 1);
+                current
+                    .write_entry(&e, None)
+                    .expect("new current should have space");
+            }
+        }
+        disk.write_page(current.id, &current.data);
+
+        let (key_dir, _, _) = bootstrap(&disk).await;
+        let key_dir = RwLock::new(key_dir);
+
+        let before = disk.len().await;
+        assert!(key_dir.read().await.count_deleted() == 500);
+        disk.compact(&key_dir).await?;
+        let after = disk.len().await;
+        assert!(after < before, "\nBefore: {}\n After: {}\n", before, after);
+
+        let key_dir = key_dir.read().await;
+        assert!(key_dir.len() == 500);
+        assert!(key_dir.count_deleted() == 0);
+        for i in 0..1000 {
+            let key = format!("key_{}", i);
+            let Some(data) = key_dir.get(key.as_bytes()) else {
+                assert!(i % 2 == 0, "{} should be live", key);
+                continue;
+            };
+            assert!(i % 2 == 1, "{} should have been deleted", key);
+
+            let mut page = PageInner::new(data.page_id);
+            page.data = disk.read_page(data.page_id)?;
+            let entry = page
+                .read_entry(data.offset as usize)
+                .expect("live entry should be readable");
+
+            assert!(entry.key == key.as_bytes());
+            assert!(entry.value == format!("value_{}", i).as_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_entries() -> io::Result<()> {
+        const DB_FILE: &str = "./test_entries.db";
+        let _cu = CleanUp::segments(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+
+        let entries: Vec<_> = (0..20)
+            .map(|i| {
+                let key = format!("key_{}", i);
+                Entry::new(key.as_bytes(), b"value", EntryType::Put)
+            })
+            .collect();
+
+        let mut written = Vec::new();
+        let mut current = PageInner::new(0);
+        for e in &entries {
+            let offset = match current.write_entry(e, None) {
+                Ok(o) => o,
+                Err(_) => {
+                    disk.write_page(current.id, &current.data);
+                    current = PageInner::new(current.id + 1);
+                    current
+                        .write_entry(e, None)
+                        .expect("new current should have space")
+                }
+            };
+            written.push(disk.key_data(current.id, offset));
+        }
+        disk.write_page(current.id, &current.data);
+        assert!(current.id > 0);
+
+        let got: Vec<_> = disk.entries().collect().await;
+        assert!(got.len() == entries.len(), "Got: {}", got.len());
+        for ((got, e), at) in got.into_iter().zip(&entries).zip(&written) {
+            let (got_at, got) = got?;
+            assert!(
+                (&got_at, &got) == (at, e),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                (at, e),
+                (got_at, got)
+            );
+        }
+
+        // Cut the last entry short, everything before it still comes through
+        let last = written.last().expect("should have written entries");
+        let end = HEADER_LEN + last.page_id as u64 * PAGE_SIZE as u64 + last.offset + 3;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(segment_path(DB_FILE.as_ref(), 0))?
+            .set_len(end)?;
+
+        let got: Vec<_> = disk.entries().collect().await;
+        assert!(got.len() == entries.len(), "Got: {}", got.len());
+        assert!(got[..entries.len() - 1].iter().all(|r| r.is_ok()));
+        let err = got
+            .last()
+            .unwrap()
+            .as_ref()
+            .expect_err("should be truncated");
+        assert!(err.kind() == io::ErrorKind::UnexpectedEof, "Got: {:?}", err);
+
+        Ok(())
     }
 }
@@ -0,0 +1,271 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::atomic::{AtomicU32, Ordering::*},
+};
+
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::storagev2::{
+    disk::Disk,
+    page::{DynPage, PageID},
+    page_manager::{PageManagerTrait, DEFAULT_READ_SIZE},
+    replacer::{LRUKHandle, DEFAULT_K},
+};
+
+pub struct DynPin<'a> {
+    pub page: &'a RwLock<DynPage>,
+    i: Option<usize>,
+    replacer: LRUKHandle,
+}
+
+impl Drop for DynPin<'_> {
+    fn drop(&mut self) {
+        if let Some(i) = self.i {
+            tokio::task::block_in_place(|| {
+                self.replacer.blocking_unpin(i);
+            });
+        }
+    }
+}
+
+impl<'a> DynPin<'a> {
+    pub async fn write(&self) -> RwLockWriteGuard<'_, DynPage> {
+        self.page.write().await
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, DynPage> {
+        self.page.read().await
+    }
+
+    /// Unpins without blocking the thread, instead of waiting for drop to do it.
+    pub async fn unpin(mut self) {
+        if let Some(i) = self.i.take() {
+            self.replacer.unpin(i).await;
+        }
+    }
+}
+
+/// Page manager for a page size picked at runtime. Unlike `PageCache` there is no dedicated write
+/// page, every page lives in one of the read frames.
+pub struct DynPageManager<const K: usize = DEFAULT_K> {
+    disk: Disk,
+    page_size: usize,
+    page_table: RwLock<HashMap<PageID, usize>>,
+    frames: Vec<RwLock<DynPage>>,
+    free: Mutex<Vec<usize>>,
+    dirty: Mutex<HashSet<usize>>,
+    next_id: AtomicU32,
+    replacer: LRUKHandle,
+}
+
+impl<const K: usize> DynPageManager<K> {
+    pub fn with_page_size(disk: Disk, page_size: usize) -> Self {
+        let next_id = disk
+            .page_count(page_size)
+            .expect("Couldn't read db file size");
+        let next_id = AtomicU32::new(next_id as PageID);
+        let page_table = RwLock::new(HashMap::new());
+        let frames = (0..DEFAULT_READ_SIZE)
+            .map(|_| RwLock::new(DynPage::new(0, page_size)))
+            .collect();
+        let free = Mutex::new((0..DEFAULT_READ_SIZE).rev().collect());
+        let dirty = Mutex::new(HashSet::new());
+        let replacer = LRUKHandle::new::<K>();
+
+        Self {
+            disk,
+            page_size,
+            page_table,
+            frames,
+            free,
+            dirty,
+            next_id,
+            replacer,
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub async fn fetch_page(&self, page_id: PageID) -> Option<DynPin<'_>> {
+        if let Some(i) = self.page_table.read().await.get(&page_id) {
+            self.replacer.record_access(*i).await;
+            self.replacer.pin(*i).await;
+
+            return Some(self.pin(*i));
+        }
+
+        let (i, mut page) = self.replace_page(page_id).await?;
+        self.disk
+            .read_page_into(page_id, &mut page.data)
+            .expect("Couldn't read page");
+        drop(page);
+
+        Some(self.pin(i))
+    }
+
+    /// Same as `fetch_page`, but marks the page as dirty so it is written back to disk before its
+    /// frame is reused.
+    pub async fn fetch_page_mut(&self, page_id: PageID) -> Option<DynPin<'_>> {
+        let pin = self.fetch_page(page_id).await?;
+        if let Some(i) = pin.i {
+            self.dirty.lock().await.insert(i);
+        }
+
+        Some(pin)
+    }
+
+    pub async fn new_page(&self) -> Option<PageID> {
+        let page_id = self.next_id.fetch_add(1, SeqCst);
+
+        let (i, page) = self.replace_page(page_id).await?;
+        if let Err(e) = self.disk.write_page_from(page.id, &page.data) {
+            eprintln!("error: could not write new page {page_id}: {e}");
+        }
+        drop(page);
+
+        // Release the pin taken by replace_page
+        self.pin(i).unpin().await;
+
+        Some(page_id)
+    }
+
+    /// Writes every dirty page back to disk. The pages stay cached, but are clean afterwards.
+    pub async fn flush_all(&self) -> io::Result<()> {
+        let mut dirty = self.dirty.lock().await;
+        for i in dirty.drain() {
+            let page = self.frames[i].read().await;
+            self.disk.write_page_from(page.id, &page.data)?;
+        }
+
+        Ok(())
+    }
+
+    fn pin(&self, i: usize) -> DynPin<'_> {
+        DynPin {
+            page: &self.frames[i],
+            i: Some(i),
+            replacer: self.replacer.clone(),
+        }
+    }
+
+    /// Claims a pinned frame for `page_id`, evicting if there are no free frames. The page
+    /// previously held by the frame is written back first if it is dirty.
+    async fn replace_page(
+        &self,
+        page_id: PageID,
+    ) -> Option<(usize, RwLockWriteGuard<'_, DynPage>)> {
+        let i = match self.free.lock().await.pop() {
+            Some(i) => i,
+            None => self.replacer.evict().await?,
+        };
+        self.replacer.remove(i).await;
+        self.replacer.record_access(i).await;
+        self.replacer.pin(i).await;
+
+        let mut page = self.frames[i].write().await;
+        let mut page_table = self.page_table.write().await;
+
+        if page_table.get(&page.id) == Some(&i) {
+            page_table.remove(&page.id);
+        }
+        if self.dirty.lock().await.remove(&i) {
+            if let Err(e) = self.disk.write_page_from(page.id, &page.data) {
+                eprintln!("error: could not write back page {}: {e}", page.id);
+            }
+        }
+
+        page.clear(page_id);
+        page_table.insert(page_id, i);
+
+        Some((i, page))
+    }
+}
+
+impl<const K: usize> PageManagerTrait for DynPageManager<K> {
+    type Pin<'a> = DynPin<'a>;
+
+    async fn fetch_page(&self, page_id: PageID) -> Option<DynPin<'_>> {
+        self.fetch_page(page_id).await
+    }
+
+    async fn new_page(&self) -> Option<PageID> {
+        self.new_page().await
+    }
+
+    async fn unpin_page<'a>(&'a self, pin: DynPin<'a>) {
+        pin.unpin().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        disk::Disk,
+        dyn_page_manager::DynPageManager,
+        log::{Entry, EntryType},
+        page::{PageError, PAGE_SIZE},
+        page_manager::PageManagerTrait,
+        test::CleanUp,
+    };
+
+    async fn create_page<M: PageManagerTrait>(m: &M) -> Option<u32> {
+        let page_id = m.new_page().await?;
+        let pin = m.fetch_page(page_id).await?;
+        m.unpin_page(pin).await;
+
+        Some(page_id)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dyn_page_size() -> io::Result<()> {
+        const DB_FILE: &str = "./test_dyn_page_size.db";
+        const DYN_PAGE_SIZE: usize = PAGE_SIZE * 4;
+        let _cu = CleanUp::segments(DB_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+
+        let m = DynPageManager::<2>::with_page_size(disk, DYN_PAGE_SIZE);
+        let page_id = create_page(&m).await.expect("should have space for page");
+
+        let pin = m.fetch_page_mut(page_id).await.expect("should fetch page");
+        let mut written = Vec::new();
+        {
+            let mut page = pin.write().await;
+            assert!(page.capacity() == DYN_PAGE_SIZE);
+
+            for i in 0.. {
+                let entry = Entry::new(format!("key_{}", i).as_bytes(), b"value", EntryType::Put);
+                match page.write_entry(&entry, None) {
+                    Ok(offset) => written.push((offset, entry)),
+                    Err(PageError::NotEnoughSpace) => break,
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+        }
+        m.unpin_page(pin).await;
+        assert!(written.iter().map(|(_, e)| e.len()).sum::<usize>() > PAGE_SIZE);
+
+        m.flush_all().await?;
+        drop(m);
+
+        let disk = Disk::new(DB_FILE).await?;
+        let m = DynPageManager::<2>::with_page_size(disk, DYN_PAGE_SIZE);
+        let pin = m.fetch_page(page_id).await.expect("should fetch page");
+        let page = pin.read().await;
+        for (offset, entry) in written {
+            let got = page.read_entry(offset as usize);
+            assert!(
+                got.as_ref() == Ok(&entry),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                entry,
+                got
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -1,103 +1,542 @@
-use std::collections::HashMap;
+use std::{
+    cmp,
+    collections::BTreeMap,
+    fs, io,
+    ops::Bound::{Excluded, Included, Unbounded},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use bytes::BytesMut;
+use bytes::{Buf, BufMut, BytesMut};
+use futures_util::{future::ready, StreamExt};
 
 use crate::storagev2::{
+    bloom::BloomFilter,
     disk::Disk,
     log::EntryType,
-    page::{Page, PageID, PAGE_SIZE},
+    page::{Page, PageID, PageInner, PAGE_SIZE},
+    segment::FileID,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct KeyData {
+    pub file_id: FileID,
     pub page_id: PageID,
     pub offset: u64,
 }
 
 impl KeyData {
-    pub fn new(page_id: PageID, offset: u64) -> Self {
-        Self { page_id, offset }
+    pub fn new(file_id: FileID, page_id: PageID, offset: u64) -> Self {
+        Self {
+            file_id,
+            page_id,
+            offset,
+        }
     }
 }
 
-type KeyDirMap = HashMap<BytesMut, KeyData>;
+type KeyDirMap = BTreeMap<BytesMut, KeyData>;
 
-#[derive(Debug, PartialEq)]
+// The bloom filter is sized for twice the keys present when it is built, but never for fewer
+// than BLOOM_CAPACITY
+const BLOOM_CAPACITY: usize = 1 << 16;
+const BLOOM_RATE: f64 = 0.01;
+
+#[derive(Debug)]
 pub struct KeyDir {
     inner: KeyDirMap,
+    bloom: BloomFilter,
+    // Tombstones in the data file that compaction hasn't dropped yet
+    deleted: usize,
+    // Keys dropped by `expire`, only kept in memory
+    expired: usize,
+    // Live keys, counted apart from the map for `len_approx`
+    len: Arc<AtomicUsize>,
+}
+
+// A clone counts its own keys, a snapshot's count shouldn't follow the key dir it was taken of
+impl Clone for KeyDir {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            bloom: self.bloom.clone(),
+            deleted: self.deleted,
+            expired: self.expired,
+            len: Arc::new(AtomicUsize::new(self.inner.len())),
+        }
+    }
+}
+
+impl PartialEq for KeyDir {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl Default for KeyDir {
+    fn default() -> Self {
+        Self::from_map(KeyDirMap::new())
+    }
 }
 
 impl KeyDir {
+    fn from_map(inner: KeyDirMap) -> Self {
+        let capacity = cmp::max(inner.len() * 2, BLOOM_CAPACITY);
+        let mut bloom = BloomFilter::with_rate(capacity, BLOOM_RATE);
+        for k in inner.keys() {
+            bloom.insert(k);
+        }
+
+        Self {
+            len: Arc::new(AtomicUsize::new(inner.len())),
+            inner,
+            bloom,
+            deleted: 0,
+            expired: 0,
+        }
+    }
+
     pub fn get(&self, k: &[u8]) -> Option<&KeyData> {
+        if !self.bloom.contains(k) {
+            return None;
+        }
+
         self.inner.get(k)
     }
 
     pub fn insert(&mut self, k: &[u8], v: KeyData) -> Option<KeyData> {
+        self.bloom.insert(k);
         let k = BytesMut::from(k);
 
-        self.inner.insert(k, v)
+        let old = self.inner.insert(k, v);
+        if old.is_none() {
+            self.len.fetch_add(1, Ordering::Release);
+        }
+
+        old
     }
 
+    /// Call once per tombstone written, whether or not the key was present. The key's bits are
+    /// left set in the bloom filter, so removed keys count towards `bloom_false_positive_rate`
+    /// until the next bootstrap.
     pub fn remove(&mut self, k: &[u8]) -> Option<KeyData> {
-        self.inner.remove(k)
+        self.deleted += 1;
+        let removed = self.inner.remove(k);
+        if removed.is_some() {
+            self.len.fetch_sub(1, Ordering::Release);
+        }
+
+        removed
+    }
+
+    /// Adds every key in `other`, keeping whichever location of a key in both was written later.
+    /// Entries are only ever appended, so that is the one further into the data file. Tombstones
+    /// aren't kept in a key dir, so a key deleted in `other`'s part of the file stays if `self`
+    /// has it.
+    pub fn merge(&mut self, other: KeyDir) {
+        for (k, v) in other.inner {
+            match self.inner.get(&k[..]) {
+                Some(cur) if (cur.page_id, cur.offset) >= (v.page_id, v.offset) => {}
+                _ => {
+                    self.insert(&k, v);
+                }
+            }
+        }
+
+        self.deleted += other.deleted;
+        self.expired += other.expired;
+    }
+
+    /// Removes every key, handing them back in order. Nothing is counted, call `remove` for each
+    /// tombstone written afterwards.
+    pub fn take_keys(&mut self) -> Vec<BytesMut> {
+        self.len.store(0, Ordering::Release);
+        std::mem::take(&mut self.inner).into_keys().collect()
+    }
+
+    /// Number of live keys, counted by the map itself.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Number of live keys from a counter updated on every write, so reading it doesn't touch the
+    /// map. Meant for figures that are only reported, like `dbsize` and `info`. Callers that have
+    /// to agree exactly with the keys they go on to look at should use `len`.
+    pub fn len_approx(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    /// The counter `len_approx` reads, so it can be read without taking the key dir's lock. It
+    /// keeps counting this key dir's keys through `replace`.
+    pub fn len_handle(&self) -> Arc<AtomicUsize> {
+        self.len.clone()
+    }
+
+    /// Swaps in `other`'s keys, keeping the counter `len_handle` handed out.
+    pub fn replace(&mut self, other: KeyDir) {
+        let len = self.len.clone();
+        *self = other;
+        len.store(self.inner.len(), Ordering::Release);
+        self.len = len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Number of tombstones still in the data file, which compaction would drop.
+    pub fn count_deleted(&self) -> usize {
+        self.deleted
+    }
+
+    /// Removes a key whose entry expired, unlike `remove` there is no tombstone for it.
+    pub fn expire(&mut self, k: &[u8]) -> Option<KeyData> {
+        let removed = self.inner.remove(k);
+        if removed.is_some() {
+            self.expired += 1;
+            self.len.fetch_sub(1, Ordering::Release);
+        }
+
+        removed
+    }
+
+    /// Number of keys removed by `expire` since this key dir was loaded.
+    pub fn count_expired(&self) -> usize {
+        self.expired
+    }
+
+    /// Rough number of bytes the keys, their locations and the bloom filter take, not counting
+    /// the map's own bookkeeping.
+    pub fn memory_usage(&self) -> usize {
+        let entries: usize = self
+            .inner
+            .keys()
+            .map(|k| k.capacity() + size_of::<(BytesMut, KeyData)>())
+            .sum();
+
+        entries + self.bloom.memory_usage()
+    }
+
+    /// Called by compaction once it has dropped `n` tombstones.
+    pub fn tombstones_dropped(&mut self, n: usize) {
+        self.deleted = self.deleted.saturating_sub(n);
+    }
+
+    pub fn bloom_false_positive_rate(&self) -> f64 {
+        self.bloom.false_positive_rate()
+    }
+
+    /// Iterates over the keys in `[start, end)` in lexicographic order. An empty `end` leaves the
+    /// range unbounded, and a `start` at or past `end` yields nothing.
+    pub fn scan<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl Iterator<Item = (&'a [u8], &'a KeyData)> {
+        let end = match end.is_empty() {
+            true => Unbounded,
+            false => Excluded(cmp::max(start, end)),
+        };
+
+        self.inner
+            .range::<[u8], _>((Included(start), end))
+            .map(|(k, v)| (&k[..], v))
+    }
+
+    /// Iterates over every key starting with `prefix` in lexicographic order. An empty prefix
+    /// matches every key.
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &[u8],
+    ) -> impl Iterator<Item = (&'a [u8], &'a KeyData)> {
+        self.scan(prefix, &prefix_end(prefix))
+    }
+
+    /// Iterates over every key matching the glob `pattern` in lexicographic order, see
+    /// `glob_match`. Only the literal prefix before the first wildcard narrows the range, so a
+    /// pattern starting with a wildcard visits every key.
+    pub fn matching<'a>(
+        &'a self,
+        pattern: &'a [u8],
+    ) -> impl Iterator<Item = (&'a [u8], &'a KeyData)> {
+        let literal = pattern
+            .iter()
+            .position(|c| matches!(c, b'*' | b'?' | b'\\'))
+            .unwrap_or(pattern.len());
+
+        self.scan_prefix(&pattern[..literal])
+            .filter(move |(k, _)| glob_match(pattern, k))
     }
+
+    /// Saves every key's location so the next bootstrap doesn't have to scan the data file. The
+    /// tombstone count (u64) comes first, then each record is file_id (u32), page_id (u32), offset
+    /// (u64), key length (u32) and the key, followed by a CRC32 of everything before it. Written to a
+    /// temporary file first so a crash never leaves a partial hint.
+    pub fn write_hint_file(&self, path: &Path) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.deleted as u64);
+        for (k, data) in &self.inner {
+            buf.put_u32(data.file_id);
+            buf.put_u32(data.page_id);
+            buf.put_u64(data.offset);
+            buf.put_u32(k.len() as u32);
+            buf.put_slice(k);
+        }
+        let checksum = crc32fast::hash(&buf);
+        buf.put_u32(checksum);
+
+        let tmp = path.with_extension("hint.tmp");
+        fs::write(&tmp, &buf)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn read_hint_file(path: &Path) -> io::Result<KeyDir> {
+        let buf = fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupt hint file");
+
+        if buf.len() < 4 {
+            return Err(invalid());
+        }
+        let (records, mut checksum) = buf.split_at(buf.len() - 4);
+        if checksum.get_u32() != crc32fast::hash(records) {
+            return Err(invalid());
+        }
+
+        let mut src = records;
+        if src.remaining() < 8 {
+            return Err(invalid());
+        }
+        let deleted = src.get_u64() as usize;
+
+        let mut inner = KeyDirMap::new();
+        while src.has_remaining() {
+            if src.remaining() < 20 {
+                return Err(invalid());
+            }
+            let file_id = src.get_u32();
+            let page_id = src.get_u32();
+            let offset = src.get_u64();
+            let len = src.get_u32() as usize;
+            if src.remaining() < len {
+                return Err(invalid());
+            }
+
+            inner.insert(
+                BytesMut::from(&src[..len]),
+                KeyData::new(file_id, page_id, offset),
+            );
+            src.advance(len);
+        }
+
+        let mut kd = Self::from_map(inner);
+        kd.deleted = deleted;
+
+        Ok(kd)
+    }
+}
+
+/// Where the hint file for the data file at `db` lives.
+pub fn hint_path(db: &Path) -> PathBuf {
+    db.with_extension("hint")
+}
+
+/// The smallest key greater than every key starting with `prefix`, found by incrementing the last
+/// byte that isn't 0xFF and dropping everything after it, e.g. `[1, 2, 0xFF]` -> `[1, 3]`. If
+/// there is no such byte (empty or all 0xFF) every key past `prefix` matches, so an empty
+/// (unbounded) end is returned.
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let Some(i) = prefix.iter().rposition(|b| *b != 0xFF) else {
+        return Vec::new();
+    };
+
+    let mut end = prefix[..=i].to_vec();
+    end[i] += 1;
+    end
+}
+
+/// Whether all of `key` matches `pattern`, where `*` matches any run of bytes, `?` any single byte
+/// and `\\` matches the byte after it literally.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Pattern position after the last `*` and the key position it was tried at, to backtrack to
+    let mut star = None;
+
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'\\') => (pattern.get(p + 1) == Some(&key[k])).then_some(2),
+            Some(c) => (*c == key[k]).then_some(1),
+            None => None,
+        };
+
+        match (step, star) {
+            (Some(n), _) => {
+                p += n;
+                k += 1;
+            }
+            // Let the last `*` swallow one more byte
+            (None, Some((star_p, star_k))) => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, star_k + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Loads the hint file if it was written after the data file was last modified, otherwise it may
+/// be missing entries.
+fn read_fresh_hint(disk: &Disk) -> io::Result<Option<KeyDir>> {
+    let hint = hint_path(disk.path());
+    let hint_modified = match fs::metadata(&hint) {
+        Ok(m) => m.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if hint_modified <= disk.modified()? {
+        return Ok(None);
+    }
+
+    KeyDir::read_hint_file(&hint).map(Some)
 }
 
 pub async fn bootstrap(disk: &Disk) -> (KeyDir, Page, PageID) {
     let len = disk.len().await;
     let pages = len / PAGE_SIZE;
 
-    let page = Page::default();
-    let mut page_w = page.write().await;
-    let mut inner = HashMap::new();
-    for page_id in 0..pages as u32 {
-        page_w.data = disk.read_page(page_id).expect("should read page");
-        page_w.id = page_id;
-        // Could probably get away with not fully resetting the page on each iteration
-
-        let mut offset = 0;
-        while let Some(entry) = page_w.read_entry(offset) {
-            match entry.t {
-                EntryType::Put => {
-                    inner.insert(
-                        entry.key.clone(),
-                        KeyData {
-                            page_id,
-                            offset: offset as u64,
-                        },
-                    );
-                }
-                EntryType::Delete => {
-                    inner.remove(&entry.key);
-                }
-            };
+    let latest_id = pages.saturating_sub(1) as PageID;
+    let page = Page::new(latest_id);
+    if pages > 0 {
+        // Not checked, a write torn by a crash is expected here and `from_bytes` stops before it
+        let mut data = [0; PAGE_SIZE];
+        disk.read_page_into(latest_id, &mut data)
+            .expect("should read page");
+        *page.write().await = PageInner::from_bytes(latest_id, data);
+    }
 
-            offset = offset + entry.len();
-        }
+    match read_fresh_hint(disk) {
+        Ok(Some(kd)) => return (kd, page, latest_id),
+        Ok(None) => {}
+        // Scanning the data file always works, so a bad hint isn't fatal
+        Err(e) => eprintln!("error: could not read hint file: {e}"),
+    }
+
+    (rebuild(disk).await, page, latest_id)
+}
+
+/// Scans the data file for the key dir, ignoring any hint file.
+pub async fn rebuild(disk: &Disk) -> KeyDir {
+    let pages = disk.len().await / PAGE_SIZE;
+
+    let mut inner = BTreeMap::new();
+    let mut deleted = 0;
+    // A partial page at the end of the file is left out, it gets overwritten by the next page
+    let entries = disk
+        .entries()
+        .take_while(|r| ready(!matches!(r, Ok((at, _)) if at.page_id as usize >= pages)));
+    tokio::pin!(entries);
+    while let Some(res) = entries.next().await {
+        let (at, entry) = match res {
+            Ok(e) => e,
+            Err(e) => {
+                // The rest of the page can't be trusted once an entry fails to validate
+                eprintln!("error: {e}");
+                continue;
+            }
+        };
+
+        // The latest node under a list's key is its head
+        match entry.t {
+            EntryType::Put | EntryType::ListNode => {
+                inner.insert(entry.key, at);
+            }
+            EntryType::Delete => {
+                inner.remove(&entry.key);
+                deleted += 1;
+            }
+        };
     }
 
-    let latest_id = page_w.id;
-    drop(page_w);
+    let mut kd = KeyDir::from_map(inner);
+    kd.deleted = deleted;
 
-    (KeyDir { inner }, page, latest_id)
+    kd
 }
 
 #[cfg(test)]
 mod test {
-    use std::{collections::HashMap, io};
+    use std::{
+        collections::BTreeMap,
+        io,
+        path::Path,
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use proptest::{collection::vec, prelude::*};
+    use tokio::sync::RwLock;
 
     use crate::storagev2::{
         disk::Disk,
-        key_dir::{bootstrap, KeyData, KeyDir},
+        key_dir::{bootstrap, glob_match, hint_path, prefix_end, KeyData, KeyDir},
         log::{Entry, EntryType},
-        page::PageInner,
+        page::{PageInner, PAGE_SIZE},
         test::CleanUp,
     };
 
+    #[tokio::test]
+    async fn test_bootstrap_segments() -> io::Result<()> {
+        const DB_FILE: &str = "./test_bootstrap_segments.db";
+        let _cu = CleanUp::segments(DB_FILE);
+        let disk = Disk::with_segment_size(DB_FILE, 2 * PAGE_SIZE as u64).await?;
+
+        let mut current = PageInner::new(0);
+        for i in 0..40 {
+            let key = format!("key_{}", i);
+            let e = Entry::new(key.as_bytes(), b"value", EntryType::Put);
+            if current.write_entry(&e, None).is_err() {
+                disk.write_page(current.id, &current.data);
+                current = PageInner::new(current.id + 1);
+                current.write_entry(&e, None).expect("should have space");
+            }
+        }
+        disk.write_page(current.id, &current.data);
+        assert!(
+            disk.file_id(current.id) > 1,
+            "Got: {}",
+            disk.file_id(current.id)
+        );
+
+        let (key_dir, _, latest_id) = bootstrap(&disk).await;
+        assert!(latest_id == current.id);
+        assert!(key_dir.len() == 40, "Got: {}", key_dir.len());
+        for (_, data) in key_dir.scan(b"", b"") {
+            assert!(
+                data.file_id == disk.file_id(data.page_id),
+                "Got: {:?}",
+                data
+            );
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_bootstrap() -> io::Result<()> {
         const DB_FILE: &str = "./test_bootstrap.db";
-        let _cu = CleanUp::file(DB_FILE);
+        let _cu = CleanUp::segments(DB_FILE);
         let disk = Disk::new(DB_FILE).await?;
 
         let entries = [
@@ -115,12 +554,12 @@ mod test {
         let mut current_id = 0;
         let mut current = PageInner::new(current_id);
         for e in entries {
-            if let Err(_) = current.write_entry(&e) {
+            if current.write_entry(&e, None).is_err() {
                 disk.write_page(current.id, &current.data);
                 current_id += 1;
                 current = PageInner::new(current_id);
                 current
-                    .write_entry(&e)
+                    .write_entry(&e, None)
                     .expect("new current should have space");
             }
         }
@@ -128,38 +567,40 @@ mod test {
 
         let (key_dir, _, _) = bootstrap(&disk).await;
 
-        let expected = KeyDir {
-            inner: HashMap::from([
-                (
-                    "key2".into(),
-                    KeyData {
-                        page_id: 0,
-                        offset: 35,
-                    },
-                ),
-                (
-                    "key3".into(),
-                    KeyData {
-                        page_id: 0,
-                        offset: 70,
-                    },
-                ),
-                (
-                    "key4".into(),
-                    KeyData {
-                        page_id: 1,
-                        offset: 0,
-                    },
-                ),
-                (
-                    "key5".into(),
-                    KeyData {
-                        page_id: 1,
-                        offset: 35,
-                    },
-                ),
-            ]),
-        };
+        let expected = KeyDir::from_map(BTreeMap::from([
+            (
+                "key2".into(),
+                KeyData {
+                    file_id: 0,
+                    page_id: 0,
+                    offset: 47,
+                },
+            ),
+            (
+                "key3".into(),
+                KeyData {
+                    file_id: 0,
+                    page_id: 0,
+                    offset: 94,
+                },
+            ),
+            (
+                "key4".into(),
+                KeyData {
+                    file_id: 0,
+                    page_id: 1,
+                    offset: 94,
+                },
+            ),
+            (
+                "key5".into(),
+                KeyData {
+                    file_id: 0,
+                    page_id: 1,
+                    offset: 141,
+                },
+            ),
+        ]));
 
         assert!(
             key_dir == expected,
@@ -167,7 +608,237 @@ mod test {
             expected,
             key_dir,
         );
+        assert!(key_dir.len() == 4 && !key_dir.is_empty());
+        assert!(key_dir.count_deleted() == 2);
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_hint_file() -> io::Result<()> {
+        const DB_FILE: &str = "./test_hint_file.db";
+        const HINT_FILE: &str = "./test_hint_file.hint";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_hint = CleanUp::file(HINT_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        assert!(hint_path(Path::new(DB_FILE)) == Path::new(HINT_FILE));
+
+        let mut current = PageInner::new(0);
+        for i in 0..20 {
+            let key = format!("key_{}", i);
+            let e = match i % 3 {
+                2 => Entry::new(format!("key_{}", i - 1).as_bytes(), &[], EntryType::Delete),
+                _ => Entry::new(key.as_bytes(), b"value", EntryType::Put),
+            };
+            if current.write_entry(&e, None).is_err() {
+                disk.write_page(current.id, &current.data);
+                current = PageInner::new(current.id + 1);
+                current
+                    .write_entry(&e, None)
+                    .expect("new current should have space");
+            }
+        }
+        disk.write_page(current.id, &current.data);
+
+        let (scanned, _, scanned_id) = bootstrap(&disk).await;
+
+        // Modification times are only as fine grained as the kernel's clock tick
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        scanned.write_hint_file(Path::new(HINT_FILE))?;
+
+        let got = KeyDir::read_hint_file(Path::new(HINT_FILE))?;
+        assert!(
+            got == scanned,
+            "\nExpected: {:?}\n     Got: {:?}\n",
+            scanned,
+            got,
+        );
+
+        let (hinted, _, hinted_id) = bootstrap(&disk).await;
+        assert!(
+            hinted == scanned,
+            "\nExpected: {:?}\n     Got: {:?}\n",
+            scanned,
+            hinted,
+        );
+        assert!(hinted_id == scanned_id);
+
+        // Writing to the data file afterwards makes the hint stale
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let e = Entry::new(b"new", b"value", EntryType::Put);
+        current = PageInner::new(current.id + 1);
+        current.write_entry(&e, None).expect("should have space");
+        disk.write_page(current.id, &current.data);
+
+        let (got, _, _) = bootstrap(&disk).await;
+        assert!(got.get(b"new").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut key_dir = KeyDir::default();
+        for (i, k) in [&b"b"[..], b"a", b"ab", b"c", b"ba"].iter().enumerate() {
+            key_dir.insert(k, KeyData::new(0, 0, i as u64));
+        }
+
+        let keys = |start: &[u8], end: &[u8]| -> Vec<Vec<u8>> {
+            key_dir.scan(start, end).map(|(k, _)| k.to_vec()).collect()
+        };
+
+        assert!(keys(b"a", b"b") == vec![b"a".to_vec(), b"ab".to_vec()]);
+        assert!(keys(b"ab", b"c") == vec![b"ab".to_vec(), b"b".to_vec(), b"ba".to_vec()]);
+        assert!(keys(b"b", b"") == vec![b"b".to_vec(), b"ba".to_vec(), b"c".to_vec()]);
+        assert!(keys(b"", b"").len() == 5);
+        assert!(keys(b"b", b"b").is_empty());
+        assert!(keys(b"c", b"a").is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_len_approx() {
+        const WRITERS: usize = 4;
+        const KEYS: usize = 500;
+        let key_dir = Arc::new(RwLock::new(KeyDir::default()));
+        let len = key_dir.read().await.len_handle();
+
+        // Each writer inserts its own keys and deletes them again, some more than once
+        let mut writers = Vec::new();
+        for w in 0..WRITERS {
+            let key_dir = key_dir.clone();
+            writers.push(tokio::spawn(async move {
+                for i in 0..KEYS {
+                    let key = format!("{}:{}", w, i);
+                    let mut key_dir = key_dir.write().await;
+                    key_dir.insert(key.as_bytes(), KeyData::new(0, 0, i as u64));
+                    if i % 2 == 0 {
+                        key_dir.remove(key.as_bytes());
+                        tokio::task::yield_now().await;
+                        key_dir.remove(key.as_bytes());
+                    }
+                }
+            }));
+        }
+
+        // Read through the handle, which doesn't wait for writers holding the lock. A count that
+        // went below zero would wrap around past the most there can be.
+        let reader = {
+            let len = len.clone();
+            tokio::spawn(async move {
+                loop {
+                    let got = len.load(Ordering::Acquire);
+                    assert!(got <= WRITERS * KEYS, "Got: {}", got);
+                    if got == WRITERS * KEYS / 2 {
+                        break;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        for writer in writers {
+            writer.await.expect("writer shouldn't panic");
+        }
+        reader.await.expect("reader shouldn't panic");
+
+        let mut key_dir = key_dir.write().await;
+        assert!(len.load(Ordering::Acquire) == key_dir.len());
+        key_dir.expire(b"0:1");
+        assert!(key_dir.len_approx() == WRITERS * KEYS / 2 - 1);
+        // Clones count on their own
+        let clone = key_dir.clone();
+        key_dir.take_keys();
+        assert!(key_dir.len_approx() == 0 && clone.len_approx() == clone.len());
+        // The handle keeps following the key dir when its keys are swapped out
+        key_dir.replace(clone);
+        assert!(len.load(Ordering::Acquire) == WRITERS * KEYS / 2 - 1);
+    }
+
+    #[test]
+    fn test_merge() {
+        // Two scans of different parts of the file, "b" and "c" written in both
+        let mut early = KeyDir::default();
+        early.insert(b"a", KeyData::new(0, 0, 0));
+        early.insert(b"b", KeyData::new(0, 0, 10));
+        early.insert(b"c", KeyData::new(0, 1, 0));
+        early.remove(b"gone");
+        let mut late = KeyDir::default();
+        late.insert(b"b", KeyData::new(1, 2, 0));
+        late.insert(b"c", KeyData::new(1, 2, 10));
+        late.insert(b"d", KeyData::new(1, 3, 0));
+
+        let mut expected = KeyDir::default();
+        expected.insert(b"a", KeyData::new(0, 0, 0));
+        expected.insert(b"b", KeyData::new(1, 2, 0));
+        expected.insert(b"c", KeyData::new(1, 2, 10));
+        expected.insert(b"d", KeyData::new(1, 3, 0));
+
+        // Whichever order they finish in
+        for (mut a, b) in [(early.clone(), late.clone()), (late, early)] {
+            a.merge(b);
+            assert!(a == expected, "\nExpected: {:?}\nGot: {:?}\n", expected, a);
+            assert!(a.count_deleted() == 1);
+            assert!(a.get(b"d").is_some());
+        }
+    }
+
+    #[test]
+    fn test_matching() {
+        let mut key_dir = KeyDir::default();
+        for (i, k) in [&b"user:1"[..], b"user:22", b"users", b"ab:c", b"a*b", b"b"]
+            .iter()
+            .enumerate()
+        {
+            key_dir.insert(k, KeyData::new(0, 0, i as u64));
+        }
+
+        let keys = |pattern: &[u8]| -> Vec<Vec<u8>> {
+            key_dir.matching(pattern).map(|(k, _)| k.to_vec()).collect()
+        };
+
+        assert!(keys(b"*").len() == 6);
+        assert!(keys(b"user:*") == vec![b"user:1".to_vec(), b"user:22".to_vec()]);
+        assert!(keys(b"??:?") == vec![b"ab:c".to_vec()]);
+        assert!(keys(b"*s") == vec![b"users".to_vec()]);
+        assert!(keys(b"a\\*b") == vec![b"a*b".to_vec()]);
+        assert!(keys(b"user").is_empty());
+        assert!(keys(b"*:*2") == vec![b"user:22".to_vec()]);
+
+        assert!(glob_match(b"", b""));
+        assert!(glob_match(b"**", b""));
+        assert!(!glob_match(b"?", b""));
+        assert!(!glob_match(b"a\\", b"a\\"));
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert!(prefix_end(b"ab") == b"ac");
+        assert!(prefix_end(&[1, 2, 0xFF]) == [1, 3]);
+        assert!(prefix_end(&[0xFF, 0xFF]).is_empty());
+        assert!(prefix_end(b"").is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn test_scan_prefix(
+            keys in vec(vec(prop_oneof![Just(0xFFu8), Just(0u8), any::<u8>()], 0..4), 0..32),
+            prefix in vec(prop_oneof![Just(0xFFu8), Just(0u8), any::<u8>()], 0..3),
+        ) {
+            let mut key_dir = KeyDir::default();
+            for (i, k) in keys.iter().enumerate() {
+                key_dir.insert(k, KeyData::new(0, 0, i as u64));
+            }
+
+            let got: Vec<&[u8]> = key_dir.scan_prefix(&prefix).map(|(k, _)| k).collect();
+
+            let mut expected: Vec<&[u8]> = keys
+                .iter()
+                .filter(|k| k.starts_with(&prefix))
+                .map(|k| &k[..])
+                .collect();
+            expected.sort();
+            expected.dedup();
+
+            prop_assert_eq!(got, expected);
+        }
+    }
 }
@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::storagev2::{
+    log::Entry,
+    page::{PageID, PAGE_SIZE},
+};
+
+/// Most items a node holds. A node also has to fit in a page, so with large items it holds fewer.
+#[cfg(not(test))]
+pub const NODE_ITEMS: usize = 64;
+#[cfg(test)]
+pub const NODE_ITEMS: usize = 4;
+
+// In place of the next node's page id in the tail
+const NO_NEXT: PageID = PageID::MAX;
+
+/// One node of a list, stored as the value of a `ListNode` entry under the list's key. The key
+/// dir points at the head, the rest are only reachable by following `next`.
+///
+/// Nodes are never changed in place. Changing one means writing it again, and every node before
+/// it too since their `next` would be stale, so the head is always written last. That way a node
+/// only ever points at entries written before it, and the latest entry under a list's key is its
+/// head, which is all bootstrap looks at. An empty list has no nodes, its key is deleted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListNode {
+    /// Page id and offset of the next node, `None` for the tail.
+    pub next: Option<(PageID, u64)>,
+    /// Items in this node and every one after it, so the head knows the length of the list.
+    pub len: u64,
+    pub items: VecDeque<Bytes>,
+}
+
+impl ListNode {
+    // next page id + next offset + len + item count
+    pub const HEADER_LEN: usize = 4 + 8 + 8 + 4;
+    // Length prefix of each item
+    pub const ITEM_HEADER_LEN: usize = 4;
+
+    pub fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        let (page_id, offset) = self.next.unwrap_or((NO_NEXT, 0));
+        buf.put_u32(page_id);
+        buf.put_u64(offset);
+        buf.put_u64(self.len);
+        buf.put_u32(self.items.len() as u32);
+        for item in &self.items {
+            buf.put_u32(item.len() as u32);
+            buf.put(&item[..]);
+        }
+
+        buf
+    }
+
+    /// `None` if `src` isn't a whole node.
+    pub fn decode(mut src: &[u8]) -> Option<Self> {
+        if src.remaining() < Self::HEADER_LEN {
+            return None;
+        }
+        let page_id = src.get_u32();
+        let offset = src.get_u64();
+        let len = src.get_u64();
+        let count = src.get_u32() as usize;
+
+        let mut items = VecDeque::with_capacity(count.min(NODE_ITEMS));
+        for _ in 0..count {
+            if src.remaining() < Self::ITEM_HEADER_LEN {
+                return None;
+            }
+            let item_len = src.get_u32() as usize;
+            if src.remaining() < item_len {
+                return None;
+            }
+            items.push_back(Bytes::copy_from_slice(&src[..item_len]));
+            src.advance(item_len);
+        }
+
+        Some(Self {
+            next: (page_id != NO_NEXT).then_some((page_id, offset)),
+            len,
+            items,
+        })
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        let items: usize = self
+            .items
+            .iter()
+            .map(|i| Self::ITEM_HEADER_LEN + i.len())
+            .sum();
+        Self::HEADER_LEN + items
+    }
+
+    /// Whether `item` can be added while the node, stored under `key`, still fits in a page.
+    pub fn has_room(&self, key: &[u8], item: &[u8]) -> bool {
+        let entry_len = Entry::METADATA_LEN
+            + key.len()
+            + self.encoded_len()
+            + Self::ITEM_HEADER_LEN
+            + item.len()
+            + Entry::CHECKSUM_LEN;
+
+        self.items.len() < NODE_ITEMS && entry_len <= PAGE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::{
+        list::{ListNode, NODE_ITEMS},
+        page::PAGE_SIZE,
+    };
+
+    #[test]
+    fn test_list_node() {
+        let mut node = ListNode {
+            next: Some((3, 42)),
+            len: 10,
+            ..Default::default()
+        };
+        for i in 0..NODE_ITEMS {
+            assert!(node.has_room(b"list", b"item"));
+            node.items.push_back(format!("item_{}", i).into());
+        }
+        assert!(!node.has_room(b"list", b"item"));
+
+        let got = ListNode::decode(&node.encode());
+        assert!(
+            got.as_ref() == Some(&node),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            Some(&node),
+            got
+        );
+        assert!(node.encode().len() == node.encoded_len());
+
+        let tail = ListNode::default();
+        assert!(ListNode::decode(&tail.encode()) == Some(tail));
+        // Cut short
+        assert!(ListNode::decode(&node.encode()[..ListNode::HEADER_LEN + 2]).is_none());
+
+        // Doesn't fit in a page even on its own
+        assert!(!ListNode::default().has_room(b"list", &[0; PAGE_SIZE]));
+    }
+}
@@ -1,45 +1,132 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    error::Error,
+    fmt, io,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use bytes::{BufMut, BytesMut};
+use zerocopy::{
+    byteorder::{BigEndian, U64},
+    FromBytes, Immutable, KnownLayout, Unaligned,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EntryType {
-    Put,    // 0
-    Delete, // 1
+    Put,      // 0
+    Delete,   // 1
+    ListNode, // 2, see `list::ListNode`
 }
 
-impl From<u8> for EntryType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for EntryType {
+    type Error = EntryError;
+
+    fn try_from(value: u8) -> Result<Self, EntryError> {
         match value {
-            0 => EntryType::Put,
-            1 => EntryType::Delete,
-            _ => unreachable!(),
+            0 => Ok(EntryType::Put),
+            1 => Ok(EntryType::Delete),
+            2 => Ok(EntryType::ListNode),
+            _ => Err(EntryError::InvalidEntryType(value)),
         }
     }
 }
 
-impl Into<u8> for EntryType {
-    fn into(self) -> u8 {
-        match self {
+impl From<EntryType> for u8 {
+    fn from(value: EntryType) -> Self {
+        match value {
             EntryType::Put => 0,
             EntryType::Delete => 1,
+            EntryType::ListNode => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryError {
+    KeyTooLarge(u64),
+    ValueTooLarge(u64),
+    InvalidEntryType(u8),
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryError::KeyTooLarge(len) => write!(f, "key of {len} bytes is too large"),
+            EntryError::ValueTooLarge(len) => write!(f, "value of {len} bytes is too large"),
+            EntryError::InvalidEntryType(t) => write!(f, "unknown entry type {t}"),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl Error for EntryError {}
+
+/// Largest key and value an entry may have. Anything read back that claims more is treated as
+/// corrupt rather than allocated for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntryLimits {
+    pub max_key_len: u64,
+    pub max_value_len: u64,
+}
+
+impl Default for EntryLimits {
+    fn default() -> Self {
+        Self {
+            max_key_len: 64 * 1024,
+            max_value_len: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// lz4_flex only implements the default LZ4 level, passing one opts a write into compression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionLevel {
+    Lz4,
+}
+
+pub fn timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time before UNIX epoch")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Entry {
+    pub version: u8,
     pub t: EntryType,
+    // Whether the value is lz4 compressed, with its uncompressed size prepended
+    pub compressed: bool,
     pub time: u64,
+    // Unix timestamp in milliseconds
+    pub expire_at: Option<u64>,
     pub key: BytesMut,
     pub value: BytesMut,
 }
 
 impl Entry {
+    // Stored in the upper nibble of the header byte. Version 0 entries were written before
+    // expire_at was added.
+    pub const VERSION: u8 = 1;
+    // Set in the header byte when the value is compressed, the low 3 bits hold the entry type
+    pub const COMPRESSED_FLAG: u8 = 0x08;
+    // t + time + expire_at + key_s + value_s
+    pub const METADATA_LEN: usize = 1 + 8 + 8 + 8 + 8;
     // t + time + key_s + value_s
-    pub const METADATA_LEN: usize = 1 + 8 + 8 + 8;
+    pub const METADATA_LEN_V0: usize = 1 + 8 + 8 + 8;
+    // crc32 of the header, key and value, stored after the value
+    pub const CHECKSUM_LEN: usize = 4;
+    // Longest uncompressed value `encoding` reports as `embstr`, the same cut off Redis uses
+    pub const EMBSTR_MAX_LEN: usize = 44;
+
+    pub fn metadata_len(version: u8) -> usize {
+        match version {
+            0 => Self::METADATA_LEN_V0,
+            _ => Self::METADATA_LEN,
+        }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
-        Self::METADATA_LEN + self.key.len() + self.value.len()
+        Self::metadata_len(self.version) + self.key.len() + self.value.len() + Self::CHECKSUM_LEN
     }
 
     pub fn new(key: &[u8], value: &[u8], t: EntryType) -> Entry {
@@ -49,22 +136,185 @@ impl Entry {
             .as_secs();
 
         Entry {
+            version: Self::VERSION,
             t,
+            compressed: false,
             time,
+            expire_at: None,
             key: key.into(),
             value: value.into(),
         }
     }
 
+    /// Type of the value as `type` reports it, a tombstone has none.
+    pub fn value_type(&self) -> &'static str {
+        match self.t {
+            EntryType::Put => "string",
+            EntryType::Delete => "none",
+            EntryType::ListNode => "list",
+        }
+    }
+
+    /// How the value is stored, `lz4` if compressed, `embstr` if it is short enough to be read
+    /// along with the entry's header and `raw` otherwise. Only compression is flagged in the
+    /// entry, the rest goes by the value's length.
+    pub fn encoding(&self) -> &'static str {
+        match self.compressed {
+            true => "lz4",
+            false if self.value.len() <= Self::EMBSTR_MAX_LEN => "embstr",
+            false => "raw",
+        }
+    }
+
+    /// Length of the value once decompressed, which a compressed value has stored in front of it
+    /// so it doesn't have to be.
+    pub fn value_len(&self) -> usize {
+        match self.compressed && self.value.len() >= 4 {
+            true => {
+                let size: [u8; 4] = self.value[..4].try_into().unwrap();
+                u32::from_le_bytes(size) as usize
+            }
+            false => self.value.len(),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expire_at
+            .is_some_and(|expire_at| expire_at <= timestamp_millis())
+    }
+
+    /// Checks the entry against the default `EntryLimits`.
+    pub fn validate(&self) -> Result<(), EntryError> {
+        self.validate_with(&EntryLimits::default())
+    }
+
+    /// For a compressed value the uncompressed size it claims is checked too, as that is what
+    /// `decompress` allocates.
+    pub fn validate_with(&self, limits: &EntryLimits) -> Result<(), EntryError> {
+        let key_len = self.key.len() as u64;
+        if key_len > limits.max_key_len {
+            return Err(EntryError::KeyTooLarge(key_len));
+        }
+
+        let mut value_len = self.value.len() as u64;
+        if self.compressed && self.value.len() >= 4 {
+            let size: [u8; 4] = self.value[..4].try_into().unwrap();
+            value_len = value_len.max(u32::from_le_bytes(size) as u64);
+        }
+        if value_len > limits.max_value_len {
+            return Err(EntryError::ValueTooLarge(value_len));
+        }
+
+        Ok(())
+    }
+
+    pub fn compress(&self) -> Entry {
+        if self.compressed {
+            return self.clone();
+        }
+
+        Entry {
+            compressed: true,
+            value: lz4_flex::compress_prepend_size(&self.value)[..].into(),
+            key: self.key.clone(),
+            ..*self
+        }
+    }
+
+    pub fn decompress(&self) -> io::Result<Entry> {
+        if !self.compressed {
+            return Ok(self.clone());
+        }
+
+        let value = lz4_flex::decompress_size_prepended(&self.value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Entry {
+            compressed: false,
+            value: value[..].into(),
+            key: self.key.clone(),
+            ..*self
+        })
+    }
+
     pub fn as_bytes(&self) -> BytesMut {
         let mut ret = BytesMut::with_capacity(self.len());
-        ret.put_u8(self.t.into());
+        let mut header: u8 = self.t.into();
+        if self.compressed {
+            header |= Self::COMPRESSED_FLAG;
+        }
+        ret.put_u8(self.version << 4 | header);
         ret.put_u64(self.time);
+        if self.version > 0 {
+            ret.put_u64(self.expire_at.unwrap_or(0));
+        }
         ret.put_u64(self.key.len() as u64);
         ret.put_u64(self.value.len() as u64);
         ret.put(self.key.clone());
         ret.put(self.value.clone());
+        ret.put_u32(crc32fast::hash(&ret));
 
         ret
     }
 }
+
+/// The metadata at the start of a current version entry, laid out as it is in a page so it can
+/// be read in place. Version 0 entries have no `expire_at` and don't match it.
+#[derive(Debug, FromBytes, KnownLayout, Immutable, Unaligned)]
+#[repr(C)]
+pub struct EntryHeader {
+    // Version, compressed flag and entry type, as in `Entry::as_bytes`
+    pub header: u8,
+    pub time: U64<BigEndian>,
+    // 0 for no expiry
+    pub expire_at: U64<BigEndian>,
+    pub key_len: U64<BigEndian>,
+    pub value_len: U64<BigEndian>,
+}
+
+// Read in place, so it has to be exactly the metadata of an entry
+const _: () = assert!(std::mem::size_of::<EntryHeader>() == Entry::METADATA_LEN);
+
+impl EntryHeader {
+    pub fn version(&self) -> u8 {
+        self.header >> 4
+    }
+
+    pub fn compressed(&self) -> bool {
+        self.header & Entry::COMPRESSED_FLAG != 0
+    }
+}
+
+/// An entry read in place, borrowing its key and value from the page. The value is as stored, so
+/// still compressed if `header.compressed()`.
+#[derive(Debug)]
+pub struct EntryRef<'a> {
+    pub t: EntryType,
+    pub header: &'a EntryHeader,
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl EntryRef<'_> {
+    /// Space the entry takes up in the page.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        Entry::METADATA_LEN + self.key.len() + self.value.len() + Entry::CHECKSUM_LEN
+    }
+}
+
+impl From<EntryRef<'_>> for Entry {
+    fn from(entry: EntryRef<'_>) -> Self {
+        let expire_at = entry.header.expire_at.get();
+
+        Entry {
+            version: entry.header.version(),
+            t: entry.t,
+            compressed: entry.header.compressed(),
+            time: entry.header.time.get(),
+            expire_at: (expire_at != 0).then_some(expire_at),
+            key: entry.key.into(),
+            value: entry.value.into(),
+        }
+    }
+}
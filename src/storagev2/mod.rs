@@ -1,14 +1,25 @@
+pub mod bloom;
 pub mod disk;
+pub mod dyn_page_manager;
 pub mod key_dir;
+pub mod list;
 pub mod log;
 pub mod page;
 pub mod page_manager;
 pub mod replacer;
+pub mod segment;
+pub mod snapshot;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+pub mod uring;
+pub mod wal;
 
 pub mod test {
+    use crate::storagev2::segment::SegmentManager;
+
     pub enum Type {
         File,
         Dir,
+        Segments,
     }
 
     pub struct CleanUp(&'static str, Type);
@@ -21,6 +32,11 @@ pub mod test {
         pub fn dir(dir: &'static str) -> Self {
             Self(dir, Type::Dir)
         }
+
+        /// Every segment of the data file `db`.
+        pub fn segments(db: &'static str) -> Self {
+            Self(db, Type::Segments)
+        }
     }
 
     impl Drop for CleanUp {
@@ -36,6 +52,11 @@ pub mod test {
                         eprintln!("error: could not remove {} - {}", self.0, e);
                     }
                 }
+                Type::Segments => {
+                    if let Err(e) = SegmentManager::remove(self.0) {
+                        eprintln!("error: could not remove {} - {}", self.0, e);
+                    }
+                }
             }
         }
     }
@@ -1,7 +1,10 @@
 use bytes::Buf;
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use zerocopy::FromBytes;
 
-use crate::storagev2::log::Entry;
+use crate::storagev2::log::{
+    CompressionLevel, Entry, EntryError, EntryHeader, EntryRef, EntryType,
+};
 
 #[cfg(not(test))]
 pub const PAGE_SIZE: usize = 4 * 1024;
@@ -9,6 +12,13 @@ pub const PAGE_SIZE: usize = 4 * 1024;
 #[cfg(test)]
 pub const PAGE_SIZE: usize = 256;
 
+/// The last bytes of every `PAGE_SIZE` page, holding a CRC32 of the rest, see `page_checksum`.
+/// Entries never go there.
+pub const PAGE_CHECKSUM_LEN: usize = 4;
+
+// Where the checksum starts, and how much room there is for entries
+const PAGE_DATA_LEN: usize = PAGE_SIZE - PAGE_CHECKSUM_LEN;
+
 pub type PageID = u32;
 
 #[macro_export]
@@ -27,6 +37,20 @@ macro_rules! get_bytes {
 #[derive(Debug, PartialEq)]
 pub enum PageError {
     NotEnoughSpace,
+    NoEntry,
+    ChecksumMismatch,
+    Decompress,
+    // A version 0 entry, which can't be read in place
+    OldVersion,
+    // A write that would run past the end of the page
+    OutOfBounds,
+    InvalidEntry(EntryError),
+}
+
+impl From<EntryError> for PageError {
+    fn from(e: EntryError) -> Self {
+        PageError::InvalidEntry(e)
+    }
 }
 
 pub struct Page(RwLock<PageInner>);
@@ -76,73 +100,604 @@ impl PageInner {
         Self { id, data, len }
     }
 
+    /// A page read back from disk. New entries go after the last one that reads, anything past it
+    /// is either zeroed or a torn write and gets overwritten.
     pub fn from_bytes(id: PageID, data: [u8; PAGE_SIZE]) -> Self {
-        let mut empty = 0;
-
-        const WINDOW: usize = 8;
-        for w in data.windows(WINDOW).rev() {
-            if u64::from_be_bytes(w.try_into().unwrap()) == 0 {
-                empty += WINDOW;
-            }
+        let mut len = 0;
+        while let Ok(entry) = read_entry_raw(&data[..PAGE_DATA_LEN], len) {
+            len += entry.len();
         }
 
-        let len = PAGE_SIZE - empty;
         Self { id, data, len }
     }
 
-    pub fn write_entry(&mut self, entry: &Entry) -> Result<u64, PageError> {
-        let len = entry.len();
+    /// Compresses the value first if a level is given, unless that wouldn't save any space.
+    pub fn write_entry(
+        &mut self,
+        entry: &Entry,
+        level: Option<CompressionLevel>,
+    ) -> Result<u64, PageError> {
+        write_entry(&mut self.data[..PAGE_DATA_LEN], &mut self.len, entry, level)
+    }
 
-        let offset = self.len;
-        if offset + len > PAGE_SIZE {
-            return Err(PageError::NotEnoughSpace);
-        }
-        self.len += len;
+    /// Reads the entry at `offset`, decompressing its value if needed. The entry is validated
+    /// first, so a corrupt length can't make decompression allocate.
+    pub fn read_entry(&self, offset: usize) -> Result<Entry, PageError> {
+        read_entry(&self.data[..PAGE_DATA_LEN], offset)
+    }
 
-        put_bytes!(self.data, entry.as_bytes(), offset, len);
+    /// Reads the entry at `offset` as it is stored, so `len` is the space it takes up in the page.
+    pub fn read_entry_raw(&self, offset: usize) -> Result<Entry, PageError> {
+        read_entry_raw(&self.data[..PAGE_DATA_LEN], offset)
+    }
 
-        Ok(offset as u64)
+    /// Reads the entry at `offset` in place, without copying its key or value. Checked the same
+    /// way as `read_entry_raw`, the value isn't decompressed.
+    pub fn read_entry_zerocopy(&self, offset: usize) -> Result<EntryRef<'_>, PageError> {
+        read_entry_zerocopy(&self.data[..PAGE_DATA_LEN], offset)
     }
 
-    // TODO: handle invalid bounds
-    pub fn read_entry(&self, offset: usize) -> Option<Entry> {
-        let mut src = &self.data[offset..];
+    /// Every entry in the page in the order they were written, with values decompressed. Stops at
+    /// the zeroed space after the last entry, or at the first entry that fails to read.
+    pub fn iter_entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        iter_entries(&self.data[..PAGE_DATA_LEN])
+    }
 
-        let rm = offset + Entry::METADATA_LEN;
-        if rm >= PAGE_SIZE {
-            return None;
+    /// CRC32 of everything but the checksum at the end, see `page_checksum`.
+    pub fn checksum(&self) -> u32 {
+        page_checksum(&self.data)
+    }
+
+    /// Overwrites the bytes at `offset` in place, leaving the page as long as it was. Nothing is
+    /// checked besides the bounds, so overwriting part of an entry leaves its checksum stale until
+    /// the rest of it is rewritten. Pages are only written back if they were fetched with
+    /// `fetch_page_mut`, which marks them dirty.
+    pub fn write_at(&mut self, offset: usize, data: &[u8]) -> Result<(), PageError> {
+        match offset.checked_add(data.len()) {
+            Some(end) if end <= PAGE_DATA_LEN => {
+                self.data[offset..end].copy_from_slice(data);
+                Ok(())
+            }
+            _ => Err(PageError::OutOfBounds),
         }
+    }
+
+    /// Bytes left for entries, an entry fits if its `len` is at most this. The checksum's bytes
+    /// don't count.
+    pub fn remaining_capacity(&self) -> usize {
+        PAGE_DATA_LEN - self.len
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.remaining_capacity() == 0
+    }
 
-        let t = src.get_u8();
-        let time = src.get_u64();
-        let key_len = src.get_u64();
-        let value_len = src.get_u64();
+    pub fn reset(&mut self) {
+        self.data = [0; PAGE_SIZE];
+        self.len = 0;
+    }
+
+    /// Empties the page and gives it to `id`, reusing it rather than building a new one.
+    pub fn clear(&mut self, id: PageID) {
+        self.reset();
+        self.id = id;
+    }
+}
+
+/// A page whose size is only known at runtime, otherwise the same as `PageInner`.
+#[derive(Debug)]
+pub struct DynPage {
+    pub id: PageID,
+    pub data: Box<[u8]>,
+    capacity: usize,
+    len: usize,
+}
 
-        // Commented out: index out of bounds errors
-        // Uncommented: key returns wrong value
-        // if rm + (key_len + value_len) as usize > PAGE_SIZE {
-        //     eprintln!("error: log entry was written that exceeded page size");
-        //     return None;
-        // }
+impl DynPage {
+    pub fn new(id: PageID, capacity: usize) -> Self {
+        let data = vec![0; capacity].into_boxed_slice();
+        let len = 0;
 
-        if time == 0 && key_len == 0 && value_len == 0 {
-            return None;
+        Self {
+            id,
+            data,
+            capacity,
+            len,
         }
+    }
 
-        // let rest = &src[0..];
-        let key = get_bytes!(&src[0..], 0, key_len);
-        let value = get_bytes!(&src[0..], key_len as usize, value_len);
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Compresses the value first if a level is given, unless that wouldn't save any space.
+    pub fn write_entry(
+        &mut self,
+        entry: &Entry,
+        level: Option<CompressionLevel>,
+    ) -> Result<u64, PageError> {
+        write_entry(&mut self.data, &mut self.len, entry, level)
+    }
+
+    /// Reads the entry at `offset`, decompressing its value if needed.
+    pub fn read_entry(&self, offset: usize) -> Result<Entry, PageError> {
+        read_entry(&self.data, offset)
+    }
 
-        Some(Entry {
-            t: t.into(),
-            time,
-            key: key.into(),
-            value: value.into(),
-        })
+    /// Reads the entry at `offset` as it is stored, so `len` is the space it takes up in the page.
+    pub fn read_entry_raw(&self, offset: usize) -> Result<Entry, PageError> {
+        read_entry_raw(&self.data, offset)
+    }
+
+    /// Every entry in the page in the order they were written, with values decompressed. Stops at
+    /// the zeroed space after the last entry, or at the first entry that fails to read.
+    pub fn iter_entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        iter_entries(&self.data)
+    }
+
+    /// Bytes left for entries, an entry fits if its `len` is at most this.
+    pub fn remaining_capacity(&self) -> usize {
+        self.capacity - self.len
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.remaining_capacity() == 0
     }
 
     pub fn reset(&mut self) {
-        self.data = [0; PAGE_SIZE];
+        self.data.fill(0);
         self.len = 0;
     }
+
+    /// Empties the page and gives it to `id`, keeping its buffer.
+    pub fn clear(&mut self, id: PageID) {
+        self.reset();
+        self.id = id;
+    }
+}
+
+// Shared by the fixed and runtime sized pages, the capacity is the length of `data`
+fn write_entry(
+    data: &mut [u8],
+    data_len: &mut usize,
+    entry: &Entry,
+    level: Option<CompressionLevel>,
+) -> Result<u64, PageError> {
+    let compressed;
+    let entry = match level {
+        Some(CompressionLevel::Lz4) if !entry.compressed => {
+            compressed = entry.compress();
+            match compressed.len() < entry.len() {
+                true => &compressed,
+                false => entry,
+            }
+        }
+        _ => entry,
+    };
+    let len = entry.len();
+
+    let offset = *data_len;
+    if offset + len > data.len() {
+        return Err(PageError::NotEnoughSpace);
+    }
+    *data_len += len;
+
+    put_bytes!(data, entry.as_bytes(), offset, len);
+
+    Ok(offset as u64)
+}
+
+fn iter_entries(data: &[u8]) -> impl Iterator<Item = Entry> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let entry = read_entry_raw(data, offset).ok()?;
+        offset += entry.len();
+
+        entry.validate().ok()?;
+        entry.decompress().ok()
+    })
+}
+
+/// CRC32 of a `PAGE_SIZE` page without the checksum stored at its end.
+pub fn page_checksum(data: &[u8; PAGE_SIZE]) -> u32 {
+    crc32fast::hash(&data[..PAGE_DATA_LEN])
+}
+
+/// Stores `page_checksum` in the last `PAGE_CHECKSUM_LEN` bytes of `data`.
+pub fn set_page_checksum(data: &mut [u8; PAGE_SIZE]) {
+    let checksum = page_checksum(data);
+    data[PAGE_DATA_LEN..].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Whether the checksum stored in `data` matches the rest of it. A page that is all zeros, as one
+/// that was never written reads, has nothing to check and passes.
+pub fn verify_page_checksum(data: &[u8; PAGE_SIZE]) -> bool {
+    let stored = u32::from_be_bytes(data[PAGE_DATA_LEN..].try_into().expect("4 bytes"));
+
+    stored == page_checksum(data) || data.iter().all(|b| *b == 0)
+}
+
+/// An entry on its own, as `Entry::as_bytes` encodes it. Checked and decompressed the same way as
+/// `PageInner::read_entry`.
+pub fn decode_entry(bytes: &[u8]) -> Result<Entry, PageError> {
+    read_entry(bytes, 0)
+}
+
+fn read_entry(data: &[u8], offset: usize) -> Result<Entry, PageError> {
+    let entry = read_entry_raw(data, offset)?;
+    entry.validate()?;
+
+    entry.decompress().map_err(|_| PageError::Decompress)
+}
+
+fn read_entry_raw(data: &[u8], offset: usize) -> Result<Entry, PageError> {
+    if offset + Entry::METADATA_LEN_V0 >= data.len() {
+        return Err(PageError::NoEntry);
+    }
+
+    let mut src = &data[offset..];
+    let header = src.get_u8();
+    let version = header >> 4;
+    let compressed = header & Entry::COMPRESSED_FLAG != 0;
+    let t = header & 0x07;
+
+    // Either an entry from a newer build or a corrupt header
+    if version > Entry::VERSION {
+        return Err(PageError::ChecksumMismatch);
+    }
+    let t = EntryType::try_from(t)?;
+
+    let rm = offset + Entry::metadata_len(version);
+    if rm >= data.len() {
+        return Err(PageError::NoEntry);
+    }
+
+    let time = src.get_u64();
+    let expire_at = match version {
+        0 => 0,
+        _ => src.get_u64(),
+    };
+    let key_len = src.get_u64() as usize;
+    let value_len = src.get_u64() as usize;
+
+    if time == 0 && key_len == 0 && value_len == 0 {
+        return Err(PageError::NoEntry);
+    }
+
+    // A corrupt header can claim lengths that run off the end of the page, in which case
+    // there is no stored checksum to compare against
+    let end = match rm
+        .checked_add(key_len)
+        .and_then(|l| l.checked_add(value_len))
+    {
+        Some(end) if end + Entry::CHECKSUM_LEN <= data.len() => end,
+        _ => return Err(PageError::ChecksumMismatch),
+    };
+
+    let stored = (&data[end..]).get_u32();
+    if stored != crc32fast::hash(&data[offset..end]) {
+        return Err(PageError::ChecksumMismatch);
+    }
+
+    let key = get_bytes!(src, 0, key_len);
+    let value = get_bytes!(src, key_len, value_len);
+
+    Ok(Entry {
+        version,
+        t,
+        compressed,
+        time,
+        expire_at: (expire_at != 0).then_some(expire_at),
+        key: key.into(),
+        value: value.into(),
+    })
+}
+
+fn read_entry_zerocopy(data: &[u8], offset: usize) -> Result<EntryRef<'_>, PageError> {
+    let src = data.get(offset..).unwrap_or_default();
+    let Ok((header, _)) = EntryHeader::ref_from_prefix(src) else {
+        return Err(PageError::NoEntry);
+    };
+
+    // Either an entry from a newer build or a corrupt header
+    if header.version() > Entry::VERSION {
+        return Err(PageError::ChecksumMismatch);
+    }
+    let t = EntryType::try_from(header.header & 0x07)?;
+
+    let key_len = header.key_len.get() as usize;
+    let value_len = header.value_len.get() as usize;
+    // `time` is where it is in version 0 entries too, so the zeroed space reads the same
+    if header.time.get() == 0 && key_len == 0 && value_len == 0 {
+        return Err(PageError::NoEntry);
+    }
+    if header.version() < Entry::VERSION {
+        return Err(PageError::OldVersion);
+    }
+
+    let rm = offset + Entry::METADATA_LEN;
+    let end = match rm
+        .checked_add(key_len)
+        .and_then(|l| l.checked_add(value_len))
+    {
+        Some(end) if end + Entry::CHECKSUM_LEN <= data.len() => end,
+        _ => return Err(PageError::ChecksumMismatch),
+    };
+
+    let stored = (&data[end..]).get_u32();
+    if stored != crc32fast::hash(&data[offset..end]) {
+        return Err(PageError::ChecksumMismatch);
+    }
+
+    Ok(EntryRef {
+        t,
+        header,
+        key: &data[rm..rm + key_len],
+        value: &data[rm + key_len..end],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use crate::storagev2::{
+        log::{CompressionLevel, Entry, EntryError, EntryLimits, EntryType},
+        page::{PageError, PageInner, PAGE_CHECKSUM_LEN, PAGE_SIZE},
+    };
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut page = PageInner::new(0);
+
+        let entry = Entry::new(b"test_key", b"test_value", EntryType::Put);
+        let offset = page.write_entry(&entry, None).expect("should not be full") as usize;
+        assert_eq!(page.read_entry(offset), Ok(entry));
+
+        // Flip a bit in the value
+        page.data[offset + Entry::METADATA_LEN + 9] ^= 1;
+        assert_eq!(page.read_entry(offset), Err(PageError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn test_write_at() {
+        let mut page = PageInner::new(0);
+
+        let entry = Entry::new(b"key", b"value", EntryType::Put);
+        let offset = page.write_entry(&entry, None).expect("should not be full") as usize;
+        let before = page.remaining_capacity();
+
+        // Same size as the old entry, so it fits in its place
+        let new = Entry::new(b"key", b"VALUE", EntryType::Put);
+        page.write_at(offset, &new.as_bytes()).expect("should fit");
+        assert!(page.read_entry(offset) == Ok(new));
+        assert!(page.remaining_capacity() == before);
+
+        // The checksum at the very end is off limits
+        let end = PAGE_SIZE - PAGE_CHECKSUM_LEN;
+        page.write_at(end - 2, b"ab")
+            .expect("should fit at the end");
+        assert!(&page.data[end - 2..end] == b"ab");
+        assert!(page.write_at(end - 1, b"ab") == Err(PageError::OutOfBounds));
+        assert!(page.write_at(usize::MAX, b"a") == Err(PageError::OutOfBounds));
+        assert!(&page.data[end - 2..end] == b"ab");
+    }
+
+    #[test]
+    fn test_iter_entries() {
+        let mut page = PageInner::new(0);
+
+        let entries = [
+            Entry::new(b"key1", b"value1", EntryType::Put),
+            Entry::new(b"key2", b"value2", EntryType::Put),
+            Entry::new(b"key1", b"", EntryType::Delete),
+        ];
+        for e in &entries {
+            page.write_entry(e, None).expect("should not be full");
+        }
+
+        let got: Vec<_> = page.iter_entries().collect();
+        assert!(
+            got == entries,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entries,
+            got
+        );
+    }
+
+    #[test]
+    fn test_remaining_capacity() {
+        let mut page = PageInner::new(0);
+        assert!(page.remaining_capacity() == PAGE_SIZE - PAGE_CHECKSUM_LEN);
+
+        let entry = Entry::new(b"key", b"value", EntryType::Put);
+        let overhead = entry.len() - entry.value.len();
+        // Leave room for one more entry with some value
+        while page.remaining_capacity() >= entry.len() + overhead {
+            let before = page.remaining_capacity();
+            page.write_entry(&entry, None).expect("should fit");
+            assert!(page.remaining_capacity() == before - entry.len());
+        }
+
+        // Fill whatever is left exactly
+        let value = vec![b'v'; page.remaining_capacity() - overhead];
+        let last = Entry::new(b"key", &value, EntryType::Put);
+        page.write_entry(&last, None).expect("should fit exactly");
+        assert!(page.is_full());
+        assert!(page.write_entry(&entry, None) == Err(PageError::NotEnoughSpace));
+
+        page.clear(7);
+        assert!(page.id == 7);
+        assert!(page.remaining_capacity() == PAGE_SIZE - PAGE_CHECKSUM_LEN);
+        assert!(page.iter_entries().next().is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        let limits = EntryLimits {
+            max_key_len: 4,
+            max_value_len: 16,
+        };
+
+        let entry = Entry::new(b"key", b"value", EntryType::Put);
+        assert!(entry.validate_with(&limits).is_ok());
+
+        let entry = Entry::new(b"key_5", b"value", EntryType::Put);
+        let got = entry.validate_with(&limits);
+        assert!(
+            got == Err(EntryError::KeyTooLarge(5)),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            EntryError::KeyTooLarge(5),
+            got
+        );
+
+        // Small once compressed, but claims more than the limit when decompressed
+        let entry = Entry::new(b"key", &[b'a'; 64], EntryType::Put).compress();
+        assert!(entry.value.len() <= 16);
+        let got = entry.validate_with(&limits);
+        assert!(
+            got == Err(EntryError::ValueTooLarge(64)),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            EntryError::ValueTooLarge(64),
+            got
+        );
+    }
+
+    #[test]
+    fn test_invalid_entry() {
+        let mut page = PageInner::new(0);
+
+        let entry = Entry::new(b"key", b"", EntryType::Put);
+        let mut bytes = entry.as_bytes();
+        bytes[0] |= 0x05;
+        let len = bytes.len();
+        let checksum = crc32fast::hash(&bytes[..len - Entry::CHECKSUM_LEN]);
+        bytes[len - Entry::CHECKSUM_LEN..].copy_from_slice(&checksum.to_be_bytes());
+        crate::put_bytes!(page.data, bytes, 0, len);
+
+        let expected = Err(PageError::InvalidEntry(EntryError::InvalidEntryType(5)));
+        let got = page.read_entry(0);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        // A compressed value claiming a size past the limit is rejected before decompressing
+        let mut value = BytesMut::new();
+        value.put_u32_le(u32::MAX);
+        value.put(&lz4_flex::compress(b"value")[..]);
+        let entry = Entry {
+            compressed: true,
+            value,
+            ..Entry::new(b"key", b"", EntryType::Put)
+        };
+        let mut page = PageInner::new(0);
+        let offset = page.write_entry(&entry, None).expect("should not be full") as usize;
+
+        let expected = Err(PageError::InvalidEntry(EntryError::ValueTooLarge(
+            u32::MAX as u64,
+        )));
+        let got = page.read_entry(offset);
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+    }
+
+    #[test]
+    fn test_read_v0_entry() {
+        let mut page = PageInner::new(0);
+
+        // Entry written before the expire_at field existed
+        let mut bytes = BytesMut::new();
+        bytes.put_u8(EntryType::Put.into());
+        bytes.put_u64(1);
+        bytes.put_u64(3);
+        bytes.put_u64(5);
+        bytes.put(&b"keyvalue"[..]);
+        bytes.put_u32(crc32fast::hash(&bytes));
+        crate::put_bytes!(page.data, bytes, 0, bytes.len());
+
+        let entry = page.read_entry(0).expect("should read v0 entry");
+        assert!(entry.version == 0);
+        assert!(entry.expire_at.is_none());
+        assert!(&entry.key[..] == b"key" && &entry.value[..] == b"value");
+        assert!(entry.len() == bytes.len());
+
+        // Its fields are laid out differently
+        let got = page.read_entry_zerocopy(0).map(|_| ());
+        assert!(got == Err(PageError::OldVersion), "Got: {:?}", got);
+    }
+
+    #[test]
+    fn test_read_entry_zerocopy() {
+        let mut page = PageInner::new(0);
+
+        let mut expiring = Entry::new(b"key2", b"value2", EntryType::Put);
+        expiring.expire_at = Some(42);
+        let entries = [
+            Entry::new(b"key1", b"value1", EntryType::Put),
+            expiring,
+            Entry::new(b"key3", &[b'a'; 100], EntryType::Put).compress(),
+            Entry::new(b"key1", b"", EntryType::Delete),
+        ];
+        let mut offsets = Vec::new();
+        for e in &entries {
+            offsets.push(page.write_entry(e, None).expect("should not be full") as usize);
+        }
+
+        for offset in &offsets {
+            let expected = page.read_entry_raw(*offset);
+            let got = page.read_entry_zerocopy(*offset);
+            assert!(
+                got.as_ref()
+                    .is_ok_and(|e| e.len() == expected.as_ref().unwrap().len()),
+                "Got: {:?}",
+                got
+            );
+            let got = got.map(Entry::from);
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+
+        // Past the last entry
+        let end = offsets[3] + entries[3].len();
+        assert!(page.read_entry_zerocopy(end).map(|_| ()) == Err(PageError::NoEntry));
+        assert!(page.read_entry_zerocopy(PAGE_SIZE).map(|_| ()) == Err(PageError::NoEntry));
+
+        // Flip a bit in the key
+        page.data[offsets[1] + Entry::METADATA_LEN] ^= 1;
+        let got = page.read_entry_zerocopy(offsets[1]).map(|_| ());
+        assert!(got == Err(PageError::ChecksumMismatch), "Got: {:?}", got);
+    }
+
+    #[test]
+    fn test_compressed_entry() {
+        let mut page = PageInner::new(0);
+
+        let entry = Entry::new(b"test_key", &[b'a'; 128], EntryType::Put);
+        let offset = page
+            .write_entry(&entry, Some(CompressionLevel::Lz4))
+            .expect("should not be full") as usize;
+
+        let raw = page.read_entry_raw(offset).expect("should read raw entry");
+        assert!(raw.compressed);
+        assert!(raw.len() < entry.len());
+
+        let got = page.read_entry(offset);
+        assert!(
+            got.as_ref() == Ok(&entry),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entry,
+            got
+        );
+    }
 }
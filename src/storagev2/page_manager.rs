@@ -1,18 +1,28 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io,
     sync::{
-        atomic::{AtomicU32, Ordering::*},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::*},
         Arc,
     },
+    time::Instant,
 };
 
-use tokio::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use tokio::{
+    sync::{broadcast, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    task::JoinSet,
+};
 
 use crate::storagev2::{
     disk::Disk,
-    page::{Page, PageID, PageInner},
-    replacer::LRUKHandle,
+    key_dir::{self, hint_path, KeyData, KeyDir},
+    log::{Entry, EntryType},
+    page::{Page, PageError, PageID, PageInner, PAGE_SIZE},
+    replacer::{LRUKHandle, DEFAULT_K},
+    segment::FileID,
+    wal::WriteAheadLog,
 };
 
 #[derive(Debug, PartialEq)]
@@ -22,6 +32,25 @@ pub enum PageIndex {
 }
 
 pub const DEFAULT_READ_SIZE: usize = 8;
+/// Writes a `subscribe_writes` receiver can fall behind by before it misses some.
+pub const WRITE_STREAM_CAPACITY: usize = 4096;
+
+/// Operations shared by the `PAGE_SIZE` page cache and the runtime sized `DynPageManager`.
+#[allow(async_fn_in_trait)]
+pub trait PageManagerTrait {
+    type Pin<'a>
+    where
+        Self: 'a;
+
+    async fn fetch_page(&self, page_id: PageID) -> Option<Self::Pin<'_>>;
+
+    async fn new_page(&self) -> Option<PageID>;
+
+    async fn unpin_page<'a>(&'a self, pin: Self::Pin<'a>);
+}
+
+/// The page manager for pages of the compile time `PAGE_SIZE`.
+pub type PageManager = PageCache;
 
 pub struct Pin<'a> {
     pub page: &'a Page,
@@ -44,6 +73,14 @@ impl<'a> Pin<'a> {
         Self { page, i, replacer }
     }
 
+    /// Unpins without blocking the thread, instead of waiting for drop to do it.
+    pub async fn unpin(mut self) {
+        // Leave the pin pointing at the write page so drop doesn't unpin a second time
+        if let PageIndex::Read(i) = std::mem::replace(&mut self.i, PageIndex::Write) {
+            self.replacer.unpin(i).await;
+        }
+    }
+
     pub async fn write(&self) -> RwLockWriteGuard<'_, PageInner> {
         self.page.write().await
     }
@@ -53,27 +90,239 @@ impl<'a> Pin<'a> {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PageManagerStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub dirty_flushes: u64,
+}
+
+#[derive(Default)]
+struct StatsCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    dirty_flushes: AtomicU64,
+}
+
+/// Settings for `PageCache`, starting from the defaults and overriding fields builder style:
+/// `PageManagerConfig::new().deletion_ratio_threshold(0.5)`. Checked by `PageManagerBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageManagerConfig {
+    deletion_ratio_threshold: f64,
+    k_value: usize,
+    max_read_pages: usize,
+    dirty_threshold: f64,
+}
+
+impl Default for PageManagerConfig {
+    fn default() -> Self {
+        Self {
+            deletion_ratio_threshold: 0.3,
+            k_value: DEFAULT_K,
+            max_read_pages: DEFAULT_READ_SIZE,
+            dirty_threshold: 1.0,
+        }
+    }
+}
+
+impl PageManagerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fraction of written entries that are tombstones before compaction kicks in.
+    pub fn deletion_ratio_threshold(mut self, threshold: f64) -> Self {
+        self.deletion_ratio_threshold = threshold;
+        self
+    }
+
+    /// How many accesses back the replacer looks when picking a read page to evict.
+    pub fn k_value(mut self, k: usize) -> Self {
+        self.k_value = k;
+        self
+    }
+
+    /// Number of read frames, the write page comes on top of these.
+    pub fn max_read_pages(mut self, pages: usize) -> Self {
+        self.max_read_pages = pages;
+        self
+    }
+
+    /// Fraction of read frames that may be dirty. Marking one more dirty past it writes them all
+    /// back at once, at 1.0 they are only written back when evicted.
+    pub fn dirty_threshold(mut self, threshold: f64) -> Self {
+        self.dirty_threshold = threshold;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_read_pages == 0 {
+            return Err(ConfigError::NoReadPages);
+        }
+        if self.k_value == 0 {
+            return Err(ConfigError::ZeroK);
+        }
+        // Written so NaN fails too
+        if !(self.dirty_threshold > 0.0 && self.dirty_threshold <= 1.0) {
+            return Err(ConfigError::DirtyThreshold(self.dirty_threshold));
+        }
+        if self.deletion_ratio_threshold.is_nan() || self.deletion_ratio_threshold < 0.0 {
+            return Err(ConfigError::DeletionRatioThreshold(
+                self.deletion_ratio_threshold,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    NoReadPages,
+    ZeroK,
+    DirtyThreshold(f64),
+    DeletionRatioThreshold(f64),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::NoReadPages => write!(f, "max_read_pages must be at least 1"),
+            ConfigError::ZeroK => write!(f, "k_value must be at least 1"),
+            ConfigError::DirtyThreshold(t) => {
+                write!(f, "dirty_threshold must be in (0, 1], got {}", t)
+            }
+            ConfigError::DeletionRatioThreshold(t) => {
+                write!(f, "deletion_ratio_threshold can't be negative, got {}", t)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Builds a `PageCache` from a validated `PageManagerConfig`.
+pub struct PageManagerBuilder {
+    disk: Disk,
+    wal: WriteAheadLog,
+    latest: Page,
+    latest_id: PageID,
+    config: PageManagerConfig,
+}
+
+impl PageManagerBuilder {
+    pub fn new(disk: Disk, wal: WriteAheadLog, latest: Page, latest_id: PageID) -> Self {
+        Self {
+            disk,
+            wal,
+            latest,
+            latest_id,
+            config: PageManagerConfig::default(),
+        }
+    }
+
+    pub fn config(mut self, config: PageManagerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> Result<PageCache, ConfigError> {
+        self.config.validate()?;
+
+        Ok(PageCache(Arc::new(PageCacheInner::with_config(
+            self.disk,
+            self.wal,
+            self.latest,
+            self.latest_id,
+            self.config,
+        ))))
+    }
+}
+
 #[derive(Clone)]
 pub struct PageCache(Arc<PageCacheInner>);
 
 impl PageCache {
-    pub fn new(disk: Disk, lruk: usize, latest: Page, latest_id: PageID) -> Self {
-        Self(Arc::new(PageCacheInner::new(disk, lruk, latest, latest_id)))
+    #[deprecated(note = "use PageManagerBuilder, which also checks the config")]
+    pub fn new(disk: Disk, wal: WriteAheadLog, latest: Page, latest_id: PageID) -> Self {
+        Self(Arc::new(PageCacheInner::new(disk, wal, latest, latest_id)))
+    }
+
+    /// Doesn't check `config`, `PageManagerBuilder` does.
+    pub fn with_config(
+        disk: Disk,
+        wal: WriteAheadLog,
+        latest: Page,
+        latest_id: PageID,
+        config: PageManagerConfig,
+    ) -> Self {
+        Self(Arc::new(PageCacheInner::with_config(
+            disk, wal, latest, latest_id, config,
+        )))
     }
 
     pub fn inc_id(&self) -> PageID {
         self.0.inc_id()
     }
 
+    /// Where the entry at `offset` in `page_id` lives, including which segment that is.
+    pub fn key_data(&self, page_id: PageID, offset: u64) -> KeyData {
+        self.0.key_data(page_id, offset)
+    }
+
+    /// Moves on to a new write page. Once enough of the entries written are tombstones, a
+    /// compaction is started in the background.
     pub async fn replace_current(
         &self,
         current: &mut RwLockWriteGuard<'_, PageInner>,
+        key_dir: &Arc<RwLock<KeyDir>>,
     ) -> io::Result<()> {
-        self.0.replace_current(current).await
+        self.0.replace_current(current).await?;
+
+        if self.0.should_compact() {
+            let m = self.clone();
+            let key_dir = key_dir.clone();
+            tokio::spawn(async move {
+                if let Err(e) = m.compact(&key_dir).await {
+                    eprintln!("compaction error: {}", e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    pub async fn compact(&self, key_dir: &RwLock<KeyDir>) -> io::Result<()> {
+        self.0.compact(key_dir).await
+    }
+
+    pub fn compactions(&self) -> u64 {
+        self.0.compactions()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.0.memory_usage()
+    }
+
+    pub async fn access_count(&self, page_id: PageID) -> usize {
+        self.0.access_count(page_id).await
+    }
+
+    pub async fn last_access_time(&self, page_id: PageID) -> Option<Instant> {
+        self.0.last_access_time(page_id).await
+    }
+
+    pub async fn pin_count(&self, page_id: PageID) -> Option<u64> {
+        self.0.pin_count(page_id).await
+    }
+
+    pub async fn reload(&self, key_dir: &RwLock<KeyDir>) -> io::Result<usize> {
+        self.0.reload(key_dir).await
     }
 
-    #[cfg(test)]
-    pub async fn new_page<'a>(&mut self) -> Option<PageID> {
+    pub async fn new_page(&self) -> Option<PageID> {
         self.0.new_page().await
     }
 
@@ -81,43 +330,171 @@ impl PageCache {
         self.0.fetch_page(page_id).await
     }
 
+    pub async fn fetch_page_mut(&self, page_id: PageID) -> Option<Pin<'_>> {
+        self.0.fetch_page_mut(page_id).await
+    }
+
+    pub async fn fetch_entry(&self, page_id: PageID, offset: u64) -> Option<Entry> {
+        self.0.fetch_entry(page_id, offset).await
+    }
+
+    pub async fn evict_page(&self, page_id: PageID) -> io::Result<()> {
+        self.0.evict_page(page_id).await
+    }
+
+    pub async fn prefetch_pages(&self, ids: &[PageID]) -> io::Result<()> {
+        self.0.prefetch_pages(ids).await
+    }
+
+    /// Every live `(key, value)` in the database, see `PageCacheInner::iter_all_entries`.
+    pub fn iter_all_entries<'a>(
+        &'a self,
+        key_dir: &'a RwLock<KeyDir>,
+    ) -> impl Stream<Item = io::Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        self.0.iter_all_entries(key_dir)
+    }
+
     pub async fn get_current(&self) -> RwLockWriteGuard<'_, PageInner> {
         self.0.get_current().await
     }
 
+    /// Has to be held, before the current page, while writing list nodes.
+    pub async fn lock_lists(&self) -> RwLockReadGuard<'_, ()> {
+        self.0.lock_lists().await
+    }
+
     pub async fn flush_current(&self) {
         self.0.flush_current().await
     }
+
+    pub async fn log_write(&self, page_id: PageID, offset: u64, entry: &Entry) -> io::Result<()> {
+        self.0.log_write(page_id, offset, entry).await
+    }
+
+    /// Every entry logged from now on, in the order they were logged, with a sequence number one
+    /// more than the last one's and the entry as `Entry::as_bytes` encodes it. A receiver more
+    /// than `WRITE_STREAM_CAPACITY` writes behind gets `Lagged` and has missed some.
+    pub fn subscribe_writes(&self) -> broadcast::Receiver<(u64, Bytes)> {
+        self.0.writes.subscribe()
+    }
+
+    /// Writes and logs `entry`, moving on to a new write page first if it doesn't fit, and
+    /// returns where it went. The write page is released before returning, so callers that need
+    /// their key dir updates ordered with the writes should hold `get_current` instead.
+    pub async fn write_entry_auto(&self, entry: &Entry) -> io::Result<(PageID, usize)> {
+        self.0.write_entry_auto(entry).await
+    }
+
+    /// Also saves a hint file for `key_dir`, so the next bootstrap can skip scanning the data file.
+    pub async fn checkpoint(&self, key_dir: &RwLock<KeyDir>) -> io::Result<()> {
+        self.0.checkpoint().await?;
+        self.0.write_hint(&*key_dir.read().await).await
+    }
+
+    /// Also saves a hint file for `key_dir`, see `checkpoint`.
+    pub async fn flush_all(&self, key_dir: &RwLock<KeyDir>) -> io::Result<()> {
+        self.0.flush_all().await?;
+        self.0.write_hint(&*key_dir.read().await).await
+    }
+
+    pub fn stats(&self) -> PageManagerStats {
+        self.0.stats()
+    }
 }
 
-struct PageCacheInner<const READ_SIZE: usize = DEFAULT_READ_SIZE> {
-    disk: Disk,
+impl PageManagerTrait for PageCache {
+    type Pin<'a> = Pin<'a>;
+
+    async fn fetch_page(&self, page_id: PageID) -> Option<Pin<'_>> {
+        self.0.fetch_page(page_id).await
+    }
+
+    async fn new_page(&self) -> Option<PageID> {
+        self.0.new_page().await
+    }
+
+    async fn unpin_page<'a>(&'a self, pin: Pin<'a>) {
+        pin.unpin().await
+    }
+}
+
+struct PageCacheInner {
+    // Only write locked to swap in a compacted file
+    disk: RwLock<Disk>,
+    wal: Mutex<WriteAheadLog>,
     page_table: RwLock<HashMap<PageID, PageIndex>>,
     current: Page,
-    read: [Page; READ_SIZE],
+    read: Box<[Page]>,
     free: Mutex<Vec<usize>>,
+    dirty: Mutex<HashSet<usize>>,
     next_id: AtomicU32,
     replacer: LRUKHandle,
+    stats: StatsCounters,
+    // Entries and tombstones logged since startup or the last compaction
+    entries: AtomicU64,
+    deleted: AtomicU64,
+    deletion_ratio_threshold: f64,
+    dirty_threshold: f64,
+    compacting: AtomicBool,
+    // Held by list writes and, exclusively, by compaction, see `Disk::compact_pages`
+    lists: RwLock<()>,
+    // Compactions since startup, locations taken before one may point anywhere
+    compactions: AtomicU64,
+    // Compaction keeps the segment size, so this never changes
+    segment_size: u64,
+    // Sequence number of the last entry logged and where logged entries are sent, for replicas
+    write_seq: AtomicU64,
+    writes: broadcast::Sender<(u64, Bytes)>,
 }
 
-impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
-    pub fn new(disk: Disk, lruk: usize, latest: Page, latest_id: PageID) -> Self {
+impl PageCacheInner {
+    pub fn new(disk: Disk, wal: WriteAheadLog, latest: Page, latest_id: PageID) -> Self {
+        Self::with_config(disk, wal, latest, latest_id, PageManagerConfig::default())
+    }
+
+    pub fn with_config(
+        disk: Disk,
+        wal: WriteAheadLog,
+        latest: Page,
+        latest_id: PageID,
+        config: PageManagerConfig,
+    ) -> Self {
+        let segment_size = disk.segment_size();
+        let disk = RwLock::new(disk);
+        let wal = Mutex::new(wal);
         let next_id = latest_id + 1;
         let page_table = RwLock::new(HashMap::from([(latest_id, PageIndex::Write)]));
         let current = latest;
-        let read: [_; READ_SIZE] = std::array::from_fn(|_| Page::default());
+        let read = (0..config.max_read_pages)
+            .map(|_| Page::default())
+            .collect();
         let next_id = AtomicU32::new(next_id);
-        let free = Mutex::new((0..READ_SIZE).rev().collect());
-        let replacer = LRUKHandle::new(lruk);
+        let free = Mutex::new((0..config.max_read_pages).rev().collect());
+        let dirty = Mutex::new(HashSet::new());
+        let replacer = LRUKHandle::with_k(config.k_value);
+        let stats = StatsCounters::default();
 
         Self {
             disk,
+            wal,
             page_table,
             current,
             read,
             free,
+            dirty,
             next_id,
             replacer,
+            stats,
+            entries: AtomicU64::new(0),
+            deleted: AtomicU64::new(0),
+            deletion_ratio_threshold: config.deletion_ratio_threshold,
+            dirty_threshold: config.dirty_threshold,
+            compacting: AtomicBool::new(false),
+            lists: RwLock::new(()),
+            compactions: AtomicU64::new(0),
+            segment_size,
+            write_seq: AtomicU64::new(0),
+            writes: broadcast::channel(WRITE_STREAM_CAPACITY).0,
         }
     }
 
@@ -125,55 +502,53 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
         self.next_id.fetch_add(1, SeqCst)
     }
 
+    pub fn key_data(&self, page_id: PageID, offset: u64) -> KeyData {
+        let file_id = (PAGE_SIZE as u64 * u64::from(page_id) / self.segment_size) as FileID;
+
+        KeyData::new(file_id, page_id, offset)
+    }
+
     pub async fn replace_current(
         &self,
         current: &mut RwLockWriteGuard<'_, PageInner>,
     ) -> io::Result<()> {
-        self.disk.write_page(current.id, &current.data);
+        self.disk.read().await.write_page(current.id, &current.data);
 
         let mut page_table = self.page_table.write().await;
 
         let old_id = current.id;
-        if let None = page_table.remove(&old_id) {
+        if page_table.remove(&old_id).is_none() {
             eprintln!("No write page while replacing write page");
         }
 
         let page_id = self.inc_id();
-        current.reset();
-        current.id = page_id;
+        current.clear(page_id);
         page_table.insert(page_id, PageIndex::Write);
 
         Ok(())
     }
 
-    #[cfg(test)]
-    pub async fn new_page<'a>(&self) -> Option<PageID> {
-        let i = match self.free.lock().await.pop() {
-            Some(i) => i,
-            None => self.replacer.evict().await?,
-        };
-        self.replacer.remove(i).await;
-        self.replacer.record_access(i).await;
-        self.replacer.pin(i).await;
-
+    pub async fn new_page(&self) -> Option<PageID> {
         let page_id = self.inc_id();
 
-        let pin = Pin::new(&self.read[i], PageIndex::Read(i), self.replacer.clone());
-        let mut page = pin.write().await;
-        page.reset();
-        page.id = page_id;
+        let (i, page) = self.replace_page(page_id).await?;
+        self.disk.read().await.write_page(page.id, &page.data);
+        drop(page);
 
-        self.disk.write_page(page.id, &page.data);
-        self.page_table
-            .write()
-            .await
-            .insert(page_id, PageIndex::Read(i));
+        // Release the pin taken by replace_page
+        drop(Pin::new(
+            &self.read[i],
+            PageIndex::Read(i),
+            self.replacer.clone(),
+        ));
 
         Some(page_id)
     }
 
     pub async fn fetch_page(&self, page_id: PageID) -> Option<Pin<'_>> {
         if let Some(i) = self.page_table.read().await.get(&page_id) {
+            self.stats.hits.fetch_add(1, Relaxed);
+
             return match i {
                 PageIndex::Write => Some(Pin::new(
                     &self.current,
@@ -181,7 +556,7 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
                     self.replacer.clone(),
                 )),
                 PageIndex::Read(i) => {
-                    assert!(*i < READ_SIZE);
+                    assert!(*i < self.read.len());
                     self.replacer.record_access(*i).await;
                     self.replacer.pin(*i).await;
 
@@ -194,27 +569,23 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
             };
         };
 
-        let i = match self.free.lock().await.pop() {
-            Some(i) => i,
-            None => self.replacer.evict().await?,
-        };
-        self.replacer.remove(i).await;
-        self.replacer.record_access(i).await;
-        self.replacer.pin(i).await;
-
-        assert!(i < READ_SIZE);
-
-        // Replace page
-        let page_data = self.disk.read_page(page_id).expect("Couldn't read page");
-        let mut page = self.read[i].write().await;
-        page.reset();
-        page.id = page_id;
-        page.data = page_data;
+        self.stats.misses.fetch_add(1, Relaxed);
 
-        self.page_table
-            .write()
+        let (i, mut page) = self.replace_page(page_id).await?;
+        match self
+            .disk
+            .read()
             .await
-            .insert(page.id, PageIndex::Read(i));
+            .read_page_checked(page_id, &mut page.data)
+        {
+            Ok(()) => {}
+            // Every entry has a checksum of its own, so the ones that still match can be read
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("error: page {page_id}: {e}");
+            }
+            Err(e) => panic!("Couldn't read page: {e}"),
+        }
+        drop(page);
 
         Some(Pin::new(
             &self.read[i],
@@ -223,127 +594,631 @@ impl<const READ_SIZE: usize> PageCacheInner<READ_SIZE> {
         ))
     }
 
-    pub async fn get_current(&self) -> RwLockWriteGuard<'_, PageInner> {
-        self.current.write().await
-    }
+    /// Same as `fetch_page`, but marks the page as dirty so it is written back to disk before its
+    /// frame is reused.
+    pub async fn fetch_page_mut(&self, page_id: PageID) -> Option<Pin<'_>> {
+        let pin = self.fetch_page(page_id).await?;
+        if let PageIndex::Read(i) = pin.i {
+            let mut dirty = self.dirty.lock().await;
+            let limit = self.dirty_threshold * self.read.len() as f64;
+            if !dirty.contains(&i) && (dirty.len() + 1) as f64 > limit {
+                drop(dirty);
+                if let Err(e) = self.flush_all().await {
+                    eprintln!("error: could not write back dirty pages: {e}");
+                }
+                dirty = self.dirty.lock().await;
+            }
+            dirty.insert(i);
+        }
 
-    pub async fn flush_current(&self) {
-        let current = self.current.write().await;
-        self.disk.write_page(current.id, &current.data);
+        Some(pin)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::io;
-
-    use crate::storagev2::{
-        disk::Disk,
-        key_dir::KeyData,
-        log::{Entry, EntryType},
-        page::Page,
-        page_manager::{PageCacheInner, DEFAULT_READ_SIZE},
-        test::CleanUp,
-    };
-
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_page_manager() -> io::Result<()> {
-        const DB_FILE: &str = "./test_page_manager.db";
-        let _cu = CleanUp::file(DB_FILE);
-        let disk = Disk::new(DB_FILE).await?;
+    /// Reads the entry at `offset` in `page_id`. Entries that have expired are treated as if they
+    /// don't exist.
+    pub async fn fetch_entry(&self, page_id: PageID, offset: u64) -> Option<Entry> {
+        // The write page can move on between looking it up and locking it, by then it is on disk
+        // and fetching again reads it from there
+        let entry = loop {
+            let pin = self.fetch_page(page_id).await?;
+            let page = pin.read().await;
+            if page.id == page_id {
+                break page.read_entry(offset as usize).ok()?;
+            }
+        };
 
-        let m = PageCacheInner::<DEFAULT_READ_SIZE>::new(disk, 2, Page::new(0), 0);
+        if entry.is_expired() {
+            return None;
+        }
 
-        let mut page_w = m.get_current().await;
+        Some(entry)
+    }
 
-        let entry_a = Entry::new(b"test_keya", b"test_valuea", EntryType::Put);
-        let entry_b = Entry::new(b"test_keyb", b"test_valueb", EntryType::Put);
-        let offset_a = page_w.write_entry(&entry_a).expect("should not be full");
-        let offset_b = page_w.write_entry(&entry_b).expect("should not be full");
+    /// Every live `(key, value)` in page order, leaving out tombstones, expired entries and values
+    /// `key_dir` no longer points to. Only one page is pinned and locked at a time, so writes made
+    /// while iterating may or may not show up, and entries a compaction moves in the meantime are
+    /// missed.
+    pub fn iter_all_entries<'a>(
+        &'a self,
+        key_dir: &'a RwLock<KeyDir>,
+    ) -> impl Stream<Item = io::Result<(Vec<u8>, Vec<u8>)>> + 'a {
+        stream::unfold(0, move |page_id| async move {
+            if page_id >= self.next_id.load(SeqCst) {
+                return None;
+            }
 
-        assert!(offset_a == 0);
-        assert!(offset_b as usize == entry_a.len());
-        drop(page_w);
+            let entries = self.live_entries(page_id, key_dir).await;
+            Some((stream::iter(entries), page_id + 1))
+        })
+        .flatten()
+    }
 
-        let kda = KeyData {
-            page_id: 0,
-            offset: 0,
-        };
-        let kdb = KeyData {
-            page_id: 0,
-            offset: entry_a.len() as u64,
+    async fn live_entries(
+        &self,
+        page_id: PageID,
+        key_dir: &RwLock<KeyDir>,
+    ) -> Vec<io::Result<(Vec<u8>, Vec<u8>)>> {
+        let Some(pin) = self.fetch_page(page_id).await else {
+            let e = format!("no free frame to read page {page_id}");
+            return vec![Err(io::Error::other(e))];
         };
-        let page_a = m
-            .fetch_page(kda.page_id)
-            .await
-            .expect("should fetch current page");
-        let got_a = page_a.read().await.read_entry(kda.offset as usize).unwrap();
-
-        let page_b = m
-            .fetch_page(kdb.page_id)
-            .await
-            .expect("should fetch current page");
-        let got_b = page_b.read().await.read_entry(kdb.offset as usize).unwrap();
 
-        assert!(
-            entry_a == got_a,
-            "\nExpected: {:?}\nGot: {:?}\n",
-            entry_a,
-            got_a
-        );
-        assert!(
-            entry_b == got_b,
-            "\nExpected: {:?}\nGot: {:?}\n",
-            entry_b,
-            got_b
-        );
+        let mut entries = Vec::new();
+        let mut error = None;
+        {
+            let page = pin.read().await;
+            let mut offset = 0;
+            loop {
+                match page.read_entry_raw(offset) {
+                    Ok(entry) => {
+                        let len = entry.len();
+                        entries.push((offset, entry));
+                        offset += len;
+                    }
+                    Err(PageError::NoEntry) => break,
+                    Err(e) => {
+                        let e = format!("page {page_id} offset {offset}: {e:?}");
+                        error = Some(io::Error::new(io::ErrorKind::InvalidData, e));
+                        break;
+                    }
+                }
+            }
+        }
+        pin.unpin().await;
 
-        Ok(())
-    }
+        let kd = key_dir.read().await;
+        let mut live: Vec<_> = entries
+            .into_iter()
+            .filter(|(offset, entry)| {
+                entry.t == EntryType::Put
+                    && !entry.is_expired()
+                    && kd.get(&entry.key) == Some(&self.key_data(page_id, *offset as u64))
+            })
+            .map(|(_, entry)| {
+                entry
+                    .validate()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let entry = entry.decompress()?;
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_replacer() -> io::Result<()> {
-        const DB_FILE: &str = "./test_replacer.db";
-        let _cu = CleanUp::file(DB_FILE);
-        let disk = Disk::new(DB_FILE).await?;
+                Ok((entry.key.to_vec(), entry.value.to_vec()))
+            })
+            .collect();
+        live.extend(error.map(Err));
 
-        let m = PageCacheInner::<3>::new(disk, 2, Page::new(0), 0);
+        live
+    }
 
+    /// Reads the pages that aren't cached yet into read frames, up to one per frame at a time in
+    /// parallel, so later `fetch_page` calls for them are hits. Stops early if every frame is
+    /// pinned.
+    pub async fn prefetch_pages(self: &Arc<Self>, ids: &[PageID]) -> io::Result<()> {
+        let mut missing = Vec::new();
         {
-            let _ = m.new_page().await.expect("should have space for page 1"); // ts = 0
-            let _ = m.new_page().await.expect("should have space for page 2"); // ts = 1
-            let _ = m.new_page().await.expect("should have space for page 3"); // ts = 2
+            let page_table = self.page_table.read().await;
+            for id in ids {
+                if !page_table.contains_key(id) && !missing.contains(id) {
+                    missing.push(*id);
+                }
+            }
+        }
 
-            let kd1 = KeyData {
-                page_id: 1,
-                offset: 0,
-            };
-            let kd2 = KeyData {
-                page_id: 2,
-                offset: 0,
-            };
-            let kd3 = KeyData {
-                page_id: 3,
-                offset: 0,
-            };
+        for chunk in missing.chunks(self.read.len()) {
+            let mut reads = JoinSet::new();
+            for &page_id in chunk {
+                let m = self.clone();
+                reads.spawn_blocking(move || (page_id, m.disk.blocking_read().read_page(page_id)));
+            }
 
-            m.fetch_page(kd1.page_id).await; // ts = 3
-            m.fetch_page(kd2.page_id).await; // ts = 4
-            m.fetch_page(kd1.page_id).await; // ts = 5
+            while let Some(res) = reads.join_next().await {
+                let (page_id, data) = res.map_err(io::Error::other)?;
+                let data = data?;
 
-            m.fetch_page(kd1.page_id).await; // ts = 6
-            m.fetch_page(kd2.page_id).await; // ts = 7
-            m.fetch_page(kd1.page_id).await; // ts = 8
-            m.fetch_page(kd2.page_id).await; // ts = 9
+                // Someone else may have fetched it while it was being read
+                if self.page_table.read().await.contains_key(&page_id) {
+                    continue;
+                }
 
-            m.fetch_page(kd3.page_id).await; // ts = 10 - Least accessed, should get evicted
+                let Some((i, mut page)) = self.replace_page(page_id).await else {
+                    return Ok(());
+                };
+                page.data = data;
+                drop(page);
+
+                // Release the pin taken by replace_page
+                self.replacer.unpin(i).await;
+            }
         }
 
-        let new_page_id = m.new_page().await.expect("a page should have been evicted");
-        assert!(new_page_id == 4, "Got: {}", new_page_id);
+        Ok(())
+    }
 
-        let pages = &m.read;
-        let expected_ids = vec![1, 2, 4];
+    /// Drops `page_id` from the cache, writing it back first if it is dirty, and frees its frame.
+    /// Fails if the page isn't cached, is the write page, or is pinned.
+    pub async fn evict_page(&self, page_id: PageID) -> io::Result<()> {
+        let not_cached = || io::Error::new(io::ErrorKind::NotFound, "page isn't cached");
+        let i = match self.page_table.read().await.get(&page_id) {
+            Some(PageIndex::Read(i)) => *i,
+            Some(PageIndex::Write) => {
+                let e = "the write page can't be evicted";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+            None => return Err(not_cached()),
+        };
+
+        // Same order as `replace_page`. Pages are only pinned under the page table lock, so the
+        // count can't go up while it is held.
+        let mut page = self.read[i].write().await;
+        let mut page_table = self.page_table.write().await;
+        if page.id != page_id || page_table.get(&page_id) != Some(&PageIndex::Read(i)) {
+            return Err(not_cached());
+        }
+        if self.replacer.pin_count(i).await != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "page is pinned",
+            ));
+        }
+
+        if self.dirty.lock().await.remove(&i) {
+            self.disk.read().await.write_page(page.id, &page.data);
+            self.stats.dirty_flushes.fetch_add(1, Relaxed);
+        }
+        page_table.remove(&page_id);
+        page.reset();
+        self.replacer.remove(i).await;
+        self.free.lock().await.push(i);
+
+        Ok(())
+    }
+
+    /// Claims a pinned read frame for `page_id`, evicting if there are no free frames. The page
+    /// previously held by the frame is written back first if it is dirty.
+    async fn replace_page(
+        &self,
+        page_id: PageID,
+    ) -> Option<(usize, RwLockWriteGuard<'_, PageInner>)> {
+        let i = match self.free.lock().await.pop() {
+            Some(i) => i,
+            None => {
+                let i = self.replacer.evict().await?;
+                self.stats.evictions.fetch_add(1, Relaxed);
+                i
+            }
+        };
+        self.replacer.remove(i).await;
+        self.replacer.record_access(i).await;
+        self.replacer.pin(i).await;
+
+        assert!(i < self.read.len());
+
+        let mut page = self.read[i].write().await;
+        let mut page_table = self.page_table.write().await;
+
+        if page_table.get(&page.id) == Some(&PageIndex::Read(i)) {
+            page_table.remove(&page.id);
+        }
+        if self.dirty.lock().await.remove(&i) {
+            self.disk.read().await.write_page(page.id, &page.data);
+            self.stats.dirty_flushes.fetch_add(1, Relaxed);
+        }
+
+        page.clear(page_id);
+        page_table.insert(page_id, PageIndex::Read(i));
+
+        Some((i, page))
+    }
+
+    pub async fn get_current(&self) -> RwLockWriteGuard<'_, PageInner> {
+        self.current.write().await
+    }
+
+    pub async fn lock_lists(&self) -> RwLockReadGuard<'_, ()> {
+        self.lists.read().await
+    }
+
+    pub async fn flush_current(&self) {
+        let current = self.current.write().await;
+        self.disk.read().await.write_page(current.id, &current.data);
+    }
+
+    pub async fn write_entry_auto(&self, entry: &Entry) -> io::Result<(PageID, usize)> {
+        let mut current = self.get_current().await;
+
+        // Only worth moving on once, an entry that doesn't fit a new page never will
+        if current.remaining_capacity() < entry.len() {
+            self.replace_current(&mut current).await?;
+        }
+        let offset = current
+            .write_entry(entry, None)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "entry too large"))?;
+        self.log_write(current.id, offset, entry).await?;
+
+        Ok((current.id, offset as usize))
+    }
+
+    pub async fn log_write(&self, page_id: PageID, offset: u64, entry: &Entry) -> io::Result<()> {
+        self.wal.lock().await.append(page_id, offset, entry)?;
+
+        self.entries.fetch_add(1, Relaxed);
+        if entry.t == EntryType::Delete {
+            self.deleted.fetch_add(1, Relaxed);
+        }
+
+        // Writers log while holding the current page, so the sequence follows the write order
+        let seq = self.write_seq.fetch_add(1, Relaxed) + 1;
+        if self.writes.receiver_count() > 0 {
+            let _ = self.writes.send((seq, entry.as_bytes().freeze()));
+        }
+
+        Ok(())
+    }
+
+    /// Writes every modified page to disk and truncates the WAL, since all of its records are now
+    /// reflected in the data file.
+    pub async fn checkpoint(&self) -> io::Result<()> {
+        // Writers log while holding the current page, so take it first to keep lock order
+        let current = self.current.write().await;
+        let mut wal = self.wal.lock().await;
+
+        self.disk.read().await.write_page(current.id, &current.data);
+        self.flush_all().await?;
+
+        wal.truncate()
+    }
+
+    /// Writes every dirty read page back to disk. The pages stay cached, but are clean afterwards.
+    pub async fn flush_all(&self) -> io::Result<()> {
+        // Frames are locked before `dirty` everywhere else, so it is only held to copy it
+        let mut dirty: Vec<_> = self.dirty.lock().await.iter().copied().collect();
+        dirty.sort_unstable();
+
+        for i in dirty {
+            let page = self.read[i].read().await;
+            // Already written back if the frame was replaced or evicted since
+            if !self.dirty.lock().await.remove(&i) {
+                continue;
+            }
+            self.disk.read().await.write_page(page.id, &page.data);
+            self.stats.dirty_flushes.fetch_add(1, Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Number of compactions since startup, counted right before each one swaps in the new file.
+    /// It only changes while the key dir is write locked, so it can be read along with locations
+    /// from the key dir.
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(SeqCst)
+    }
+
+    /// Bytes taken by page frames, the read frames plus the current page.
+    pub fn memory_usage(&self) -> usize {
+        (self.read.len() + 1) * PAGE_SIZE
+    }
+
+    /// Accesses the replacer has recorded for the frame holding `page_id`. 0 unless the page is
+    /// in a read frame, the current page isn't tracked and a page's history goes with its frame.
+    pub async fn access_count(&self, page_id: PageID) -> usize {
+        let i = match self.page_table.read().await.get(&page_id) {
+            Some(PageIndex::Read(i)) => *i,
+            _ => return 0,
+        };
+
+        self.replacer.page_history(i).await.len()
+    }
+
+    /// When the frame holding `page_id` was last accessed, `None` unless the page is in a read
+    /// frame, see `access_count`.
+    pub async fn last_access_time(&self, page_id: PageID) -> Option<Instant> {
+        let i = match self.page_table.read().await.get(&page_id) {
+            Some(PageIndex::Read(i)) => *i,
+            _ => return None,
+        };
+
+        self.replacer.last_access(i).await
+    }
+
+    /// How many `Pin`s are held on the frame holding `page_id`, `None` unless the page is in a
+    /// read frame, see `access_count`. The write page is never pinned.
+    pub async fn pin_count(&self, page_id: PageID) -> Option<u64> {
+        let i = match self.page_table.read().await.get(&page_id) {
+            Some(PageIndex::Read(i)) => *i,
+            _ => return None,
+        };
+
+        Some(self.replacer.pin_count(i).await)
+    }
+
+    pub fn should_compact(&self) -> bool {
+        let entries = self.entries.load(Relaxed);
+        let deleted = self.deleted.load(Relaxed);
+
+        entries > 0
+            && deleted as f64 / entries as f64 > self.deletion_ratio_threshold
+            && !self.compacting.load(Relaxed)
+    }
+
+    /// Compacts the pages before the current one while writes carry on in the current page. Does
+    /// nothing if a compaction is already running.
+    pub async fn compact(&self, key_dir: &RwLock<KeyDir>) -> io::Result<()> {
+        if self
+            .compacting
+            .compare_exchange(false, true, SeqCst, SeqCst)
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        let res = self.compact_before_current(key_dir).await;
+        self.compacting.store(false, SeqCst);
+
+        res
+    }
+
+    async fn compact_before_current(&self, key_dir: &RwLock<KeyDir>) -> io::Result<()> {
+        // List nodes may point at any node before them, so none can be written until the swap and
+        // those already in the current page have to end up before `end` too
+        let _lists = self.lists.write().await;
+
+        // Everything before the current page is made durable, later writes only touch the current
+        // page and the ones after it
+        let mut current = self.current.write().await;
+        if current.iter_entries().any(|e| e.t == EntryType::ListNode) {
+            self.replace_current(&mut current).await?;
+        }
+        let end = current.id;
+        self.flush_all().await?;
+        self.disk.read().await.sync()?;
+        let entries = self.entries.load(SeqCst);
+        let deleted = self.deleted.load(SeqCst);
+        drop(current);
+
+        let compaction = self.disk.read().await.compact_pages(end, key_dir).await?;
+        let kept = compaction.kept() as u64;
+
+        let mut kd = key_dir.write().await;
+        let mut disk = self.disk.write().await;
+        // Before anything moves, so a read that checks the count before and after can tell
+        self.compactions.fetch_add(1, SeqCst);
+
+        // The dropped records are in the old file already and their page ids are about to be
+        // reused, so this has to happen before the swap
+        self.wal.lock().await.retain_from(end)?;
+        disk.swap(compaction, &mut kd).await?;
+        drop(disk);
+        self.write_hint(&kd).await?;
+
+        // Cached pages before `end` are from the old file
+        self.drop_read_pages(end).await;
+        drop(kd);
+
+        self.entries.fetch_add(kept, SeqCst);
+        self.entries.fetch_sub(entries, SeqCst);
+        self.deleted.fetch_sub(deleted, SeqCst);
+
+        Ok(())
+    }
+
+    /// Drops every cached read page before `end` without writing it back. Readers that don't hold
+    /// the key dir while they use a page, like MGET, can still have one pinned or a miss can be
+    /// about to reuse its frame, so frames aren't freed. They get an id no page has, which makes
+    /// pinned readers fetch again, and are reused once evicted.
+    async fn drop_read_pages(&self, end: PageID) {
+        for (i, page) in self.read.iter().enumerate() {
+            let mut page = page.write().await;
+            let mut page_table = self.page_table.write().await;
+
+            if page.id < end && page_table.get(&page.id) == Some(&PageIndex::Read(i)) {
+                page_table.remove(&page.id);
+                page.clear(PageID::MAX);
+                self.dirty.lock().await.remove(&i);
+            }
+        }
+    }
+
+    /// Writes every page to disk, empties the read cache and rebuilds `key_dir` by scanning the
+    /// data file, returning how many keys it found. Writes and reads wait until it is done.
+    pub async fn reload(&self, key_dir: &RwLock<KeyDir>) -> io::Result<usize> {
+        // Writers hold the current page while they update the key dir, take it first to keep
+        // lock order
+        let current = self.current.write().await;
+        let mut kd = key_dir.write().await;
+
+        self.disk.read().await.write_page(current.id, &current.data);
+        self.flush_all().await?;
+        self.drop_read_pages(PageID::MAX).await;
+
+        kd.replace(key_dir::rebuild(&*self.disk.read().await).await);
+
+        Ok(kd.len())
+    }
+
+    /// Entries only in the current page are in the hint too. That's fine, their WAL records get
+    /// replayed on startup, which makes the data file newer than the hint so it isn't used.
+    pub async fn write_hint(&self, key_dir: &KeyDir) -> io::Result<()> {
+        let hint = hint_path(self.disk.read().await.path());
+        key_dir.write_hint_file(&hint)
+    }
+
+    pub fn stats(&self) -> PageManagerStats {
+        PageManagerStats {
+            hits: self.stats.hits.load(Relaxed),
+            misses: self.stats.misses.load(Relaxed),
+            evictions: self.stats.evictions.load(Relaxed),
+            dirty_flushes: self.stats.dirty_flushes.load(Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering::SeqCst},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures_util::StreamExt;
+    use tokio::sync::RwLock;
+
+    use crate::storagev2::{
+        disk::Disk,
+        key_dir::{bootstrap, KeyData, KeyDir},
+        log::{timestamp_millis, Entry, EntryType},
+        page::{Page, PageInner, PAGE_SIZE},
+        page_manager::{
+            ConfigError, PageCache, PageCacheInner, PageManagerBuilder, PageManagerConfig,
+            PageManagerStats,
+        },
+        test::CleanUp,
+        wal::WriteAheadLog,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_page_manager() -> io::Result<()> {
+        const DB_FILE: &str = "./test_page_manager.db";
+        const WAL_FILE: &str = "./test_page_manager.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::new(disk, wal, Page::new(0), 0);
+
+        let mut page_w = m.get_current().await;
+
+        let entry_a = Entry::new(b"test_keya", b"test_valuea", EntryType::Put);
+        let entry_b = Entry::new(b"test_keyb", b"test_valueb", EntryType::Put);
+        let offset_a = page_w
+            .write_entry(&entry_a, None)
+            .expect("should not be full");
+        let offset_b = page_w
+            .write_entry(&entry_b, None)
+            .expect("should not be full");
+
+        assert!(offset_a == 0);
+        assert!(offset_b as usize == entry_a.len());
+        drop(page_w);
+
+        let kda = KeyData {
+            file_id: 0,
+            page_id: 0,
+            offset: 0,
+        };
+        let kdb = KeyData {
+            file_id: 0,
+            page_id: 0,
+            offset: entry_a.len() as u64,
+        };
+        let page_a = m
+            .fetch_page(kda.page_id)
+            .await
+            .expect("should fetch current page");
+        let got_a = page_a.read().await.read_entry(kda.offset as usize).unwrap();
+
+        let page_b = m
+            .fetch_page(kdb.page_id)
+            .await
+            .expect("should fetch current page");
+        let got_b = page_b.read().await.read_entry(kdb.offset as usize).unwrap();
+
+        assert!(
+            entry_a == got_a,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entry_a,
+            got_a
+        );
+        assert!(
+            entry_b == got_b,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entry_b,
+            got_b
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replacer() -> io::Result<()> {
+        const DB_FILE: &str = "./test_replacer.db";
+        const WAL_FILE: &str = "./test_replacer.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::with_config(
+            disk,
+            wal,
+            Page::new(0),
+            0,
+            PageManagerConfig::new().max_read_pages(3),
+        );
+
+        {
+            let _ = m.new_page().await.expect("should have space for page 1"); // ts = 0
+            let _ = m.new_page().await.expect("should have space for page 2"); // ts = 1
+            let _ = m.new_page().await.expect("should have space for page 3"); // ts = 2
+
+            let kd1 = KeyData {
+                file_id: 0,
+                page_id: 1,
+                offset: 0,
+            };
+            let kd2 = KeyData {
+                file_id: 0,
+                page_id: 2,
+                offset: 0,
+            };
+            let kd3 = KeyData {
+                file_id: 0,
+                page_id: 3,
+                offset: 0,
+            };
+
+            m.fetch_page(kd1.page_id).await; // ts = 3
+            m.fetch_page(kd2.page_id).await; // ts = 4
+            m.fetch_page(kd1.page_id).await; // ts = 5
+
+            m.fetch_page(kd1.page_id).await; // ts = 6
+            m.fetch_page(kd2.page_id).await; // ts = 7
+            m.fetch_page(kd1.page_id).await; // ts = 8
+            m.fetch_page(kd2.page_id).await; // ts = 9
+
+            m.fetch_page(kd3.page_id).await; // ts = 10 - Least accessed, should get evicted
+        }
+
+        let new_page_id = m.new_page().await.expect("a page should have been evicted");
+        assert!(new_page_id == 4, "Got: {}", new_page_id);
+
+        let pages = &m.read;
+        let expected_ids = vec![1, 2, 4];
         let mut actual_ids = Vec::new();
         for page in pages.iter() {
             actual_ids.push(page.read().await.id)
@@ -356,6 +1231,701 @@ mod test {
             actual_ids
         );
 
+        // Page 4 took over page 3's frame, which starts its history over at ts = 11
+        let expected_histories = vec![vec![0, 3, 5, 6, 8], vec![1, 4, 7, 9], vec![11]];
+        let mut histories = Vec::new();
+        for i in 0..pages.len() {
+            histories.push(m.replacer.page_history(i).await);
+        }
+
+        assert!(
+            expected_histories == histories,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected_histories,
+            histories
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dirty_write_back() -> io::Result<()> {
+        const DB_FILE: &str = "./test_dirty_write_back.db";
+        const WAL_FILE: &str = "./test_dirty_write_back.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::with_config(
+            disk,
+            wal,
+            Page::new(0),
+            0,
+            PageManagerConfig::new().max_read_pages(1),
+        );
+
+        let page_id = m.new_page().await.expect("should have space for page 1");
+        let entry = Entry::new(b"test_key", b"test_value", EntryType::Put);
+        let offset = {
+            let pin = m
+                .fetch_page_mut(page_id)
+                .await
+                .expect("should fetch page 1");
+            let mut page_w = pin.write().await;
+            page_w
+                .write_entry(&entry, None)
+                .expect("should not be full")
+        };
+
+        // Only one read frame, so this evicts page 1
+        let _ = m.new_page().await.expect("page 1 should have been evicted");
+        assert!(m.page_table.read().await.get(&page_id).is_none());
+
+        let pin = m.fetch_page(page_id).await.expect("should fetch page 1");
+        let got = pin.read().await.read_entry(offset as usize);
+
+        assert!(
+            got.as_ref() == Ok(&entry),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entry,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_evict_page() -> io::Result<()> {
+        const DB_FILE: &str = "./test_evict_page.db";
+        const WAL_FILE: &str = "./test_evict_page.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::new(disk, wal, Page::new(0), 0);
+
+        let page_id = m.new_page().await.expect("should have space for page 1");
+        let entry = Entry::new(b"test_key", b"test_value", EntryType::Put);
+        let pin = m
+            .fetch_page_mut(page_id)
+            .await
+            .expect("should fetch page 1");
+        let offset = pin
+            .write()
+            .await
+            .write_entry(&entry, None)
+            .expect("should not be full");
+
+        let got = m.evict_page(page_id).await.map_err(|e| e.kind());
+        assert!(got == Err(io::ErrorKind::ResourceBusy), "Got: {:?}", got);
+        pin.unpin().await;
+
+        // Dirty, so written back on the way out
+        m.evict_page(page_id).await?;
+        assert!(m.page_table.read().await.get(&page_id).is_none());
+        assert!(m.stats().dirty_flushes == 1);
+        let got = m.evict_page(page_id).await.map_err(|e| e.kind());
+        assert!(got == Err(io::ErrorKind::NotFound), "Got: {:?}", got);
+        let got = m.evict_page(0).await.map_err(|e| e.kind());
+        assert!(got == Err(io::ErrorKind::InvalidInput), "Got: {:?}", got);
+
+        let pin = m.fetch_page(page_id).await.expect("should fetch page 1");
+        let got = pin.read().await.read_entry(offset as usize);
+        assert!(
+            got.as_ref() == Ok(&entry),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            entry,
+            got
+        );
+        assert!(m.stats().misses == 1, "the fetch should have gone to disk");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_all_while_evicting() -> io::Result<()> {
+        const DB_FILE: &str = "./test_flush_all_while_evicting.db";
+        const WAL_FILE: &str = "./test_flush_all_while_evicting.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = Arc::new(PageCacheInner::with_config(
+            disk,
+            wal,
+            Page::new(0),
+            0,
+            PageManagerConfig::new().max_read_pages(2),
+        ));
+
+        let mut pages = Vec::new();
+        for n in 0..8u8 {
+            let page_id = m.new_page().await.expect("should have space");
+            let entry = Entry::new(&[b'k', n], &[n; 16], EntryType::Put);
+            let pin = m.fetch_page_mut(page_id).await.expect("should fetch page");
+            let offset = pin.write().await.write_entry(&entry, None);
+            pin.unpin().await;
+            pages.push((page_id, offset.expect("should not be full"), entry));
+        }
+
+        // A miss part way through replacing a dirty frame, which is locked and `dirty` is next
+        let i = *m
+            .dirty
+            .lock()
+            .await
+            .iter()
+            .next()
+            .expect("a frame is dirty");
+        let frame = m.read[i].write().await;
+        let flusher = {
+            let m = m.clone();
+            tokio::spawn(async move { m.flush_all().await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let dirty = tokio::time::timeout(Duration::from_secs(5), m.dirty.lock())
+            .await
+            .expect("flush_all shouldn't hold dirty while it waits for a frame");
+        drop(dirty);
+        drop(frame);
+        flusher.await.expect("flusher shouldn't panic")?;
+
+        // Nearly every fetch misses and evicts a dirty frame while another task writes them back
+        let done = Arc::new(AtomicBool::new(false));
+        let flusher = {
+            let (m, done) = (m.clone(), done.clone());
+            tokio::spawn(async move {
+                while !done.load(SeqCst) {
+                    m.flush_all().await.expect("should write back");
+                }
+            })
+        };
+        let fetchers: Vec<_> = (0..4)
+            .map(|n| {
+                let m = m.clone();
+                let ids: Vec<_> = pages.iter().map(|(page_id, _, _)| *page_id).collect();
+                tokio::spawn(async move {
+                    for page_id in ids.iter().cycle().skip(n).take(2000) {
+                        let pin = m.fetch_page_mut(*page_id).await.expect("should fetch page");
+                        pin.unpin().await;
+                    }
+                })
+            })
+            .collect();
+        let both = async {
+            for fetcher in fetchers {
+                fetcher.await.expect("fetcher shouldn't panic");
+            }
+            done.store(true, SeqCst);
+            flusher.await.expect("flusher shouldn't panic");
+        };
+        tokio::time::timeout(Duration::from_secs(30), both)
+            .await
+            .expect("flush_all and evictions shouldn't deadlock");
+
+        for (page_id, offset, entry) in pages {
+            let pin = m.fetch_page(page_id).await.expect("should fetch page");
+            let got = pin.read().await.read_entry(offset as usize);
+            pin.unpin().await;
+            assert!(
+                got.as_ref() == Ok(&entry),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                entry,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    // Shared between connection tasks as is, without a lock of its own around it
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<PageCache>();
+        assert_send_sync::<PageCacheInner>();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_config() -> io::Result<()> {
+        const DB_FILE: &str = "./test_config.db";
+        const WAL_FILE: &str = "./test_config.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+
+        let config = PageManagerConfig::new().max_read_pages(0);
+        assert!(config.validate() == Err(ConfigError::NoReadPages));
+        let config = PageManagerConfig::new().dirty_threshold(f64::NAN);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DirtyThreshold(_))
+        ));
+
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+        let built = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .config(PageManagerConfig::new().k_value(0))
+            .build();
+        assert!(matches!(built, Err(ConfigError::ZeroK)));
+
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+        let config = PageManagerConfig::new()
+            .max_read_pages(4)
+            .dirty_threshold(0.5);
+        let m = PageCacheInner::with_config(disk, wal, Page::new(0), 0, config);
+
+        // Two of the four frames may be dirty, the third writes the first two back
+        for expected in [1, 2, 1] {
+            let page_id = m.new_page().await.expect("should have space");
+            let pin = m.fetch_page_mut(page_id).await.expect("should fetch page");
+            pin.unpin().await;
+
+            let dirty = m.dirty.lock().await.len();
+            assert!(
+                dirty == expected,
+                "\nExpected: {}\nGot: {}\n",
+                expected,
+                dirty
+            );
+        }
+        assert!(m.stats().dirty_flushes == 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_write_entry_auto() -> io::Result<()> {
+        const DB_FILE: &str = "./test_write_entry_auto.db";
+        const WAL_FILE: &str = "./test_write_entry_auto.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::new(disk, wal, Page::new(0), 0);
+
+        let mut written = Vec::new();
+        for i in 0..20 {
+            let key = format!("key_{}", i);
+            let entry = Entry::new(key.as_bytes(), b"value", EntryType::Put);
+            written.push((m.write_entry_auto(&entry).await?, entry));
+        }
+        assert!(written.last().unwrap().0 .0 > 0, "should have rotated");
+
+        for ((page_id, offset), entry) in written {
+            let got = match m.fetch_page(page_id).await {
+                Some(pin) => pin.read().await.read_entry(offset),
+                None => panic!("should fetch page {}", page_id),
+            };
+            assert!(
+                got.as_ref() == Ok(&entry),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                entry,
+                got
+            );
+        }
+
+        let too_large = Entry::new(b"key", &[0; PAGE_SIZE], EntryType::Put);
+        let err = m
+            .write_entry_auto(&too_large)
+            .await
+            .expect_err("shouldn't fit");
+        assert!(err.kind() == io::ErrorKind::InvalidInput, "Got: {:?}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_next_id_after_restart() -> io::Result<()> {
+        const DB_FILE: &str = "./test_next_id_after_restart.db";
+        const WAL_FILE: &str = "./test_next_id_after_restart.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::new(disk, wal, Page::new(0), 0);
+        let mut written = Vec::new();
+        for i in 0..20 {
+            let entry = Entry::new(format!("key_{}", i).as_bytes(), b"value", EntryType::Put);
+            written.push((m.write_entry_auto(&entry).await?, entry));
+        }
+        // The last page is only in the wal
+        drop(m);
+        let last_id = written.last().unwrap().0 .0;
+
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+        wal.replay(&disk)?;
+        let (_, latest, latest_id) = bootstrap(&disk).await;
+        assert!(
+            latest_id == last_id,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            last_id,
+            latest_id
+        );
+
+        let m = PageCacheInner::new(disk, wal, latest, latest_id);
+        for i in 20..40 {
+            let entry = Entry::new(format!("key_{}", i).as_bytes(), b"value", EntryType::Put);
+            let (page_id, offset) = m.write_entry_auto(&entry).await?;
+            assert!(page_id >= last_id, "page {} reused after restart", page_id);
+            written.push(((page_id, offset), entry));
+        }
+        assert!(m.inc_id() > written.last().unwrap().0 .0);
+
+        // Nothing written before the restart was overwritten
+        for ((page_id, offset), entry) in written {
+            let got = match m.fetch_page(page_id).await {
+                Some(pin) => pin.read().await.read_entry(offset),
+                None => panic!("should fetch page {}", page_id),
+            };
+            assert!(
+                got.as_ref() == Ok(&entry),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                entry,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_expired() -> io::Result<()> {
+        const DB_FILE: &str = "./test_fetch_expired.db";
+        const WAL_FILE: &str = "./test_fetch_expired.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::new(disk, wal, Page::new(0), 0);
+
+        let mut expired = Entry::new(b"expired", b"value", EntryType::Put);
+        expired.expire_at = Some(timestamp_millis() - 1000);
+        let mut live = Entry::new(b"live", b"value", EntryType::Put);
+        live.expire_at = Some(timestamp_millis() + 60 * 1000);
+
+        let mut page_w = m.get_current().await;
+        let offset_expired = page_w
+            .write_entry(&expired, None)
+            .expect("should not be full");
+        let offset_live = page_w.write_entry(&live, None).expect("should not be full");
+        drop(page_w);
+
+        assert!(m.fetch_entry(0, offset_expired).await.is_none());
+
+        let got = m.fetch_entry(0, offset_live).await;
+        assert!(
+            got.as_ref() == Some(&live),
+            "\nExpected: {:?}\nGot: {:?}\n",
+            live,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_iter_all_entries() -> io::Result<()> {
+        const DB_FILE: &str = "./test_iter_all_entries.db";
+        const WAL_FILE: &str = "./test_iter_all_entries.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::new(disk, wal, Page::new(0), 0);
+        let key_dir = RwLock::new(KeyDir::default());
+
+        let mut expected = Vec::new();
+        for i in 0..20 {
+            let key = format!("key_{}", i);
+            let entry = Entry::new(key.as_bytes(), b"value", EntryType::Put);
+            let (page_id, offset) = m.write_entry_auto(&entry).await?;
+            let kd = m.key_data(page_id, offset as u64);
+            key_dir.write().await.insert(key.as_bytes(), kd);
+            expected.push((key.into_bytes(), b"value".to_vec()));
+        }
+
+        // Overwritten, deleted and expired keys
+        let mut kd = key_dir.write().await;
+        let entry = Entry::new(b"key_0", b"new_value", EntryType::Put);
+        let (page_id, offset) = m.write_entry_auto(&entry).await?;
+        kd.insert(b"key_0", m.key_data(page_id, offset as u64));
+        expected[0].1 = b"new_value".to_vec();
+
+        m.write_entry_auto(&Entry::new(b"key_1", b"", EntryType::Delete))
+            .await?;
+        kd.remove(b"key_1");
+        expected.remove(1);
+
+        let mut entry = Entry::new(b"key_2", b"value", EntryType::Put);
+        entry.expire_at = Some(timestamp_millis() - 1000);
+        let (page_id, offset) = m.write_entry_auto(&entry).await?;
+        kd.insert(b"key_2", m.key_data(page_id, offset as u64));
+        expected.remove(1);
+        drop(kd);
+
+        let mut got = m
+            .iter_all_entries(&key_dir)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<io::Result<Vec<_>>>()?;
+        got.sort();
+        expected.sort();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_all() -> io::Result<()> {
+        const DB_FILE: &str = "./test_flush_all.db";
+        const WAL_FILE: &str = "./test_flush_all.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::with_config(
+            disk,
+            wal,
+            Page::new(0),
+            0,
+            PageManagerConfig::new().max_read_pages(2),
+        );
+
+        let mut written = Vec::new();
+        for i in 0..2 {
+            let page_id = m.new_page().await.expect("should have space for page");
+            let entry = Entry::new(format!("key_{}", i).as_bytes(), b"value", EntryType::Put);
+
+            let pin = m.fetch_page_mut(page_id).await.expect("should fetch page");
+            let offset = pin
+                .write()
+                .await
+                .write_entry(&entry, None)
+                .expect("should not be full");
+            drop(pin);
+
+            written.push((page_id, offset, entry));
+        }
+
+        m.flush_all().await?;
+        assert!(m.dirty.lock().await.is_empty());
+        drop(m);
+
+        let disk = Disk::new(DB_FILE).await?;
+        for (page_id, offset, entry) in written {
+            let mut page = PageInner::new(page_id);
+            page.data = disk.read_page(page_id)?;
+            let got = page.read_entry(offset as usize);
+
+            assert!(
+                got.as_ref() == Ok(&entry),
+                "\nExpected: {:?}\nGot: {:?}\n",
+                entry,
+                got
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stats() -> io::Result<()> {
+        const DB_FILE: &str = "./test_stats.db";
+        const WAL_FILE: &str = "./test_stats.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageCacheInner::with_config(
+            disk,
+            wal,
+            Page::new(0),
+            0,
+            PageManagerConfig::new().max_read_pages(1),
+        );
+
+        let page_id = m.new_page().await.expect("should have space for page 1");
+        m.fetch_page_mut(page_id)
+            .await
+            .expect("should fetch page 1"); // hit
+
+        let _ = m.new_page().await.expect("page 1 should be evicted"); // eviction, flush
+        m.fetch_page(page_id).await.expect("should fetch page 1"); // miss, eviction
+        m.fetch_page(0).await.expect("should fetch current page"); // hit
+
+        let expected = PageManagerStats {
+            hits: 2,
+            misses: 1,
+            evictions: 2,
+            dirty_flushes: 1,
+        };
+        let got = m.stats();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compaction() -> io::Result<()> {
+        const DB_FILE: &str = "./test_compaction.db";
+        const WAL_FILE: &str = "./test_compaction.wal";
+        const HINT_FILE: &str = "./test_compaction.hint";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let _cu_hint = CleanUp::file(HINT_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let config = PageManagerConfig::new()
+            .deletion_ratio_threshold(0.3)
+            .max_read_pages(2);
+        let m = PageCacheInner::with_config(disk, wal, Page::new(0), 0, config);
+        let kd = RwLock::new(KeyDir::default());
+
+        async fn write(m: &PageCacheInner, kd: &RwLock<KeyDir>, entry: Entry) {
+            let (page_id, offset) = m
+                .write_entry_auto(&entry)
+                .await
+                .expect("should write entry");
+
+            let mut kd = kd.write().await;
+            match entry.t {
+                EntryType::Put | EntryType::ListNode => {
+                    kd.insert(&entry.key, m.key_data(page_id, offset as u64))
+                }
+                EntryType::Delete => kd.remove(&entry.key),
+            };
+        }
+
+        for i in 0..20 {
+            let key = format!("key_{}", i);
+            let value = format!("value_{}", i);
+            write(
+                &m,
+                &kd,
+                Entry::new(key.as_bytes(), value.as_bytes(), EntryType::Put),
+            )
+            .await;
+        }
+        // Cache a page from before the compaction, it has to be dropped
+        m.fetch_entry(0, 0).await.expect("should fetch first entry");
+        assert!(!m.should_compact());
+
+        for i in 0..10 {
+            let key = format!("key_{}", i);
+            write(&m, &kd, Entry::new(key.as_bytes(), &[], EntryType::Delete)).await;
+        }
+        assert!(m.should_compact());
+
+        m.compact(&kd).await?;
+        assert!(!m.should_compact());
+
+        write(&m, &kd, Entry::new(b"key_20", b"value_20", EntryType::Put)).await;
+
+        for i in 0..21 {
+            let key = format!("key_{}", i);
+            let data = kd
+                .read()
+                .await
+                .get(key.as_bytes())
+                .map(|d| (d.page_id, d.offset));
+            let Some((page_id, offset)) = data else {
+                assert!(i < 10, "{} should be live", key);
+                continue;
+            };
+            assert!(i >= 10, "{} should have been deleted", key);
+
+            let entry = m
+                .fetch_entry(page_id, offset)
+                .await
+                .expect("live entry should be readable");
+            assert!(entry.key == key.as_bytes());
+            assert!(entry.value == format!("value_{}", i).as_bytes());
+        }
+
+        m.checkpoint().await?;
+        let disk = Disk::new(DB_FILE).await?;
+        let (got, _, _) = bootstrap(&disk).await;
+        let expected = kd.read().await;
+        assert!(
+            got == *expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            *expected,
+            got
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prefetch_pages() -> io::Result<()> {
+        const DB_FILE: &str = "./test_prefetch_pages.db";
+        const WAL_FILE: &str = "./test_prefetch_pages.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        for page_id in 1..=3 {
+            let mut page = PageInner::new(page_id);
+            page.data[0] = page_id as u8;
+            disk.write_page(page_id, &page.data);
+        }
+
+        let m = Arc::new(PageCacheInner::with_config(
+            disk,
+            wal,
+            Page::new(0),
+            0,
+            PageManagerConfig::new().max_read_pages(4),
+        ));
+
+        // The current page and duplicates are skipped
+        m.prefetch_pages(&[1, 2, 3, 1, 0]).await?;
+        let cached = m.page_table.read().await.len();
+        assert!(cached == 4, "Got: {}", cached);
+
+        for page_id in 1..=3 {
+            let pin = m.fetch_page(page_id).await.expect("should fetch page");
+            let page = pin.read().await;
+            assert!(page.id == page_id && page.data[0] == page_id as u8);
+        }
+
+        let expected = PageManagerStats {
+            hits: 3,
+            ..Default::default()
+        };
+        let got = m.stats();
+        assert!(
+            got == expected,
+            "\nExpected: {:?}\nGot: {:?}\n",
+            expected,
+            got
+        );
+
         Ok(())
     }
 }
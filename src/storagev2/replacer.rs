@@ -1,4 +1,7 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Instant,
+};
 
 use tokio::sync::{mpsc, oneshot};
 
@@ -7,6 +10,8 @@ struct LRUKNode {
     i: usize,
     history: Vec<u64>,
     pin: u64,
+    // Timestamps only order accesses, this is when the latest one happened
+    last_access: Instant,
 }
 
 impl LRUKNode {
@@ -15,6 +20,7 @@ impl LRUKNode {
             i,
             history: vec![ts],
             pin: 0,
+            last_access: Instant::now(),
         }
     }
 
@@ -31,18 +37,34 @@ impl LRUKNode {
     }
 }
 
-#[derive(Default, Debug)]
-struct LRUKReplacer {
+pub const DEFAULT_K: usize = 2;
+
+pub type DefaultReplacer = LRUKReplacer<DEFAULT_K>;
+
+/// `K` is the default for `k`, which can also be picked at runtime with `with_k`.
+#[derive(Debug)]
+pub struct LRUKReplacer<const K: usize> {
     nodes: HashMap<usize, LRUKNode>,
     current_ts: u64,
     k: usize,
 }
 
-impl LRUKReplacer {
-    pub fn new(k: usize) -> Self {
+impl<const K: usize> Default for LRUKReplacer<K> {
+    fn default() -> Self {
+        Self::with_k(K)
+    }
+}
+
+impl<const K: usize> LRUKReplacer<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_k(k: usize) -> Self {
         Self {
+            nodes: HashMap::new(),
+            current_ts: 0,
             k,
-            ..Default::default()
         }
     }
 
@@ -86,7 +108,9 @@ impl LRUKReplacer {
     pub fn record_access(&mut self, i: usize) {
         match self.nodes.entry(i) {
             Entry::Occupied(mut node) => {
-                node.get_mut().history.push(self.current_ts);
+                let node = node.get_mut();
+                node.history.push(self.current_ts);
+                node.last_access = Instant::now();
                 self.current_ts += 1;
             }
             Entry::Vacant(entry) => {
@@ -102,12 +126,30 @@ impl LRUKReplacer {
         }
     }
 
+    /// Pins are counted, a frame only becomes evictable again once every pin is released.
     pub fn unpin(&mut self, i: usize) {
         if let Some(node) = self.nodes.get_mut(&i) {
-            node.pin -= 1;
+            match node.pin.checked_sub(1) {
+                Some(pin) => node.pin = pin,
+                None => eprintln!("replacer error: frame {i} unpinned more than it was pinned"),
+            }
         }
     }
 
+    pub fn pin_count(&self, i: usize) -> u64 {
+        self.nodes.get(&i).map_or(0, |node| node.pin)
+    }
+
+    /// Timestamps of the recorded accesses to frame `i`, oldest first. Empty if it isn't tracked.
+    pub fn page_history(&self, i: usize) -> &[u64] {
+        self.nodes.get(&i).map_or(&[], |node| &node.history)
+    }
+
+    /// When frame `i` was last accessed, `None` if it isn't tracked.
+    pub fn last_access(&self, i: usize) -> Option<Instant> {
+        self.nodes.get(&i).map(|node| node.last_access)
+    }
+
     pub fn remove(&mut self, i: usize) {
         match self.nodes.entry(i) {
             Entry::Occupied(node) => {
@@ -127,16 +169,32 @@ pub enum LRUKMessage {
     Pin(usize),
     Unpin(usize),
     Remove(usize),
+    History {
+        i: usize,
+        reply: oneshot::Sender<Vec<u64>>,
+    },
+    LastAccess {
+        i: usize,
+        reply: oneshot::Sender<Option<Instant>>,
+    },
+    PinCount {
+        i: usize,
+        reply: oneshot::Sender<u64>,
+    },
 }
 
-pub struct LRUKActor {
-    inner: LRUKReplacer,
+pub struct LRUKActor<const K: usize> {
+    inner: LRUKReplacer<K>,
     rx: mpsc::Receiver<LRUKMessage>,
 }
 
-impl LRUKActor {
-    pub fn new(k: usize, rx: mpsc::Receiver<LRUKMessage>) -> Self {
-        let inner = LRUKReplacer::new(k);
+impl<const K: usize> LRUKActor<K> {
+    pub fn new(rx: mpsc::Receiver<LRUKMessage>) -> Self {
+        Self::with_k(rx, K)
+    }
+
+    pub fn with_k(rx: mpsc::Receiver<LRUKMessage>, k: usize) -> Self {
+        let inner = LRUKReplacer::with_k(k);
 
         Self { inner, rx }
     }
@@ -155,6 +213,21 @@ impl LRUKActor {
                 LRUKMessage::Pin(i) => self.inner.pin(i),
                 LRUKMessage::Unpin(i) => self.inner.unpin(i),
                 LRUKMessage::Remove(i) => self.inner.remove(i),
+                LRUKMessage::History { i, reply } => {
+                    if reply.send(self.inner.page_history(i).to_vec()).is_err() {
+                        eprintln!("replacer channel error: could not reply to history message");
+                    }
+                }
+                LRUKMessage::LastAccess { i, reply } => {
+                    if reply.send(self.inner.last_access(i)).is_err() {
+                        eprintln!("replacer channel error: could not reply to last access message");
+                    }
+                }
+                LRUKMessage::PinCount { i, reply } => {
+                    if reply.send(self.inner.pin_count(i)).is_err() {
+                        eprintln!("replacer channel error: could not reply to pin count message");
+                    }
+                }
             }
         }
     }
@@ -166,10 +239,14 @@ pub struct LRUKHandle {
 }
 
 impl LRUKHandle {
-    pub fn new(k: usize) -> Self {
+    pub fn new<const K: usize>() -> Self {
+        Self::with_k(K)
+    }
+
+    pub fn with_k(k: usize) -> Self {
         let (tx, rx) = mpsc::channel(256);
 
-        let mut replacer = LRUKActor::new(k, rx);
+        let mut replacer = LRUKActor::<DEFAULT_K>::with_k(rx, k);
         let _jh = tokio::spawn(async move { replacer.run().await });
 
         Self { tx }
@@ -214,4 +291,99 @@ impl LRUKHandle {
             eprintln!("replacer channel error: {e}");
         }
     }
+
+    pub async fn page_history(&self, i: usize) -> Vec<u64> {
+        let (tx, rx) = oneshot::channel();
+
+        if let Err(e) = self.tx.send(LRUKMessage::History { i, reply: tx }).await {
+            eprintln!("replacer channel error: {e}");
+        }
+
+        rx.await.expect("replacer has been killed")
+    }
+
+    pub async fn last_access(&self, i: usize) -> Option<Instant> {
+        let (tx, rx) = oneshot::channel();
+
+        if let Err(e) = self.tx.send(LRUKMessage::LastAccess { i, reply: tx }).await {
+            eprintln!("replacer channel error: {e}");
+        }
+
+        rx.await.expect("replacer has been killed")
+    }
+
+    pub async fn pin_count(&self, i: usize) -> u64 {
+        let (tx, rx) = oneshot::channel();
+
+        if let Err(e) = self.tx.send(LRUKMessage::PinCount { i, reply: tx }).await {
+            eprintln!("replacer channel error: {e}");
+        }
+
+        rx.await.expect("replacer has been killed")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storagev2::replacer::LRUKReplacer;
+
+    #[test]
+    fn test_page_history() {
+        let mut replacer = LRUKReplacer::<2>::new();
+        for i in [0, 1, 0, 2, 0] {
+            replacer.record_access(i);
+        }
+
+        assert!(replacer.page_history(0) == [0, 2, 4]);
+        assert!(replacer.page_history(1) == [1]);
+        assert!(replacer.page_history(3).is_empty());
+
+        replacer.remove(0);
+        assert!(replacer.page_history(0).is_empty());
+    }
+
+    #[test]
+    fn test_pin_count() {
+        let mut replacer = LRUKReplacer::<2>::new();
+        replacer.record_access(0);
+        replacer.record_access(1);
+
+        replacer.pin(0);
+        replacer.pin(0);
+        replacer.unpin(0);
+        assert!(replacer.pin_count(0) == 1);
+
+        // Frame 0 is older, but still has a pin
+        let got = replacer.evict();
+        assert!(got == Some(1), "Got: {:?}", got);
+
+        replacer.unpin(0);
+        replacer.unpin(0);
+        assert!(replacer.pin_count(0) == 0);
+        replacer.remove(1);
+        let got = replacer.evict();
+        assert!(got == Some(0), "Got: {:?}", got);
+    }
+
+    fn evict_after<const K: usize>(accesses: &[usize]) -> Option<usize> {
+        let mut replacer = LRUKReplacer::<K>::new();
+        for i in accesses {
+            replacer.record_access(*i);
+        }
+
+        replacer.evict()
+    }
+
+    #[test]
+    fn test_k() {
+        let accesses = [0, 1, 0, 1, 1];
+
+        // k = 2: frame 0 has a k-distance of 2, frame 1 has a k-distance of 1
+        let got = evict_after::<2>(&accesses);
+        assert!(got == Some(0), "Got: {:?}", got);
+
+        // k = 3: frame 0 hasn't been accessed k times, frame 1 has a k-distance of 3
+        let got = evict_after::<3>(&accesses);
+        assert!(got == Some(1), "Got: {:?}", got);
+    }
 }
@@ -0,0 +1,485 @@
+use std::{
+    error::Error,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use nix::{
+    sys::{stat::fstat, uio},
+    unistd,
+};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
+
+use crate::storagev2::page::PAGE_SIZE;
+
+pub type FileID = u32;
+
+/// What offsets and lengths of reads and writes have to be multiples of with `O_DIRECT`.
+pub const DIRECT_BLOCK: usize = 512;
+
+/// Bytes at the start of every segment before its data, the first 8 holding the
+/// `StorageVersion` it was written with and the rest left zeroed. A whole `DIRECT_BLOCK`, so the
+/// data after it stays aligned for `O_DIRECT`.
+pub const HEADER_LEN: u64 = DIRECT_BLOCK as u64;
+
+/// How the data in a segment is laid out, bumped whenever that changes in a way older builds
+/// can't read. Stored as a big endian u64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StorageVersion {
+    V1 = 1,
+}
+
+impl StorageVersion {
+    /// What segments are written with, and the only version they can be opened with.
+    pub const CURRENT: Self = Self::V1;
+
+    pub fn from_u64(v: u64) -> Option<Self> {
+        match v {
+            1 => Some(Self::V1),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StorageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", *self as u64)
+    }
+}
+
+/// A segment was written with a version other than the one this build supports, returned inside
+/// an `io::Error` of kind `InvalidData`. Data files from before versions were stored have none,
+/// so whatever their first 8 bytes happen to be is found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IncompatibleVersion {
+    pub found: u64,
+    pub supported: StorageVersion,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match StorageVersion::from_u64(self.found) {
+            Some(found) => write!(
+                f,
+                "data file is storage {}, only {} is supported",
+                found, self.supported
+            ),
+            None => write!(
+                f,
+                "data file has unknown storage version {}, only {} is supported",
+                self.found, self.supported
+            ),
+        }
+    }
+}
+
+impl Error for IncompatibleVersion {}
+
+// Pages are read and written with O_DIRECT as they are, test pages are too small for it
+#[cfg(not(test))]
+const _: () = assert!(PAGE_SIZE.is_multiple_of(DIRECT_BLOCK));
+
+// O_DIRECT also needs buffers aligned in memory, 4096 covers any logical block size. Reads and
+// writes go through these rather than the caller's buffer
+#[derive(Clone, FromBytes, IntoBytes, Immutable, KnownLayout)]
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; 4096]);
+
+#[cfg(not(test))]
+pub const DEFAULT_SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
+#[cfg(test)]
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1024 * PAGE_SIZE as u64;
+
+/// Splits what reads and writes as one data file into segment files of at most `segment_size`
+/// bytes, named after the data file with the segment's id appended (`main.db.0`, `main.db.1`,
+/// ...). Only the last segment is written to, once a write goes past it the last segment is
+/// synced and sealed and a new one is opened. Each segment starts with a `HEADER_LEN` byte header
+/// that offsets don't count.
+pub struct SegmentManager {
+    base: PathBuf,
+    segment_size: u64,
+    files: RwLock<Vec<File>>,
+    // Opened with O_DIRECT, bypassing the OS page cache
+    direct: bool,
+}
+
+impl SegmentManager {
+    /// Opens every existing segment of `base`, or creates the first one. A data file from before
+    /// segments existed becomes segment 0. `segment_size` is rounded up to a whole number of
+    /// pages. Fails with `IncompatibleVersion` if a segment wasn't written with
+    /// `StorageVersion::CURRENT`.
+    pub fn open(base: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        Self::open_with(base, segment_size, false)
+    }
+
+    /// Same as `open`, but every segment is opened with `O_DIRECT`, so reads and writes skip the
+    /// OS page cache. Their offsets and lengths have to be multiples of `DIRECT_BLOCK`, and
+    /// `segment_size` is rounded up to one too. Only supported on Linux.
+    pub fn open_direct(base: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        Self::open_with(base, segment_size, true)
+    }
+
+    fn open_with(base: impl AsRef<Path>, segment_size: u64, direct: bool) -> io::Result<Self> {
+        let base = base.as_ref().to_path_buf();
+        let unit = match direct {
+            true => PAGE_SIZE.max(DIRECT_BLOCK) as u64,
+            false => PAGE_SIZE as u64,
+        };
+        let segment_size = segment_size.max(1).div_ceil(unit) * unit;
+
+        let first = segment_path(&base, 0);
+        if base.is_file() && !first.exists() {
+            fs::rename(&base, &first)?;
+        }
+
+        let mut files = Vec::new();
+        loop {
+            let path = segment_path(&base, files.len() as FileID);
+            if !files.is_empty() && !path.exists() {
+                break;
+            }
+            files.push(open_segment(&path, direct)?);
+        }
+
+        Ok(Self {
+            base,
+            segment_size,
+            files: RwLock::new(files),
+            direct,
+        })
+    }
+
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    pub fn segment_size(&self) -> u64 {
+        self.segment_size
+    }
+
+    pub fn count(&self) -> usize {
+        self.files.read().unwrap().len()
+    }
+
+    /// The segment holding byte `offset` of the data.
+    pub fn file_id(&self, offset: u64) -> FileID {
+        (offset / self.segment_size) as FileID
+    }
+
+    /// Length of the data across every segment. Segments before the last count as full.
+    pub fn len(&self) -> io::Result<u64> {
+        let files = self.files.read().unwrap();
+        let last = files.last().expect("there is always a segment");
+        let last_len = (fstat(last.as_raw_fd())?.st_size as u64).saturating_sub(HEADER_LEN);
+
+        Ok((files.len() as u64 - 1) * self.segment_size + last_len)
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Most recent modification time of any segment.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        let mut modified = SystemTime::UNIX_EPOCH;
+        for f in self.files.read().unwrap().iter() {
+            modified = modified.max(f.metadata()?.modified()?);
+        }
+
+        Ok(modified)
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`. Whatever lies past the end of the data is
+    /// left as is.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if self.direct {
+            // Starts out as `buf` so whatever isn't read is still left as is
+            let mut aligned = aligned_copy(buf, offset)?;
+            self.read_at_unchecked(&mut aligned.as_mut_bytes()[..buf.len()], offset)?;
+            buf.copy_from_slice(&aligned.as_bytes()[..buf.len()]);
+
+            return Ok(());
+        }
+
+        self.read_at_unchecked(buf, offset)
+    }
+
+    fn read_at_unchecked(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let files = self.files.read().unwrap();
+
+        let mut read = 0;
+        while read < buf.len() {
+            let (file_id, at, len) = self.split(offset + read as u64, buf.len() - read);
+            let Some(f) = files.get(file_id as usize) else {
+                break;
+            };
+            uio::pread(
+                f.as_raw_fd(),
+                &mut buf[read..read + len],
+                (HEADER_LEN + at) as i64,
+            )?;
+            read += len;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` starting at `offset`, opening new segments if it runs past the last one.
+    pub fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        if self.direct {
+            let aligned = aligned_copy(data, offset)?;
+            return self.write_at_unchecked(&aligned.as_bytes()[..data.len()], offset);
+        }
+
+        self.write_at_unchecked(data, offset)
+    }
+
+    fn write_at_unchecked(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let last = self.file_id(offset + data.len().max(1) as u64 - 1);
+        if last as usize >= self.count() {
+            self.open_through(last)?;
+        }
+
+        let files = self.files.read().unwrap();
+        let mut written = 0;
+        while written < data.len() {
+            let (file_id, at, len) = self.split(offset + written as u64, data.len() - written);
+            let fd = files[file_id as usize].as_raw_fd();
+            uio::pwrite(fd, &data[written..written + len], (HEADER_LEN + at) as i64)?;
+            written += len;
+        }
+
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        for f in self.files.read().unwrap().iter() {
+            unistd::fsync(f.as_raw_fd())?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs only the segments holding `len` bytes from `offset`. With `data_only` metadata such
+    /// as the modification time may not be flushed.
+    pub fn sync_at(&self, offset: u64, len: usize, data_only: bool) -> io::Result<()> {
+        let first = self.file_id(offset);
+        let last = self.file_id(offset + len.max(1) as u64 - 1);
+
+        let files = self.files.read().unwrap();
+        for f in files.iter().take(last as usize + 1).skip(first as usize) {
+            match data_only {
+                true => unistd::fdatasync(f.as_raw_fd())?,
+                false => unistd::fsync(f.as_raw_fd())?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gives the blocks holding `len` bytes from `offset` back to the filesystem, the range reading
+    /// as zeros afterwards while every segment keeps its length. Only whole filesystem blocks are
+    /// freed, the parts of the range in partial blocks are just zeroed.
+    #[cfg(target_os = "linux")]
+    pub fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        use nix::fcntl::{fallocate, FallocateFlags};
+
+        let flags = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+        let files = self.files.read().unwrap();
+        let mut punched = 0;
+        while punched < len {
+            let (file_id, at, n) = self.split(offset + punched, (len - punched) as usize);
+            let Some(f) = files.get(file_id as usize) else {
+                break;
+            };
+            fallocate(f.as_raw_fd(), flags, (HEADER_LEN + at) as i64, n as i64)?;
+            punched += n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Moves every segment over to `base`, replacing its segments and removing any left over past
+    /// the new last one. Each segment is renamed on its own, so this isn't atomic as a whole.
+    pub fn rename_to(mut self, base: impl AsRef<Path>) -> io::Result<Self> {
+        let base = base.as_ref().to_path_buf();
+        let count = self.count();
+
+        for file_id in 0..count as FileID {
+            fs::rename(
+                segment_path(&self.base, file_id),
+                segment_path(&base, file_id),
+            )?;
+        }
+        remove_from(&base, count as FileID)?;
+
+        self.base = base;
+        Ok(self)
+    }
+
+    /// Removes every segment of `base`.
+    pub fn remove(base: impl AsRef<Path>) -> io::Result<()> {
+        remove_from(base.as_ref(), 0)
+    }
+
+    // Segment, offset in it and how much of `len` fits before the segment ends
+    fn split(&self, offset: u64, len: usize) -> (FileID, u64, usize) {
+        let at = offset % self.segment_size;
+        let len = len.min((self.segment_size - at) as usize);
+
+        (self.file_id(offset), at, len)
+    }
+
+    fn open_through(&self, file_id: FileID) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        while files.len() <= file_id as usize {
+            // Sealed, nothing is written to it again
+            let last = files.last().expect("there is always a segment");
+            unistd::fsync(last.as_raw_fd())?;
+
+            let path = segment_path(&self.base, files.len() as FileID);
+            files.push(open_segment(&path, self.direct)?);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn segment_path(base: &Path, file_id: FileID) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{}", file_id));
+
+    PathBuf::from(path)
+}
+
+/// Opens the segment at `path`, writing its header if it is new and checking its version if not.
+fn open_segment(path: &Path, direct: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    if direct {
+        set_direct(&mut options)?;
+    }
+    let f = options.open(path)?;
+
+    // Aligned either way, it is only a block
+    let mut header = aligned_copy(&[0; HEADER_LEN as usize], 0)?;
+    let header = &mut header.as_mut_bytes()[..HEADER_LEN as usize];
+    if fstat(f.as_raw_fd())?.st_size == 0 {
+        header[..8].copy_from_slice(&(StorageVersion::CURRENT as u64).to_be_bytes());
+        uio::pwrite(f.as_raw_fd(), header, 0)?;
+
+        return Ok(f);
+    }
+
+    // A file shorter than the header reads as version 0
+    uio::pread(f.as_raw_fd(), header, 0)?;
+    let found = u64::from_be_bytes(header[..8].try_into().expect("8 bytes"));
+    if found != StorageVersion::CURRENT as u64 {
+        let e = IncompatibleVersion {
+            found,
+            supported: StorageVersion::CURRENT,
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    Ok(f)
+}
+
+#[cfg(target_os = "linux")]
+fn set_direct(options: &mut OpenOptions) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    options.custom_flags(nix::fcntl::OFlag::O_DIRECT.bits());
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct(_: &mut OpenOptions) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "O_DIRECT is only supported on Linux",
+    ))
+}
+
+/// `data` copied into memory aligned for O_DIRECT, checking that `offset` and its length are
+/// multiples of `DIRECT_BLOCK`.
+fn aligned_copy(data: &[u8], offset: u64) -> io::Result<Vec<AlignedBlock>> {
+    if !offset.is_multiple_of(DIRECT_BLOCK as u64) || !data.len().is_multiple_of(DIRECT_BLOCK) {
+        let e = format!(
+            "O_DIRECT needs {} byte blocks, got {} bytes at {}",
+            DIRECT_BLOCK,
+            data.len(),
+            offset
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+
+    let blocks = data.len().div_ceil(size_of::<AlignedBlock>());
+    let mut aligned = vec![AlignedBlock::new_zeroed(); blocks];
+    aligned.as_mut_bytes()[..data.len()].copy_from_slice(data);
+
+    Ok(aligned)
+}
+
+fn remove_from(base: &Path, file_id: FileID) -> io::Result<()> {
+    for file_id in file_id.. {
+        match fs::remove_file(segment_path(base, file_id)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        page::PAGE_SIZE,
+        segment::{segment_path, SegmentManager},
+        test::CleanUp,
+    };
+
+    #[test]
+    fn test_segments() -> io::Result<()> {
+        const DB_FILE: &str = "./test_segments.db";
+        let _cu = CleanUp::segments(DB_FILE);
+
+        let segments = SegmentManager::open(DB_FILE, 2 * PAGE_SIZE as u64)?;
+        assert!(segments.count() == 1);
+
+        // Straddles the first two segments
+        let data: Vec<u8> = (0..3 * PAGE_SIZE).map(|i| i as u8).collect();
+        segments.write_at(&data, PAGE_SIZE as u64)?;
+        assert!(segments.count() == 2);
+        assert!(segments.len()? == 4 * PAGE_SIZE as u64);
+        assert!(segments.file_id(3 * PAGE_SIZE as u64) == 1);
+
+        let mut buf = vec![0; data.len()];
+        segments.read_at(&mut buf, PAGE_SIZE as u64)?;
+        assert!(buf == data);
+        drop(segments);
+
+        let segments = SegmentManager::open(DB_FILE, 2 * PAGE_SIZE as u64)?;
+        assert!(segments.count() == 2);
+        assert!(segment_path(DB_FILE.as_ref(), 1).exists());
+
+        // Skipping ahead opens every segment in between
+        segments.write_at(&data[..PAGE_SIZE], 8 * PAGE_SIZE as u64)?;
+        assert!(segments.count() == 5);
+
+        let mut buf = vec![0; data.len()];
+        segments.read_at(&mut buf, PAGE_SIZE as u64)?;
+        assert!(buf == data);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::{error::Error, fmt};
+
+use tokio::sync::RwLock;
+
+use crate::storagev2::{
+    key_dir::{KeyData, KeyDir},
+    log::Entry,
+    page_manager::PageCache,
+};
+
+/// A compaction ran since the snapshot was taken, so its locations may point at other entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnapshotStale;
+
+impl fmt::Display for SnapshotStale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "snapshot is stale, the data file was compacted since it was taken"
+        )
+    }
+}
+
+impl Error for SnapshotStale {}
+
+/// A copy of the key dir at one point in time. Entries are never overwritten in place, so reading
+/// through it sees every key as it was then while writes carry on, without locking the key dir
+/// again. Compaction doesn't wait for snapshots, once one has run every read fails with
+/// `SnapshotStale`.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    kd: KeyDir,
+    compactions: u64,
+}
+
+impl Snapshot {
+    /// Copies the key dir, which stays read locked while it is copied.
+    pub async fn take(m: &PageCache, key_dir: &RwLock<KeyDir>) -> Self {
+        let kd = key_dir.read().await;
+
+        Self {
+            compactions: m.compactions(),
+            kd: kd.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.kd.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kd.is_empty()
+    }
+
+    /// The entry `k` had when the snapshot was taken, `None` if it had none or it has expired
+    /// since.
+    pub async fn get(&self, m: &PageCache, k: &[u8]) -> Result<Option<Entry>, SnapshotStale> {
+        match self.kd.get(k) {
+            Some(data) => self.fetch(m, k, data).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Keys in `[start, end)` and where their entries were, see `KeyDir::scan`. `fetch` reads
+    /// them.
+    pub fn scan<'a>(
+        &'a self,
+        start: &[u8],
+        end: &[u8],
+    ) -> impl Iterator<Item = (&'a [u8], &'a KeyData)> {
+        self.kd.scan(start, end)
+    }
+
+    /// Every key and where its entry was, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &KeyData)> {
+        self.kd.scan(b"", b"")
+    }
+
+    /// Reads the entry for `k` at `data`, one of the locations from `scan` or `iter`.
+    pub async fn fetch(
+        &self,
+        m: &PageCache,
+        k: &[u8],
+        data: &KeyData,
+    ) -> Result<Option<Entry>, SnapshotStale> {
+        self.check(m)?;
+        let entry = m.fetch_entry(data.page_id, data.offset).await;
+        // A compaction that started while reading counts too, whatever was read may be from the
+        // new file
+        self.check(m)?;
+
+        match entry {
+            Some(entry) if entry.key != k => Err(SnapshotStale),
+            entry => Ok(entry),
+        }
+    }
+
+    fn check(&self, m: &PageCache) -> Result<(), SnapshotStale> {
+        match m.compactions() == self.compactions {
+            true => Ok(()),
+            false => Err(SnapshotStale),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use crate::storagev2::{
+        disk::Disk,
+        key_dir::KeyDir,
+        log::{Entry, EntryType},
+        page::Page,
+        page_manager::{PageCache, PageManagerBuilder},
+        snapshot::{Snapshot, SnapshotStale},
+        test::CleanUp,
+        wal::WriteAheadLog,
+    };
+
+    async fn write(m: &PageCache, kd: &RwLock<KeyDir>, entry: Entry) -> io::Result<()> {
+        let (page_id, offset) = m.write_entry_auto(&entry).await?;
+
+        let mut kd = kd.write().await;
+        match entry.t {
+            EntryType::Put | EntryType::ListNode => {
+                kd.insert(&entry.key, m.key_data(page_id, offset as u64))
+            }
+            EntryType::Delete => kd.remove(&entry.key),
+        };
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot() -> io::Result<()> {
+        const DB_FILE: &str = "./test_snapshot.db";
+        const WAL_FILE: &str = "./test_snapshot.wal";
+        const HINT_FILE: &str = "./test_snapshot.hint";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let _cu_hint = CleanUp::file(HINT_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let m = PageManagerBuilder::new(disk, wal, Page::new(0), 0)
+            .build()
+            .expect("default config should be valid");
+        let kd = Arc::new(RwLock::new(KeyDir::default()));
+
+        for i in 0..20 {
+            let key = format!("key_{}", i);
+            write(&m, &kd, Entry::new(key.as_bytes(), b"old", EntryType::Put)).await?;
+        }
+        let snapshot = Snapshot::take(&m, &kd).await;
+
+        // Neither shows up in the snapshot
+        write(&m, &kd, Entry::new(b"key_0", b"new", EntryType::Put)).await?;
+        write(&m, &kd, Entry::new(b"key_1", &[], EntryType::Delete)).await?;
+        write(&m, &kd, Entry::new(b"key_20", b"new", EntryType::Put)).await?;
+
+        for key in ["key_0", "key_1"] {
+            let got = snapshot
+                .get(&m, key.as_bytes())
+                .await
+                .map(|e| e.map(|e| e.value));
+            let expected = Ok(Some("old".into()));
+            assert!(
+                got == expected,
+                "\nExpected: {:?}\nGot: {:?}\n",
+                expected,
+                got
+            );
+        }
+        assert!(snapshot.get(&m, b"key_20").await == Ok(None));
+
+        assert!(snapshot.len() == 20);
+        let keys: Vec<_> = snapshot.scan(b"key_1", b"key_2").map(|(k, _)| k).collect();
+        assert!(keys.len() == 11, "Got: {:?}", keys);
+        for (k, data) in snapshot.iter() {
+            let entry = snapshot.fetch(&m, k, data).await;
+            assert!(
+                entry.is_ok_and(|e| e.is_some()),
+                "{:?} should be readable",
+                k
+            );
+        }
+
+        m.compact(&kd).await?;
+        let got = snapshot.get(&m, b"key_2").await;
+        assert!(got == Err(SnapshotStale), "Got: {:?}", got);
+
+        // A new one is fine again
+        let snapshot = Snapshot::take(&m, &kd).await;
+        let got = snapshot.get(&m, b"key_0").await.map(|e| e.map(|e| e.value));
+        assert!(got == Ok(Some("new".into())), "Got: {:?}", got);
+
+        Ok(())
+    }
+}
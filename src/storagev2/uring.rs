@@ -0,0 +1,203 @@
+use std::{
+    fs::OpenOptions,
+    io,
+    path::Path,
+    rc::Rc,
+    sync::mpsc as std_mpsc,
+    thread::{self, JoinHandle},
+};
+
+use tokio::sync::mpsc;
+use tokio_uring::fs::File;
+
+use crate::storagev2::{disk::DiskBackend, page::PageID};
+
+enum Request {
+    Read {
+        offset: u64,
+        len: usize,
+        // The bytes read, fewer than `len` past the end of the file
+        reply: std_mpsc::SyncSender<io::Result<Vec<u8>>>,
+    },
+    Write {
+        offset: u64,
+        data: Vec<u8>,
+        reply: std_mpsc::SyncSender<io::Result<()>>,
+    },
+    Sync {
+        reply: std_mpsc::SyncSender<io::Result<()>>,
+    },
+}
+
+/// A single data file read and written through io_uring. The ring runs on its own thread, each
+/// request is submitted from a task there so any number can be in flight at once, while callers
+/// block until theirs completes just like with `Disk`. Unlike `Disk` the file isn't split into
+/// segments and writes aren't synced until `sync` is called.
+pub struct IoUringDisk {
+    tx: Option<mpsc::UnboundedSender<Request>>,
+    ring: Option<JoinHandle<()>>,
+}
+
+impl IoUringDisk {
+    pub fn open(file: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let ring = thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || tokio_uring::start(serve(File::from_std(file), rx)))?;
+
+        Ok(Self {
+            tx: Some(tx),
+            ring: Some(ring),
+        })
+    }
+
+    fn send<T>(&self, request: impl FnOnce(std_mpsc::SyncSender<T>) -> Request) -> T {
+        let (reply, rx) = std_mpsc::sync_channel(1);
+        self.tx
+            .as_ref()
+            .expect("only taken on drop")
+            .send(request(reply))
+            .expect("ring thread outlives the disk");
+
+        rx.recv().expect("ring thread should reply")
+    }
+}
+
+impl Drop for IoUringDisk {
+    fn drop(&mut self) {
+        // The ring stops once its channel closes
+        drop(self.tx.take());
+        if let Some(ring) = self.ring.take() {
+            if ring.join().is_err() {
+                eprintln!("error: io_uring thread panicked");
+            }
+        }
+    }
+}
+
+impl DiskBackend for IoUringDisk {
+    fn read_page_into(&self, page_id: PageID, buf: &mut [u8]) -> io::Result<()> {
+        let offset = buf.len() as u64 * u64::from(page_id);
+        let len = buf.len();
+
+        let data = self.send(|reply| Request::Read { offset, len, reply })?;
+        buf[..data.len()].copy_from_slice(&data);
+
+        Ok(())
+    }
+
+    fn write_page_from(&self, page_id: PageID, data: &[u8]) -> io::Result<()> {
+        let offset = data.len() as u64 * u64::from(page_id);
+        let data = data.to_vec();
+
+        self.send(|reply| Request::Write {
+            offset,
+            data,
+            reply,
+        })
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.send(|reply| Request::Sync { reply })
+    }
+}
+
+async fn serve(file: File, mut rx: mpsc::UnboundedReceiver<Request>) {
+    let file = Rc::new(file);
+    while let Some(request) = rx.recv().await {
+        let file = file.clone();
+        tokio_uring::spawn(async move {
+            // Nobody to tell if the caller has gone away
+            match request {
+                Request::Read { offset, len, reply } => {
+                    let _ = reply.send(read(&file, offset, len).await);
+                }
+                Request::Write {
+                    offset,
+                    data,
+                    reply,
+                } => {
+                    let _ = reply.send(write(&file, offset, data).await);
+                }
+                Request::Sync { reply } => {
+                    let _ = reply.send(file.sync_all().await);
+                }
+            }
+        });
+    }
+}
+
+async fn read(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let read = data.len();
+        let buf = Vec::with_capacity(len - read);
+        let (n, buf) = file.read_at(buf, offset + read as u64).await;
+        if n? == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf);
+    }
+
+    Ok(data)
+}
+
+async fn write(file: &File, mut offset: u64, mut data: Vec<u8>) -> io::Result<()> {
+    while !data.is_empty() {
+        let (n, buf) = file.write_at(data, offset).await;
+        let n = n?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        data = buf;
+        data.drain(..n);
+        offset += n as u64;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        disk::{DiskBackend, IoUringDisk},
+        page::PAGE_SIZE,
+        test::CleanUp,
+    };
+
+    #[test]
+    fn test_io_uring_disk() -> io::Result<()> {
+        const DB_FILE: &str = "./test_io_uring_disk.db";
+        let _cu = CleanUp::file(DB_FILE);
+
+        let disk = IoUringDisk::open(DB_FILE)?;
+        let pages: Vec<Vec<u8>> = (0..4).map(|i| vec![i as u8 + 1; PAGE_SIZE]).collect();
+        for (page_id, data) in pages.iter().enumerate() {
+            disk.write_page_from(page_id as u32, data)?;
+        }
+        disk.sync()?;
+        drop(disk);
+
+        let disk = IoUringDisk::open(DB_FILE)?;
+        let mut buf = vec![0; PAGE_SIZE];
+        for (page_id, data) in pages.iter().enumerate().rev() {
+            disk.read_page_into(page_id as u32, &mut buf)?;
+            assert!(&buf == data, "page {} doesn't match", page_id);
+        }
+
+        // Past the end of the file
+        disk.read_page_into(10, &mut buf)?;
+        assert!(buf == pages[0]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    collections::{hash_map, HashMap},
+    io,
+    os::fd::AsRawFd,
+    path::Path,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use nix::{sys::uio, unistd};
+use tokio::fs::{File, OpenOptions};
+
+use crate::storagev2::{
+    disk::Disk,
+    log::Entry,
+    page::{PageID, PAGE_SIZE},
+};
+
+pub struct WriteAheadLog {
+    file: File,
+    len: u64,
+}
+
+impl WriteAheadLog {
+    // page_id + offset + entry_len
+    pub const RECORD_HEADER_LEN: usize = 4 + 8 + 8;
+
+    pub async fn new(file: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file)
+            .await?;
+        let len = file.metadata().await?.len();
+
+        Ok(Self { file, len })
+    }
+
+    /// Durably records that `entry` is written to `page_id` at `offset`.
+    pub fn append(&mut self, page_id: PageID, offset: u64, entry: &Entry) -> io::Result<()> {
+        let bytes = entry.as_bytes();
+
+        let mut record = BytesMut::with_capacity(Self::RECORD_HEADER_LEN + bytes.len());
+        record.put_u32(page_id);
+        record.put_u64(offset);
+        record.put_u64(bytes.len() as u64);
+        record.put(bytes);
+
+        let fd = self.file.as_raw_fd();
+        let mut written = 0;
+        while written < record.len() {
+            written += uio::pwrite(fd, &record[written..], (self.len as usize + written) as i64)?;
+        }
+        unistd::fsync(fd)?;
+        self.len += record.len() as u64;
+
+        Ok(())
+    }
+
+    /// Applies every logged write that didn't make it into its page on disk. Returns the number of
+    /// records that had to be replayed.
+    pub fn replay(&self, disk: &Disk) -> io::Result<usize> {
+        let buf = self.read_all()?;
+
+        let mut pages: HashMap<PageID, [u8; PAGE_SIZE]> = HashMap::new();
+        let mut replayed = 0;
+        for (page_id, offset, bytes) in records(&buf) {
+            let len = bytes.len();
+            if offset + len > PAGE_SIZE {
+                break;
+            }
+
+            let data = match pages.entry(page_id) {
+                hash_map::Entry::Occupied(e) => e.into_mut(),
+                // Not checked, torn writes are what the log is replayed over
+                hash_map::Entry::Vacant(e) => {
+                    let data = e.insert([0; PAGE_SIZE]);
+                    disk.read_page_into(page_id, data)?;
+                    data
+                }
+            };
+            if data[offset..offset + len] != *bytes {
+                crate::put_bytes!(data, bytes, offset, len);
+                replayed += 1;
+            }
+        }
+
+        for (page_id, data) in &pages {
+            disk.write_page(*page_id, data);
+        }
+
+        Ok(replayed)
+    }
+
+    /// Drops the records for pages before `page_id`, for when those pages are durable and their
+    /// ids are about to be reused.
+    pub fn retain_from(&mut self, page_id: PageID) -> io::Result<()> {
+        let buf = self.read_all()?;
+
+        let mut kept = BytesMut::new();
+        let mut src = &buf[..];
+        for (id, _, bytes) in records(&buf) {
+            let len = Self::RECORD_HEADER_LEN + bytes.len();
+            if id >= page_id {
+                kept.put(&src[..len]);
+            }
+            src = &src[len..];
+        }
+
+        let fd = self.file.as_raw_fd();
+        let mut written = 0;
+        while written < kept.len() {
+            written += uio::pwrite(fd, &kept[written..], written as i64)?;
+        }
+        unistd::ftruncate(fd, kept.len() as i64)?;
+        unistd::fsync(fd)?;
+        self.len = kept.len() as u64;
+
+        Ok(())
+    }
+
+    fn read_all(&self) -> io::Result<Vec<u8>> {
+        let fd = self.file.as_raw_fd();
+        let mut buf = vec![0; self.len as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            match uio::pread(fd, &mut buf[read..], read as i64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        buf.truncate(read);
+
+        Ok(buf)
+    }
+
+    pub fn truncate(&mut self) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        unistd::ftruncate(fd, 0)?;
+        unistd::fsync(fd)?;
+        self.len = 0;
+
+        Ok(())
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+/// Iterates over the (page_id, offset, entry bytes) records in `buf`, stopping at the first one
+/// that is torn or corrupt.
+fn records(buf: &[u8]) -> impl Iterator<Item = (PageID, usize, &[u8])> {
+    let mut src = buf;
+    std::iter::from_fn(move || {
+        if src.remaining() < WriteAheadLog::RECORD_HEADER_LEN {
+            return None;
+        }
+        let page_id = src.get_u32();
+        let offset = src.get_u64() as usize;
+        let len = src.get_u64() as usize;
+
+        // A torn write at the tail of the log is expected after a crash, anything after it
+        // was never acknowledged
+        if len > src.remaining() || !valid_entry(&src[..len]) {
+            src = &[];
+            return None;
+        }
+
+        let (bytes, rest) = src.split_at(len);
+        src = rest;
+
+        Some((page_id, offset, bytes))
+    })
+}
+
+fn valid_entry(bytes: &[u8]) -> bool {
+    if bytes.len() < Entry::METADATA_LEN_V0 + Entry::CHECKSUM_LEN {
+        return false;
+    }
+
+    let (data, mut checksum) = bytes.split_at(bytes.len() - Entry::CHECKSUM_LEN);
+    checksum.get_u32() == crc32fast::hash(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::storagev2::{
+        disk::Disk,
+        key_dir::bootstrap,
+        log::{Entry, EntryType},
+        page::PageInner,
+        test::CleanUp,
+        wal::WriteAheadLog,
+    };
+
+    #[tokio::test]
+    async fn test_replay() -> io::Result<()> {
+        const DB_FILE: &str = "./test_replay.db";
+        const WAL_FILE: &str = "./test_replay.wal";
+        let _cu = CleanUp::segments(DB_FILE);
+        let _cu_wal = CleanUp::file(WAL_FILE);
+        let disk = Disk::new(DB_FILE).await?;
+        let mut wal = WriteAheadLog::new(WAL_FILE).await?;
+
+        let entries = [
+            Entry::new(b"key1", b"value1", EntryType::Put),
+            Entry::new(b"key2", b"value2", EntryType::Put),
+        ];
+
+        // Only the first entry makes it to disk before the "crash"
+        let mut page = PageInner::new(0);
+        let offset = page
+            .write_entry(&entries[0], None)
+            .expect("should not be full");
+        wal.append(page.id, offset, &entries[0])?;
+        disk.write_page(page.id, &page.data);
+
+        let offset = page
+            .write_entry(&entries[1], None)
+            .expect("should not be full");
+        wal.append(page.id, offset, &entries[1])?;
+
+        let replayed = wal.replay(&disk)?;
+        assert!(replayed == 1, "Got: {}", replayed);
+
+        let (key_dir, _, _) = bootstrap(&disk).await;
+        assert!(key_dir.get(b"key1").is_some());
+        assert!(key_dir.get(b"key2").is_some());
+
+        wal.truncate()?;
+        assert!(wal.len() == 0);
+        assert!(wal.replay(&disk)? == 0);
+
+        Ok(())
+    }
+}